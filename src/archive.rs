@@ -0,0 +1,536 @@
+//! Zip and tar archives: input read in place, and output written as a stream.
+//!
+//! An archive member is named `<archive>!/<inner path>` everywhere a file path
+//! would appear (plans, report, quarantine), so its provenance is kept. Members
+//! are only ever streamed: headers are parsed from the entry stream, and output
+//! is extracted straight into the layout by [`for_each_member`].
+//!
+//! [`ArchiveWriter`] is the other direction: sorted instances are appended as
+//! entries named by their layout path, so no output tree is ever materialised.
+
+use crate::dicom::{classify_error, has_dicom_prefix, read_meta_from_reader, FailureKind, ScanFailure};
+use crate::sink::{Content, Sink, SinkError, SinkResult};
+use crate::sort::{Plan, ScanOutcome};
+use anyhow::{anyhow, bail, Context, Result};
+use dicom_object::Tag;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Separates the archive path from the member path.
+pub const MEMBER_SEPARATOR: &str = "!/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        Some(Kind::TarZst)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else {
+        None
+    }
+}
+
+/// Whether `path` names an archive we can read (by extension).
+pub fn is_archive(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// The provenance path of `inner` within `archive`.
+pub fn member_path(archive: &Path, inner: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}{}", archive.display(), MEMBER_SEPARATOR, inner))
+}
+
+/// Split a provenance path back into archive and member; `None` for plain files.
+pub fn split_member(path: &Path) -> Option<(PathBuf, String)> {
+    let s = path.to_str()?;
+    s.match_indices(MEMBER_SEPARATOR).find_map(|(i, _)| {
+        let archive = Path::new(&s[..i]);
+        is_archive(archive).then(|| (archive.to_path_buf(), s[i + MEMBER_SEPARATOR.len()..].to_string()))
+    })
+}
+
+/// Why a member name cannot be joined onto another folder, if it cannot: it is
+/// absolute, has a drive prefix or has a `..` component.
+pub(crate) fn unsafe_name(name: &str) -> Option<&'static str> {
+    if name.starts_with(['/', '\\']) {
+        return Some("is absolute");
+    }
+    let mut parts = name.split(['/', '\\']);
+    if parts.next().is_some_and(|p| p.len() >= 2 && p.as_bytes()[0].is_ascii_alphabetic() && p.as_bytes()[1] == b':') {
+        return Some("has a drive prefix");
+    }
+    name.split(['/', '\\']).any(|p| p == "..").then_some("climbs out with `..`")
+}
+
+/// Call `f` with the name and content stream of every regular file in `archive`, in archive order.
+fn walk(archive: &Path, f: &mut dyn FnMut(&str, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let open = || File::open(archive).with_context(|| format!("open archive: {}", archive.display()));
+    match kind(archive).with_context(|| format!("not a supported archive: {}", archive.display()))? {
+        Kind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(open()?))
+                .with_context(|| format!("read zip: {}", archive.display()))?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).with_context(|| format!("read zip entry {} of {}", i, archive.display()))?;
+                if entry.is_file() {
+                    let name = entry.name().to_string();
+                    f(&name, &mut entry)?;
+                }
+            }
+            Ok(())
+        }
+        Kind::Tar => walk_tar(archive, BufReader::new(open()?), f),
+        Kind::TarGz => walk_tar(archive, GzDecoder::new(BufReader::new(open()?)), f),
+        Kind::TarZst => {
+            let decoder = zstd::Decoder::new(open()?).with_context(|| format!("read zstd: {}", archive.display()))?;
+            walk_tar(archive, decoder, f)
+        }
+    }
+}
+
+fn walk_tar<R: Read>(archive: &Path, reader: R, f: &mut dyn FnMut(&str, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries().with_context(|| format!("read tar: {}", archive.display()))? {
+        let mut entry = entry.with_context(|| format!("read tar entry in {}", archive.display()))?;
+        if entry.header().entry_type().is_file() {
+            let name = entry.path()?.to_string_lossy().into_owned();
+            f(&name, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Header-parse every member of `archive`. Members without the DICOM magic are
+/// rejected without parsing; an unreadable archive is a single failure.
+///
+/// Two members with the same name would share one path, and only one of them
+/// could be extracted. A member name that is absolute or climbs out with `..`
+/// would place a quarantined copy outside the quarantine folder. Either way the
+/// archive is rejected as a whole.
+pub fn scan(archive: &Path, extra: &[Tag]) -> ScanOutcome {
+    let mut outcome = ScanOutcome::default();
+    let mut names = HashSet::new();
+    let mut reject_all = false;
+    let result = walk(archive, &mut |name, entry| {
+        if let Some(why) = unsafe_name(name) {
+            reject_all = true;
+            bail!("member name {:?} {}", name, why);
+        }
+        if !names.insert(name.to_string()) {
+            reject_all = true;
+            bail!("member {:?} appears more than once", name);
+        }
+        let path = member_path(archive, name);
+        let mut head = Vec::with_capacity(132);
+        entry.take(132).read_to_end(&mut head)?;
+        if !has_dicom_prefix(&head) {
+            let error = anyhow!("no DICM magic code");
+            outcome.failures.push(ScanFailure { path, kind: FailureKind::NotDicom, error });
+            return Ok(());
+        }
+        match read_meta_from_reader(&path, Cursor::new(head).chain(entry), extra) {
+            Ok(m) => outcome.metas.push(m),
+            Err(error) => outcome.failures.push(ScanFailure { path, kind: classify_error(&error), error }),
+        }
+        Ok(())
+    });
+    if let Err(error) = result {
+        if reject_all {
+            outcome = ScanOutcome::default();
+        }
+        outcome.failures.push(ScanFailure { path: archive.to_path_buf(), kind: FailureKind::Malformed, error });
+    }
+    outcome
+}
+
+/// Stream the member behind each plan's `src` to `f`, reading every archive once.
+pub fn for_each_member(plans: &[Plan], mut f: impl FnMut(&Plan, &mut dyn Read) -> Result<()>) -> Result<()> {
+    let mut by_archive: HashMap<PathBuf, HashMap<String, &Plan>> = HashMap::new();
+    for p in plans {
+        let (archive, inner) = split_member(&p.src).with_context(|| format!("not an archive member: {}", p.src.display()))?;
+        if by_archive.entry(archive).or_default().insert(inner, p).is_some() {
+            bail!("{} is listed twice", p.src.display());
+        }
+    }
+    let mut archives: Vec<_> = by_archive.into_iter().collect();
+    archives.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (archive, mut wanted) in archives {
+        walk(&archive, &mut |name, entry| match wanted.remove(name) {
+            Some(p) => f(p, entry),
+            None => Ok(()),
+        })?;
+        if let Some(p) = wanted.values().next() {
+            bail!("{} is no longer in the archive", p.src.display());
+        }
+    }
+    Ok(())
+}
+
+/// Entry sink for `--output-archive`: `.zip`, `.tar`, `.tar.gz`/`.tgz` or
+/// `.tar.zst`/`.tzst` by extension, or an uncompressed tar on stdout for `-`.
+///
+/// Files are written to `<path>.part` and renamed by [`Sink::finish`], so an
+/// interrupted run never leaves a truncated archive under the final name.
+pub struct ArchiveWriter {
+    path: PathBuf,
+    /// `None` once finished. Entries are written one at a time.
+    out: Mutex<Option<Out>>,
+    /// SHA-256 of every entry written so far, by entry name.
+    written: Mutex<HashMap<String, String>>,
+}
+
+enum Out {
+    Zip(Box<zip::ZipWriter<BufWriter<File>>>),
+    Tar(tar::Builder<TarStream>),
+}
+
+enum TarStream {
+    Plain(BufWriter<File>),
+    Gz(GzEncoder<BufWriter<File>>),
+    Zst(zstd::Encoder<'static, BufWriter<File>>),
+    Stdout(BufWriter<io::Stdout>),
+}
+
+impl Write for TarStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TarStream::Plain(w) => w.write(buf),
+            TarStream::Gz(w) => w.write(buf),
+            TarStream::Zst(w) => w.write(buf),
+            TarStream::Stdout(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TarStream::Plain(w) => w.flush(),
+            TarStream::Gz(w) => w.flush(),
+            TarStream::Zst(w) => w.flush(),
+            TarStream::Stdout(w) => w.flush(),
+        }
+    }
+}
+
+impl TarStream {
+    /// Write any compression trailer and flush.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            TarStream::Plain(mut w) => w.flush(),
+            TarStream::Gz(w) => w.finish()?.flush(),
+            TarStream::Zst(w) => w.finish()?.flush(),
+            TarStream::Stdout(mut w) => w.flush(),
+        }
+    }
+}
+
+/// Hashes what passes through.
+struct Hashing<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Read for Hashing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let out = if path == Path::new("-") {
+            Out::Tar(tar::Builder::new(TarStream::Stdout(BufWriter::new(io::stdout()))))
+        } else {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_ascii_lowercase();
+            let part = part_path(path);
+            let file = || -> Result<BufWriter<File>> {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent).with_context(|| format!("create dir: {}", parent.display()))?;
+                }
+                let f = File::create(&part).with_context(|| format!("create {}", part.display()))?;
+                Ok(BufWriter::new(f))
+            };
+            if name.ends_with(".zip") {
+                Out::Zip(Box::new(zip::ZipWriter::new(file()?)))
+            } else if name.ends_with(".tar") {
+                Out::Tar(tar::Builder::new(TarStream::Plain(file()?)))
+            } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+                Out::Tar(tar::Builder::new(TarStream::Gz(GzEncoder::new(file()?, Default::default()))))
+            } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+                let enc = zstd::Encoder::new(file()?, 0).context("start zstd stream")?;
+                Out::Tar(tar::Builder::new(TarStream::Zst(enc)))
+            } else {
+                bail!("unsupported output archive {} (use .zip, .tar, .tar.gz, .tar.zst or -)", path.display());
+            }
+        };
+        Ok(Self { path: path.to_path_buf(), out: Mutex::new(Some(out)), written: Mutex::default() })
+    }
+
+    /// The archive path as given (`-` for stdout).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `size` bytes from `data` as entry `name`.
+    fn add(&self, name: &str, size: u64, data: &mut dyn Read) -> SinkResult<()> {
+        let mut out = self.out.lock().unwrap();
+        if self.written.lock().unwrap().contains_key(name) {
+            return Err(SinkError::Exists(member_path(&self.path, name)));
+        }
+        let path = &self.path;
+        let out = out.as_mut().ok_or(SinkError::Unsupported { sink: "archive", op: "add entries once finished" })?;
+        let mut reader = Hashing { inner: data, hasher: Sha256::new(), len: 0 };
+        match out {
+            Out::Zip(zip) => {
+                let opts = zip::write::SimpleFileOptions::default().large_file(size >= u32::MAX as u64);
+                zip.start_file(name, opts).map_err(|e| SinkError::Other(e.into()))?;
+                io::copy(&mut reader, zip).map_err(|e| SinkError::io("write", &member_path(path, name), e))?;
+            }
+            Out::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+                header.set_cksum();
+                // Tar needs the exact size up front; never write more than announced.
+                tar.append_data(&mut header, name, (&mut reader).take(size))
+                    .map_err(|e| SinkError::io("write", &member_path(path, name), e))?;
+            }
+        }
+        if reader.len != size {
+            return Err(SinkError::Other(format!("{}: expected {} bytes, read {}", name, size, reader.len).into()));
+        }
+        let hash = reader.hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        self.written.lock().unwrap().insert(name.to_string(), hash);
+        Ok(())
+    }
+}
+
+impl Sink for ArchiveWriter {
+    fn exists(&self, dst: &Path) -> SinkResult<bool> {
+        Ok(self.written.lock().unwrap().contains_key(&entry_name(dst)))
+    }
+
+    /// Entries are compared by content hash alone.
+    fn holds(&self, dst: &Path, content: Content<'_>, _sop_uid: Option<&str>) -> SinkResult<bool> {
+        let hash = self.written.lock().unwrap().get(&entry_name(dst)).cloned();
+        match hash {
+            Some(hash) => Ok(hash == content.sha256()?),
+            None => Ok(false),
+        }
+    }
+
+    /// Buffered in memory, since a tar header needs the size up front.
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf).map_err(|e| SinkError::io("read", dst, e))?;
+        self.add(&entry_name(dst), buf.len() as u64, &mut buf.as_slice())
+    }
+
+    /// Streamed from disk with the size from `stat`.
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        let mut f = File::open(src).map_err(|e| SinkError::io("open", src, e))?;
+        let size = f.metadata().map_err(|e| SinkError::io("stat", src, e))?.len();
+        self.add(&entry_name(dst), size, &mut f)
+    }
+
+    fn remove(&self, _dst: &Path) -> SinkResult<()> {
+        Err(SinkError::Unsupported { sink: "archive", op: "replace entries" })
+    }
+
+    /// Write the trailer and move the archive to its final name.
+    fn finish(&mut self) -> SinkResult<()> {
+        let path = &self.path;
+        let io_err = |e| SinkError::io("finish", path, e);
+        match self.out.get_mut().unwrap().take() {
+            Some(Out::Zip(zip)) => zip.finish().map_err(|e| SinkError::Other(e.into()))?.flush().map_err(io_err)?,
+            Some(Out::Tar(tar)) => tar.into_inner().map_err(io_err)?.finish().map_err(io_err)?,
+            None => return Ok(()),
+        }
+        if path != Path::new("-") {
+            let part = part_path(path);
+            fs::rename(&part, path).map_err(|e| SinkError::io("rename to", path, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".part");
+    PathBuf::from(s)
+}
+
+/// The archive entry name for a destination planned under an empty output root.
+pub fn entry_name(dst: &Path) -> String {
+    dst.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::{execute, ConflictAction};
+    use crate::sink::{CopySink, MoveSink};
+    use crate::sort::{plan_operations, PlanOptions};
+    use crate::types::OnConflict;
+    use crate::synth::{instance, CT_IMAGE_STORAGE};
+    use dicom_core::VR;
+    use dicom_dictionary_std::tags;
+    use std::io::Write;
+
+    /// Encode a CT instance and also leave it in `dir`, named by its SOP UID.
+    fn dicom_bytes(dir: &Path, sop: &str, n: i32) -> Vec<u8> {
+        let bytes = instance(CT_IMAGE_STORAGE, sop, &[
+            (tags::PATIENT_ID, VR::LO, "P1"),
+            (tags::STUDY_INSTANCE_UID, VR::UI, "1.2"),
+            (tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3"),
+            (tags::INSTANCE_NUMBER, VR::IS, &n.to_string()),
+        ]);
+        std::fs::write(dir.join(sop), &bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_member_paths_round_trip() {
+        let p = member_path(Path::new("in/a!b.tar.gz"), "x/y.dcm");
+        assert_eq!(p, PathBuf::from("in/a!b.tar.gz!/x/y.dcm"));
+        assert_eq!(split_member(&p), Some((PathBuf::from("in/a!b.tar.gz"), "x/y.dcm".into())));
+        assert_eq!(split_member(Path::new("in/notes!/x.dcm")), None);
+    }
+
+    #[test]
+    fn test_scan_and_extract_zip_and_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("bundle.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let opts = zip::write::SimpleFileOptions::default();
+        for (name, bytes) in [
+            ("study/IM1", dicom_bytes(dir.path(), "1.2.3.1", 1)),
+            ("study/IM2", dicom_bytes(dir.path(), "1.2.3.2", 2)),
+            ("README.txt", b"not dicom".to_vec()),
+        ] {
+            zip.start_file(name, opts).unwrap();
+            zip.write_all(&bytes).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let tgz_path = dir.path().join("more.tgz");
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(File::create(&tgz_path).unwrap(), Default::default()));
+        let bytes = dicom_bytes(dir.path(), "1.2.3.3", 3);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "IM3", bytes.as_slice()).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let outcome = crate::sort::scan(&[zip_path.clone(), tgz_path.clone()]);
+        let mut sources: Vec<_> = outcome.metas.iter().map(|m| m.path.clone()).collect();
+        sources.sort();
+        assert_eq!(sources, [member_path(&zip_path, "study/IM1"), member_path(&zip_path, "study/IM2"), member_path(&tgz_path, "IM3")]);
+        assert_eq!(outcome.failures.len(), 1);
+        assert_eq!(outcome.failures[0].path, member_path(&zip_path, "README.txt"));
+        assert_eq!(outcome.failures[0].kind, FailureKind::NotDicom);
+
+        let out = dir.path().join("out");
+        let plans = plan_operations(&outcome.metas, &out, &PlanOptions::default());
+        let conflicts = execute(plans.clone(), &MoveSink::default(), OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert!(conflicts.is_empty());
+        let written = crate::fs_ops::collect_files(&out, false).unwrap();
+        assert_eq!(written.len(), 3);
+        assert!(written.iter().all(|p| !p.file_name().unwrap().to_string_lossy().ends_with(".part")));
+        assert!(zip_path.exists() && tgz_path.exists());
+
+        // A name that appears twice rejects the whole archive, not just one of them.
+        let twice = dir.path().join("twice.tar");
+        let mut tar = tar::Builder::new(File::create(&twice).unwrap());
+        for sop in ["1.2.3.4", "1.2.3.5"] {
+            let bytes = dicom_bytes(dir.path(), sop, 4);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "IM4", bytes.as_slice()).unwrap();
+        }
+        tar.into_inner().unwrap();
+        let outcome = crate::sort::scan(std::slice::from_ref(&twice));
+        assert!(outcome.metas.is_empty());
+        assert_eq!(outcome.failures.len(), 1);
+        assert_eq!((outcome.failures[0].path.as_path(), outcome.failures[0].kind), (twice.as_path(), FailureKind::Malformed));
+        assert!(for_each_member(&[plans[0].clone(), plans[0].clone()], |_, _| Ok(())).is_err());
+
+        // So does a name that climbs out, so quarantine never sees it.
+        for name in ["../../escaped.txt", "/etc/escaped.txt", "C:\\escaped.txt", "a/../../escaped.txt"] {
+            let slip = dir.path().join("slip.zip");
+            let mut zip = zip::ZipWriter::new(File::create(&slip).unwrap());
+            zip.start_file("ok.txt", opts).unwrap();
+            zip.start_file(name, opts).unwrap();
+            zip.write_all(b"not dicom").unwrap();
+            zip.finish().unwrap();
+            let outcome = crate::sort::scan(std::slice::from_ref(&slip));
+            assert_eq!(outcome.failures.len(), 1, "{}", name);
+            assert_eq!(outcome.failures[0].path, slip);
+        }
+
+        // Re-extracting matches what is already there.
+        let conflicts = execute(plans, &CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(conflicts.len(), 3);
+        assert!(conflicts.iter().all(|c| c.action == ConflictAction::SkippedIdentical));
+        assert_eq!(crate::fs_ops::collect_files(&out, false).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_output_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        std::fs::create_dir_all(&input).unwrap();
+        dicom_bytes(&input, "1.2.3.1", 1);
+        dicom_bytes(&input, "1.2.3.2", 2);
+        let outcome = crate::sort::scan(&crate::fs_ops::collect_files(&input, false).unwrap());
+        let plans = plan_operations(&outcome.metas, Path::new(""), &PlanOptions::default());
+
+        for name in ["out.zip", "out.tar.zst"] {
+            let path = dir.path().join(name);
+            let mut w = ArchiveWriter::create(&path).unwrap();
+            // A repeated plan finds its identical entry already written.
+            let repeated = plans.iter().chain(&plans[..1]).cloned().collect();
+            let conflicts = execute(repeated, &w, OnConflict::SkipIdentical, false, None, 1).unwrap();
+            assert!(part_path(&path).exists() && !path.exists());
+            w.finish().unwrap();
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].action, ConflictAction::SkippedIdentical);
+            assert!(!part_path(&path).exists());
+
+            let mut names = Vec::new();
+            walk(&path, &mut |n, _| {
+                names.push(n.to_string());
+                Ok(())
+            })
+            .unwrap();
+            let expected: Vec<_> = plans.iter().map(|p| entry_name(&p.dst)).collect();
+            assert_eq!(names, expected, "{}", name);
+        }
+        assert!(ArchiveWriter::create(&dir.path().join("out.rar")).is_err());
+    }
+}
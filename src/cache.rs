@@ -0,0 +1,217 @@
+//! On-disk cache of parsed headers (`--scan-cache`), so repeated runs over a
+//! growing input only parse new or changed files.
+//!
+//! [`ScanCache`] is a [`MetaSource`] in front of [`FileSource`]. An entry is
+//! keyed by absolute path and holds the file's size, mtime and inode (on Unix)
+//! when it was parsed. A file whose stamp still matches is served from the
+//! cache; any other is parsed and its entry replaced. Files that fail to parse
+//! are not cached. [`ScanCache::save`] keeps only the entries read in this run,
+//! so the cache follows the input as files are removed.
+//!
+//! The cache is a JSON file. It records the format version and the extra tags
+//! the headers were read with; if either differs, every file is parsed again.
+//! It holds header fields, PHI included, so it must be kept as private as the input.
+
+use crate::fs_ops;
+use crate::dicom::{DicomMeta, ScanFailure};
+use crate::source::{FileSource, MetaSource};
+use anyhow::{Context, Result};
+use dicom_object::Tag;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Format version written to the cache file.
+const VERSION: u32 = 1;
+
+/// What a file looked like when it was parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    size: u64,
+    modified: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        let md = fs::metadata(path).ok()?;
+        #[cfg(unix)]
+        let inode = Some(std::os::unix::fs::MetadataExt::ino(&md));
+        #[cfg(not(unix))]
+        let inode = None;
+        Some(Self { size: md.len(), modified: md.modified().ok()?, inode })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    path: PathBuf,
+    #[serde(flatten)]
+    stamp: Stamp,
+    meta: DicomMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    /// The extra tags every cached header was read with.
+    tags: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+/// How a run used the cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Served from the cache.
+    pub hits: usize,
+    /// Not cached yet, or changed since; parsed again.
+    pub parsed: usize,
+    /// Entries of the loaded cache that were not read in this run.
+    pub dropped: usize,
+}
+
+pub struct ScanCache {
+    path: PathBuf,
+    tags: Vec<String>,
+    /// The cache as loaded, by absolute path.
+    old: HashMap<PathBuf, (Stamp, DicomMeta)>,
+    /// Everything read in this run, for [`Self::save`].
+    new: Mutex<HashMap<PathBuf, (Stamp, DicomMeta)>>,
+    hits: AtomicUsize,
+    parsed: AtomicUsize,
+}
+
+impl ScanCache {
+    /// Load the cache at `path` for a scan capturing `extra` tags. With
+    /// `rebuild`, or when the file is missing, unreadable or was written for
+    /// other tags, start empty; the file is only replaced by [`Self::save`].
+    pub fn open(path: &Path, extra: &[Tag], rebuild: bool) -> Result<Self> {
+        let tags: Vec<String> = extra.iter().map(|t| t.to_string()).collect();
+        let mut old = HashMap::new();
+        if !rebuild && path.exists() {
+            let s = fs::read_to_string(path).with_context(|| format!("read scan cache: {}", path.display()))?;
+            match serde_json::from_str::<CacheFile>(&s) {
+                Ok(f) if f.version == VERSION && f.tags == tags => {
+                    old = f.entries.into_iter().map(|e| (e.path, (e.stamp, e.meta))).collect();
+                }
+                Ok(_) => tracing::info!("Scan cache {} was written for other tags or by another version; parsing every file", path.display()),
+                Err(e) => tracing::warn!("Scan cache {} is unreadable ({}); parsing every file", path.display(), e),
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            tags,
+            old,
+            new: Mutex::default(),
+            hits: AtomicUsize::new(0),
+            parsed: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let new = self.new.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            parsed: self.parsed.load(Ordering::Relaxed),
+            dropped: self.old.keys().filter(|k| !new.contains_key(*k)).count(),
+        }
+    }
+
+    /// Write the entries read in this run, replacing the file in one rename, readable by its owner only.
+    pub fn save(&self) -> Result<()> {
+        let mut entries: Vec<_> = self
+            .new
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, (stamp, meta))| Entry { path: path.clone(), stamp: stamp.clone(), meta: meta.clone() })
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let file = CacheFile { version: VERSION, tags: self.tags.clone(), entries };
+        let s = serde_json::to_string(&file).context("serialize scan cache")?;
+        // Cached headers hold patient identifiers.
+        fs_ops::write_private(&self.path, s.as_bytes())
+            .with_context(|| format!("write scan cache: {}", self.path.display()))
+    }
+}
+
+impl MetaSource for ScanCache {
+    fn read(&self, path: &Path, extra: &[Tag]) -> Result<DicomMeta> {
+        // Anything odd about the file is left for the parser to report.
+        let (Some(stamp), Ok(key)) = (Stamp::of(path), std::path::absolute(path)) else {
+            return FileSource.read(path, extra);
+        };
+        let meta = match self.old.get(&key) {
+            Some((s, m)) if *s == stamp => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                DicomMeta { path: path.to_path_buf(), ..m.clone() }
+            }
+            _ => {
+                let m = FileSource.read(path, extra)?;
+                self.parsed.fetch_add(1, Ordering::Relaxed);
+                m
+            }
+        };
+        self.new.lock().unwrap().insert(key, (stamp, meta.clone()));
+        Ok(meta)
+    }
+
+    fn failure(&self, path: &Path, error: anyhow::Error) -> ScanFailure {
+        FileSource.failure(path, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::collect_files;
+    use crate::sort::scan_source;
+    use crate::synth::{generate, SynthOptions};
+    use dicom_dictionary_std::tags;
+
+    #[test]
+    fn test_unchanged_files_are_served_from_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in");
+        let opts = SynthOptions { patients: 1, series: 1, slices: 4, rows: 8, columns: 8, junk: 1, ..Default::default() };
+        generate(&input, &opts).unwrap();
+        let files = collect_files(&input, false).unwrap();
+        let path = tmp.path().join("cache.json");
+
+        let scan = |extra: &[Tag], rebuild: bool| {
+            let cache = ScanCache::open(&path, extra, rebuild).unwrap();
+            let outcome = scan_source(&cache, &files, extra);
+            cache.save().unwrap();
+            (outcome, cache.stats())
+        };
+        let (first, stats) = scan(&[], false);
+        assert_eq!((stats.hits, stats.parsed, first.failures.len()), (0, 4, 1));
+
+        let (second, stats) = scan(&[], false);
+        assert_eq!((stats.hits, stats.parsed), (4, 0));
+        let uids = |o: &crate::sort::ScanOutcome| {
+            let mut v: Vec<_> = o.metas.iter().map(|m| (m.path.clone(), m.sop_uid.clone())).collect();
+            v.sort();
+            v
+        };
+        assert_eq!(uids(&first), uids(&second));
+
+        // A rewritten file is parsed again; a removed one is dropped.
+        let dicoms: Vec<_> = second.metas.iter().map(|m| m.path.clone()).collect();
+        let bytes = fs::read(&dicoms[0]).unwrap();
+        fs::write(&dicoms[0], [bytes.as_slice(), &[0, 0]].concat()).unwrap();
+        fs::remove_file(&dicoms[1]).unwrap();
+        let (_, stats) = scan(&[], false);
+        assert_eq!((stats.hits, stats.parsed, stats.dropped), (2, 1, 1));
+
+        let (_, stats) = scan(&[tags::MODALITY], false);
+        assert_eq!((stats.hits, stats.parsed), (0, 3));
+        let (_, stats) = scan(&[tags::MODALITY], true);
+        assert_eq!((stats.hits, stats.parsed), (0, 3));
+    }
+}
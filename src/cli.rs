@@ -1,44 +1,438 @@
-use clap::Parser;
-use std::path::PathBuf;
-use dcmsort::types::{Mode, Layout, SortBy};
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use dcmsort::planfile::Output;
+use dcmsort::types::{Mode, Layout, OnConflict, SortBy, VolumeLayout};
 
 #[derive(Parser, Debug)]
-#[command(name = "dcmsort", version, about = "Sort DICOM files by metadata (header-only).")]
+#[command(
+    name = "dcmsort",
+    version,
+    about = "Sort DICOM files by metadata (header-only).",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
-    /// Input directory containing DICOM files
-    #[arg(long, value_name = "DIR")]
-    pub input: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
-    /// Output directory
-    #[arg(long, value_name = "DIR")]
-    pub output: PathBuf,
+    /// Sort a directory (the default when no subcommand is given)
+    #[command(flatten)]
+    pub sort: SortArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Receive instances over DICOM C-STORE and sort them on arrival
+    Listen(ListenArgs),
+    /// Send a sorted output tree to a remote node over DICOM C-STORE, in sorted order
+    Send(SendArgs),
+    /// Scan and plan like the default command, but save the plan to a JSON file instead of executing it
+    Plan(PlanArgs),
+    /// Carry out a saved plan after checking that its source files are unchanged
+    Apply(ApplyArgs),
+    /// Sort files as they arrive in a hot folder, batch by batch, until stopped
+    Watch(WatchArgs),
+    /// Put files recorded in a --journal back where they came from
+    Undo(UndoArgs),
+    /// Check that every input instance is in a sorted output exactly once, with identical content
+    Verify(VerifyArgs),
+    /// Write a deterministic synthetic CT dataset (no real patient data) for testing
+    Synth(SynthArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SortArgs {
+    /// Input directory containing DICOM files, or a DICOMDIR whose records
+    /// list the files to sort
+    #[arg(long, value_name = "PATH", required = true)]
+    pub input: Option<PathBuf>,
 
     /// File operation mode
-    #[arg(long, value_enum, default_value_t = Mode::Copy)]
+    #[arg(long, value_enum, default_value_t = Mode::Copy, conflicts_with = "output_archive")]
     pub mode: Mode,
 
     /// Print planned operations without touching the filesystem
     #[arg(long, default_value_t = false)]
     pub dry_run: bool,
 
+    /// When a move falls back to copying across devices, compare SHA-256 of
+    /// the source and the copy before deleting the source
+    #[arg(long, default_value_t = false)]
+    pub verify_moves: bool,
+
+    /// Copy, move or link up to N files at once (1 keeps everything sequential;
+    /// archive output is always written one entry at a time)
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub jobs: usize,
+
     /// Follow symlinks while scanning (off by default)
     #[arg(long, default_value_t = false)]
     pub follow_symlinks: bool,
 
+    /// Sorting strategy within a series
+    #[arg(long, value_enum, default_value_t = SortBy::Auto)]
+    pub sort_by: SortBy,
+
+    /// Split series that mix orientations, image sizes or frames of reference
+    /// into sub-stacks with their own folder suffix and ordering
+    #[arg(long, default_value_t = false)]
+    pub split_series: bool,
+
+    /// Per-component ImageOrientationPatient tolerance used by --split-series
+    #[arg(long, value_name = "TOL", default_value_t = 1e-3)]
+    pub orientation_tolerance: f64,
+
+    /// Detect repeated slice positions (dynamics, fMRI, multi-echo) and
+    /// lay out each volume separately
+    #[arg(long, value_enum, default_value_t = VolumeLayout::None)]
+    pub volumes: VolumeLayout,
+
+    /// Only sort instances matching this expression, e.g.
+    /// "Modality == CT and StudyDate in 2024..2024" (repeatable; all must hold)
+    #[arg(long, value_name = "EXPR")]
+    pub filter: Vec<String>,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    #[command(flatten)]
+    pub journal: JournalArgs,
+
+    /// Write a DICOMDIR at the output root; destination paths must be valid
+    /// media file IDs (use --layout media)
+    #[arg(long, default_value_t = false)]
+    pub dicomdir: bool,
+
+    /// Write a JSON report (metadata and rejected files)
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Copy/move files that failed to parse into this directory (uses --mode)
+    #[arg(long, value_name = "DIR")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Plan and execute series by series while the input is still being
+    /// scanned, so memory is bounded by the largest series, not the input
+    #[arg(long, default_value_t = false, conflicts_with_all = ["report", "dicomdir"])]
+    pub stream: bool,
+
+    /// Folder for the temporary per-series header files of --stream
+    /// (default: the system temporary folder)
+    #[arg(long, value_name = "DIR", requires = "stream")]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Keep parsed headers in this file and reuse them for files whose size,
+    /// modification time and inode are unchanged. Every header is kept in
+    /// memory, so not with --stream
+    #[arg(long, value_name = "FILE", conflicts_with = "stream")]
+    pub scan_cache: Option<PathBuf>,
+
+    /// Ignore the existing --scan-cache and parse every file again
+    #[arg(long, default_value_t = false, requires = "scan_cache")]
+    pub rebuild_cache: bool,
+
+    /// Record what was sorted where in this file, and on later runs only sort
+    /// new or changed files, renumbering the series they join
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["stream", "dicomdir"])]
+    pub state: Option<PathBuf>,
+}
+
+impl SortArgs {
+    pub fn input(&self) -> &Path {
+        self.input.as_deref().expect("--input is required")
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub sort: SortArgs,
+
+    /// Plan file to write
+    #[arg(long = "out", value_name = "FILE")]
+    pub out: PathBuf,
+
+    /// Also record a SHA-256 of every source file, so `apply` detects changed
+    /// content even when size and modification time are unchanged
+    #[arg(long, default_value_t = false)]
+    pub hash: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    #[command(flatten)]
+    pub sort: SortArgs,
+
+    /// Seconds a file's size and modification time must stay unchanged
+    /// before it is sorted
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    pub settle: u64,
+
+    /// Also hold stable files until their series has had no new file for
+    /// this many seconds, so a series is sorted in one batch
+    #[arg(long, value_name = "SECS")]
+    pub series_quiet: Option<u64>,
+
+    /// Longest wait, in seconds, before retrying a failed batch (the wait
+    /// starts at 1 second and doubles with every failure)
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    pub max_backoff: u64,
+
+    /// Append one JSON line per batch (files, placed, conflicts, rejected, excluded,
+    /// duration, error) to this file
+    #[arg(long, value_name = "FILE")]
+    pub batch_log: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    /// Plan file written by `dcmsort plan`
+    #[arg(value_name = "PLAN")]
+    pub plan: PathBuf,
+
+    /// Print planned operations without touching the filesystem
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Write a JSON report (series and conflicts)
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// When a move falls back to copying across devices, compare SHA-256 of
+    /// the source and the copy before deleting the source
+    #[arg(long, default_value_t = false)]
+    pub verify_moves: bool,
+
+    /// Copy, move or link up to N files at once (1 keeps everything sequential;
+    /// archive output is always written one entry at a time)
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub jobs: usize,
+
+    #[command(flatten)]
+    pub journal: JournalArgs,
+}
+
+/// Recording file operations so they can be undone; shared by directory sorting and `apply`.
+#[derive(Args, Debug)]
+pub struct JournalArgs {
+    /// Record every file operation in this new journal file before doing it,
+    /// so `dcmsort undo` can reverse the run
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    pub journal: Option<PathBuf>,
+
+    /// If the run fails, undo every file operation it made (input files are
+    /// moved back, output files and folders removed)
+    #[arg(long, default_value_t = false, conflicts_with = "dry_run")]
+    pub rollback_on_error: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct UndoArgs {
+    /// Journal written by --journal
+    #[arg(value_name = "JOURNAL")]
+    pub journal: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// The unsorted input (directory or archive)
+    #[arg(long, value_name = "PATH")]
+    pub input: PathBuf,
+
+    /// The sorted output (directory or archive)
+    #[arg(long, value_name = "PATH")]
+    pub output: PathBuf,
+
+    /// Write a JSON report of every problem found (`verify`)
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ListenArgs {
+    /// TCP port to listen on
+    #[arg(long, default_value_t = 11112)]
+    pub port: u16,
+
+    /// Address to bind
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0")]
+    pub bind: String,
+
+    /// Our AE title; associations addressed to another AE title are rejected
+    #[arg(long, value_name = "AE", default_value = "DCMSORT")]
+    pub ae_title: String,
+
+    /// Calling AE title allowed to send (repeatable); any AE is accepted if omitted
+    #[arg(long = "accept-ae", value_name = "AE")]
+    pub accept_ae: Vec<String>,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SendArgs {
+    /// Sorted output tree to send
+    #[arg(long, value_name = "DIR")]
+    pub input: PathBuf,
+
+    /// Remote host
+    #[arg(long)]
+    pub host: String,
+
+    /// Remote port
+    #[arg(long, default_value_t = 104)]
+    pub port: u16,
+
+    /// Remote (called) AE title
+    #[arg(long, value_name = "AE")]
+    pub called_ae: String,
+
+    /// Our (calling) AE title
+    #[arg(long, value_name = "AE", default_value = "DCMSORT")]
+    pub calling_ae: String,
+
+    /// Extra attempts for an instance that fails
+    #[arg(long, default_value_t = 2)]
+    pub retries: u32,
+
+    /// Pause between attempts, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub retry_delay_ms: u64,
+
+    /// Write a JSON report with the outcome of every instance (`sent`)
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SynthArgs {
+    /// Directory to write the unsorted dataset into
+    #[arg(long, value_name = "DIR")]
+    pub output: PathBuf,
+
+    /// Seed for UIDs, file names and the choice of broken instances
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+
+    #[arg(long, default_value_t = 2)]
+    pub patients: u32,
+
+    /// Studies per patient
+    #[arg(long, default_value_t = 1)]
+    pub studies: u32,
+
+    /// Single-frame series per study
+    #[arg(long, default_value_t = 2)]
+    pub series: u32,
+
+    /// Slices per series (frames per multi-frame object)
+    #[arg(long, default_value_t = 20)]
+    pub slices: u32,
+
+    #[arg(long, default_value_t = 64)]
+    pub rows: u16,
+
+    #[arg(long, default_value_t = 64)]
+    pub columns: u16,
+
+    /// Distance between slices in mm
+    #[arg(long, value_name = "MM", default_value_t = 2.5)]
+    pub slice_spacing: f64,
+
+    /// Tilt every second series by this angle
+    #[arg(long, value_name = "DEG", default_value_t = 0.0)]
+    pub oblique: f64,
+
+    /// Instances that lose InstanceNumber, geometry, series/study UID or PatientID (in turn)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub missing_tags: u32,
+
+    /// Extra copies of existing instances, repeating their SOPInstanceUID
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub duplicates: u32,
+
+    /// Enhanced CT multi-frame objects per study
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub multiframe: u32,
+
+    /// Non-DICOM and broken files (text, empty, truncated, unknown transfer syntax, binary)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub junk: u32,
+}
+
+/// Where and how sorted files are written; shared by directory sorting and `listen`.
+#[derive(Args, Debug)]
+pub struct OutputArgs {
+    /// Output directory
+    #[arg(long, value_name = "DIR", required_unless_present = "output_archive")]
+    pub output: Option<PathBuf>,
+
+    /// Write the sorted layout into this archive instead of a directory
+    /// (.zip, .tar, .tar.gz, .tar.zst, or - for a tar stream on stdout)
+    #[arg(long, value_name = "FILE", conflicts_with = "output")]
+    pub output_archive: Option<PathBuf>,
+
+    /// What to do when a destination file already exists
+    #[arg(long, value_enum, default_value_t = OnConflict::SkipIdentical)]
+    pub on_conflict: OnConflict,
+
     /// Folder layout strategy
     #[arg(long, value_enum, default_value_t = Layout::PatientStudySeries)]
     pub layout: Layout,
 
-    /// Sorting strategy within a series
-    #[arg(long, value_enum, default_value_t = SortBy::Auto)]
-    pub sort_by: SortBy,
+    /// Custom path template, e.g. "{PatientID}/{StudyDate:%Y-%m}/{Modality}_{SeriesNumber:03}/{Index:05}.dcm".
+    /// Takes precedence over --layout.
+    #[arg(long, value_name = "TEMPLATE")]
+    pub template: Option<String>,
 
     /// Allow PHI-like fields (PatientName / descriptions) to appear in folder names.
     /// Default is OFF for safety.
     #[arg(long, default_value_t = false)]
     pub include_phi: bool,
 
-    /// Write a JSON report (metadata only)
+    /// Replace the patient, study and series folders of --layout with
+    /// HMAC-SHA256 pseudonyms keyed by the secret in this file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["pseudonym_key_env", "template", "include_phi"])]
+    pub pseudonym_key_file: Option<PathBuf>,
+
+    /// Like --pseudonym-key-file, but read the secret from this environment variable
+    #[arg(long, value_name = "VAR", conflicts_with_all = ["template", "include_phi"])]
+    pub pseudonym_key_env: Option<String>,
+
+    /// Write the pseudonym -> original lookup table to this file (keep it private)
     #[arg(long, value_name = "FILE")]
-    pub report: Option<PathBuf>,
+    pub pseudonym_table: Option<PathBuf>,
+
+    /// Rewrite output files per the DICOM PS3.15 Basic Application Level
+    /// Confidentiality Profile (requires --deid-map)
+    #[arg(long, default_value_t = false, requires = "deid_map")]
+    pub anonymize: bool,
+
+    /// Private mapping file holding the salt and original -> replacement
+    /// identifiers; reuse it to keep UIDs and pseudonyms consistent across runs
+    #[arg(long, value_name = "FILE", requires = "anonymize")]
+    pub deid_map: Option<PathBuf>,
+
+    /// Keep dates and times (Retain Longitudinal Temporal Information with Full Dates Option)
+    #[arg(long, default_value_t = false, requires = "anonymize")]
+    pub retain_dates: bool,
+
+    /// Keep descriptions with names, IDs and date-like numbers removed (Clean Descriptors Option)
+    #[arg(long, default_value_t = false, requires = "anonymize")]
+    pub clean_descriptors: bool,
+}
+
+impl OutputArgs {
+    pub fn output(&self) -> &Path {
+        self.output.as_deref().expect("--output is required")
+    }
+
+    /// The output directory or archive, whichever was given.
+    pub fn target(&self) -> Output {
+        match &self.output_archive {
+            Some(path) => Output::Archive(path.clone()),
+            None => Output::Dir(self.output().to_path_buf()),
+        }
+    }
 }
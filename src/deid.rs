@@ -0,0 +1,682 @@
+//! De-identification following the DICOM PS3.15 Annex E Basic Application
+//! Level Confidentiality Profile.
+//!
+//! The action table follows Table E.1-1. Dummy values (D) are zero-length
+//! instead, and the X/Z/U* choices for reference sequences are made by
+//! processing the sequence, so the UIDs inside are remapped. Attributes of the
+//! Patient (0010), Study Scheduling (0032) and Visit (0038) groups that are not
+//! in the table are removed too, so a newer identifying attribute there is
+//! never kept by default. Private attributes, curve data and overlay comments
+//! are removed, and every sequence is processed recursively.
+//!
+//! UIDs are replaced by `2.25.<n>` UIDs derived from a salted SHA-256 hash,
+//! and patients by `ANON_<hex>` pseudonyms, so the same input always maps to
+//! the same output as long as the mapping file (which holds the salt) is
+//! reused. The mapping file also records every replacement for authorised
+//! re-identification and must be kept private.
+//!
+//! Options:
+//! - *Retain Longitudinal Temporal Information with Full Dates*: keep dates and times.
+//! - *Clean Descriptors*: keep descriptions, but strip patient names,
+//!   identifiers and date-like numbers from them instead of removing them.
+//!
+//! Burned-in annotations in pixel data are not handled.
+
+use crate::dicom::DicomMeta;
+use crate::fs_ops;
+use anyhow::{Context, Result};
+use dicom_core::header::Header;
+use dicom_core::value::Value;
+use dicom_core::{DataElement, PrimitiveValue, VR};
+use dicom_dictionary_std::tags;
+use dicom_object::file::ReadPreamble;
+use dicom_object::{DefaultDicomObject, InMemDicomObject, OpenFileOptions, Tag};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct DeidOptions {
+    /// Retain Longitudinal Temporal Information with Full Dates Option.
+    pub retain_dates: bool,
+    /// Clean Descriptors Option.
+    pub clean_descriptors: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    /// X: remove the attribute.
+    Remove,
+    /// Z: replace with a zero-length value.
+    Zero,
+    /// U: replace with a consistently remapped UID.
+    Uid,
+    /// Replace with the patient pseudonym.
+    Pseudonym,
+    /// A date/time: Zero or Remove unless dates are retained.
+    Temporal(bool),
+    /// A free-text descriptor: Remove, or clean under the Clean Descriptors Option.
+    Descriptor,
+}
+
+/// Persisted salt and replacement table.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Mapping {
+    salt: String,
+    patients: BTreeMap<String, String>,
+    uids: BTreeMap<String, String>,
+}
+
+pub struct Deidentifier {
+    opts: DeidOptions,
+    mapping: Mutex<Mapping>,
+}
+
+impl Deidentifier {
+    /// Load the mapping file if it exists, otherwise start a new one with a fresh salt.
+    pub fn open(map_path: &Path, opts: DeidOptions) -> Result<Self> {
+        let mapping = if map_path.exists() {
+            let s = fs::read_to_string(map_path)
+                .with_context(|| format!("read de-identification map: {}", map_path.display()))?;
+            serde_json::from_str(&s)
+                .with_context(|| format!("parse de-identification map: {}", map_path.display()))?
+        } else {
+            Mapping { salt: fresh_salt()?, ..Default::default() }
+        };
+        Ok(Self { opts, mapping: Mutex::new(mapping) })
+    }
+
+    pub fn options(&self) -> DeidOptions {
+        self.opts
+    }
+
+    /// Write the mapping file, readable by its owner only. Contains the salt
+    /// and original identifiers.
+    pub fn save(&self, map_path: &Path) -> Result<()> {
+        let mapping = self.mapping.lock().unwrap();
+        let s = serde_json::to_string_pretty(&*mapping).context("serialize de-identification map")?;
+        fs_ops::write_private(map_path, s.as_bytes())
+            .with_context(|| format!("write de-identification map: {}", map_path.display()))?;
+        Ok(())
+    }
+
+    /// De-identified copy of a header, used for naming output paths and for the report.
+    /// Geometry and volume-splitting attributes are kept so ordering is unaffected.
+    pub fn apply_meta(&self, m: &DicomMeta) -> DicomMeta {
+        let pseudonym = self.pseudonym(m.patient_id.as_deref());
+        let phi = phi_tokens(&[m.patient_name.clone(), m.patient_id.clone()]);
+        let descriptor = |d: &Option<String>| match self.opts.clean_descriptors {
+            true => d.as_deref().map(|s| clean_text(s, &phi)).filter(|s| !s.is_empty()),
+            false => None,
+        };
+
+        DicomMeta {
+            patient_id: Some(pseudonym.clone()),
+            patient_name: Some(pseudonym),
+            study_uid: m.study_uid.as_deref().map(|u| self.uid(u)),
+            series_uid: m.series_uid.as_deref().map(|u| self.uid(u)),
+            sop_uid: m.sop_uid.as_deref().map(|u| self.uid(u)),
+            frame_of_reference_uid: m.frame_of_reference_uid.as_deref().map(|u| self.uid(u)),
+            study_date: if self.opts.retain_dates { m.study_date.clone() } else { None },
+            study_time: if self.opts.retain_dates { m.study_time.clone() } else { None },
+            study_id: None,
+            accession_number: None,
+            study_description: descriptor(&m.study_description),
+            series_description: descriptor(&m.series_description),
+            // Raw tags may hold anything; only keep them if they survive the action table.
+            extra: m
+                .extra
+                .iter()
+                .filter(|(k, _)| k.parse::<Tag>().is_ok_and(|t| action(t).is_none() && t.group() % 2 == 0))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            ..m.clone()
+        }
+    }
+
+    /// Read `src` in full, de-identify it and write it to `dst`.
+    pub fn write_file(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut obj = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Auto)
+            .open_file(src)
+            .with_context(|| format!("open DICOM: {}", src.display()))?;
+        self.rewrite(&mut obj);
+        obj.write_to_file(dst)
+            .with_context(|| format!("write de-identified {} -> {}", src.display(), dst.display()))?;
+        Ok(())
+    }
+
+    /// Like [`Self::write_file`] for a stream, returning the encoded file;
+    /// `src` only labels errors.
+    pub fn encode(&self, src: &Path, data: &mut dyn Read) -> Result<Vec<u8>> {
+        let mut obj = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Auto)
+            .from_reader(data)
+            .with_context(|| format!("read DICOM: {}", src.display()))?;
+        self.rewrite(&mut obj);
+        let mut buf = Vec::new();
+        obj.write_all(&mut buf)
+            .with_context(|| format!("encode de-identified {}", src.display()))?;
+        Ok(buf)
+    }
+
+    fn rewrite(&self, obj: &mut DefaultDicomObject) {
+        let phi = phi_tokens(&[str_value(obj, tags::PATIENT_NAME), str_value(obj, tags::PATIENT_ID)]);
+        self.process(obj, &phi, true);
+
+        let sop = obj
+            .element(tags::SOP_INSTANCE_UID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|s| s.trim_end_matches('\0').trim().to_string());
+        let meta_sop = obj.meta().media_storage_sop_instance_uid.trim_end_matches('\0').trim().to_string();
+        let new_sop = sop.unwrap_or_else(|| self.uid(&meta_sop));
+        obj.update_meta(|m| m.media_storage_sop_instance_uid = new_sop);
+    }
+
+    fn process(&self, obj: &mut InMemDicomObject, phi: &[String], top_level: bool) {
+        let entries: Vec<(Tag, VR)> = obj.iter().map(|e| (e.tag(), e.vr())).collect();
+
+        for (tag, vr) in entries {
+            // Private attributes, curve data (50xx) and overlay data/comments (60xx).
+            let group = tag.group();
+            if group % 2 == 1
+                || (0x5000..=0x50FF).contains(&group)
+                || ((0x6000..=0x60FF).contains(&group) && matches!(tag.element(), 0x3000 | 0x4000))
+            {
+                obj.remove_element(tag);
+                continue;
+            }
+
+            match action(tag) {
+                None if vr == VR::SQ => {
+                    obj.update_value(tag, |v| {
+                        if let Some(items) = v.items_mut() {
+                            for item in items.iter_mut() {
+                                self.process(item, phi, false);
+                            }
+                        }
+                    });
+                }
+                None => {}
+                Some(Action::Remove) => {
+                    obj.remove_element(tag);
+                }
+                Some(Action::Zero) => {
+                    obj.put(DataElement::empty(tag, vr));
+                }
+                Some(Action::Temporal(zero)) => {
+                    if self.opts.retain_dates {
+                        continue;
+                    }
+                    if zero {
+                        obj.put(DataElement::empty(tag, vr));
+                    } else {
+                        obj.remove_element(tag);
+                    }
+                }
+                Some(Action::Descriptor) => {
+                    let cleaned = match self.opts.clean_descriptors {
+                        true => str_value(obj, tag).map(|s| clean_text(&s, phi)),
+                        false => None,
+                    };
+                    match cleaned {
+                        Some(c) if !c.is_empty() => {
+                            obj.put(DataElement::new(tag, vr, PrimitiveValue::from(c)));
+                        }
+                        _ => {
+                            obj.remove_element(tag);
+                        }
+                    }
+                }
+                Some(Action::Pseudonym) => {
+                    let id = str_value(obj, tags::PATIENT_ID);
+                    let p = self.pseudonym(id.as_deref());
+                    obj.put(DataElement::new(tag, vr, PrimitiveValue::from(p)));
+                }
+                Some(Action::Uid) => {
+                    let mapped: Option<Vec<String>> = str_value(obj, tag)
+                        .map(|s| s.split('\\').map(|u| self.uid(u.trim())).collect());
+                    if let Some(uids) = mapped {
+                        let value = PrimitiveValue::Strs(uids.into_iter().collect());
+                        obj.put(DataElement::new(tag, vr, Value::Primitive(value)));
+                    }
+                }
+            }
+        }
+
+        if top_level {
+            let mut method = String::from("DCMSORT PS3.15 E.1 Basic Profile");
+            if self.opts.retain_dates {
+                method.push_str("; Retain Longitudinal Temporal Information Full Dates");
+            }
+            if self.opts.clean_descriptors {
+                method.push_str("; Clean Descriptors");
+            }
+            obj.put(DataElement::new(tags::PATIENT_IDENTITY_REMOVED, VR::CS, PrimitiveValue::from("YES")));
+            obj.put(DataElement::new(tags::DEIDENTIFICATION_METHOD, VR::LO, PrimitiveValue::from(method)));
+            let temporal = if self.opts.retain_dates { "UNMODIFIED" } else { "REMOVED" };
+            obj.put(DataElement::new(
+                tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+                VR::CS,
+                PrimitiveValue::from(temporal),
+            ));
+        }
+    }
+
+    /// Consistent replacement UID under the `2.25` root.
+    pub fn uid(&self, original: &str) -> String {
+        let original = original.trim_end_matches('\0').trim();
+        if original.is_empty() {
+            return String::new();
+        }
+        let mut mapping = self.mapping.lock().unwrap();
+        if let Some(u) = mapping.uids.get(original) {
+            return u.clone();
+        }
+        let digest = salted_hash(&mapping.salt, "uid", original);
+        let n = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        let new = format!("2.25.{}", n);
+        mapping.uids.insert(original.to_string(), new.clone());
+        new
+    }
+
+    /// Consistent patient pseudonym; every instance without a PatientID shares one.
+    pub fn pseudonym(&self, patient_id: Option<&str>) -> String {
+        let id = patient_id.unwrap_or("").trim();
+        let mut mapping = self.mapping.lock().unwrap();
+        if let Some(p) = mapping.patients.get(id) {
+            return p.clone();
+        }
+        let digest = salted_hash(&mapping.salt, "patient", id);
+        let hex: String = digest[..6].iter().map(|b| format!("{:02X}", b)).collect();
+        let new = format!("ANON_{}", hex);
+        mapping.patients.insert(id.to_string(), new.clone());
+        new
+    }
+}
+
+// Retired attributes still occur in older files and are part of the profile.
+#[allow(deprecated)]
+fn action(tag: Tag) -> Option<Action> {
+    use Action::*;
+    Some(match tag {
+        tags::PATIENT_NAME | tags::PATIENT_ID => Pseudonym,
+
+        tags::STUDY_INSTANCE_UID
+        | tags::SERIES_INSTANCE_UID
+        | tags::SOP_INSTANCE_UID
+        | tags::FRAME_OF_REFERENCE_UID
+        | tags::REFERENCED_SOP_INSTANCE_UID
+        | tags::REFERENCED_FRAME_OF_REFERENCE_UID
+        | tags::RELATED_FRAME_OF_REFERENCE_UID
+        | tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID
+        | tags::IRRADIATION_EVENT_UID
+        | tags::STORAGE_MEDIA_FILE_SET_UID
+        | tags::INSTANCE_CREATOR_UID
+        | tags::CONCATENATION_UID
+        | tags::DIMENSION_ORGANIZATION_UID
+        | tags::CONTEXT_GROUP_EXTENSION_CREATOR_UID
+        | tags::CREATOR_VERSION_UID
+        | tags::DEVICE_UID
+        | tags::DOSE_REFERENCE_UID
+        | tags::FAILED_SOP_INSTANCE_UID_LIST
+        | tags::FIDUCIAL_UID
+        | tags::LARGE_PALETTE_COLOR_LOOKUP_TABLE_UID
+        | tags::PALETTE_COLOR_LOOKUP_TABLE_UID
+        | tags::REFERENCED_GENERAL_PURPOSE_SCHEDULED_PROCEDURE_STEP_TRANSACTION_UID
+        | tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE
+        | tags::REQUESTED_SOP_INSTANCE_UID
+        | tags::SPECIMEN_UID
+        | tags::TARGET_FRAME_OF_REFERENCE_UID
+        | tags::TEMPLATE_EXTENSION_CREATOR_UID
+        | tags::TEMPLATE_EXTENSION_ORGANIZATION_UID
+        | tags::TRANSACTION_UID
+        | tags::UID => Uid,
+
+        tags::ACCESSION_NUMBER
+        | tags::PATIENT_BIRTH_DATE
+        | tags::PATIENT_SEX
+        | tags::REFERRING_PHYSICIAN_NAME
+        | tags::STUDY_ID
+        | tags::CONTENT_CREATOR_NAME
+        | tags::FILLER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST
+        | tags::PLACER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST
+        | tags::DETECTOR_ID
+        | tags::GANTRY_ID
+        | tags::GENERATOR_ID
+        | tags::VERIFYING_OBSERVER_NAME
+        | tags::VERIFYING_OBSERVER_SEQUENCE => Zero,
+
+        tags::STUDY_DATE | tags::STUDY_TIME | tags::CONTENT_DATE | tags::CONTENT_TIME => Temporal(true),
+        tags::SERIES_DATE
+        | tags::SERIES_TIME
+        | tags::ACQUISITION_DATE
+        | tags::ACQUISITION_TIME
+        | tags::ACQUISITION_DATE_TIME
+        | tags::INSTANCE_CREATION_DATE
+        | tags::INSTANCE_CREATION_TIME
+        | tags::PERFORMED_PROCEDURE_STEP_START_DATE
+        | tags::PERFORMED_PROCEDURE_STEP_START_TIME
+        | tags::PERFORMED_PROCEDURE_STEP_END_DATE
+        | tags::PERFORMED_PROCEDURE_STEP_END_TIME
+        | tags::OVERLAY_DATE
+        | tags::OVERLAY_TIME
+        | tags::CURVE_DATE
+        | tags::CURVE_TIME
+        | tags::ADMITTING_DATE
+        | tags::ADMITTING_TIME
+        | tags::LAST_MENSTRUAL_DATE => Temporal(false),
+
+        tags::STUDY_DESCRIPTION
+        | tags::SERIES_DESCRIPTION
+        | tags::PROTOCOL_NAME
+        | tags::IMAGE_COMMENTS
+        | tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION
+        | tags::REQUESTED_PROCEDURE_DESCRIPTION
+        | tags::ADMITTING_DIAGNOSES_DESCRIPTION
+        | tags::DERIVATION_DESCRIPTION
+        | tags::ACQUISITION_DEVICE_PROCESSING_DESCRIPTION
+        | tags::CONTRAST_BOLUS_AGENT => Descriptor,
+
+        tags::INSTITUTION_NAME
+        | tags::INSTITUTION_ADDRESS
+        | tags::INSTITUTIONAL_DEPARTMENT_NAME
+        | tags::INSTITUTION_CODE_SEQUENCE
+        | tags::STATION_NAME
+        | tags::DEVICE_SERIAL_NUMBER
+        | tags::PHYSICIANS_OF_RECORD
+        | tags::PERFORMING_PHYSICIAN_NAME
+        | tags::NAME_OF_PHYSICIANS_READING_STUDY
+        | tags::OPERATORS_NAME
+        | tags::REQUESTING_PHYSICIAN
+        | tags::REFERRING_PHYSICIAN_ADDRESS
+        | tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS
+        | tags::ISSUER_OF_PATIENT_ID
+        | tags::PATIENT_BIRTH_TIME
+        | tags::OTHER_PATIENT_I_DS_SEQUENCE
+        | tags::OTHER_PATIENT_NAMES
+        | tags::PATIENT_BIRTH_NAME
+        | tags::PATIENT_AGE
+        | tags::PATIENT_SIZE
+        | tags::PATIENT_WEIGHT
+        | tags::PATIENT_ADDRESS
+        | tags::PATIENT_MOTHER_BIRTH_NAME
+        | tags::MILITARY_RANK
+        | tags::BRANCH_OF_SERVICE
+        | tags::MEDICAL_RECORD_LOCATOR
+        | tags::COUNTRY_OF_RESIDENCE
+        | tags::REGION_OF_RESIDENCE
+        | tags::PATIENT_TELEPHONE_NUMBERS
+        | tags::ETHNIC_GROUP
+        | tags::OCCUPATION
+        | tags::ADDITIONAL_PATIENT_HISTORY
+        | tags::PATIENT_COMMENTS
+        | tags::PATIENT_STATE
+        | tags::PREGNANCY_STATUS
+        | tags::SMOKING_STATUS
+        | tags::MEDICAL_ALERTS
+        | tags::ALLERGIES
+        | tags::REFERENCED_PATIENT_SEQUENCE
+        | tags::REQUEST_ATTRIBUTES_SEQUENCE
+        | tags::PERFORMED_PROCEDURE_STEP_ID
+        | tags::REQUESTED_PROCEDURE_ID
+        | tags::SCHEDULED_PROCEDURE_STEP_ID
+        | tags::RESPONSIBLE_PERSON
+        | tags::RESPONSIBLE_ORGANIZATION
+        | tags::PERSON_NAME
+        | tags::TEXT_COMMENTS
+        | tags::ADMISSION_ID
+        | tags::CURRENT_PATIENT_LOCATION
+        | tags::OTHER_PATIENT_I_DS
+        | tags::PATIENT_RELIGIOUS_PREFERENCE
+        | tags::PATIENT_BIRTH_DATE_IN_ALTERNATIVE_CALENDAR
+        | tags::PATIENT_DEATH_DATE_IN_ALTERNATIVE_CALENDAR
+        | tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE
+        | tags::PATIENT_PRIMARY_LANGUAGE_CODE_SEQUENCE
+        | tags::PATIENT_TRANSPORT_ARRANGEMENTS
+        | tags::INSURANCE_PLAN_IDENTIFICATION
+        | tags::REFERENCED_PATIENT_ALIAS_SEQUENCE
+        | tags::RESPONSIBLE_PERSON_ROLE
+        | tags::SPECIAL_NEEDS
+        | tags::PRE_MEDICATION
+        | tags::CONTENT_SEQUENCE
+        | tags::ACQUISITION_CONTEXT_SEQUENCE
+        | tags::ACQUISITION_COMMENTS
+        | tags::ICON_IMAGE_SEQUENCE
+        | tags::IDENTIFYING_COMMENTS
+        | tags::ORIGINAL_ATTRIBUTES_SEQUENCE
+        | tags::MODIFIED_ATTRIBUTES_SEQUENCE
+        | tags::MODIFYING_DEVICE_ID
+        | tags::MODIFYING_DEVICE_MANUFACTURER
+        | tags::DIGITAL_SIGNATURE_UID
+        | tags::REFERENCED_DIGITAL_SIGNATURE_SEQUENCE
+        | tags::REFERENCED_SOP_INSTANCE_MAC_SEQUENCE
+        | tags::CONSULTING_PHYSICIAN_NAME
+        | tags::CONSULTING_PHYSICIAN_IDENTIFICATION_SEQUENCE
+        | tags::PERFORMING_PHYSICIAN_IDENTIFICATION_SEQUENCE
+        | tags::PHYSICIANS_OF_RECORD_IDENTIFICATION_SEQUENCE
+        | tags::PHYSICIANS_READING_STUDY_IDENTIFICATION_SEQUENCE
+        | tags::REFERRING_PHYSICIAN_IDENTIFICATION_SEQUENCE
+        | tags::OPERATOR_IDENTIFICATION_SEQUENCE
+        | tags::AUTHOR_OBSERVER_SEQUENCE
+        | tags::PARTICIPANT_SEQUENCE
+        | tags::CUSTODIAL_ORGANIZATION_SEQUENCE
+        | tags::CONTRIBUTION_DESCRIPTION
+        | tags::VERIFYING_ORGANIZATION
+        | tags::VERIFYING_OBSERVER_IDENTIFICATION_CODE_SEQUENCE
+        | tags::ACTUAL_HUMAN_PERFORMERS_SEQUENCE
+        | tags::HUMAN_PERFORMER_NAME
+        | tags::HUMAN_PERFORMER_ORGANIZATION
+        | tags::SCHEDULED_HUMAN_PERFORMERS_SEQUENCE
+        | tags::SCHEDULED_PERFORMING_PHYSICIAN_NAME
+        | tags::SCHEDULED_PERFORMING_PHYSICIAN_IDENTIFICATION_SEQUENCE
+        | tags::SCHEDULED_PROCEDURE_STEP_DESCRIPTION
+        | tags::SCHEDULED_PROCEDURE_STEP_LOCATION
+        | tags::SCHEDULED_STATION_AE_TITLE
+        | tags::SCHEDULED_STATION_NAME
+        | tags::SCHEDULED_STATION_NAME_CODE_SEQUENCE
+        | tags::SCHEDULED_STATION_GEOGRAPHIC_LOCATION_CODE_SEQUENCE
+        | tags::SCHEDULED_STUDY_LOCATION
+        | tags::SCHEDULED_STUDY_LOCATION_AE_TITLE
+        | tags::SCHEDULED_PATIENT_INSTITUTION_RESIDENCE
+        | tags::PERFORMED_STATION_NAME
+        | tags::PERFORMED_STATION_AE_TITLE
+        | tags::PERFORMED_STATION_NAME_CODE_SEQUENCE
+        | tags::PERFORMED_STATION_GEOGRAPHIC_LOCATION_CODE_SEQUENCE
+        | tags::PERFORMED_LOCATION
+        | tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE
+        | tags::REASON_FOR_STUDY
+        | tags::REASON_FOR_THE_IMAGING_SERVICE_REQUEST
+        | tags::REASON_FOR_THE_REQUESTED_PROCEDURE
+        | tags::REQUESTED_CONTRAST_AGENT
+        | tags::REQUESTED_PROCEDURE_COMMENTS
+        | tags::REQUESTED_PROCEDURE_LOCATION
+        | tags::REQUESTING_SERVICE
+        | tags::ORDER_CALLBACK_PHONE_NUMBER
+        | tags::ORDER_ENTERED_BY
+        | tags::ORDER_ENTERER_LOCATION
+        | tags::ADMITTING_DIAGNOSES_CODE_SEQUENCE
+        | tags::DISCHARGE_DIAGNOSIS_DESCRIPTION
+        | tags::ISSUER_OF_ADMISSION_ID
+        | tags::SERVICE_EPISODE_ID
+        | tags::SERVICE_EPISODE_DESCRIPTION
+        | tags::VISIT_COMMENTS
+        | tags::STUDY_COMMENTS
+        | tags::STUDY_ID_ISSUER
+        | tags::INTERPRETATION_APPROVER_SEQUENCE
+        | tags::INTERPRETATION_AUTHOR
+        | tags::INTERPRETATION_DIAGNOSIS_DESCRIPTION
+        | tags::INTERPRETATION_ID_ISSUER
+        | tags::INTERPRETATION_RECORDER
+        | tags::INTERPRETATION_TEXT
+        | tags::INTERPRETATION_TRANSCRIBER
+        | tags::RESULTS_COMMENTS
+        | tags::RESULTS_ID_ISSUER
+        | tags::RESULTS_DISTRIBUTION_LIST_SEQUENCE
+        | tags::DISTRIBUTION_ADDRESS
+        | tags::DISTRIBUTION_NAME
+        | tags::INTENDED_RECIPIENTS_OF_RESULTS_IDENTIFICATION_SEQUENCE
+        | tags::NAMES_OF_INTENDED_RECIPIENTS_OF_RESULTS
+        | tags::TOPIC_AUTHOR
+        | tags::TOPIC_KEYWORDS
+        | tags::TOPIC_SUBJECT
+        | tags::TOPIC_TITLE => Remove,
+
+        // Patient, Study Scheduling and Visit attributes the table does not name.
+        _ if matches!(tag.group(), 0x0010 | 0x0032 | 0x0038) => Remove,
+
+        _ => return None,
+    })
+}
+
+fn str_value(obj: &InMemDicomObject, tag: Tag) -> Option<String> {
+    let s = obj.element(tag).ok()?.to_str().ok()?.trim().to_string();
+    if s.is_empty() { None } else { Some(s) }
+}
+
+/// Identifying words that must not survive in cleaned descriptors.
+fn phi_tokens(values: &[Option<String>]) -> Vec<String> {
+    let mut out = Vec::new();
+    for v in values.iter().flatten() {
+        out.extend(
+            v.split(|c: char| c == '^' || c == '=' || c.is_whitespace())
+                .filter(|t| t.len() >= 2)
+                .map(|t| t.to_ascii_uppercase()),
+        );
+    }
+    out
+}
+
+/// Clean Descriptors: drop words matching PHI tokens and 6+ digit numbers (dates, MRNs).
+fn clean_text(text: &str, phi: &[String]) -> String {
+    text.split_whitespace()
+        .filter(|w| {
+            let core: String = w.chars().filter(|c| c.is_alphanumeric()).collect();
+            let upper = core.to_ascii_uppercase();
+            let numeric = core.len() >= 6 && core.chars().all(|c| c.is_ascii_digit());
+            !numeric && !phi.iter().any(|t| upper.contains(t.as_str()))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn salted_hash(salt: &str, domain: &str, value: &str) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(salt.as_bytes());
+    h.update([0u8]);
+    h.update(domain.as_bytes());
+    h.update([0u8]);
+    h.update(value.as_bytes());
+    h.finalize().into()
+}
+
+/// 32 bytes from the OS CSPRNG. Anyone who can guess the salt can brute-force
+/// the mapping, so there is no weaker fallback.
+fn fresh_salt() -> Result<String> {
+    let mut buf = [0u8; 32];
+    getrandom::fill(&mut buf).map_err(|e| anyhow::anyhow!("no OS random source for the de-identification salt: {}", e))?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deid(dir: &Path, opts: DeidOptions) -> Deidentifier {
+        Deidentifier::open(&dir.join("map.json"), opts).unwrap()
+    }
+
+    #[test]
+    fn test_uid_mapping_is_consistent_and_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = deid(dir.path(), DeidOptions::default());
+        let a = d.uid("1.2.840.1");
+        assert_eq!(a, d.uid("1.2.840.1"));
+        assert_ne!(a, d.uid("1.2.840.2"));
+        assert!(a.starts_with("2.25.") && a.len() <= 64);
+        d.save(&dir.path().join("map.json")).unwrap();
+
+        let again = deid(dir.path(), DeidOptions::default());
+        assert_eq!(again.uid("1.2.840.1"), a);
+        assert_eq!(again.pseudonym(Some("MRN1")), d.pseudonym(Some("MRN1")));
+        assert!(!dir.path().join("map.json.part").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_map_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.json");
+        // Saving replaces a file that was readable by everyone.
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let d = Deidentifier::open(&dir.path().join("new.json"), DeidOptions::default()).unwrap();
+        d.uid("1.2.840.1");
+        d.save(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_clean_text() {
+        let phi = vec!["DOE".to_string(), "JOHN".to_string(), "MRN12345".to_string()];
+        assert_eq!(clean_text("T1 MPRAGE Doe 20240315", &phi), "T1 MPRAGE");
+        assert_eq!(clean_text("CHEST (mrn12345)", &phi), "CHEST");
+    }
+
+    #[test]
+    fn test_write_file_applies_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("in.dcm");
+        let dst = dir.path().join("out.dcm");
+        let bytes = crate::synth::instance("1.2.840.10008.5.1.4.1.1.4", "1.2.3.4.5", &[
+            (tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3"),
+            (tags::PATIENT_NAME, VR::PN, "DOE^JOHN"),
+            (tags::PATIENT_ID, VR::LO, "MRN1"),
+            (tags::STUDY_DATE, VR::DA, "20240315"),
+            (tags::INSTITUTION_NAME, VR::LO, "General Hospital"),
+            (tags::SERIES_DESCRIPTION, VR::LO, "T1 DOE"),
+            (Tag(0x0029, 0x0010), VR::LO, "SIEMENS CSA HEADER"),
+            (tags::MODALITY, VR::CS, "MR"),
+            // Other Patient IDs, retired but still sent by older systems.
+            (Tag(0x0010, 0x1000), VR::LO, "OLDMRN7"),
+            (tags::DEVICE_UID, VR::UI, "1.2.9"),
+            (Tag(0x0010, 0x9999), VR::LO, "NEWER PATIENT ATTRIBUTE"),
+        ]);
+        fs::write(&src, bytes).unwrap();
+
+        let d = deid(dir.path(), DeidOptions { retain_dates: false, clean_descriptors: true });
+        d.write_file(&src, &dst).unwrap();
+        // Rewrites are deterministic, so reruns can skip identical output.
+        let encoded = d.encode(&src, &mut fs::File::open(&src).unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), encoded);
+
+        let out = dicom_object::open_file(&dst).unwrap();
+        let get = |t| out.element(t).ok().and_then(|e| e.to_str().ok()).map(|s| s.trim_end_matches('\0').trim().to_string());
+        let pseudo = d.pseudonym(Some("MRN1"));
+        assert_eq!(get(tags::PATIENT_ID).as_deref(), Some(pseudo.as_str()));
+        assert_eq!(get(tags::PATIENT_NAME).as_deref(), Some(pseudo.as_str()));
+        assert_eq!(get(tags::SOP_INSTANCE_UID), Some(d.uid("1.2.3.4.5")));
+        assert_eq!(out.meta().media_storage_sop_instance_uid.trim_end_matches('\0'), d.uid("1.2.3.4.5"));
+        assert_eq!(get(tags::STUDY_DATE).as_deref(), Some(""));
+        assert_eq!(get(tags::INSTITUTION_NAME), None);
+        assert_eq!(get(tags::SERIES_DESCRIPTION).as_deref(), Some("T1"));
+        assert_eq!(get(tags::MODALITY).as_deref(), Some("MR"));
+        assert_eq!(get(tags::PATIENT_IDENTITY_REMOVED).as_deref(), Some("YES"));
+        assert!(out.element(Tag(0x0029, 0x0010)).is_err());
+        assert!(out.element(Tag(0x0010, 0x1000)).is_err());
+        assert!(out.element(Tag(0x0010, 0x9999)).is_err());
+        assert_eq!(get(tags::DEVICE_UID), Some(d.uid("1.2.9")));
+
+        // Raw tags captured for templates and filters are dropped the same way.
+        let mut m = DicomMeta::default();
+        m.extra.insert(Tag(0x0010, 0x1000).to_string(), "OLDMRN7".into());
+        m.extra.insert(tags::SLICE_THICKNESS.to_string(), "1.0".into());
+        assert_eq!(d.apply_meta(&m).extra.keys().collect::<Vec<_>>(), [&tags::SLICE_THICKNESS.to_string()]);
+    }
+}
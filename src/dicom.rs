@@ -2,10 +2,13 @@ use anyhow::{Context, Result};
 use dicom_dictionary_std::tags;
 use dicom_object::{DefaultDicomObject, OpenFileOptions, Tag};
 use dicom_object::file::ReadPreamble;
-use serde::Serialize;
+use dicom_object::ReadError;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DicomMeta {
     pub path: PathBuf,
 
@@ -15,9 +18,14 @@ pub struct DicomMeta {
     pub study_uid: Option<String>,
     pub series_uid: Option<String>,
     pub sop_uid: Option<String>,
+    pub sop_class_uid: Option<String>,
+    pub transfer_syntax: Option<String>,
 
     pub modality: Option<String>,
     pub study_date: Option<String>,
+    pub study_time: Option<String>,
+    pub study_id: Option<String>,
+    pub accession_number: Option<String>,
     pub series_number: Option<i32>,
     pub instance_number: Option<i32>,
 
@@ -26,9 +34,29 @@ pub struct DicomMeta {
 
     pub image_position_patient: Option<[f64; 3]>,
     pub image_orientation_patient: Option<[f64; 6]>,
+    pub frame_of_reference_uid: Option<String>,
+    pub rows: Option<u32>,
+    pub columns: Option<u32>,
+
+    // Candidate axes for splitting repeated positions into volumes.
+    pub temporal_position_identifier: Option<i32>,
+    pub trigger_time: Option<f64>,
+    pub acquisition_number: Option<i32>,
+    pub echo_numbers: Option<i32>,
+    pub acquisition_time: Option<String>,
+
+    /// Additional raw tags requested by the caller (e.g. by a path template),
+    /// keyed by their `(GGGG,EEEE)` representation.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
 }
 
 pub fn read_meta(path: &Path) -> Result<DicomMeta> {
+    read_meta_with_tags(path, &[])
+}
+
+/// Like [`read_meta`], but also captures the string value of each tag in `extra`.
+pub fn read_meta_with_tags(path: &Path, extra: &[Tag]) -> Result<DicomMeta> {
     // Header-only read: stop before Pixel Data (7FE0,0010).
     // This avoids loading huge pixel payloads into memory.
     let obj: DefaultDicomObject = OpenFileOptions::new()
@@ -36,30 +64,65 @@ pub fn read_meta(path: &Path) -> Result<DicomMeta> {
         .read_until(tags::PIXEL_DATA)
         .open_file(path)
         .with_context(|| format!("open DICOM (header-only): {}", path.display()))?;
+    Ok(meta_from_object(path, &obj, extra))
+}
 
-    Ok(DicomMeta {
+/// Like [`read_meta_with_tags`], for a file that is only available as a stream
+/// (e.g. an archive member); `path` is recorded as its provenance.
+pub fn read_meta_from_reader<R: Read>(path: &Path, reader: R, extra: &[Tag]) -> Result<DicomMeta> {
+    let obj: DefaultDicomObject = OpenFileOptions::new()
+        .read_preamble(ReadPreamble::Auto)
+        .read_until(tags::PIXEL_DATA)
+        .from_reader(reader)
+        .with_context(|| format!("read DICOM (header-only): {}", path.display()))?;
+    Ok(meta_from_object(path, &obj, extra))
+}
+
+fn meta_from_object(path: &Path, obj: &DefaultDicomObject, extra: &[Tag]) -> DicomMeta {
+    DicomMeta {
         path: path.to_path_buf(),
 
-        patient_id: opt_str(&obj, tags::PATIENT_ID),
-        patient_name: opt_str(&obj, tags::PATIENT_NAME),
+        patient_id: opt_str(obj, tags::PATIENT_ID),
+        patient_name: opt_str(obj, tags::PATIENT_NAME),
 
-        study_uid: opt_str(&obj, tags::STUDY_INSTANCE_UID),
-        series_uid: opt_str(&obj, tags::SERIES_INSTANCE_UID),
-        sop_uid: opt_str(&obj, tags::SOP_INSTANCE_UID),
+        study_uid: opt_str(obj, tags::STUDY_INSTANCE_UID),
+        series_uid: opt_str(obj, tags::SERIES_INSTANCE_UID),
+        sop_uid: opt_str(obj, tags::SOP_INSTANCE_UID),
+        sop_class_uid: opt_str(obj, tags::SOP_CLASS_UID),
+        transfer_syntax: Some(obj.meta().transfer_syntax().trim_end_matches(['\0', ' ']).to_string()),
 
-        modality: opt_str(&obj, tags::MODALITY),
-        study_date: opt_str(&obj, tags::STUDY_DATE),
-        series_number: opt_i32(&obj, tags::SERIES_NUMBER),
-        instance_number: opt_i32(&obj, tags::INSTANCE_NUMBER),
+        modality: opt_str(obj, tags::MODALITY),
+        study_date: opt_str(obj, tags::STUDY_DATE),
+        study_time: opt_str(obj, tags::STUDY_TIME),
+        study_id: opt_str(obj, tags::STUDY_ID),
+        accession_number: opt_str(obj, tags::ACCESSION_NUMBER),
+        series_number: opt_i32(obj, tags::SERIES_NUMBER),
+        instance_number: opt_i32(obj, tags::INSTANCE_NUMBER),
 
-        study_description: opt_str(&obj, tags::STUDY_DESCRIPTION),
-        series_description: opt_str(&obj, tags::SERIES_DESCRIPTION),
+        study_description: opt_str(obj, tags::STUDY_DESCRIPTION),
+        series_description: opt_str(obj, tags::SERIES_DESCRIPTION),
 
-        image_position_patient: opt_f64_vec(&obj, tags::IMAGE_POSITION_PATIENT)
-            .and_then(|v| v_to_3(v)),
-        image_orientation_patient: opt_f64_vec(&obj, tags::IMAGE_ORIENTATION_PATIENT)
-            .and_then(|v| v_to_6(v)),
-    })
+        image_position_patient: opt_f64_vec(obj, tags::IMAGE_POSITION_PATIENT)
+            .and_then(v_to_3),
+        image_orientation_patient: opt_f64_vec(obj, tags::IMAGE_ORIENTATION_PATIENT)
+            .and_then(v_to_6),
+        frame_of_reference_uid: opt_str(obj, tags::FRAME_OF_REFERENCE_UID),
+        rows: opt_u32(obj, tags::ROWS),
+        columns: opt_u32(obj, tags::COLUMNS),
+
+        temporal_position_identifier: opt_i32(obj, tags::TEMPORAL_POSITION_IDENTIFIER),
+        trigger_time: opt_f64(obj, tags::TRIGGER_TIME),
+        acquisition_number: opt_i32(obj, tags::ACQUISITION_NUMBER),
+        // EchoNumbers is IS with VM 1-n; the first value identifies the echo.
+        echo_numbers: opt_str(obj, tags::ECHO_NUMBERS)
+            .and_then(|s| s.split('\\').next().and_then(|v| v.trim().parse().ok())),
+        acquisition_time: opt_str(obj, tags::ACQUISITION_TIME),
+
+        extra: extra
+            .iter()
+            .filter_map(|&tag| opt_str(obj, tag).map(|v| (tag.to_string(), v)))
+            .collect(),
+    }
 }
 
 impl DicomMeta {
@@ -68,13 +131,17 @@ impl DicomMeta {
     /// Returns None if required tags are missing or invalid.
     pub fn geom_order(&self) -> Option<f64> {
         let ipp = self.image_position_patient?;
+        Some(dot(ipp, self.slice_normal()?))
+    }
+
+    /// cross(row, col) from ImageOrientationPatient.
+    pub fn slice_normal(&self) -> Option<[f64; 3]> {
         let iop = self.image_orientation_patient?;
 
         let row = [iop[0], iop[1], iop[2]];
         let col = [iop[3], iop[4], iop[5]];
 
-        let n = cross(row, col);
-        Some(dot(ipp, n))
+        Some(cross(row, col))
     }
 
     pub fn stable_id(&self) -> String {
@@ -84,6 +151,109 @@ impl DicomMeta {
                 .map(|s| s.to_string_lossy().to_string())
                 .unwrap_or_else(|| "UNKNOWN".to_string()))
     }
+
+    /// Value of a tag captured via [`read_meta_with_tags`].
+    pub fn extra_value(&self, tag: Tag) -> Option<&str> {
+        self.extra.get(&tag.to_string()).map(String::as_str)
+    }
+}
+
+/// Why a file could not be turned into a [`DicomMeta`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    NotDicom,
+    Truncated,
+    PermissionDenied,
+    UnsupportedTransferSyntax,
+    Malformed,
+}
+
+impl FailureKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureKind::NotDicom => "not_dicom",
+            FailureKind::Truncated => "truncated",
+            FailureKind::PermissionDenied => "permission_denied",
+            FailureKind::UnsupportedTransferSyntax => "unsupported_transfer_syntax",
+            FailureKind::Malformed => "malformed",
+        }
+    }
+}
+
+/// A file rejected during scanning, with the full error chain.
+#[derive(Debug, Serialize)]
+pub struct ScanFailure {
+    pub path: PathBuf,
+    pub kind: FailureKind,
+    #[serde(serialize_with = "serialize_chain")]
+    pub error: anyhow::Error,
+}
+
+impl ScanFailure {
+    pub fn new(path: &Path, error: anyhow::Error) -> Self {
+        let mut kind = classify_error(&error);
+        // Files shorter than the preamble fail with a plain EOF; only call them
+        // truncated if they actually start like a DICOM file.
+        if matches!(kind, FailureKind::Truncated | FailureKind::Malformed) && !has_dicom_magic(path) {
+            kind = FailureKind::NotDicom;
+        }
+        Self { path: path.to_path_buf(), kind, error }
+    }
+}
+
+/// True if the file carries the "DICM" magic, either after a 128-byte preamble
+/// or at offset 0 (preamble-less files).
+pub fn has_dicom_magic(path: &Path) -> bool {
+    let mut buf = Vec::with_capacity(132);
+    if std::fs::File::open(path).and_then(|f| f.take(132).read_to_end(&mut buf)).is_err() {
+        return false;
+    }
+    has_dicom_prefix(&buf)
+}
+
+/// [`has_dicom_magic`] for the first 132 (or fewer) bytes of a stream.
+pub fn has_dicom_prefix(buf: &[u8]) -> bool {
+    buf.starts_with(b"DICM") || buf.get(128..132) == Some(b"DICM".as_slice())
+}
+
+/// Map an error from [`read_meta`] to a [`FailureKind`] by walking its source chain.
+pub fn classify_error(err: &anyhow::Error) -> FailureKind {
+    let mut eof = false;
+    for cause in err.chain() {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            match io.kind() {
+                std::io::ErrorKind::PermissionDenied => return FailureKind::PermissionDenied,
+                std::io::ErrorKind::UnexpectedEof => eof = true,
+                _ => {}
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<ReadError>() {
+            if matches!(
+                e,
+                ReadError::ReadUnrecognizedTransferSyntax { .. }
+                    | ReadError::ReadUnsupportedTransferSyntax { .. }
+                    | ReadError::ReadUnsupportedTransferSyntaxWithSuggestion { .. }
+            ) {
+                return FailureKind::UnsupportedTransferSyntax;
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<dicom_object::meta::Error>() {
+            // A missing magic code means this was never DICOM, even if the
+            // underlying read also hit EOF on a tiny file.
+            if matches!(
+                e,
+                dicom_object::meta::Error::NotDicom { .. } | dicom_object::meta::Error::ReadMagicCode { .. }
+            ) {
+                return FailureKind::NotDicom;
+            }
+        }
+    }
+    if eof { FailureKind::Truncated } else { FailureKind::Malformed }
+}
+
+fn serialize_chain<S: Serializer>(err: &anyhow::Error, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(err.chain().map(|c| c.to_string()))
 }
 
 fn opt_str(obj: &DefaultDicomObject, tag: Tag) -> Option<String> {
@@ -95,6 +265,15 @@ fn opt_i32(obj: &DefaultDicomObject, tag: Tag) -> Option<i32> {
     opt_str(obj, tag)?.parse::<i32>().ok()
 }
 
+fn opt_f64(obj: &DefaultDicomObject, tag: Tag) -> Option<f64> {
+    opt_str(obj, tag)?.parse::<f64>().ok()
+}
+
+fn opt_u32(obj: &DefaultDicomObject, tag: Tag) -> Option<u32> {
+    // US values are binary, so go through to_int rather than the string form.
+    obj.element(tag).ok()?.to_int::<u32>().ok()
+}
+
 fn opt_f64_vec(obj: &DefaultDicomObject, tag: Tag) -> Option<Vec<f64>> {
     // Multi-valued DS often uses '\' separator.
     let raw = opt_str(obj, tag)?;
@@ -125,3 +304,49 @@ fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
 fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
     a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{instance, CT_IMAGE_STORAGE};
+    use dicom_core::VR;
+    use std::fs;
+
+    fn write_minimal(path: &Path) {
+        let bytes = instance(CT_IMAGE_STORAGE, "1.2.3.4", &[
+            (tags::PATIENT_ID, VR::LO, "PAT01"),
+            (tags::SERIES_DESCRIPTION, VR::LO, "A fairly long description"),
+        ]);
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_read_valid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("ok.dcm");
+        write_minimal(&p);
+        let m = read_meta(&p).unwrap();
+        assert_eq!(m.patient_id.as_deref(), Some("PAT01"));
+        assert_eq!(m.sop_uid.as_deref(), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn test_classify_not_dicom() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("notes.txt");
+        fs::write(&p, "just some text, definitely not DICOM").unwrap();
+        let err = read_meta(&p).unwrap_err();
+        assert_eq!(ScanFailure::new(&p, err).kind, FailureKind::NotDicom);
+    }
+
+    #[test]
+    fn test_classify_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("cut.dcm");
+        write_minimal(&p);
+        let bytes = fs::read(&p).unwrap();
+        fs::write(&p, &bytes[..bytes.len() - 10]).unwrap();
+        let err = read_meta(&p).unwrap_err();
+        assert_eq!(ScanFailure::new(&p, err).kind, FailureKind::Truncated);
+    }
+}
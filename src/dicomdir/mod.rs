@@ -0,0 +1,457 @@
+//! DICOMDIR (PS3.10 Media Storage Directory): written for a sorted output
+//! tree here, read from incoming media in [`read`].
+//!
+//! The directory is encoded by hand in Explicit VR Little Endian: directory
+//! records point at each other by byte offset within the file, so every record
+//! is measured first and written once all offsets are known.
+
+use crate::dicom::DicomMeta;
+use crate::net::{EXPLICIT_VR_LE, IMPLEMENTATION_CLASS_UID, IMPLEMENTATION_VERSION_NAME};
+use anyhow::{bail, ensure, Context, Result};
+use dicom_dictionary_std::{tags, uids};
+use dicom_object::{FileMetaTableBuilder, Tag};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+pub mod read;
+
+/// File name of the directory at the root of the file-set.
+pub const FILE_NAME: &str = "DICOMDIR";
+
+/// PS3.10 8.2: at most 8 components of 1-8 characters from A-Z, 0-9 and `_`.
+const MAX_COMPONENTS: usize = 8;
+const MAX_COMPONENT_LEN: usize = 8;
+
+type Element = (Tag, [u8; 2], Vec<u8>);
+
+/// An 8-character media file ID component: `prefix` plus 7 base-36 digits of
+/// SHA-256(`value`). Stable across runs, so re-sorting the same data lands in
+/// the same folders.
+pub fn media_name(prefix: char, value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let mut n = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+    let mut name = String::with_capacity(MAX_COMPONENT_LEN);
+    name.push(prefix);
+    for _ in 1..MAX_COMPONENT_LEN {
+        let digit = char::from_digit((n % 36) as u32, 36).expect("base-36 digit");
+        name.push(digit.to_ascii_uppercase());
+        n /= 36;
+    }
+    name
+}
+
+/// The Referenced File ID of `path` relative to the file-set `root`, or an
+/// error if the path breaks the media interchange naming rules.
+pub fn file_id(root: &Path, path: &Path) -> Result<Vec<String>> {
+    let rel = path
+        .strip_prefix(root)
+        .with_context(|| format!("{} is outside the file-set {}", path.display(), root.display()))?;
+    let mut parts = Vec::new();
+    for c in rel.components() {
+        let Component::Normal(c) = c else {
+            bail!("{}: unexpected path component {:?}", path.display(), c);
+        };
+        match c.to_str().filter(|s| is_media_component(s)) {
+            Some(s) => parts.push(s.to_string()),
+            None => bail!(
+                "{}: {:?} is not a valid media file ID component (1-{} characters of A-Z, 0-9 and _)",
+                path.display(),
+                c,
+                MAX_COMPONENT_LEN
+            ),
+        }
+    }
+    ensure!(
+        !parts.is_empty() && parts.len() <= MAX_COMPONENTS,
+        "{}: media file IDs have 1 to {} components, found {}",
+        path.display(),
+        MAX_COMPONENTS,
+        parts.len()
+    );
+    Ok(parts)
+}
+
+pub(crate) fn is_media_component(s: &str) -> bool {
+    (1..=MAX_COMPONENT_LEN).contains(&s.len())
+        && s.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Write `root/DICOMDIR` describing `instances` (final path and header of each
+/// file) as PATIENT / STUDY / SERIES / instance records. Returns the path written.
+pub fn write_dicomdir(root: &Path, instances: &[(PathBuf, &DicomMeta)]) -> Result<PathBuf> {
+    let bytes = dicomdir_bytes(root, instances)?;
+    let path = root.join(FILE_NAME);
+    let tmp = root.join(format!("{}.tmp", FILE_NAME));
+    fs::create_dir_all(root).with_context(|| format!("create dir: {}", root.display()))?;
+    fs::write(&tmp, bytes).with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("rename {} -> {}", tmp.display(), path.display()))?;
+    Ok(path)
+}
+
+/// The encoded DICOMDIR for `instances` under `root`, for writers other than
+/// the filesystem (e.g. an output archive, where `root` is empty).
+pub fn dicomdir_bytes(root: &Path, instances: &[(PathBuf, &DicomMeta)]) -> Result<Vec<u8>> {
+    let mut items = instances
+        .iter()
+        .map(|(path, m)| Ok((file_id(root, path)?, *m)))
+        .collect::<Result<Vec<_>>>()?;
+    items.sort_by(|(a_id, a), (b_id, b)| {
+        hierarchy_key(a).cmp(&hierarchy_key(b)).then_with(|| a_id.cmp(b_id))
+    });
+
+    let mut patients: Vec<Record> = Vec::new();
+    for (id, m) in &items {
+        let [patient, study, series] =
+            [&m.patient_id, &m.study_uid, &m.series_uid].map(|v| v.clone().unwrap_or_default());
+        if patients.last().is_none_or(|r| r.key != patient) {
+            patients.push(patient_record(m, patient));
+        }
+        let studies = &mut patients.last_mut().expect("pushed above").children;
+        if studies.last().is_none_or(|r| r.key != study) {
+            studies.push(study_record(m, study));
+        }
+        let series_list = &mut studies.last_mut().expect("pushed above").children;
+        if series_list.last().is_none_or(|r| r.key != series) {
+            series_list.push(series_record(m, series));
+        }
+        series_list.last_mut().expect("pushed above").children.push(instance_record(id, m));
+    }
+
+    encode(&patients, &file_set_uid(&items))
+}
+
+type HierarchyKey<'a> = (Option<&'a str>, Option<&'a str>, Option<&'a str>, Option<i32>, Option<&'a str>);
+
+fn hierarchy_key(m: &DicomMeta) -> HierarchyKey<'_> {
+    (
+        m.patient_id.as_deref(),
+        m.study_date.as_deref(),
+        m.study_uid.as_deref(),
+        m.series_number,
+        m.series_uid.as_deref(),
+    )
+}
+
+/// A directory record before offsets are assigned.
+struct Record {
+    kind: &'static str,
+    /// Value that identifies this record among its siblings.
+    key: String,
+    elements: Vec<Element>,
+    children: Vec<Record>,
+}
+
+impl Record {
+    fn new(kind: &'static str, key: String, mut elements: Vec<Element>) -> Self {
+        // Record keys are copied verbatim; anything beyond ASCII is written as UTF-8.
+        if elements.iter().any(|(_, _, v)| !v.is_ascii()) {
+            elements.push(text(tags::SPECIFIC_CHARACTER_SET, *b"CS", "ISO_IR 192"));
+        }
+        Self { kind, key, elements, children: Vec::new() }
+    }
+}
+
+fn patient_record(m: &DicomMeta, key: String) -> Record {
+    Record::new("PATIENT", key, vec![
+        text(tags::PATIENT_NAME, *b"PN", m.patient_name.as_deref().unwrap_or_default()),
+        text(tags::PATIENT_ID, *b"LO", m.patient_id.as_deref().unwrap_or_default()),
+    ])
+}
+
+fn study_record(m: &DicomMeta, key: String) -> Record {
+    Record::new("STUDY", key, vec![
+        text(tags::STUDY_DATE, *b"DA", m.study_date.as_deref().unwrap_or_default()),
+        text(tags::STUDY_TIME, *b"TM", m.study_time.as_deref().unwrap_or_default()),
+        text(tags::ACCESSION_NUMBER, *b"SH", m.accession_number.as_deref().unwrap_or_default()),
+        text(tags::STUDY_DESCRIPTION, *b"LO", m.study_description.as_deref().unwrap_or_default()),
+        text(tags::STUDY_INSTANCE_UID, *b"UI", m.study_uid.as_deref().unwrap_or_default()),
+        text(tags::STUDY_ID, *b"SH", m.study_id.as_deref().unwrap_or_default()),
+    ])
+}
+
+fn series_record(m: &DicomMeta, key: String) -> Record {
+    Record::new("SERIES", key, vec![
+        text(tags::MODALITY, *b"CS", m.modality.as_deref().unwrap_or_default()),
+        text(tags::SERIES_INSTANCE_UID, *b"UI", m.series_uid.as_deref().unwrap_or_default()),
+        text(tags::SERIES_NUMBER, *b"IS", &m.series_number.map(|n| n.to_string()).unwrap_or_default()),
+    ])
+}
+
+fn instance_record(id: &[String], m: &DicomMeta) -> Record {
+    let class = m.sop_class_uid.as_deref().unwrap_or_default();
+    let mut elements = vec![
+        text(tags::REFERENCED_FILE_ID, *b"CS", &id.join("\\")),
+        text(tags::REFERENCED_SOP_CLASS_UID_IN_FILE, *b"UI", class),
+        text(tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE, *b"UI", m.sop_uid.as_deref().unwrap_or_default()),
+        text(tags::INSTANCE_NUMBER, *b"IS", &m.instance_number.map(|n| n.to_string()).unwrap_or_default()),
+    ];
+    if let Some(ts) = &m.transfer_syntax {
+        elements.push(text(tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE, *b"UI", ts));
+    }
+    Record::new(record_type(class), id.join("/"), elements)
+}
+
+/// Directory Record Type for a storage SOP Class (PS3.3 F.5); images are the default.
+fn record_type(sop_class: &str) -> &'static str {
+    const PREFIX: &str = "1.2.840.10008.5.1.4.1.1.";
+    let Some(rest) = sop_class.strip_prefix(PREFIX) else {
+        return "IMAGE";
+    };
+    match rest {
+        "88.59" => "KEY OBJECT DOC",
+        "481.2" => "RT DOSE",
+        "481.3" => "RT STRUCTURE SET",
+        "481.5" => "RT PLAN",
+        _ if rest.starts_with("88.") => "SR DOCUMENT",
+        _ if rest.starts_with("11.") => "PRESENTATION",
+        _ if rest.starts_with("104.") => "ENCAP DOC",
+        _ => "IMAGE",
+    }
+}
+
+/// A short-form text element, padded to even length (UIDs with NUL, the rest with spaces).
+fn text(tag: Tag, vr: [u8; 2], value: &str) -> Element {
+    let mut v = value.as_bytes().to_vec();
+    if v.len() % 2 == 1 {
+        v.push(if &vr == b"UI" { 0 } else { b' ' });
+    }
+    (tag, vr, v)
+}
+
+fn put(buf: &mut Vec<u8>, (tag, vr, value): &Element) {
+    buf.extend_from_slice(&tag.group().to_le_bytes());
+    buf.extend_from_slice(&tag.element().to_le_bytes());
+    buf.extend_from_slice(vr);
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn ul(tag: Tag, v: u32) -> Element {
+    (tag, *b"UL", v.to_le_bytes().to_vec())
+}
+
+fn us(tag: Tag, v: u16) -> Element {
+    (tag, *b"US", v.to_le_bytes().to_vec())
+}
+
+/// Records in file order (depth first) with the indices they link to.
+struct Flat<'a> {
+    record: &'a Record,
+    next: Option<usize>,
+    lower: Option<usize>,
+}
+
+/// Append `records` and their descendants; returns the index of the first one.
+fn flatten<'a>(records: &'a [Record], out: &mut Vec<Flat<'a>>) -> Option<usize> {
+    let mut first = None;
+    let mut prev: Option<usize> = None;
+    for r in records {
+        let idx = out.len();
+        out.push(Flat { record: r, next: None, lower: None });
+        out[idx].lower = flatten(&r.children, out);
+        if let Some(p) = prev {
+            out[p].next = Some(idx);
+        }
+        first.get_or_insert(idx);
+        prev = Some(idx);
+    }
+    first
+}
+
+fn encode_item(r: &Record, next: u32, lower: u32) -> Vec<u8> {
+    let mut elements = vec![
+        ul(tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD, next),
+        us(tags::RECORD_IN_USE_FLAG, 0xFFFF),
+        ul(tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY, lower),
+        text(tags::DIRECTORY_RECORD_TYPE, *b"CS", r.kind),
+    ];
+    elements.extend(r.elements.iter().cloned());
+    elements.sort_by_key(|e| e.0);
+
+    let mut body = Vec::new();
+    for e in &elements {
+        put(&mut body, e);
+    }
+    let mut item = Vec::with_capacity(body.len() + 8);
+    item.extend_from_slice(&0xFFFEu16.to_le_bytes());
+    item.extend_from_slice(&0xE000u16.to_le_bytes());
+    item.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    item.extend_from_slice(&body);
+    item
+}
+
+/// Everything before the first item: data set elements and the sequence header.
+fn encode_head(first: u32, last: u32, items_len: u32) -> Vec<u8> {
+    let mut head = Vec::new();
+    put(&mut head, &text(tags::FILE_SET_ID, *b"CS", ""));
+    put(&mut head, &ul(tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY, first));
+    put(&mut head, &ul(tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY, last));
+    put(&mut head, &us(tags::FILE_SET_CONSISTENCY_FLAG, 0));
+    let sq = tags::DIRECTORY_RECORD_SEQUENCE;
+    head.extend_from_slice(&sq.group().to_le_bytes());
+    head.extend_from_slice(&sq.element().to_le_bytes());
+    head.extend_from_slice(b"SQ\0\0");
+    head.extend_from_slice(&items_len.to_le_bytes());
+    head
+}
+
+fn encode(patients: &[Record], sop_instance_uid: &str) -> Result<Vec<u8>> {
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(uids::MEDIA_STORAGE_DIRECTORY_STORAGE)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(EXPLICIT_VR_LE)
+        .implementation_class_uid(IMPLEMENTATION_CLASS_UID)
+        .implementation_version_name(IMPLEMENTATION_VERSION_NAME)
+        .build()
+        .context("build DICOMDIR file meta group")?;
+    let mut out = vec![0u8; 128];
+    out.extend_from_slice(b"DICM");
+    meta.write(&mut out).context("write DICOMDIR file meta group")?;
+
+    let mut flat = Vec::new();
+    let first_root = flatten(patients, &mut flat);
+    let mut last_root = first_root;
+    while let Some(next) = last_root.and_then(|i| flat[i].next) {
+        last_root = Some(next);
+    }
+
+    // Offsets count from the first byte of the file, preamble included.
+    let sizes: Vec<usize> = flat.iter().map(|f| encode_item(f.record, 0, 0).len()).collect();
+    let base = out.len() + encode_head(0, 0, 0).len();
+    let mut offsets = Vec::with_capacity(flat.len());
+    let mut at = base;
+    for size in &sizes {
+        offsets.push(u32::try_from(at).context("DICOMDIR exceeds 4 GiB")?);
+        at += size;
+    }
+    let items_len = u32::try_from(at - base).context("DICOMDIR exceeds 4 GiB")?;
+    let offset = |i: Option<usize>| i.map_or(0, |i| offsets[i]);
+
+    out.extend(encode_head(offset(first_root), offset(last_root), items_len));
+    for f in &flat {
+        out.extend(encode_item(f.record, offset(f.next), offset(f.lower)));
+    }
+    Ok(out)
+}
+
+/// Derived from the referenced instances, so an unchanged tree gets the same UID.
+fn file_set_uid(items: &[(Vec<String>, &DicomMeta)]) -> String {
+    let mut h = Sha256::new();
+    for (id, m) in items {
+        h.update(id.join("\\").as_bytes());
+        h.update([0]);
+        h.update(m.sop_uid.as_deref().unwrap_or_default().as_bytes());
+        h.update([0]);
+    }
+    let digest = h.finalize();
+    format!("2.25.{}", u128::from_be_bytes(digest[..16].try_into().expect("16 bytes")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sort::{plan_operations, PlanOptions};
+    use crate::types::{Layout, VolumeLayout};
+
+    fn img(series: &str, n: i32, z: f64, temporal: i32) -> DicomMeta {
+        DicomMeta {
+            path: PathBuf::from(format!("in/{}_{}", series, n)),
+            patient_id: Some("PAT-1".into()),
+            patient_name: Some("Müller^Jörg".into()),
+            study_uid: Some("1.2.3".into()),
+            series_uid: Some(format!("1.2.3.{}", series)),
+            sop_uid: Some(format!("1.2.3.{}.{}", series, n)),
+            sop_class_uid: Some("1.2.840.10008.5.1.4.1.1.4".into()),
+            transfer_syntax: Some(EXPLICIT_VR_LE.into()),
+            modality: Some("MR".into()),
+            series_number: series.parse().ok(),
+            instance_number: Some(n),
+            image_position_patient: Some([0.0, 0.0, z]),
+            image_orientation_patient: Some([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+            temporal_position_identifier: Some(temporal),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_media_layout_yields_valid_file_ids() {
+        let name = media_name('P', "PAT-1");
+        assert_eq!(name, media_name('P', "PAT-1"));
+        assert!(is_media_component(&name) && name.starts_with('P'));
+
+        // Two dynamics of one series exercise the volume folders too.
+        let metas: Vec<_> = (0..4).map(|i| img("1", i + 1, (i % 2) as f64, 1 + i / 2)).collect();
+        let opts = PlanOptions { layout: Layout::Media, volumes: VolumeLayout::Filenames, ..Default::default() };
+        let root = Path::new("out");
+        let plans = plan_operations(&metas, root, &opts);
+        for p in &plans {
+            let id = file_id(root, &p.dst).unwrap();
+            assert_eq!(id.len(), 5, "{:?}", id);
+        }
+        assert!(plans.iter().any(|p| p.dst.ends_with("V0000002/I0000002")));
+
+        assert!(file_id(root, Path::new("out/P1/00001_1.2.3.dcm")).is_err());
+        assert!(file_id(root, Path::new("out/p1/I0000001")).is_err());
+        assert!(file_id(root, Path::new("out/A/B/C/D/E/F/G/H/I")).is_err());
+    }
+
+    #[test]
+    fn test_dicomdir_records_and_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let metas = [img("2", 1, 0.0, 1), img("1", 2, 1.0, 1), img("1", 1, 0.0, 1)];
+        let entries: Vec<_> = metas
+            .iter()
+            .map(|m| {
+                let series = media_name('E', m.series_uid.as_deref().unwrap());
+                let file = format!("I{:07}", m.instance_number.unwrap());
+                (dir.path().join("P1").join("S1").join(series).join(file), m)
+            })
+            .collect();
+        let path = write_dicomdir(dir.path(), &entries).unwrap();
+
+        let obj = dicom_object::open_file(&path).unwrap();
+        assert_eq!(obj.meta().media_storage_sop_class_uid(), uids::MEDIA_STORAGE_DIRECTORY_STORAGE);
+        let records = obj.element(tags::DIRECTORY_RECORD_SEQUENCE).unwrap().items().unwrap();
+        let get = |i: usize, tag| records[i].element(tag).unwrap().to_str().unwrap().to_string();
+        let kinds: Vec<_> = (0..records.len()).map(|i| get(i, tags::DIRECTORY_RECORD_TYPE)).collect();
+        assert_eq!(kinds, ["PATIENT", "STUDY", "SERIES", "IMAGE", "IMAGE", "SERIES", "IMAGE"]);
+        assert_eq!(get(2, tags::SERIES_NUMBER), "1");
+        assert_eq!(get(4, tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE), "1.2.3.1.2");
+        assert_eq!(get(0, tags::SPECIFIC_CHARACTER_SET), "ISO_IR 192");
+        assert_eq!(get(0, tags::PATIENT_NAME), "Müller^Jörg");
+        let file_id = records[3].element(tags::REFERENCED_FILE_ID).unwrap().to_multi_str().unwrap().to_vec();
+        assert_eq!(file_id[..2], ["P1", "S1"]);
+        assert_eq!(file_id[3], "I0000001");
+
+        // Walk the items in the raw file to learn where each one starts.
+        let bytes = fs::read(&path).unwrap();
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        let offset = |obj: &dicom_object::InMemDicomObject, tag| obj.element(tag).unwrap().to_int::<u32>().unwrap();
+        let mut starts = vec![offset(&obj, tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY)];
+        for _ in 1..records.len() {
+            let pos = *starts.last().unwrap() as usize;
+            assert_eq!(bytes[pos..pos + 4], [0xFE, 0xFF, 0x00, 0xE0]);
+            starts.push((pos + 8) as u32 + u32_at(pos + 4));
+        }
+        let index = |off: u32| (off != 0).then(|| starts.iter().position(|&s| s == off).unwrap());
+        let links: Vec<_> = records
+            .iter()
+            .map(|r| {
+                let next = index(offset(r, tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD));
+                let lower = index(offset(r, tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY));
+                (next, lower)
+            })
+            .collect();
+        assert_eq!(links, [
+            (None, Some(1)),
+            (None, Some(2)),
+            (Some(5), Some(3)),
+            (Some(4), None),
+            (None, None),
+            (None, Some(6)),
+            (None, None),
+        ]);
+        assert_eq!(offset(&obj, tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY), starts[0]);
+    }
+}
@@ -0,0 +1,479 @@
+//! Use a DICOMDIR on incoming media as the list of files to sort.
+//!
+//! Records are followed through their offset links as PS3.3 F.3 intends,
+//! rather than trusting item order. Directory record keys pre-populate
+//! header fields the files leave empty, and are cross-checked against the
+//! rest.
+
+use super::FILE_NAME;
+use crate::dicom::DicomMeta;
+use crate::net::EXPLICIT_VR_LE;
+use anyhow::{bail, ensure, Context, Result};
+use dicom_dictionary_std::{tags, uids};
+use dicom_object::file::ReadPreamble;
+use dicom_object::{InMemDicomObject, OpenFileOptions, Tag};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ITEM: Tag = Tag(0xFFFE, 0xE000);
+const ITEM_DELIMITER: Tag = Tag(0xFFFE, 0xE00D);
+const SEQUENCE_DELIMITER: Tag = Tag(0xFFFE, 0xE0DD);
+const UNDEFINED_LENGTH: u32 = 0xFFFF_FFFF;
+
+/// A parsed DICOMDIR: every in-use record that references a file.
+#[derive(Debug, Clone)]
+pub struct DicomDir {
+    /// Directory holding the DICOMDIR; Referenced File IDs are relative to it.
+    pub root: PathBuf,
+    pub entries: Vec<DirEntry>,
+    /// Records whose Referenced File ID was rejected; they have no entry.
+    pub rejected: Vec<MediaIssue>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub file_id: Vec<String>,
+    /// Where the file was found on the media; `None` if it is missing.
+    pub path: Option<PathBuf>,
+    /// Keys from this record and its patient, study and series ancestors.
+    pub record: DicomMeta,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// Referenced by the DICOMDIR but not present on the media.
+    Missing,
+    /// The file header disagrees with its directory record; the header wins.
+    Mismatch,
+    /// The Referenced File ID would point outside the media, e.g. through `..`;
+    /// the record is ignored.
+    InvalidFileId,
+}
+
+/// A discrepancy between the DICOMDIR and the files, listed under `dicomdir` in the report.
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaIssue {
+    pub file_id: String,
+    pub kind: IssueKind,
+    pub detail: Option<String>,
+}
+
+impl DicomDir {
+    pub fn read(path: &Path) -> Result<Self> {
+        let obj = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Auto)
+            .open_file(path)
+            .with_context(|| format!("open DICOMDIR: {}", path.display()))?;
+        let meta = obj.meta();
+        ensure!(
+            meta.media_storage_sop_class_uid().trim_end_matches(['\0', ' ']) == uids::MEDIA_STORAGE_DIRECTORY_STORAGE,
+            "{} is not a DICOMDIR (Media Storage SOP Class {})",
+            path.display(),
+            meta.media_storage_sop_class_uid()
+        );
+        // PS3.10 requires Explicit VR Little Endian, which the offset scan below relies on.
+        ensure!(
+            meta.transfer_syntax().trim_end_matches(['\0', ' ']) == EXPLICIT_VR_LE,
+            "{}: DICOMDIR in unsupported transfer syntax {}",
+            path.display(),
+            meta.transfer_syntax()
+        );
+
+        let bytes = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let preamble = if bytes.get(128..132) == Some(b"DICM") { 128 } else { 0 };
+        // "DICM", the (0002,0000) element itself, then the rest of group 0002.
+        let start = preamble + 4 + 12 + meta.information_group_length as usize;
+        let offsets = record_offsets(&bytes, start).with_context(|| format!("scan {}", path.display()))?;
+
+        let items = match obj.element(tags::DIRECTORY_RECORD_SEQUENCE) {
+            Ok(e) => e.items().context("Directory Record Sequence is not a sequence")?,
+            Err(_) => &[],
+        };
+        ensure!(
+            offsets.len() == items.len(),
+            "{}: found {} directory records but parsed {}",
+            path.display(),
+            offsets.len(),
+            items.len()
+        );
+        let records: HashMap<u32, &InMemDicomObject> = offsets.into_iter().zip(items).collect();
+
+        let root = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let first = uint(&obj, tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY);
+        let mut walk = Walk {
+            root: &root,
+            records: &records,
+            visited: HashSet::new(),
+            listings: HashMap::new(),
+            entries: Vec::new(),
+            rejected: Vec::new(),
+        };
+        walk.siblings(first, &DicomMeta::default())?;
+        Ok(Self { entries: walk.entries, rejected: walk.rejected, root })
+    }
+
+    /// Files to scan: every referenced file that is present, in directory order.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.entries.iter().filter_map(|e| e.path.clone()).collect()
+    }
+
+    pub fn missing(&self) -> impl Iterator<Item = &DirEntry> {
+        self.entries.iter().filter(|e| e.path.is_none())
+    }
+
+    /// Fill header fields the files leave empty from their directory records,
+    /// and list missing files and records that disagree with their file.
+    pub fn reconcile(&self, metas: &mut [DicomMeta]) -> Vec<MediaIssue> {
+        let mut issues = self.rejected.clone();
+        issues.extend(self.missing().map(|e| MediaIssue { file_id: e.file_id.join("/"), kind: IssueKind::Missing, detail: None }));
+
+        let by_path: HashMap<&Path, &DirEntry> =
+            self.entries.iter().filter_map(|e| Some((e.path.as_deref()?, e))).collect();
+        for m in metas.iter_mut() {
+            let Some(e) = by_path.get(m.path.as_path()) else { continue };
+            let r = &e.record;
+            for (name, file, record) in [
+                ("SOPInstanceUID", &m.sop_uid, &r.sop_uid),
+                ("SeriesInstanceUID", &m.series_uid, &r.series_uid),
+                ("StudyInstanceUID", &m.study_uid, &r.study_uid),
+                ("PatientID", &m.patient_id, &r.patient_id),
+            ] {
+                if let (Some(f), Some(d)) = (file, record) {
+                    if f != d {
+                        issues.push(MediaIssue {
+                            file_id: e.file_id.join("/"),
+                            kind: IssueKind::Mismatch,
+                            detail: Some(format!("{}: DICOMDIR has {}, file has {}", name, d, f)),
+                        });
+                    }
+                }
+            }
+
+            fill(&mut m.patient_id, &r.patient_id);
+            fill(&mut m.patient_name, &r.patient_name);
+            fill(&mut m.study_uid, &r.study_uid);
+            fill(&mut m.study_date, &r.study_date);
+            fill(&mut m.study_time, &r.study_time);
+            fill(&mut m.study_id, &r.study_id);
+            fill(&mut m.accession_number, &r.accession_number);
+            fill(&mut m.study_description, &r.study_description);
+            fill(&mut m.series_uid, &r.series_uid);
+            fill(&mut m.modality, &r.modality);
+            fill(&mut m.series_number, &r.series_number);
+            fill(&mut m.instance_number, &r.instance_number);
+            fill(&mut m.sop_uid, &r.sop_uid);
+            fill(&mut m.sop_class_uid, &r.sop_class_uid);
+        }
+        issues
+    }
+}
+
+/// Whether a file found while walking a directory is a DICOMDIR rather than an instance.
+pub fn is_dicomdir_name(name: &std::ffi::OsStr) -> bool {
+    name.to_str().is_some_and(|n| n.eq_ignore_ascii_case(FILE_NAME))
+}
+
+fn fill<T: Clone>(dst: &mut Option<T>, src: &Option<T>) {
+    if dst.is_none() {
+        dst.clone_from(src);
+    }
+}
+
+struct Walk<'a> {
+    root: &'a Path,
+    records: &'a HashMap<u32, &'a InMemDicomObject>,
+    visited: HashSet<u32>,
+    /// Folder -> its entries by folded name, each folder listed at most once.
+    listings: HashMap<PathBuf, Option<HashMap<String, OsString>>>,
+    entries: Vec<DirEntry>,
+    rejected: Vec<MediaIssue>,
+}
+
+impl Walk<'_> {
+    /// Follow a chain of sibling records starting at `offset`, descending into each.
+    fn siblings(&mut self, mut offset: u32, inherited: &DicomMeta) -> Result<()> {
+        while offset != 0 {
+            ensure!(self.visited.insert(offset), "directory record at offset {} is linked twice", offset);
+            let rec = *self
+                .records
+                .get(&offset)
+                .with_context(|| format!("no directory record at offset {}", offset))?;
+
+            // Records not in use (0x0000) are deleted along with everything below them.
+            let in_use = rec.element(tags::RECORD_IN_USE_FLAG).ok().and_then(|e| e.to_int::<u16>().ok()) != Some(0);
+            if in_use {
+                let mut meta = inherited.clone();
+                apply_keys(rec, &mut meta);
+                if let Some(file_id) = rec.element(tags::REFERENCED_FILE_ID).ok().and_then(|e| e.to_multi_str().ok()) {
+                    let file_id: Vec<String> = file_id.iter().map(|c| c.trim().to_string()).collect();
+                    match invalid_file_id(&file_id) {
+                        Some(detail) => {
+                            self.rejected.push(MediaIssue { file_id: file_id.join("/"), kind: IssueKind::InvalidFileId, detail: Some(detail) })
+                        }
+                        None => {
+                            let path = self.resolve(&file_id);
+                            meta.path = path.clone().unwrap_or_else(|| file_id.iter().fold(self.root.to_path_buf(), |p, c| p.join(c)));
+                            self.entries.push(DirEntry { file_id, path, record: meta.clone() });
+                        }
+                    }
+                }
+                self.siblings(uint(rec, tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY), &meta)?;
+            }
+            offset = uint(rec, tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD);
+        }
+        Ok(())
+    }
+
+    /// Locate a Referenced File ID under the root. Media copied to a case-sensitive
+    /// filesystem often changes the case of names, and ISO 9660 may append `.` or `;1`.
+    fn resolve(&mut self, file_id: &[String]) -> Option<PathBuf> {
+        let exact = file_id.iter().fold(self.root.to_path_buf(), |p, c| p.join(c));
+        if exact.is_file() {
+            return Some(exact);
+        }
+        let mut path = self.root.to_path_buf();
+        for c in file_id {
+            let listing = self.listings.entry(path.clone()).or_insert_with(|| list_folded(&path)).as_ref()?;
+            let name = listing.get(&c.to_ascii_uppercase())?.clone();
+            path.push(name);
+        }
+        path.is_file().then_some(path)
+    }
+}
+
+/// The entries of `dir` keyed by their media name: upper-cased, without an
+/// ISO 9660 version or trailing dots. The first of several look-alikes wins.
+fn list_folded(dir: &Path) -> Option<HashMap<String, OsString>> {
+    let mut names = HashMap::new();
+    for name in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).map(|e| e.file_name()) {
+        if let Some(n) = name.to_str() {
+            let n = n.strip_suffix(";1").unwrap_or(n).trim_end_matches('.');
+            names.entry(n.to_ascii_uppercase()).or_insert_with(|| name.clone());
+        }
+    }
+    Some(names)
+}
+
+/// Copy the record's key attributes over those inherited from its ancestors.
+fn apply_keys(rec: &InMemDicomObject, m: &mut DicomMeta) {
+    let text = |tag| {
+        rec.element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|s| s.trim_end_matches(['\0', ' ']).trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    let set = |dst: &mut Option<String>, tag| {
+        if let Some(v) = text(tag) {
+            *dst = Some(v);
+        }
+    };
+    set(&mut m.patient_id, tags::PATIENT_ID);
+    set(&mut m.patient_name, tags::PATIENT_NAME);
+    set(&mut m.study_uid, tags::STUDY_INSTANCE_UID);
+    set(&mut m.study_date, tags::STUDY_DATE);
+    set(&mut m.study_time, tags::STUDY_TIME);
+    set(&mut m.study_id, tags::STUDY_ID);
+    set(&mut m.accession_number, tags::ACCESSION_NUMBER);
+    set(&mut m.study_description, tags::STUDY_DESCRIPTION);
+    set(&mut m.series_uid, tags::SERIES_INSTANCE_UID);
+    set(&mut m.modality, tags::MODALITY);
+    set(&mut m.sop_uid, tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE);
+    set(&mut m.sop_class_uid, tags::REFERENCED_SOP_CLASS_UID_IN_FILE);
+    set(&mut m.transfer_syntax, tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE);
+    if let Some(n) = text(tags::SERIES_NUMBER).and_then(|s| s.parse().ok()) {
+        m.series_number = Some(n);
+    }
+    if let Some(n) = text(tags::INSTANCE_NUMBER).and_then(|s| s.parse().ok()) {
+        m.instance_number = Some(n);
+    }
+}
+
+fn uint(obj: &InMemDicomObject, tag: Tag) -> u32 {
+    obj.element(tag).ok().and_then(|e| e.to_int::<u32>().ok()).unwrap_or(0)
+}
+
+/// Why a Referenced File ID cannot be joined onto the media root, if it cannot.
+/// The DICOMDIR comes with the media, so a component must not climb out of it.
+fn invalid_file_id(file_id: &[String]) -> Option<String> {
+    if file_id.is_empty() {
+        return Some("empty File ID".into());
+    }
+    file_id.iter().find_map(|c| {
+        let bad = c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\']) || Path::new(c).is_absolute();
+        bad.then(|| format!("component {:?} is not a plain name", c))
+    })
+}
+
+/// Byte offsets of the Directory Record Sequence items, in file order.
+fn record_offsets(bytes: &[u8], start: usize) -> Result<Vec<u32>> {
+    let mut c = Cursor { bytes, pos: start };
+    while c.pos < bytes.len() {
+        let (tag, len) = c.header()?;
+        if tag == tags::DIRECTORY_RECORD_SEQUENCE {
+            return c.items(len);
+        }
+        c.skip_value(len)?;
+    }
+    Ok(Vec::new())
+}
+
+/// Just enough of an Explicit VR Little Endian parser to find where items start.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.bytes.len()).context("unexpected end of file")?;
+        let s = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().expect("2 bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    /// Element header: tag and value length. Items and delimiters have no VR.
+    fn header(&mut self) -> Result<(Tag, u32)> {
+        let tag = Tag(self.u16()?, self.u16()?);
+        if tag.group() == 0xFFFE {
+            return Ok((tag, self.u32()?));
+        }
+        let vr: [u8; 2] = self.take(2)?.try_into().expect("2 bytes");
+        let long = matches!(&vr, b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN" | b"UR" | b"UT" | b"UV");
+        let len = if long {
+            self.take(2)?;
+            self.u32()?
+        } else {
+            self.u16()? as u32
+        };
+        Ok((tag, len))
+    }
+
+    fn skip_value(&mut self, len: u32) -> Result<()> {
+        if len == UNDEFINED_LENGTH {
+            self.items(len)?;
+        } else {
+            self.take(len as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Walk the items of a sequence whose header was just read; returns where each starts.
+    fn items(&mut self, len: u32) -> Result<Vec<u32>> {
+        let end = (len != UNDEFINED_LENGTH).then(|| self.pos + len as usize);
+        let mut starts = Vec::new();
+        while end != Some(self.pos) {
+            let at = u32::try_from(self.pos).context("offset exceeds 4 GiB")?;
+            match self.header()? {
+                (SEQUENCE_DELIMITER, _) if end.is_none() => break,
+                (ITEM, item_len) => {
+                    starts.push(at);
+                    if item_len == UNDEFINED_LENGTH {
+                        self.skip_item_body()?;
+                    } else {
+                        self.take(item_len as usize)?;
+                    }
+                }
+                (tag, _) => bail!("unexpected {} inside a sequence at byte {}", tag, at),
+            }
+        }
+        Ok(starts)
+    }
+
+    fn skip_item_body(&mut self) -> Result<()> {
+        loop {
+            match self.header()? {
+                (ITEM_DELIMITER, _) => return Ok(()),
+                (_, len) => self.skip_value(len)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicomdir::write_dicomdir;
+
+    fn rec(n: i32, sop: &str) -> DicomMeta {
+        DicomMeta {
+            patient_id: Some("PAT1".into()),
+            study_uid: Some("1.2".into()),
+            study_date: Some("20240102".into()),
+            series_uid: Some("1.2.3".into()),
+            modality: Some("CT".into()),
+            sop_uid: Some(sop.into()),
+            sop_class_uid: Some("1.2.840.10008.5.1.4.1.1.2".into()),
+            instance_number: Some(n),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_resolves_and_reconciles() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("cd");
+        let images = media.join("IMAGES");
+        let records = [rec(1, "1.2.3.1"), rec(2, "1.2.3.2"), rec(3, "1.2.3.3")];
+        let entries: Vec<_> = records
+            .iter()
+            .map(|m| (images.join(format!("IM00000{}", m.instance_number.unwrap())), m))
+            .collect();
+        write_dicomdir(&media, &entries).unwrap();
+
+        // IM000001 as written, IM000002 lower-cased with an ISO 9660 version, IM000003 absent.
+        fs::create_dir_all(&images).unwrap();
+        fs::write(images.join("IM000001"), b"x").unwrap();
+        fs::write(images.join("im000002;1"), b"x").unwrap();
+
+        let d = DicomDir::read(&media.join(FILE_NAME)).unwrap();
+        assert_eq!(d.entries.len(), 3);
+        assert_eq!(d.files(), [images.join("IM000001"), images.join("im000002;1")]);
+        assert_eq!(d.entries[1].record.study_date.as_deref(), Some("20240102"));
+        assert_eq!(d.entries[1].record.modality.as_deref(), Some("CT"));
+
+        // The first file's header has no PatientID and a different SOP Instance UID.
+        let mut metas = vec![
+            DicomMeta { path: images.join("IM000001"), sop_uid: Some("9.9".into()), ..Default::default() },
+            DicomMeta { path: images.join("im000002;1"), ..rec(2, "1.2.3.2") },
+        ];
+        let issues = d.reconcile(&mut metas);
+        assert_eq!(metas[0].patient_id.as_deref(), Some("PAT1"));
+        assert_eq!(metas[0].sop_uid.as_deref(), Some("9.9"));
+        let kinds: Vec<_> = issues.iter().map(|i| (i.kind, i.file_id.as_str())).collect();
+        assert_eq!(kinds, [(IssueKind::Missing, "IMAGES/IM000003"), (IssueKind::Mismatch, "IMAGES/IM000001")]);
+
+        // A File ID that climbs out of the media is reported, never resolved.
+        let path = media.join(FILE_NAME);
+        let mut bytes = fs::read(&path).unwrap();
+        let at = bytes.windows(15).position(|w| w == b"IMAGES\\IM000003").unwrap();
+        bytes[at..at + 15].copy_from_slice(b"..\\IM000003    ");
+        fs::write(&path, bytes).unwrap();
+        fs::write(dir.path().join("IM000003"), b"x").unwrap();
+        let d = DicomDir::read(&path).unwrap();
+        assert_eq!(d.entries.len(), 2);
+        assert!(d.files().iter().all(|p| p.starts_with(&media)));
+        let issues = d.reconcile(&mut []);
+        assert_eq!((issues[0].kind, issues[0].file_id.as_str()), (IssueKind::InvalidFileId, "../IM000003"));
+    }
+
+    #[test]
+    fn test_rejects_non_dicomdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.dcm");
+        fs::write(&path, crate::synth::instance(crate::synth::CT_IMAGE_STORAGE, "1.2.3", &[])).unwrap();
+        assert!(DicomDir::read(&path).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+//! Filter expressions (`--filter`): choose which scanned instances are sorted.
+//!
+//! An expression is matched against every header after the scan and before
+//! planning, e.g.
+//!
+//! ```text
+//! Modality == CT and StudyDate in 2024..2024
+//! SeriesDescription ~ "(?i)t1.*mprage" and not ImageType ~ LOCALIZER
+//! exists (0018,0050) and SliceThickness <= 1.5
+//! ```
+//!
+//! Fields are DICOM keywords or tags, as in templates. Tags that [`DicomMeta`]
+//! does not hold are captured at scan time ([`Filter::extra_tags`]).
+//!
+//! - `==`, `!=`: equal values; numbers compare as numbers.
+//! - `~`, `!~`: the value contains a match of the regular expression.
+//! - `<`, `<=`, `>`, `>=` and `in LO..HI` (either end may be left out): dates
+//!   and times (VR DA, DT, TM) compare on as many digits as the bound has, so
+//!   `2024` stands for the whole year; other values compare as numbers.
+//! - `exists Field`: the field is present and not empty.
+//! - `not`, `and`, `or` (binding in that order) and parentheses.
+//!
+//! A comparison with a missing field is false, so `!=` and `!~` are true for
+//! it. Values with spaces or operator characters are quoted with `"` or `'`.
+
+use crate::dicom::DicomMeta;
+use anyhow::{anyhow, bail, Context, Result};
+use dicom_core::dictionary::{DataDictionary, DataDictionaryEntry, VirtualVr};
+use dicom_core::VR;
+use dicom_dictionary_std::tags;
+use dicom_object::{StandardDataDictionary, Tag};
+use regex::Regex;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Characters that end a bare word.
+const SPECIAL: &str = "()\"'=!~<>";
+
+/// A parsed `--filter` expression.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr,
+}
+
+/// An instance the filter kept out of the run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Excluded {
+    pub path: PathBuf,
+    pub sop_uid: Option<String>,
+    /// The clause that was false for it.
+    pub clause: String,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Exists(Field),
+    Test(Field, Test),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    /// As written, for messages and the report.
+    name: String,
+    get: Get,
+    temporal: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Get {
+    Meta(fn(&DicomMeta) -> Option<String>),
+    Extra(Tag),
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Eq(String),
+    Ne(String),
+    Matches(Regex),
+    NotMatches(Regex),
+    Lt(String),
+    Le(String),
+    Gt(String),
+    Ge(String),
+    In(Option<String>, Option<String>),
+}
+
+impl Filter {
+    /// Parse and validate an expression. Unknown fields, bad regular
+    /// expressions and bounds that are not numbers or dates are rejected here.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut p = Parser { tokens: tokenize(source)?, at: 0 };
+        let expr = p.or().with_context(|| format!("filter {:?}", source))?;
+        if let Some(t) = p.tokens.get(p.at) {
+            bail!("filter {:?}: unexpected {}", source, t);
+        }
+        Ok(Self { expr })
+    }
+
+    /// Every expression of a repeated `--filter` must hold.
+    pub fn parse_all(sources: &[String]) -> Result<Option<Self>> {
+        let mut exprs: Vec<Expr> = sources.iter().map(|s| Self::parse(s).map(|f| f.expr)).collect::<Result<_>>()?;
+        Ok(match exprs.len() {
+            0 => None,
+            1 => Some(Self { expr: exprs.remove(0) }),
+            _ => Some(Self { expr: Expr::And(exprs) }),
+        })
+    }
+
+    /// Raw tags that must be captured at scan time for this filter.
+    pub fn extra_tags(&self) -> Vec<Tag> {
+        let mut out = Vec::new();
+        self.expr.visit(&mut |f| {
+            if let Get::Extra(t) = f.get {
+                out.push(t);
+            }
+        });
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    /// `None` if `m` passes, or the clause that excluded it.
+    pub fn check(&self, m: &DicomMeta) -> Option<String> {
+        self.expr.failing(m).map(|e| e.to_string())
+    }
+
+    /// Split `metas` into the instances that pass and the excluded ones.
+    pub fn apply(&self, metas: Vec<DicomMeta>) -> (Vec<DicomMeta>, Vec<Excluded>) {
+        let mut kept = Vec::with_capacity(metas.len());
+        let mut excluded = Vec::new();
+        for m in metas {
+            match self.check(&m) {
+                None => kept.push(m),
+                Some(clause) => excluded.push(Excluded { path: m.path, sop_uid: m.sop_uid, clause }),
+            }
+        }
+        (kept, excluded)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
+
+impl Expr {
+    fn eval(&self, m: &DicomMeta) -> bool {
+        match self {
+            Expr::And(v) => v.iter().all(|e| e.eval(m)),
+            Expr::Or(v) => v.iter().any(|e| e.eval(m)),
+            Expr::Not(e) => !e.eval(m),
+            Expr::Exists(f) => f.value(m).is_some(),
+            Expr::Test(f, t) => t.eval(f, f.value(m).as_deref()),
+        }
+    }
+
+    /// The clause that makes this false for `m`: the first false operand of an
+    /// `and`, or the whole expression otherwise.
+    fn failing(&self, m: &DicomMeta) -> Option<&Expr> {
+        match self {
+            Expr::And(v) => v.iter().find_map(|e| e.failing(m)),
+            e => (!e.eval(m)).then_some(e),
+        }
+    }
+
+    fn visit(&self, f: &mut impl FnMut(&Field)) {
+        match self {
+            Expr::And(v) | Expr::Or(v) => v.iter().for_each(|e| e.visit(f)),
+            Expr::Not(e) => e.visit(f),
+            Expr::Exists(field) | Expr::Test(field, _) => f(field),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Operands that bind looser than their operator are parenthesized.
+        let join = |f: &mut fmt::Formatter<'_>, v: &[Expr], op: &str, wrap: fn(&Expr) -> bool| {
+            for (i, e) in v.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", op)?;
+                }
+                match wrap(e) {
+                    true => write!(f, "({})", e)?,
+                    false => write!(f, "{}", e)?,
+                }
+            }
+            Ok(())
+        };
+        match self {
+            Expr::And(v) => join(f, v, "and", |e| matches!(e, Expr::Or(_))),
+            Expr::Or(v) => join(f, v, "or", |_| false),
+            Expr::Not(e) if matches!(**e, Expr::And(_) | Expr::Or(_)) => write!(f, "not ({})", e),
+            Expr::Not(e) => write!(f, "not {}", e),
+            Expr::Exists(field) => write!(f, "exists {}", field.name),
+            Expr::Test(field, t) => {
+                let (op, v) = match t {
+                    Test::Eq(v) => ("==", v.as_str()),
+                    Test::Ne(v) => ("!=", v.as_str()),
+                    Test::Matches(r) => ("~", r.as_str()),
+                    Test::NotMatches(r) => ("!~", r.as_str()),
+                    Test::Lt(v) => ("<", v.as_str()),
+                    Test::Le(v) => ("<=", v.as_str()),
+                    Test::Gt(v) => (">", v.as_str()),
+                    Test::Ge(v) => (">=", v.as_str()),
+                    Test::In(lo, hi) => {
+                        let (lo, hi) = (lo.as_deref().unwrap_or(""), hi.as_deref().unwrap_or(""));
+                        return write!(f, "{} in {}..{}", field.name, lo, hi);
+                    }
+                };
+                write!(f, "{} {} {}", field.name, op, quoted(v))
+            }
+        }
+    }
+}
+
+/// `v` as a value token that reads back the same.
+fn quoted(v: &str) -> String {
+    let bare = !v.is_empty() && !v.contains(|c: char| c.is_whitespace() || SPECIAL.contains(c)) && !is_keyword(v);
+    match (bare, v.contains('"')) {
+        (true, _) => v.to_string(),
+        (false, false) => format!("\"{}\"", v),
+        (false, true) => format!("'{}'", v),
+    }
+}
+
+fn is_keyword(w: &str) -> bool {
+    matches!(w, "and" | "or" | "not" | "in" | "exists")
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self> {
+        let tag = StandardDataDictionary.parse_tag(name).ok_or_else(|| anyhow!("unknown DICOM keyword or tag {:?}", name))?;
+        if tag == tags::PIXEL_DATA {
+            bail!("PixelData cannot be used in a filter");
+        }
+        let vr = StandardDataDictionary.by_tag(tag).map(|e| e.vr());
+        let temporal = matches!(vr, Some(VirtualVr::Exact(VR::DA | VR::DT | VR::TM)));
+        let get = meta_field(tag).map_or(Get::Extra(tag), Get::Meta);
+        Ok(Self { name: name.to_string(), get, temporal })
+    }
+
+    /// The trimmed value, or `None` when it is missing or empty.
+    fn value(&self, m: &DicomMeta) -> Option<String> {
+        let v = match self.get {
+            Get::Meta(get) => get(m),
+            Get::Extra(tag) => m.extra_value(tag).map(str::to_string),
+        }?;
+        let v = v.trim();
+        (!v.is_empty()).then(|| v.to_string())
+    }
+
+    /// Check a bound of `<`, `>` or `in` when the filter is parsed.
+    fn bound(&self, v: &str) -> Result<String> {
+        match self.temporal {
+            true => {
+                let d = digits(v);
+                if d.is_empty() || !d.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+                    bail!("{:?} is not a date or time for {}", v, self.name);
+                }
+                Ok(d)
+            }
+            false => {
+                v.parse::<f64>().map_err(|_| anyhow!("{:?} is not a number for {}", v, self.name))?;
+                Ok(v.to_string())
+            }
+        }
+    }
+
+    /// How `value` compares with `bound`; `None` if they cannot be compared.
+    fn compare(&self, value: &str, bound: &str) -> Option<Ordering> {
+        match self.temporal {
+            true => {
+                // Bytes, not str slices: a malformed value may hold multi-byte characters.
+                let (v, b) = (digits(value), bound.as_bytes());
+                let n = b.len().min(v.len());
+                Some(v.as_bytes()[..n].cmp(&b[..n]))
+            }
+            false => value.parse::<f64>().ok()?.partial_cmp(&bound.parse::<f64>().ok()?),
+        }
+    }
+}
+
+/// A date or time without its `-` and `:` separators.
+fn digits(v: &str) -> String {
+    v.trim().replace(['-', ':'], "")
+}
+
+/// The [`DicomMeta`] field that holds `tag`, if any.
+fn meta_field(tag: Tag) -> Option<fn(&DicomMeta) -> Option<String>> {
+    let get: fn(&DicomMeta) -> Option<String> = match tag {
+        tags::PATIENT_ID => |m| m.patient_id.clone(),
+        tags::PATIENT_NAME => |m| m.patient_name.clone(),
+        tags::STUDY_INSTANCE_UID => |m| m.study_uid.clone(),
+        tags::SERIES_INSTANCE_UID => |m| m.series_uid.clone(),
+        tags::SOP_INSTANCE_UID => |m| m.sop_uid.clone(),
+        tags::SOP_CLASS_UID => |m| m.sop_class_uid.clone(),
+        tags::TRANSFER_SYNTAX_UID => |m| m.transfer_syntax.clone(),
+        tags::MODALITY => |m| m.modality.clone(),
+        tags::STUDY_DATE => |m| m.study_date.clone(),
+        tags::STUDY_TIME => |m| m.study_time.clone(),
+        tags::STUDY_ID => |m| m.study_id.clone(),
+        tags::ACCESSION_NUMBER => |m| m.accession_number.clone(),
+        tags::SERIES_NUMBER => |m| num(m.series_number),
+        tags::INSTANCE_NUMBER => |m| num(m.instance_number),
+        tags::STUDY_DESCRIPTION => |m| m.study_description.clone(),
+        tags::SERIES_DESCRIPTION => |m| m.series_description.clone(),
+        tags::IMAGE_POSITION_PATIENT => |m| m.image_position_patient.map(|v| multi(&v)),
+        tags::IMAGE_ORIENTATION_PATIENT => |m| m.image_orientation_patient.map(|v| multi(&v)),
+        tags::FRAME_OF_REFERENCE_UID => |m| m.frame_of_reference_uid.clone(),
+        tags::ROWS => |m| m.rows.map(|x| x.to_string()),
+        tags::COLUMNS => |m| m.columns.map(|x| x.to_string()),
+        tags::TEMPORAL_POSITION_IDENTIFIER => |m| num(m.temporal_position_identifier),
+        tags::TRIGGER_TIME => |m| m.trigger_time.map(|x| x.to_string()),
+        tags::ACQUISITION_NUMBER => |m| num(m.acquisition_number),
+        // Only the first echo number is kept.
+        tags::ECHO_NUMBERS => |m| num(m.echo_numbers),
+        tags::ACQUISITION_TIME => |m| m.acquisition_time.clone(),
+        _ => return None,
+    };
+    Some(get)
+}
+
+fn num(v: Option<i32>) -> Option<String> {
+    v.map(|x| x.to_string())
+}
+
+/// A multi-valued number as it appears in the header.
+fn multi(v: &[f64]) -> String {
+    v.iter().map(f64::to_string).collect::<Vec<_>>().join("\\")
+}
+
+impl Test {
+    fn eval(&self, field: &Field, value: Option<&str>) -> bool {
+        let cmp = |bound: &str| value.and_then(|v| field.compare(v, bound));
+        match self {
+            Test::Eq(want) => value.is_some_and(|v| equal(v, want)),
+            Test::Ne(want) => !value.is_some_and(|v| equal(v, want)),
+            Test::Matches(r) => value.is_some_and(|v| r.is_match(v)),
+            Test::NotMatches(r) => !value.is_some_and(|v| r.is_match(v)),
+            Test::Lt(b) => cmp(b) == Some(Ordering::Less),
+            Test::Le(b) => matches!(cmp(b), Some(Ordering::Less | Ordering::Equal)),
+            Test::Gt(b) => cmp(b) == Some(Ordering::Greater),
+            Test::Ge(b) => matches!(cmp(b), Some(Ordering::Greater | Ordering::Equal)),
+            Test::In(lo, hi) => {
+                value.is_some()
+                    && lo.as_deref().is_none_or(|b| matches!(cmp(b), Some(Ordering::Greater | Ordering::Equal)))
+                    && hi.as_deref().is_none_or(|b| matches!(cmp(b), Some(Ordering::Less | Ordering::Equal)))
+            }
+        }
+    }
+}
+
+fn equal(v: &str, want: &str) -> bool {
+    match (v.parse::<f64>(), want.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => v == want,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Op(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "\"(\""),
+            Token::Close => write!(f, "\")\""),
+            Token::Op(op) => write!(f, "{:?}", op),
+            Token::Word(w) => write!(f, "{:?}", w),
+            Token::Quoted(q) => write!(f, "quoted {:?}", q),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    // Longest first.
+    const OPS: [&str; 8] = ["==", "!=", "!~", "<=", ">=", "~", "<", ">"];
+    let mut out = Vec::new();
+    let mut rest = src.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            // A tag like (0018,0050) is a word, not a group.
+            '(' if rest.get(..11).is_some_and(|t| t.ends_with(')') && StandardDataDictionary.parse_tag(t).is_some()) => {
+                out.push(Token::Word(rest[..11].to_string()));
+                11
+            }
+            '(' => {
+                out.push(Token::Open);
+                1
+            }
+            ')' => {
+                out.push(Token::Close);
+                1
+            }
+            '"' | '\'' => {
+                let end = rest[1..].find(c).ok_or_else(|| anyhow!("filter {:?}: unterminated quote", src))?;
+                out.push(Token::Quoted(rest[1..1 + end].to_string()));
+                end + 2
+            }
+            _ if SPECIAL.contains(c) => {
+                let op = OPS.iter().find(|op| rest.starts_with(**op)).ok_or_else(|| anyhow!("filter {:?}: unexpected {:?}", src, c))?;
+                out.push(Token::Op(op));
+                op.len()
+            }
+            _ => {
+                let end = rest.find(|c: char| c.is_whitespace() || SPECIAL.contains(c)).unwrap_or(rest.len());
+                out.push(Token::Word(rest[..end].to_string()));
+                end
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(out)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<Token> {
+        let t = self.tokens.get(self.at).cloned().ok_or_else(|| anyhow!("unexpected end"))?;
+        self.at += 1;
+        Ok(t)
+    }
+
+    /// Consume the keyword `k` if it comes next.
+    fn keyword(&mut self, k: &str) -> bool {
+        let found = matches!(self.tokens.get(self.at), Some(Token::Word(w)) if w == k);
+        self.at += usize::from(found);
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut v = vec![self.and()?];
+        while self.keyword("or") {
+            v.push(self.and()?);
+        }
+        Ok(if v.len() == 1 { v.remove(0) } else { Expr::Or(v) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut v = vec![self.unary()?];
+        while self.keyword("and") {
+            v.push(self.unary()?);
+        }
+        Ok(if v.len() == 1 { v.remove(0) } else { Expr::And(v) })
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.keyword("exists") {
+            return Ok(Expr::Exists(self.field()?));
+        }
+        if self.tokens.get(self.at) == Some(&Token::Open) {
+            self.at += 1;
+            let e = self.or()?;
+            return match self.next()? {
+                Token::Close => Ok(e),
+                t => bail!("expected \")\", found {}", t),
+            };
+        }
+        let field = self.field()?;
+        let test = match self.next()? {
+            Token::Word(w) if w == "in" => {
+                let range = self.value()?;
+                let (lo, hi) = range.split_once("..").ok_or_else(|| anyhow!("expected a range LO..HI after `in`, found {:?}", range))?;
+                let bound = |b: &str| (!b.is_empty()).then(|| field.bound(b)).transpose();
+                Test::In(bound(lo)?, bound(hi)?)
+            }
+            Token::Op(op) => {
+                let v = self.value()?;
+                let regex = |v: &str| Regex::new(v).with_context(|| format!("regular expression for {}", field.name));
+                match op {
+                    "==" => Test::Eq(v),
+                    "!=" => Test::Ne(v),
+                    "~" => Test::Matches(regex(&v)?),
+                    "!~" => Test::NotMatches(regex(&v)?),
+                    "<" => Test::Lt(field.bound(&v)?),
+                    "<=" => Test::Le(field.bound(&v)?),
+                    ">" => Test::Gt(field.bound(&v)?),
+                    _ => Test::Ge(field.bound(&v)?),
+                }
+            }
+            t => bail!("expected an operator after {}, found {}", field.name, t),
+        };
+        Ok(Expr::Test(field, test))
+    }
+
+    fn field(&mut self) -> Result<Field> {
+        match self.next()? {
+            Token::Word(w) if !is_keyword(&w) => Field::parse(&w),
+            t => bail!("expected a DICOM keyword or tag, found {}", t),
+        }
+    }
+
+    fn value(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Word(w) | Token::Quoted(w) => Ok(w),
+            t => bail!("expected a value, found {}", t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> DicomMeta {
+        let mut m = DicomMeta {
+            path: PathBuf::from("a.dcm"),
+            modality: Some("CT".into()),
+            study_date: Some("20240315".into()),
+            series_number: Some(3),
+            series_description: Some("T1 MPRAGE sag".into()),
+            ..Default::default()
+        };
+        m.extra.insert(tags::IMAGE_TYPE.to_string(), "ORIGINAL\\PRIMARY\\AXIAL".into());
+        m.extra.insert(tags::SLICE_THICKNESS.to_string(), "1.0".into());
+        m
+    }
+
+    #[test]
+    fn test_expressions() {
+        let m = meta();
+        let pass = |src: &str| Filter::parse(src).unwrap().check(&m).is_none();
+        assert!(pass("Modality == CT and StudyDate in 2024..2024"));
+        assert!(pass("SeriesDescription ~ \"(?i)t1.*mprage\""));
+        assert!(pass("not ImageType ~ LOCALIZER and SliceThickness <= 1.5"));
+        assert!(pass("SeriesNumber == 3.0 and SeriesNumber in 2.."));
+        assert!(pass("StudyDate >= 2024-03 and StudyDate < 20240316"));
+        assert!(pass("exists (0018,0050) and not exists AccessionNumber"));
+        assert!(pass("Modality == MR or (Modality == CT and not (SeriesNumber > 3 or SeriesNumber < 3))"));
+        assert!(pass("AccessionNumber != 123"));
+        assert!(!pass("StudyDate > 2024"));
+        assert!(!pass("AccessionNumber ~ ."));
+
+        let mut odd = meta();
+        odd.study_date = Some("2024031\u{e9}".into());
+        assert!(Filter::parse("StudyDate > 20240301").unwrap().check(&odd).is_none());
+
+        let f = Filter::parse_all(&["Modality == CT".into(), "StudyDate in ..2023 or SeriesNumber > 5".into()]).unwrap().unwrap();
+        assert_eq!(f.check(&m).as_deref(), Some("StudyDate in ..2023 or SeriesNumber > 5"));
+        assert_eq!(f.extra_tags(), Vec::<Tag>::new());
+        let (kept, excluded) = f.apply(vec![m.clone()]);
+        assert!(kept.is_empty());
+        assert_eq!(excluded[0].clause, "StudyDate in ..2023 or SeriesNumber > 5");
+        assert_eq!(Filter::parse("not ImageType ~ 'A B' and SliceThickness < 2").unwrap().extra_tags(), [tags::IMAGE_TYPE, tags::SLICE_THICKNESS]);
+
+        for bad in ["", "Modality", "Modality ==", "Modality = CT", "Nope == 1", "SeriesNumber < x", "StudyDate in 2024", "Modality ~ \"(\"", "(Modality == CT"] {
+            assert!(Filter::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
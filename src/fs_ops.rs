@@ -1,8 +1,21 @@
-use crate::types::Mode;
+use crate::archive;
+use crate::deid::Deidentifier;
+use crate::dicom::{DicomMeta, ScanFailure};
+use crate::dicomdir::read::is_dicomdir_name;
+use crate::dicomdir::{is_media_component, media_name};
+use crate::sink::{Content, Sink};
 use crate::sort::Plan;
-use anyhow::{Context, Result};
+use crate::types::OnConflict;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use walkdir::WalkDir;
 
 pub fn collect_files(root: &Path, follow_symlinks: bool) -> Result<Vec<PathBuf>> {
@@ -12,75 +25,526 @@ pub fn collect_files(root: &Path, follow_symlinks: bool) -> Result<Vec<PathBuf>>
         .into_iter()
         .filter_map(|e| e.ok())
     {
-        if entry.file_type().is_file() {
-            files.push(entry.into_path());
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        // A DICOMDIR indexes the media; it is not an instance to sort.
+        if is_dicomdir_name(entry.file_name()) {
+            tracing::debug!("Skipping DICOMDIR {}", entry.path().display());
+            continue;
         }
+        files.push(entry.into_path());
     }
     Ok(files)
 }
 
-pub fn execute(plans: Vec<Plan>, mode: Mode, dry_run: bool) -> Result<()> {
-    for p in plans {
-        let dst = unique_path(&p.dst);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictAction {
+    SkippedIdentical,
+    Overwritten,
+    Renamed,
+}
 
-        if dry_run {
-            println!("{} -> {}", p.src.display(), dst.display());
-            continue;
+/// What happened to a plan whose destination already existed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConflictRecord {
+    pub src: PathBuf,
+    pub planned: PathBuf,
+    pub actual: PathBuf,
+    pub action: ConflictAction,
+}
+
+/// Carry out `plans` into `sink`. With `deid`, each file is rewritten de-identified
+/// instead of being stored byte-for-byte; plan metas are expected to be de-identified already.
+///
+/// Destination folders are created once, up front. Plain files are then placed
+/// by up to `jobs` threads. Plans that share a destination are kept together
+/// and placed in plan order by one thread. Renames never pick a name that another
+/// plan is headed for. Together, that makes collision handling race-free, and the
+/// outcome (conflicts included, in order) does not depend on `jobs` or timing.
+///
+/// Plans whose source is an archive member are read one pass per archive, after
+/// the plain files, and handed to the sink one instance at a time. The archives
+/// are never modified, even by a sink that consumes its sources.
+///
+/// The sink is not finished, so the caller can add more (e.g. a DICOMDIR).
+pub fn execute(
+    plans: Vec<Plan>,
+    sink: &dyn Sink,
+    on_conflict: OnConflict,
+    dry_run: bool,
+    deid: Option<&Deidentifier>,
+    jobs: usize,
+) -> Result<Vec<ConflictRecord>> {
+    let (members, files): (Vec<Plan>, Vec<Plan>) =
+        plans.into_iter().partition(|p| archive::split_member(&p.src).is_some());
+    let placer = Placer {
+        sink,
+        on_conflict,
+        dry_run,
+        deid,
+        planned: files.iter().chain(&members).map(|p| p.dst.as_path()).collect(),
+    };
+
+    if !dry_run {
+        let mut parents = HashSet::new();
+        for p in files.iter().chain(&members) {
+            if parents.insert(p.dst.parent()) {
+                sink.create_parent(&p.dst)?;
+            }
         }
+    }
+
+    let mut groups: Vec<Vec<&Plan>> = Vec::new();
+    let mut by_dst: HashMap<&Path, usize> = HashMap::new();
+    for p in &files {
+        let g = *by_dst.entry(&p.dst).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[g].push(p);
+    }
+    // A dry run prints as it goes, so keep it in order.
+    let jobs = if dry_run { 1 } else { jobs.max(1) };
+    let mut conflicts = in_parallel(&groups, jobs, |p| placer.place(p, Some(Content::File(&p.src))))?;
 
-        if let Some(parent) = dst.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("create dir: {}", parent.display()))?;
+    if !members.is_empty() && sink.consumes_sources() {
+        tracing::info!("{} archive members are extracted; the archives themselves are left in place", members.len());
+    }
+    if dry_run {
+        // Members are not read in a dry run, so they cannot be compared.
+        for p in &members {
+            conflicts.extend(placer.place(p, None)?);
         }
+        return Ok(conflicts);
+    }
+    archive::for_each_member(&members, |p, entry| {
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf).with_context(|| format!("extract {}", p.src.display()))?;
+        conflicts.extend(placer.place(p, Some(Content::Bytes(&buf)))?);
+        Ok(())
+    })?;
+    Ok(conflicts)
+}
 
-        match mode {
-            Mode::Copy => {
-                fs::copy(&p.src, &dst)
-                    .with_context(|| format!("copy {} -> {}", p.src.display(), dst.display()))?;
+/// Run `f` on every plan, with each group handled in order by one of `jobs`
+/// threads. The results come back in group order. On failure, no new groups
+/// are started, and the error from the earliest failed group is returned.
+fn in_parallel<T: Send>(
+    groups: &[Vec<&Plan>],
+    jobs: usize,
+    f: impl Fn(&Plan) -> Result<Option<T>> + Sync,
+) -> Result<Vec<T>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let done = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..jobs.min(groups.len()) {
+            s.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let g = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(group) = groups.get(g) else { break };
+                    let r: Result<Vec<T>> = group.iter().filter_map(|p| f(p).transpose()).collect();
+                    if r.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    done.lock().unwrap().push((g, r));
+                }
+            });
+        }
+    });
+    let mut done = done.into_inner().unwrap();
+    done.sort_by_key(|(g, _)| *g);
+    let mut out = Vec::new();
+    for (_, r) in done {
+        out.extend(r?);
+    }
+    Ok(out)
+}
+
+/// What every [`Placer::place`] call of one `execute` shares.
+struct Placer<'a> {
+    sink: &'a dyn Sink,
+    on_conflict: OnConflict,
+    dry_run: bool,
+    deid: Option<&'a Deidentifier>,
+    /// Every planned destination; renames stay clear of them.
+    planned: HashSet<&'a Path>,
+}
+
+impl Placer<'_> {
+    /// Store one plan's `content` (`None` in a dry run for archive members) at its destination.
+    fn place(&self, p: &Plan, content: Option<Content>) -> Result<Option<ConflictRecord>> {
+        let sink = self.sink;
+        // Rewrite first, so skip-identical compares what would actually be stored.
+        let rewritten = match (self.deid, content) {
+            (Some(d), Some(Content::File(src))) => {
+                let mut f = fs::File::open(src).with_context(|| format!("open {}", src.display()))?;
+                Some(d.encode(src, &mut f)?)
             }
-            Mode::Move => {
-                if let Err(e) = fs::rename(&p.src, &dst) {
-                    // Cross-device rename fallback
-                    fs::copy(&p.src, &dst)
-                        .with_context(|| format!("copy (move fallback) {} -> {}", p.src.display(), dst.display()))?;
-                    fs::remove_file(&p.src)
-                        .with_context(|| format!("remove (move fallback) {}", p.src.display()))?;
-                    // preserve original error context (optional)
-                    let _ = e;
+            (Some(d), Some(Content::Bytes(mut b))) => Some(d.encode(&p.src, &mut b)?),
+            _ => None,
+        };
+        let content = rewritten.as_deref().map(Content::Bytes).or(content);
+
+        let (dst, conflict) = resolve_conflict(sink, p, content, self.on_conflict, &self.planned)?;
+        let action = conflict.as_ref().map(|c| c.action);
+
+        if self.dry_run {
+            match action {
+                Some(ConflictAction::SkippedIdentical) => println!("{} == {} (skip: identical)", p.src.display(), dst.display()),
+                Some(ConflictAction::Overwritten) => println!("{} -> {} (overwrite)", p.src.display(), dst.display()),
+                _ => println!("{} -> {}", p.src.display(), dst.display()),
+            }
+        } else if action == Some(ConflictAction::SkippedIdentical) {
+            // `resolve_conflict` has compared the copy, so a moved source is no longer needed.
+            if sink.consumes_sources() && archive::split_member(&p.src).is_none() {
+                sink.drop_source(&p.src, &dst)?;
+            }
+        } else {
+            if action == Some(ConflictAction::Overwritten) {
+                sink.remove(&dst)?;
+            }
+            match content.context("nothing to store")? {
+                Content::File(src) => sink.link(src, &dst)?,
+                Content::Bytes(b) => {
+                    sink.put(&dst, &mut &b[..])?;
+                    if rewritten.is_some() && sink.consumes_sources() && archive::split_member(&p.src).is_none() {
+                        // The original is about to be deleted; make sure the rewrite landed intact.
+                        if !sink.holds(&dst, Content::Bytes(b), p.meta.sop_uid.as_deref())? {
+                            bail!("stored rewrite {} does not match; kept {}", dst.display(), p.src.display());
+                        }
+                        fs::remove_file(&p.src).with_context(|| format!("remove (move) {}", p.src.display()))?;
+                    }
+                }
+            }
+        }
+        Ok(conflict)
+    }
+}
+
+/// Decide the final destination for a plan according to the conflict policy.
+/// Without `content` (a dry run over archive members), nothing counts as identical.
+/// Alternative names in `planned` belong to other plans and are never used or compared.
+fn resolve_conflict(
+    sink: &dyn Sink,
+    p: &Plan,
+    content: Option<Content>,
+    policy: OnConflict,
+    planned: &HashSet<&Path>,
+) -> Result<(PathBuf, Option<ConflictRecord>)> {
+    if !sink.exists(&p.dst)? {
+        return Ok((p.dst.clone(), None));
+    }
+
+    let record = |actual: PathBuf, action| {
+        let r = ConflictRecord { src: p.src.clone(), planned: p.dst.clone(), actual: actual.clone(), action };
+        Ok((actual, Some(r)))
+    };
+    let mut candidates = candidates(&p.dst).filter(|c| *c == p.dst || !planned.contains(c.as_path()));
+
+    match policy {
+        OnConflict::Error => bail!("destination already exists: {} (from {})", p.dst.display(), p.src.display()),
+        OnConflict::Overwrite => record(p.dst.clone(), ConflictAction::Overwritten),
+        OnConflict::Rename => {
+            for candidate in candidates {
+                if !sink.exists(&candidate)? {
+                    return record(candidate, ConflictAction::Renamed);
                 }
             }
-            Mode::HardLink => {
-                if let Err(_e) = fs::hard_link(&p.src, &dst) {
-                    // Fallback to copy if hardlink not possible (different volume, permissions, etc.)
-                    fs::copy(&p.src, &dst)
-                        .with_context(|| format!("copy (hardlink fallback) {} -> {}", p.src.display(), dst.display()))?;
+            bail!("too many collisions for {}", p.dst.display())
+        }
+        OnConflict::SkipIdentical => {
+            // Earlier runs may have renamed collisions to `_1`, `_2`, ...; check those too.
+            for candidate in candidates.by_ref() {
+                if !sink.exists(&candidate)? {
+                    return record(candidate, ConflictAction::Renamed);
+                }
+                if let Some(content) = content {
+                    if sink.holds(&candidate, content, p.meta.sop_uid.as_deref())? {
+                        return record(candidate, ConflictAction::SkippedIdentical);
+                    }
                 }
             }
+            bail!("too many collisions for {}", p.dst.display())
         }
     }
-    Ok(())
 }
 
-fn unique_path(dst: &Path) -> PathBuf {
-    if !dst.exists() {
-        return dst.to_path_buf();
+/// Hex-encoded SHA-256 of a file's contents.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut f = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher).with_context(|| format!("read {}", path.display()))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Replace `path` with `contents` in one rename, through a `.part` file that is
+/// created readable by its owner only (mode 0600 on Unix). For files holding
+/// identifiers, such as mapping tables, so a crash never leaves a truncated copy.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    // A leftover from a crash may have other permissions; the mode only applies on creation.
+    match fs::remove_file(&part) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
     }
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut f = opts.open(&part)?;
+    io::Write::write_all(&mut f, contents)?;
+    f.sync_all()?;
+    fs::rename(&part, path)
+}
 
+/// Store rejected files in `sink` under `dir/<failure kind>/<path relative to input>`.
+/// Name clashes are renamed, as with `--on-conflict rename`.
+pub fn quarantine(
+    failures: &[ScanFailure],
+    input_root: &Path,
+    dir: &Path,
+    sink: &dyn Sink,
+    dry_run: bool,
+    jobs: usize,
+) -> Result<()> {
+    let plans = failures
+        .iter()
+        .map(|f| {
+            let rel = f
+                .path
+                .strip_prefix(input_root)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| f.path.file_name().map(PathBuf::from).unwrap_or_else(|| "UNKNOWN".into()));
+            // Archive member names come from the archive; never let one climb out of `dir`.
+            if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                bail!("refusing to quarantine {} outside {}", f.path.display(), dir.display());
+            }
+            let meta = DicomMeta { path: f.path.clone(), ..Default::default() };
+            Ok(Plan::new(f.path.clone(), dir.join(f.kind.as_str()).join(rel), meta))
+        })
+        .collect::<Result<_>>()?;
+    execute(plans, sink, OnConflict::Rename, dry_run, None, jobs)?;
+    Ok(())
+}
+
+/// `dst`, then `stem_1.ext`, `stem_2.ext`, ... up to 10,000 variations.
+/// Full-length media names (`I0000001`) have no room for a suffix, so their
+/// variations are hashed 8-character names that still fit a DICOMDIR.
+// If you somehow have 10k collisions, congratulations, you found a new hobby.
+fn candidates(dst: &Path) -> impl Iterator<Item = PathBuf> + '_ {
     let parent = dst.parent().unwrap_or_else(|| Path::new("."));
     let stem = dst.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or("file".into());
     let ext = dst.extension().map(|s| s.to_string_lossy().to_string());
+    let media = ext.is_none() && stem.len() == 8 && is_media_component(&stem);
 
-    for i in 1..10_000u32 {
+    std::iter::once(dst.to_path_buf()).chain((1..10_000u32).map(move |i| {
         let name = match &ext {
             Some(ext) => format!("{}_{}.{}", stem, i, ext),
+            None if media => media_name(stem.chars().next().expect("8 characters"), &format!("{}_{}", stem, i)),
             None => format!("{}_{}", stem, i),
         };
-        let candidate = parent.join(name);
-        if !candidate.exists() {
-            return candidate;
+        parent.join(name)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom::{DicomMeta, FailureKind};
+    use crate::sink::{self, SinkResult};
+    /// What a library user might plug in: everything kept in memory.
+    #[derive(Default)]
+    struct MemSink(Mutex<HashMap<PathBuf, Vec<u8>>>);
+
+    impl Sink for MemSink {
+        fn exists(&self, dst: &Path) -> SinkResult<bool> {
+            Ok(self.0.lock().unwrap().contains_key(dst))
+        }
+        fn holds(&self, dst: &Path, content: Content<'_>, _sop_uid: Option<&str>) -> SinkResult<bool> {
+            let stored = self.0.lock().unwrap()[dst].clone();
+            Ok(Content::Bytes(&stored).sha256()? == content.sha256()?)
+        }
+        fn put(&self, dst: &Path, data: &mut dyn io::Read) -> SinkResult<()> {
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).unwrap();
+            self.0.lock().unwrap().insert(dst.to_path_buf(), buf);
+            Ok(())
+        }
+    }
+
+    fn plan(src: &Path, dst: &Path) -> Vec<Plan> {
+        vec![Plan::new(src.to_path_buf(), dst.to_path_buf(), DicomMeta::default())]
+    }
+
+    #[test]
+    fn test_rerun_skips_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        let dst = dir.path().join("out/00001_x.dcm");
+        fs::write(&src, b"same bytes").unwrap();
+
+        let first = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert!(first.is_empty());
+
+        let second = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].action, ConflictAction::SkippedIdentical);
+        assert_eq!(fs::read_dir(dir.path().join("out")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_move_drops_identical_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        let dst = dir.path().join("out/00001_x.dcm");
+        fs::create_dir_all(dst.parent().unwrap()).unwrap();
+        fs::write(&dst, b"same bytes").unwrap();
+        fs::write(&src, b"same bytes").unwrap();
+
+        let journal = crate::journal::Journal::in_memory();
+        let sink = crate::journal::Journaled::new(sink::for_mode(crate::types::Mode::Move), &journal);
+        let r = execute(plan(&src, &dst), &sink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::SkippedIdentical);
+        assert!(!src.exists());
+        assert_eq!(fs::read(&dst).unwrap(), b"same bytes");
+
+        // Undo brings the source back and keeps the copy that was already there.
+        assert_eq!(crate::journal::undo(&journal.records()).restored, 1);
+        assert_eq!(fs::read(&src).unwrap(), b"same bytes");
+        assert!(dst.exists());
+    }
+
+    #[test]
+    fn test_different_content_is_renamed_then_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        let dst = dir.path().join("out/00001_x.dcm");
+        fs::create_dir_all(dst.parent().unwrap()).unwrap();
+        fs::write(&dst, b"old bytes!").unwrap();
+        fs::write(&src, b"new bytes!").unwrap();
+
+        let r = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::Renamed);
+        assert_eq!(r[0].actual, dir.path().join("out/00001_x_1.dcm"));
+
+        // The renamed copy from the previous run is recognised.
+        let r = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::SkippedIdentical);
+        assert_eq!(r[0].actual, dir.path().join("out/00001_x_1.dcm"));
+    }
+
+    #[test]
+    fn test_media_names_are_renamed_to_media_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        let dst = dir.path().join("P0000000/I0000001");
+        fs::create_dir_all(dst.parent().unwrap()).unwrap();
+        fs::write(&dst, b"old bytes!").unwrap();
+        fs::write(&src, b"new bytes!").unwrap();
+
+        let r = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::Renamed);
+        assert_ne!(r[0].actual, dst);
+        crate::dicomdir::file_id(dir.path(), &r[0].actual).unwrap();
+
+        let r2 = execute(plan(&src, &dst), &sink::CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r2[0].action, ConflictAction::SkippedIdentical);
+        assert_eq!(r2[0].actual, r[0].actual);
+    }
+
+    #[test]
+    fn test_overwrite_and_error_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        let dst = dir.path().join("b.dcm");
+        fs::write(&src, b"new").unwrap();
+        fs::write(&dst, b"old").unwrap();
+
+        assert!(execute(plan(&src, &dst), &sink::CopySink, OnConflict::Error, false, None, 1).is_err());
+
+        let r = execute(plan(&src, &dst), &sink::HardLinkSink, OnConflict::Overwrite, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::Overwritten);
+        assert_eq!(fs::read(&dst).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_custom_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.dcm");
+        let b = dir.path().join("b.dcm");
+        fs::write(&a, b"first").unwrap();
+        fs::write(&b, b"second").unwrap();
+        let dst = Path::new("P/S/00001_x.dcm");
+
+        let sink = MemSink::default();
+        assert!(execute(plan(&a, dst), &sink, OnConflict::SkipIdentical, false, None, 1).unwrap().is_empty());
+        let r = execute(plan(&a, dst), &sink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].action, ConflictAction::SkippedIdentical);
+        let r = execute(plan(&b, dst), &sink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+        assert_eq!(r[0].actual, Path::new("P/S/00001_x_1.dcm"));
+        assert_eq!(sink.0.lock().unwrap()[&r[0].actual], b"second");
+        assert!(a.exists() && b.exists());
+
+        // Replacing needs `Sink::remove`, which this sink does not offer.
+        assert!(execute(plan(&b, dst), &sink, OnConflict::Overwrite, false, None, 1).is_err());
+    }
+
+    #[test]
+    fn test_quarantine_keeps_input_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        let bad = input.join("ward/day1/junk.txt");
+        fs::create_dir_all(bad.parent().unwrap()).unwrap();
+        let failures = || vec![ScanFailure { path: bad.clone(), kind: FailureKind::NotDicom, error: anyhow::anyhow!("no DICM") }];
+
+        for (mode, kept) in [(crate::types::Mode::Copy, true), (crate::types::Mode::Move, false)] {
+            fs::write(&bad, b"not dicom").unwrap();
+            let q = dir.path().join(format!("q-{:?}", mode));
+            quarantine(&failures(), &input, &q, sink::for_mode(mode).as_ref(), false, 1).unwrap();
+            assert_eq!(fs::read(q.join("not_dicom/ward/day1/junk.txt")).unwrap(), b"not dicom");
+            assert_eq!(bad.exists(), kept, "{:?}", mode);
         }
+
+        let slip = vec![ScanFailure { path: input.join("x.zip!/../../escaped.txt"), kind: FailureKind::NotDicom, error: anyhow::anyhow!("no DICM") }];
+        assert!(quarantine(&slip, &input, &dir.path().join("q"), &sink::CopySink, false, 1).is_err());
+        assert!(!dir.path().join("escaped.txt").exists());
     }
 
-    // If you somehow have 10k collisions, congratulations, you found a new hobby.
-    dst.to_path_buf()
+    #[test]
+    fn test_parallel_collisions_match_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut plans = Vec::new();
+        for i in 0..24 {
+            let src = dir.path().join(format!("in/{}.dcm", i));
+            fs::create_dir_all(src.parent().unwrap()).unwrap();
+            fs::write(&src, format!("file {}", i % 8)).unwrap();
+            // Three plans per destination, and `x_1.dcm` is also planned outright.
+            let dst = match i {
+                23 => "x_1.dcm".to_string(),
+                _ => format!("{}.dcm", ["x", "y", "z", "w"][i % 4]),
+            };
+            plans.push(Plan::new(src, PathBuf::from(dst), DicomMeta::default()));
+        }
+
+        let mut runs = Vec::new();
+        for jobs in [1, 4] {
+            let out = dir.path().join(format!("out{}", jobs));
+            let plans: Vec<_> = plans.iter().map(|p| Plan::new(p.src.clone(), out.join(&p.dst), DicomMeta::default())).collect();
+            let r = execute(plans, &sink::CopySink, OnConflict::Rename, false, None, jobs).unwrap();
+            let r: Vec<_> = r.iter().map(|c| (c.src.clone(), c.actual.strip_prefix(&out).unwrap().to_path_buf())).collect();
+            assert_eq!(fs::read_dir(&out).unwrap().count(), 24);
+            assert_eq!(fs::read(out.join("x_1.dcm")).unwrap(), b"file 7");
+            runs.push(r);
+        }
+        assert_eq!(runs[0].len(), 19);
+        assert_eq!(runs[0], runs[1]);
+    }
 }
@@ -0,0 +1,343 @@
+//! Write-ahead journal of what a run changes on disk, for `dcmsort undo` and
+//! `--rollback-on-error`.
+//!
+//! [`Journaled`] wraps an on-disk [`Sink`]. Before each change it appends a
+//! `begin` line describing it, and after the change a `done` line. The file is
+//! JSON lines written straight through, so it survives the process being
+//! killed mid-run (it is not fsynced, so a power loss may lose the tail).
+//! [`undo`] works backwards through the records: moved files go back to their
+//! source, created files are deleted, sources dropped as identical to an
+//! existing output are copied back from it, and created directories are
+//! removed when empty. Changes that were begun but never finished are undone as far as they
+//! got, so undoing twice is harmless.
+//!
+//! Overwriting, and deleting with no identical copy left, are never journaled:
+//! the old content is gone, so the caller must not journal `--on-conflict
+//! overwrite`, or a move that rewrites and then deletes its sources.
+
+use crate::sink::{Content, CopySink, MoveSink, Sink, SinkError, SinkResult};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A change to the filesystem. Paths are absolute, so `undo` can run from anywhere.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Op {
+    /// `src` was renamed (or copied and deleted) to `dst`.
+    Move { src: PathBuf, dst: PathBuf },
+    /// `dst` was written: a copy, hard link, rewrite or DICOMDIR.
+    Create { dst: PathBuf },
+    /// An output directory that did not exist before.
+    Dir { path: PathBuf },
+    /// `src` was deleted because `kept`, already in the output, holds the same content.
+    Consume { src: PathBuf, kept: PathBuf },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Line {
+    Begin { seq: usize, op: Op },
+    Done { seq: usize },
+}
+
+/// A journaled change and whether it completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub op: Op,
+    pub done: bool,
+}
+
+/// Shared by every worker of a run; lines are appended under one lock, so
+/// `seq` is always the record's position.
+pub struct Journal {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: Option<File>,
+    records: Vec<Record>,
+}
+
+impl Journal {
+    /// Start a new journal file. An existing file is never replaced, since
+    /// `undo` may still need it.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::options()
+            .append(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("create journal: {}", path.display()))?;
+        Ok(Self { path: Some(path.to_path_buf()), state: Mutex::new(State { file: Some(file), records: Vec::new() }) })
+    }
+
+    /// A journal kept only in memory, enough for `--rollback-on-error`.
+    pub fn in_memory() -> Self {
+        Self { path: None, state: Mutex::new(State { file: None, records: Vec::new() }) }
+    }
+
+    /// The records of a journal file. A torn last line (the process was
+    /// killed while writing it) is ignored.
+    pub fn read(path: &Path) -> Result<Vec<Record>> {
+        let s = fs::read_to_string(path).with_context(|| format!("read journal: {}", path.display()))?;
+        let lines: Vec<_> = s.lines().filter(|l| !l.trim().is_empty()).collect();
+        let mut records: Vec<Record> = Vec::new();
+        for (i, l) in lines.iter().enumerate() {
+            let line = match serde_json::from_str(l) {
+                Ok(line) => line,
+                Err(_) if i + 1 == lines.len() && !s.ends_with('\n') => break,
+                Err(e) => return Err(e).with_context(|| format!("parse journal {} line {}", path.display(), i + 1)),
+            };
+            match line {
+                Line::Begin { op, .. } => records.push(Record { op, done: false }),
+                Line::Done { seq } => match records.get_mut(seq) {
+                    Some(r) => r.done = true,
+                    None => anyhow::bail!("journal {} line {}: no operation {}", path.display(), i + 1, seq),
+                },
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        self.state.lock().unwrap().records.clone()
+    }
+
+    /// Undo everything recorded so far; see [`undo`].
+    pub fn rollback(&self) -> Undone {
+        undo(&self.records())
+    }
+
+    fn append(&self, state: &mut State, line: &Line) -> SinkResult<()> {
+        if let (Some(path), Some(file)) = (&self.path, &mut state.file) {
+            let mut s = serde_json::to_string(line).map_err(|e| SinkError::Other(e.into()))?;
+            s.push('\n');
+            file.write_all(s.as_bytes()).map_err(|e| SinkError::io("append to journal", path, e))?;
+        }
+        Ok(())
+    }
+
+    fn begin(&self, op: Op) -> SinkResult<usize> {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.records.len();
+        self.append(&mut state, &Line::Begin { seq, op: op.clone() })?;
+        state.records.push(Record { op, done: false });
+        Ok(seq)
+    }
+
+    fn done(&self, seq: usize) -> SinkResult<()> {
+        let mut state = self.state.lock().unwrap();
+        self.append(&mut state, &Line::Done { seq })?;
+        state.records[seq].done = true;
+        Ok(())
+    }
+
+    /// Journal `op`, then run `f` and mark it done if it succeeds.
+    fn around<T>(&self, op: Op, f: impl FnOnce() -> SinkResult<T>) -> SinkResult<T> {
+        let seq = self.begin(op)?;
+        let out = f()?;
+        self.done(seq)?;
+        Ok(out)
+    }
+}
+
+/// What [`undo`] did.
+#[derive(Debug, Default)]
+pub struct Undone {
+    /// Sources put back: moved back, or copied back from an identical output.
+    pub restored: usize,
+    /// Created files deleted.
+    pub removed: usize,
+    /// Changes that could not be undone, with the reason.
+    pub problems: Vec<(PathBuf, String)>,
+}
+
+/// Reverse `records`, newest first.
+pub fn undo(records: &[Record]) -> Undone {
+    let mut out = Undone::default();
+    for r in records.iter().rev() {
+        match &r.op {
+            Op::Move { src, dst } => match (src.exists(), dst.exists()) {
+                (false, true) => {
+                    let back = MoveSink::verified();
+                    match back.create_parent(src).and_then(|_| back.link(dst, src)) {
+                        Ok(()) => out.restored += 1,
+                        Err(e) => out.problems.push((src.clone(), format!("restore from {}: {}", dst.display(), e))),
+                    }
+                }
+                // Copied across devices but the source was never deleted.
+                (true, true) if !r.done => match fs::remove_file(dst) {
+                    Ok(()) => out.removed += 1,
+                    Err(e) => out.problems.push((dst.clone(), format!("remove partial move: {}", e))),
+                },
+                (true, true) => out.problems.push((src.clone(), format!("both it and {} exist; left both in place", dst.display()))),
+                // Never moved, or already restored. A rename is atomic, so an unfinished
+                // move with neither side present never had a source to begin with.
+                (true, false) => {}
+                (false, false) if !r.done => {}
+                (false, false) => out.problems.push((src.clone(), format!("missing, and so is {}", dst.display()))),
+            },
+            Op::Create { dst } => match fs::remove_file(dst) {
+                Ok(()) => out.removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => out.problems.push((dst.clone(), format!("remove: {}", e))),
+            },
+            Op::Consume { src, kept } => {
+                if !src.exists() {
+                    let back = CopySink;
+                    match back.create_parent(src).and_then(|_| back.link(kept, src)) {
+                        Ok(()) => out.restored += 1,
+                        Err(e) => out.problems.push((src.clone(), format!("restore from {}: {}", kept.display(), e))),
+                    }
+                }
+            }
+            // Left alone if anything else was put there since.
+            Op::Dir { path } => {
+                let _ = fs::remove_dir(path);
+            }
+        }
+    }
+    out
+}
+
+/// A [`Sink`] that records every change it makes to `journal` first.
+pub struct Journaled<'j> {
+    inner: Box<dyn Sink>,
+    journal: &'j Journal,
+}
+
+impl<'j> Journaled<'j> {
+    pub fn new(inner: Box<dyn Sink>, journal: &'j Journal) -> Self {
+        Self { inner, journal }
+    }
+}
+
+fn absolute(path: &Path) -> SinkResult<PathBuf> {
+    std::path::absolute(path).map_err(|e| SinkError::io("resolve", path, e))
+}
+
+impl Sink for Journaled<'_> {
+    fn create_parent(&self, dst: &Path) -> SinkResult<()> {
+        let mut missing = Vec::new();
+        let mut dir = absolute(dst)?;
+        while dir.pop() && !dir.exists() {
+            missing.push(dir.clone());
+        }
+        // Outermost first, so undoing in reverse removes the innermost first.
+        let seqs = missing
+            .into_iter()
+            .rev()
+            .map(|path| self.journal.begin(Op::Dir { path }))
+            .collect::<SinkResult<Vec<_>>>()?;
+        self.inner.create_parent(dst)?;
+        seqs.into_iter().try_for_each(|seq| self.journal.done(seq))
+    }
+
+    fn exists(&self, dst: &Path) -> SinkResult<bool> {
+        self.inner.exists(dst)
+    }
+
+    fn holds(&self, dst: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool> {
+        self.inner.holds(dst, content, sop_uid)
+    }
+
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        // Undoing a create deletes the file, so it must not have replaced one.
+        if self.inner.exists(dst)? {
+            return Err(SinkError::Exists(dst.to_path_buf()));
+        }
+        self.journal.around(Op::Create { dst: absolute(dst)? }, || self.inner.put(dst, data))
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        let op = match self.inner.consumes_sources() {
+            true => Op::Move { src: absolute(src)?, dst: absolute(dst)? },
+            false => Op::Create { dst: absolute(dst)? },
+        };
+        self.journal.around(op, || self.inner.link(src, dst))
+    }
+
+    fn remove(&self, _dst: &Path) -> SinkResult<()> {
+        Err(SinkError::Unsupported { sink: "journaled", op: "replace entries" })
+    }
+
+    fn consumes_sources(&self) -> bool {
+        self.inner.consumes_sources()
+    }
+
+    fn drop_source(&self, src: &Path, kept: &Path) -> SinkResult<()> {
+        let op = Op::Consume { src: absolute(src)?, kept: absolute(kept)? };
+        self.journal.around(op, || self.inner.drop_source(src, kept))
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        self.inner.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom::DicomMeta;
+    use crate::fs_ops::execute;
+    use crate::sink::for_mode;
+    use crate::sort::Plan;
+    use crate::types::{Mode, OnConflict};
+
+    #[test]
+    fn test_failed_move_is_undone_from_journal_and_memory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in");
+        fs::create_dir(&input).unwrap();
+        let plans = |out: &str| {
+            ["a", "b", "gone"]
+                .iter()
+                .map(|n| Plan::new(input.join(n), tmp.path().join(out).join("s/x").join(n), DicomMeta::default()))
+                .collect::<Vec<_>>()
+        };
+
+        for from_file in [true, false] {
+            fs::write(input.join("a"), b"a").unwrap();
+            fs::write(input.join("b"), b"b").unwrap();
+            let path = tmp.path().join(format!("journal{}.jsonl", from_file));
+            let journal = if from_file { Journal::create(&path).unwrap() } else { Journal::in_memory() };
+            let sink = Journaled::new(for_mode(Mode::Move), &journal);
+            // "gone" does not exist, so the run fails after moving two files.
+            assert!(execute(plans("out"), &sink, OnConflict::Error, false, None, 1).is_err());
+            assert!(!input.join("a").exists());
+
+            let records = if from_file { Journal::read(&path).unwrap() } else { journal.records() };
+            assert_eq!(records.iter().filter(|r| matches!(r.op, Op::Move { .. }) && r.done).count(), 2);
+            assert!(records.iter().any(|r| matches!(&r.op, Op::Move { src, .. } if src.ends_with("gone")) && !r.done));
+
+            let undone = undo(&records);
+            assert_eq!(undone.restored, 2);
+            assert!(undone.problems.is_empty());
+            assert_eq!(fs::read(input.join("a")).unwrap(), b"a");
+            assert!(!tmp.path().join("out").exists());
+            // Undoing again finds nothing left to do.
+            assert_eq!(undo(&records).restored, 0);
+        }
+        assert!(Journal::create(&tmp.path().join("journaltrue.jsonl")).is_err());
+    }
+
+    #[test]
+    fn test_put_never_replaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dst = tmp.path().join("DICOMDIR");
+        fs::write(&dst, b"earlier run").unwrap();
+        let journal = Journal::in_memory();
+        let sink = Journaled::new(for_mode(Mode::Copy), &journal);
+        assert!(matches!(sink.put(&dst, &mut &b"new"[..]), Err(SinkError::Exists(_))));
+        assert!(journal.records().is_empty());
+        assert_eq!(fs::read(&dst).unwrap(), b"earlier run");
+    }
+}
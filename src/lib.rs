@@ -1,6 +1,24 @@
 pub mod types;
+pub mod archive;
+pub mod cache;
+pub mod deid;
 pub mod dicom;
+pub mod dicomdir;
+pub mod filter;
 pub mod fs_ops;
+pub mod journal;
+pub mod net;
+pub mod planfile;
+pub mod pseudonym;
 pub mod report;
 pub mod sanitize;
+pub mod sink;
 pub mod sort;
+pub mod state;
+pub mod source;
+pub mod split;
+pub mod stream;
+pub mod synth;
+pub mod template;
+pub mod verify;
+pub mod watch;
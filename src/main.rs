@@ -1,9 +1,38 @@
 mod cli;
 
-use dcmsort::{fs_ops, report, sort};
+use dcmsort::{archive, dicomdir, fs_ops, planfile, report, sink, sort, state, stream, synth, verify, watch};
+use dcmsort::deid::{DeidOptions, Deidentifier};
+use dcmsort::planfile::{DeidSettings, Output, PlanFile};
+use dcmsort::pseudonym::Pseudonymizer;
+use dcmsort::sink::{MoveSink, Sink};
+use dcmsort::dicom::{DicomMeta, FailureKind, ScanFailure};
+use dcmsort::dicomdir::read::{DicomDir, IssueKind, MediaIssue};
+use dcmsort::archive::ArchiveWriter;
+use dcmsort::cache::ScanCache;
+use dcmsort::filter::{Excluded, Filter};
+use dcmsort::fs_ops::{ConflictAction, ConflictRecord};
+use dcmsort::journal::{self, Journal, Journaled, Undone};
+use dcmsort::net::scp::{self, Filer, ScpConfig};
+use dcmsort::net::send::{send_files, sorted_files, SendOptions};
+use dcmsort::report::SeriesSummary;
+use dcmsort::sort::Plan;
+use dcmsort::split::SplitOptions;
+use dcmsort::state::State;
+use dcmsort::stream::StreamOptions;
+use dcmsort::synth::SynthOptions;
+use dcmsort::template::PathTemplate;
+use dcmsort::types::{Mode, OnConflict};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
+use dicom_object::Tag;
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
@@ -13,24 +42,944 @@ fn main() -> Result<()> {
         .with_env_filter(filter)
         .with_target(false)
         .compact()
+        // Keep stdout clean for `--output-archive -`.
+        .with_writer(std::io::stderr)
         .try_init();
 
     let cli = cli::Cli::parse();
+    match &cli.command {
+        None => run_sort(&cli.sort),
+        Some(cli::Command::Listen(args)) => run_listen(args),
+        Some(cli::Command::Plan(args)) => run_plan(args),
+        Some(cli::Command::Apply(args)) => run_apply(args),
+        Some(cli::Command::Watch(args)) => run_watch(args),
+        Some(cli::Command::Undo(args)) => run_undo(args),
+        Some(cli::Command::Verify(args)) => run_verify(args),
+        Some(cli::Command::Send(args)) => run_send(args),
+        Some(cli::Command::Synth(args)) => run_synth(args),
+    }
+}
+
+/// Template, pseudonymisation and de-identification state built from [`cli::OutputArgs`].
+struct OutputSetup {
+    template: Option<PathTemplate>,
+    extra_tags: Vec<Tag>,
+    pseudonymizer: Option<Pseudonymizer>,
+    deid: Option<Deidentifier>,
+    filter: Option<Filter>,
+}
+
+impl OutputSetup {
+    fn new(o: &cli::OutputArgs) -> Result<Self> {
+        // Validate the template up front so a typo fails before any file is touched.
+        let template = o.template.as_deref().map(PathTemplate::parse).transpose()?;
+        if let Some(t) = &template {
+            if t.uses_phi() && !o.include_phi {
+                bail!("template {:?} references PHI fields; pass --include-phi to allow this", t.as_str());
+            }
+        }
+        let extra_tags = template.as_ref().map(|t| t.extra_tags()).unwrap_or_default();
+
+        let pseudonymizer = match (&o.pseudonym_key_file, &o.pseudonym_key_env) {
+            (Some(path), _) => Some(Pseudonymizer::from_key_file(path)?),
+            (None, Some(var)) => Some(Pseudonymizer::from_env(var)?),
+            (None, None) => None,
+        };
+        if o.pseudonym_table.is_some() && pseudonymizer.is_none() {
+            bail!("--pseudonym-table requires --pseudonym-key-file or --pseudonym-key-env");
+        }
 
-    let files = fs_ops::collect_files(&cli.input, cli.follow_symlinks)?;
-    tracing::info!("Found {} files under {}", files.len(), cli.input.display());
+        let deid = match (&o.anonymize, &o.deid_map) {
+            (true, Some(map)) => Some(Deidentifier::open(map, DeidOptions {
+                retain_dates: o.retain_dates,
+                clean_descriptors: o.clean_descriptors,
+            })?),
+            _ => None,
+        };
+
+        Ok(Self { template, extra_tags, pseudonymizer, deid, filter: None })
+    }
+
+    /// Add the `--filter` expressions of a directory sort, and the tags they read.
+    fn with_filter(mut self, exprs: &[String]) -> Result<Self> {
+        self.filter = Filter::parse_all(exprs)?;
+        if let Some(f) = &self.filter {
+            self.extra_tags.extend(f.extra_tags());
+            self.extra_tags.sort();
+            self.extra_tags.dedup();
+        }
+        Ok(self)
+    }
+
+    fn plan_options(&self, o: &cli::OutputArgs) -> sort::PlanOptions<'_> {
+        sort::PlanOptions {
+            layout: o.layout,
+            template: self.template.as_ref(),
+            include_phi: o.include_phi,
+            pseudonymize: self.pseudonymizer.as_ref(),
+            ..Default::default()
+        }
+    }
+
+    fn write_pseudonym_table(&self, o: &cli::OutputArgs) -> Result<()> {
+        if let (Some(p), Some(table)) = (&self.pseudonymizer, &o.pseudonym_table) {
+            p.write_lookup(table)?;
+            tracing::debug!("Wrote pseudonym lookup table: {}", table.display());
+        }
+        Ok(())
+    }
+
+    fn save_deid_map(&self, o: &cli::OutputArgs) -> Result<()> {
+        if let (Some(d), Some(map)) = (&self.deid, &o.deid_map) {
+            d.save(map)?;
+            tracing::debug!("Wrote de-identification map: {}", map.display());
+        }
+        Ok(())
+    }
+}
+
+/// Everything [`scan_and_plan`] learned about the input.
+struct Planned {
+    metas: Vec<DicomMeta>,
+    failures: Vec<ScanFailure>,
+    media_issues: Vec<MediaIssue>,
+    excluded: Vec<Excluded>,
+    /// Quarantined paths are kept relative to this folder.
+    input_root: PathBuf,
+    plans: Vec<Plan>,
+    series: Vec<SeriesSummary>,
+}
+
+/// The files to sort.
+struct Input {
+    files: Vec<PathBuf>,
+    /// The DICOMDIR that listed them, if any.
+    media: Option<DicomDir>,
+    /// Quarantined paths are kept relative to this folder.
+    root: PathBuf,
+}
+
+/// The files under, or listed by, the `--input` of `cli`.
+fn read_input(cli: &cli::SortArgs) -> Result<Input> {
+    let input = cli.input();
+    let media = (input.is_file() && !archive::is_archive(input)).then(|| DicomDir::read(input)).transpose()?;
+    let files = match &media {
+        Some(d) => {
+            let files = d.files();
+            tracing::info!("DICOMDIR {} references {} files ({} missing)", input.display(), d.entries.len(), d.entries.len() - files.len());
+            files
+        }
+        None => {
+            let files = fs_ops::collect_files(input, cli.follow_symlinks)?;
+            tracing::info!("Found {} files under {}", files.len(), input.display());
+            if input.join(dicomdir::FILE_NAME).is_file() {
+                tracing::info!("{} has a DICOMDIR; pass it as --input to sort by its records", input.display());
+            }
+            files
+        }
+    };
+    // Quarantined paths are kept relative to the media root or the archive's folder.
+    let input_root = match &media {
+        Some(d) => d.root.as_path(),
+        None if input.is_file() => input.parent().unwrap_or(input),
+        None => input,
+    };
+    Ok(Input { files, root: input_root.to_path_buf(), media })
+}
 
-    let metas = sort::scan(&files);
-    tracing::info!("Parsed {} DICOM headers (others were ignored)", metas.len());
+/// Scan `input` and plan every instance into `output`.
+/// With `state`, only new or changed files are scanned, and planned together with the series they join.
+fn scan_and_plan(cli: &cli::SortArgs, setup: &OutputSetup, output: &Output, input: Input, mut state: Option<&mut State>) -> Result<Planned> {
+    let Input { files, media, root: input_root } = input;
+    let files = match state.as_deref_mut() {
+        Some(s) => s.select(files)?,
+        None => files,
+    };
+    let cache = open_cache(cli, &setup.extra_tags)?;
+    let outcome = match &cache {
+        Some(c) => sort::scan_files(c, &files, &setup.extra_tags),
+        None => sort::scan_with_tags(&files, &setup.extra_tags),
+    };
+    if let Some(c) = &cache {
+        save_cache(c)?;
+    }
+    let mut metas = outcome.metas;
+    let media_issues = media.as_ref().map(|d| d.reconcile(&mut metas)).unwrap_or_default();
+    for issue in &media_issues {
+        match issue.kind {
+            IssueKind::Missing => tracing::warn!("DICOMDIR entry {} is missing from the media", issue.file_id),
+            IssueKind::Mismatch | IssueKind::InvalidFileId => tracing::warn!("DICOMDIR entry {}: {}", issue.file_id, issue.detail.as_deref().unwrap_or_default()),
+        }
+    }
+    let failures = outcome.failures;
+    tracing::info!("Parsed {} DICOM headers ({} rejected)", metas.len(), failures.len());
+    log_failures(&failures);
+    // Filters see the original headers; excluded instances are not planned at all.
+    let excluded = match &setup.filter {
+        Some(f) => {
+            let (kept, excluded) = f.apply(metas);
+            metas = kept;
+            log_excluded(&excluded);
+            if let Some(s) = state.as_deref_mut() {
+                s.forget(excluded.iter().map(|e| e.path.as_path()))?;
+            }
+            excluded
+        }
+        None => Vec::new(),
+    };
+    if let Some(d) = &setup.deid {
+        // Plan, name and report from de-identified headers so no PHI leaks into paths.
+        metas = metas.iter().map(|m| d.apply_meta(m)).collect();
+    }
+    if let Some(s) = &state {
+        // Grown series are renumbered as a whole.
+        let earlier = s.earlier(&metas);
+        tracing::info!("Planning {} earlier instances of the same series again", earlier.len());
+        metas.extend(earlier);
+    }
+
+    let plan_opts = plan_options(cli, setup);
+    // Archive entries are named by the layout path relative to the archive root.
+    let plans = sort::plan_operations(&metas, output.root(), &plan_opts);
+    let series = report::summarize_series(&plans);
+    let stacked = series.iter().filter(|s| s.stack.is_some()).count();
+    if stacked > 0 {
+        tracing::info!("Split heterogeneous series into {} sub-stacks", stacked);
+    }
+    for s in series.iter().filter(|s| s.volume_axis.is_some()) {
+        tracing::info!(
+            "Series {} split into {} volumes by {:?}",
+            s.series_uid.as_deref().unwrap_or("UNKNOWN_SERIES"),
+            s.volumes,
+            s.volume_axis.unwrap(),
+        );
+    }
+    tracing::info!("Planned {} operations", plans.len());
+    if cli.dicomdir {
+        check_media_names(output, &plans)?;
+    }
+    Ok(Planned { metas, failures, media_issues, excluded, input_root, plans, series })
+}
+
+/// The `--scan-cache` of `cli`, if given.
+fn open_cache(cli: &cli::SortArgs, extra_tags: &[Tag]) -> Result<Option<ScanCache>> {
+    cli.scan_cache.as_deref().map(|path| ScanCache::open(path, extra_tags, cli.rebuild_cache)).transpose()
+}
+
+fn save_cache(cache: &ScanCache) -> Result<()> {
+    let s = cache.stats();
+    tracing::info!("Scan cache: {} unchanged, {} parsed, {} no longer found", s.hits, s.parsed, s.dropped);
+    cache.save()
+}
+
+fn log_excluded(excluded: &[Excluded]) {
+    let mut by_clause: BTreeMap<&str, usize> = BTreeMap::new();
+    for e in excluded {
+        *by_clause.entry(&e.clause).or_default() += 1;
+        tracing::debug!("Excluded {} by {}", e.path.display(), e.clause);
+    }
+    for (clause, n) in by_clause {
+        tracing::info!("Filter excluded {} instances: {}", n, clause);
+    }
+}
+
+fn log_failures(failures: &[ScanFailure]) {
+    let mut by_kind: BTreeMap<FailureKind, usize> = BTreeMap::new();
+    for f in failures {
+        *by_kind.entry(f.kind).or_default() += 1;
+        if f.kind == FailureKind::NotDicom {
+            tracing::debug!("Rejected {} ({}): {:#}", f.path.display(), f.kind.as_str(), f.error);
+        } else {
+            tracing::warn!("Rejected {} ({}): {:#}", f.path.display(), f.kind.as_str(), f.error);
+        }
+    }
+    for (kind, n) in &by_kind {
+        tracing::info!("  {}: {}", kind.as_str(), n);
+    }
+}
+
+/// How `cli` plans each series.
+fn plan_options<'a>(cli: &cli::SortArgs, setup: &'a OutputSetup) -> sort::PlanOptions<'a> {
+    sort::PlanOptions {
+        sort_by: cli.sort_by,
+        split: cli.split_series.then_some(SplitOptions {
+            orientation_tolerance: cli.orientation_tolerance,
+        }),
+        volumes: cli.volumes,
+        ..setup.plan_options(&cli.output)
+    }
+}
+
+/// Reject an output and conflict policy that cannot work together.
+fn check_output(output: &Output, on_conflict: OnConflict) -> Result<()> {
+    if matches!(output, Output::Archive(_)) && matches!(on_conflict, OnConflict::Overwrite) {
+        bail!("--on-conflict overwrite is not possible with --output-archive: entries cannot be replaced");
+    }
+    Ok(())
+}
+
+/// Check every name a DICOMDIR would reference before touching the filesystem.
+fn check_media_names(output: &Output, plans: &[Plan]) -> Result<()> {
+    for p in plans {
+        dicomdir::file_id(output.root(), &p.dst).context("--dicomdir needs media file names; try --layout media")?;
+    }
+    Ok(())
+}
+
+/// The sink for `--mode`, with `--verify-moves`, journaled when there is a journal.
+fn disk_sink(mode: Mode, verify_moves: bool, journal: Option<&Journal>) -> Box<dyn Sink + '_> {
+    let inner: Box<dyn Sink> = match mode {
+        Mode::Move if verify_moves => Box::new(MoveSink::verified()),
+        _ => sink::for_mode(mode),
+    };
+    match journal {
+        Some(j) => Box::new(Journaled::new(inner, j)),
+        None => inner,
+    }
+}
+
+/// The journal for `--journal` and `--rollback-on-error`, if either was given.
+fn open_journal(args: &cli::JournalArgs, output: &Output, mode: Mode, on_conflict: OnConflict, deid: bool) -> Result<Option<Journal>> {
+    if args.journal.is_none() && !args.rollback_on_error {
+        return Ok(None);
+    }
+    if matches!(output, Output::Archive(_)) {
+        bail!("--journal and --rollback-on-error need a directory output; an unfinished archive is left as .part anyway");
+    }
+    // Neither of these can be undone: the old bytes are deleted, not moved.
+    if matches!(on_conflict, OnConflict::Overwrite) {
+        bail!("--on-conflict overwrite deletes existing files, so it cannot be journaled");
+    }
+    if matches!(mode, Mode::Move) && deid {
+        bail!("--mode move with --anonymize deletes the originals after rewriting them, so it cannot be journaled");
+    }
+    Ok(Some(match &args.journal {
+        Some(path) => Journal::create(path)?,
+        None => Journal::in_memory(),
+    }))
+}
+
+fn log_undone(u: &Undone) {
+    for (path, problem) in &u.problems {
+        tracing::error!("Could not undo {}: {}", path.display(), problem);
+    }
+    tracing::info!("Restored {} source files and removed {} created files", u.restored, u.removed);
+}
+
+/// Pass `result` through, first rolling back everything in `journal` if it
+/// failed and `rollback` is set.
+fn rollback_on_error<T>(result: Result<T>, journal: Option<&Journal>, rollback: bool) -> Result<T> {
+    match (result, journal) {
+        (Err(e), Some(j)) if rollback => {
+            tracing::error!("{:#}", e);
+            tracing::warn!("Rolling back {} journaled operations", j.records().len());
+            let u = j.rollback();
+            log_undone(&u);
+            match u.problems.is_empty() {
+                true => Err(e.context("run failed and was rolled back")),
+                false => Err(e.context(format!("run failed; {} operations could not be rolled back", u.problems.len()))),
+            }
+        }
+        (result, _) => result,
+    }
+}
+
+fn log_journal(journal: Option<&Journal>) {
+    if let Some(path) = journal.and_then(Journal::path) {
+        tracing::info!("Journal: {}; reverse this run with `dcmsort undo {}`", path.display(), path.display());
+    }
+}
+
+/// How [`apply_plans`] carries out a set of plans.
+struct Target<'a> {
+    output: &'a Output,
+    mode: Mode,
+    verify_moves: bool,
+    on_conflict: OnConflict,
+    dicomdir: bool,
+    dry_run: bool,
+    deid: Option<&'a Deidentifier>,
+    jobs: usize,
+}
+
+/// The sink for `t`. There is none for a dry run into an archive: creating one would start the file.
+fn open_sink<'j>(t: &Target, journal: Option<&'j Journal>) -> Result<Option<Box<dyn Sink + 'j>>> {
+    Ok(match t.output {
+        Output::Archive(_) if t.dry_run => None,
+        Output::Archive(path) => Some(Box::new(ArchiveWriter::create(path)?)),
+        Output::Dir(_) => Some(disk_sink(t.mode, t.verify_moves, journal)),
+    })
+}
+
+/// Execute `plans` into `sink`, or list them for a dry run into an archive.
+fn execute_plans(plans: Vec<Plan>, sink: Option<&dyn Sink>, t: &Target) -> Result<Vec<ConflictRecord>> {
+    match (sink, t.output) {
+        // Entries go into an archive one at a time anyway, and in plan order this way.
+        (Some(s), Output::Archive(_)) => fs_ops::execute(plans, s, t.on_conflict, t.dry_run, t.deid, 1),
+        (Some(s), Output::Dir(_)) => fs_ops::execute(plans, s, t.on_conflict, t.dry_run, t.deid, t.jobs),
+        (None, Output::Archive(path)) => {
+            for p in &plans {
+                println!("{} -> {}", p.src.display(), archive::member_path(path, &archive::entry_name(&p.dst)).display());
+            }
+            Ok(Vec::new())
+        }
+        (None, Output::Dir(_)) => unreachable!("only archive dry runs have no sink"),
+    }
+}
+
+fn log_conflicts(actions: &[ConflictAction]) {
+    for action in [ConflictAction::SkippedIdentical, ConflictAction::Overwritten, ConflictAction::Renamed] {
+        let n = actions.iter().filter(|a| **a == action).count();
+        if n > 0 {
+            tracing::info!("Conflicts {:?}: {}", action, n);
+        }
+    }
+}
+
+/// Execute `plans` into the output, then write the DICOMDIR and finish the sink.
+fn apply_plans(plans: Vec<Plan>, t: &Target, journal: Option<&Journal>) -> Result<Vec<ConflictRecord>> {
+    let root = t.output.root();
+    let placed: Vec<_> = match t.dicomdir {
+        true => plans.iter().map(|p| (p.src.clone(), p.dst.clone(), p.meta.clone())).collect(),
+        false => Vec::new(),
+    };
+    if t.dicomdir && !t.dry_run && journal.is_some() && root.join(dicomdir::FILE_NAME).exists() {
+        // Undo would delete the new DICOMDIR with no way to bring the old one back.
+        bail!(
+            "{} already exists and a journaled run cannot replace it; remove it first or run without --journal and --rollback-on-error",
+            root.join(dicomdir::FILE_NAME).display()
+        );
+    }
+    let mut sink = open_sink(t, journal)?;
+    let conflicts = execute_plans(plans, sink.as_deref(), t)?;
+    log_conflicts(&conflicts.iter().map(|c| c.action).collect::<Vec<_>>());
+
+    if t.dicomdir && !t.dry_run {
+        // Renamed and skipped-identical instances are referenced where they actually ended up.
+        let entries: Vec<_> = placed
+            .iter()
+            .map(|(src, dst, meta)| {
+                let actual = conflicts
+                    .iter()
+                    .find(|c| &c.src == src && &c.planned == dst)
+                    .map_or(dst, |c| &c.actual);
+                (actual.clone(), meta)
+            })
+            .collect();
+        let sink = sink.as_mut().expect("not a dry run");
+        let bytes = dicomdir::dicomdir_bytes(root, &entries).context("write DICOMDIR")?;
+        let path = root.join(dicomdir::FILE_NAME);
+        sink.create_parent(&path)?;
+        sink.put(&path, &mut bytes.as_slice())?;
+        tracing::info!("Wrote {} ({} instances)", path.display(), entries.len());
+    }
+    if let Some(mut s) = sink {
+        s.finish()?;
+        if let Output::Archive(path) = t.output {
+            tracing::info!("Wrote archive {}", path.display());
+        }
+    }
+    Ok(conflicts)
+}
+
+fn run_sort(cli: &cli::SortArgs) -> Result<()> {
+    let out = &cli.output;
+    let setup = OutputSetup::new(out)?.with_filter(&cli.filter)?;
+    let output = out.target();
+    check_output(&output, out.on_conflict)?;
+    if cli.stream {
+        return run_stream(cli, &setup, &output);
+    }
+
+    let mut state = cli.state.as_deref().map(|path| State::open(path, &output)).transpose()?;
+    let input = read_input(cli)?;
+    let journal = open_journal(&cli.journal, &output, cli.mode, out.on_conflict, setup.deid.is_some())?;
+    let Sorted { planned, conflicts, .. } = sort_input(cli, &setup, &output, input, state.as_mut(), journal.as_ref())?;
+    log_journal(journal.as_ref());
 
     if let Some(report_path) = &cli.report {
-        report::write_json(report_path, &metas)?;
+        let r = report::Report {
+            instances: &planned.metas,
+            series: &planned.series,
+            failures: &planned.failures,
+            conflicts: &conflicts,
+            dicomdir: &planned.media_issues,
+            excluded: &planned.excluded,
+            ..Default::default()
+        };
+        report::write_json(report_path, &r)?;
         tracing::info!("Wrote report: {}", report_path.display());
     }
+    Ok(())
+}
 
-    let plans = sort::plan_operations(&metas, &cli.output, cli.layout, cli.sort_by, cli.include_phi);
-    tracing::info!("Planned {} operations", plans.len());
+/// What [`sort_input`] did.
+struct Sorted {
+    /// With the plans taken out to be executed.
+    planned: Planned,
+    /// New instances placed (or skipped by `--on-conflict`).
+    placed: usize,
+    renumbered: usize,
+    conflicts: Vec<ConflictRecord>,
+}
+
+/// Scan, plan and execute `input`: the body of a directory sort, and of each `watch` batch.
+fn sort_input(
+    cli: &cli::SortArgs,
+    setup: &OutputSetup,
+    output: &Output,
+    input: Input,
+    mut state: Option<&mut State>,
+    journal: Option<&Journal>,
+) -> Result<Sorted> {
+    let out = &cli.output;
+    let mut planned = scan_and_plan(cli, setup, output, input, state.as_deref_mut())?;
+    let mut renames = match state.as_deref_mut() {
+        Some(s) => {
+            let (renames, plans) = s.split(std::mem::take(&mut planned.plans));
+            tracing::info!("{} instances to place, {} earlier ones to renumber", plans.len(), renames.len());
+            planned.plans = plans;
+            renames
+        }
+        None => Vec::new(),
+    };
+    // Before executing, so a run that fails halfway can still be re-identified
+    // and the next run reuses the same salt.
+    if !cli.dry_run {
+        setup.write_pseudonym_table(out)?;
+        setup.save_deid_map(out)?;
+    }
+
+    let target = Target {
+        output,
+        mode: cli.mode,
+        verify_moves: cli.verify_moves,
+        on_conflict: out.on_conflict,
+        dicomdir: cli.dicomdir,
+        dry_run: cli.dry_run,
+        deid: setup.deid.as_ref(),
+        jobs: cli.jobs,
+    };
+    let placed = planned.plans.len();
+    let result = (|| {
+        if let Some(dir) = &cli.quarantine {
+            let sink = disk_sink(cli.mode, cli.verify_moves, journal);
+            fs_ops::quarantine(&planned.failures, &planned.input_root, dir, sink.as_ref(), cli.dry_run, cli.jobs)?;
+            tracing::info!("Quarantined {} files into {}", planned.failures.len(), dir.display());
+        }
+        if !renames.is_empty() {
+            // Renames stay within the output, so they are moves whatever --mode says.
+            let sink = disk_sink(Mode::Move, cli.verify_moves, journal);
+            state::renumber(&mut renames, sink.as_ref(), cli.dry_run, cli.jobs)?;
+            tracing::info!("Renumbered {} earlier instances", renames.len());
+        }
+        apply_plans(std::mem::take(&mut planned.plans), &target, journal)
+    })();
+    // Again, successful or not: rewriting remaps UIDs that planning never saw.
+    let saved = if cli.dry_run { Ok(()) } else { setup.save_deid_map(out) };
+    let conflicts = rollback_on_error(result, journal, cli.journal.rollback_on_error)?;
+    saved?;
+    if let (Some(s), false) = (state, cli.dry_run) {
+        s.commit(&renames, &conflicts)?;
+        s.save()?;
+    }
+    Ok(Sorted { planned, placed, renumbered: renames.len(), conflicts })
+}
+
+/// `--stream`: plan and execute each series as soon as the scan has sealed it.
+fn run_stream(cli: &cli::SortArgs, setup: &OutputSetup, output: &Output) -> Result<()> {
+    let out = &cli.output;
+    let input = cli.input();
+    if input.is_file() && !archive::is_archive(input) {
+        bail!("--stream reads a folder or an archive; a DICOMDIR lists its files up front, so sort it without --stream");
+    }
+    let journal = open_journal(&cli.journal, output, cli.mode, out.on_conflict, setup.deid.is_some())?;
+
+    let target = Target {
+        output,
+        mode: cli.mode,
+        verify_moves: cli.verify_moves,
+        on_conflict: out.on_conflict,
+        dicomdir: false,
+        dry_run: cli.dry_run,
+        deid: setup.deid.as_ref(),
+        jobs: cli.jobs,
+    };
+    // Before executing, so the salt of a run that fails halfway is kept.
+    if !cli.dry_run {
+        setup.save_deid_map(out)?;
+    }
+    let result = stream_series(cli, setup, &target, journal.as_ref());
+    // Again, successful or not, with every UID remapped along the way.
+    let saved = if cli.dry_run { Ok(()) } else { setup.save_deid_map(out) };
+    rollback_on_error(result, journal.as_ref(), cli.journal.rollback_on_error)?;
+    saved?;
+    log_journal(journal.as_ref());
+    if !cli.dry_run {
+        // Pseudonyms are issued as series are planned, so the table is complete only now.
+        setup.write_pseudonym_table(out)?;
+    }
+    Ok(())
+}
+
+fn stream_series(cli: &cli::SortArgs, setup: &OutputSetup, t: &Target, journal: Option<&Journal>) -> Result<()> {
+    let input = cli.input();
+    let spill = match &cli.spill_dir {
+        Some(dir) => tempfile::Builder::new().prefix("dcmsort-spill").tempdir_in(dir),
+        None => tempfile::Builder::new().prefix("dcmsort-spill").tempdir(),
+    };
+    let spill = spill.context("create spill folder")?;
+    let opts = StreamOptions {
+        follow_symlinks: cli.follow_symlinks,
+        extra_tags: &setup.extra_tags,
+        deid: setup.deid.as_ref(),
+        filter: setup.filter.as_ref(),
+    };
+    let plan_opts = plan_options(cli, setup);
+    let mut sink = open_sink(t, journal)?;
+    let mut actions = Vec::new();
+    let mut planned = 0;
+
+    // One sealed series may wait while another is executed; the scan blocks after that.
+    let (tx, rx) = mpsc::sync_channel(1);
+    let (scanned, executed) = thread::scope(|s| {
+        let (spill, opts) = (spill.path(), &opts);
+        let scanner = s.spawn(move || stream::scan_series(input, spill, opts, tx));
+        let executed = (|| -> Result<()> {
+            for sealed in &rx {
+                let series = sealed.key.1.clone();
+                let metas = sealed.load()?;
+                let plans = sort::plan_operations(&metas, t.output.root(), &plan_opts);
+                tracing::debug!("Series {}: {} instances", series, plans.len());
+                planned += plans.len();
+                actions.extend(execute_plans(plans, sink.as_deref(), t)?.iter().map(|c| c.action));
+            }
+            Ok(())
+        })();
+        // Stops the scan at its next sealed series if execution failed.
+        drop(rx);
+        (scanner.join().expect("scan thread panicked"), executed)
+    });
+    executed?;
+    let scanned = scanned?;
+    tracing::info!(
+        "Found {} files under {}: {} instances in {} series batches, {} rejected",
+        scanned.files,
+        input.display(),
+        scanned.instances,
+        scanned.sealed,
+        scanned.failures.len(),
+    );
+    log_failures(&scanned.failures);
+    log_excluded(&scanned.excluded);
+    tracing::info!("Planned {} operations", planned);
+    log_conflicts(&actions);
+
+    if let Some(dir) = &cli.quarantine {
+        let input_root = if input.is_file() { input.parent().unwrap_or(input) } else { input };
+        let sink = disk_sink(cli.mode, cli.verify_moves, journal);
+        fs_ops::quarantine(&scanned.failures, input_root, dir, sink.as_ref(), cli.dry_run, cli.jobs)?;
+        tracing::info!("Quarantined {} files into {}", scanned.failures.len(), dir.display());
+    }
+    if let Some(s) = sink.as_mut() {
+        s.finish()?;
+        if let Output::Archive(path) = t.output {
+            tracing::info!("Wrote archive {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn run_plan(args: &cli::PlanArgs) -> Result<()> {
+    let cli = &args.sort;
+    let out = &cli.output;
+    if cli.dry_run {
+        bail!("plan never touches the output; use `apply --dry-run` to preview a plan");
+    }
+    if cli.quarantine.is_some() {
+        bail!("plan never touches the input; --quarantine is only available when sorting directly");
+    }
+    if cli.journal.journal.is_some() || cli.journal.rollback_on_error {
+        bail!("plan never touches any files; pass --journal or --rollback-on-error to apply");
+    }
+    if cli.stream {
+        bail!("a plan file holds every plan, so plan cannot --stream");
+    }
+    if cli.state.is_some() {
+        bail!("plan does not record anything; --state is only available when sorting directly");
+    }
+    let setup = OutputSetup::new(out)?.with_filter(&cli.filter)?;
+    let output = out.target();
+    check_output(&output, out.on_conflict)?;
+
+    let planned = scan_and_plan(cli, &setup, &output, read_input(cli)?, None)?;
+    let plan = PlanFile {
+        version: planfile::VERSION,
+        sources: planfile::fingerprint_sources(&planned.plans, args.hash)?,
+        output,
+        mode: cli.mode,
+        verify_moves: cli.verify_moves,
+        on_conflict: out.on_conflict,
+        dicomdir: cli.dicomdir,
+        deid: setup.deid.as_ref().map(|d| DeidSettings {
+            map: out.deid_map.clone().expect("--anonymize requires --deid-map"),
+            options: d.options(),
+        }),
+        plans: planned.plans,
+    };
+    setup.write_pseudonym_table(out)?;
+    // The plan names instances by de-identified UIDs; apply must rewrite them with the same mapping.
+    setup.save_deid_map(out)?;
+    plan.save(&args.out)?;
+    tracing::info!("Wrote plan for {} operations ({} source files): {}", plan.plans.len(), plan.sources.len(), args.out.display());
+
+    if let Some(report_path) = &cli.report {
+        let r = report::Report {
+            instances: &planned.metas,
+            series: &planned.series,
+            failures: &planned.failures,
+            dicomdir: &planned.media_issues,
+            ..Default::default()
+        };
+        report::write_json(report_path, &r)?;
+        tracing::info!("Wrote report: {}", report_path.display());
+    }
+    Ok(())
+}
+
+fn run_apply(args: &cli::ApplyArgs) -> Result<()> {
+    let plan = PlanFile::load(&args.plan)?;
+    check_output(&plan.output, plan.on_conflict)?;
+    let changed = plan.changed_sources();
+    for (path, change) in &changed {
+        tracing::warn!("Source {}: {}", path.display(), change.as_str());
+    }
+    if !changed.is_empty() {
+        bail!("{} source files changed since {} was written; plan again", changed.len(), args.plan.display());
+    }
+    plan.check_destinations().with_context(|| format!("apply {}", args.plan.display()))?;
+    if plan.dicomdir {
+        check_media_names(&plan.output, &plan.plans)?;
+    }
+    let deid = plan.deid.as_ref().map(|s| Deidentifier::open(&s.map, s.options)).transpose()?;
+    let journal = open_journal(&args.journal, &plan.output, plan.mode, plan.on_conflict, deid.is_some())?;
+    tracing::info!("Applying {} operations from {}", plan.plans.len(), args.plan.display());
+
+    let series = report::summarize_series(&plan.plans);
+    let target = Target {
+        output: &plan.output,
+        mode: plan.mode,
+        verify_moves: plan.verify_moves || args.verify_moves,
+        on_conflict: plan.on_conflict,
+        dicomdir: plan.dicomdir,
+        dry_run: args.dry_run,
+        deid: deid.as_ref(),
+        jobs: args.jobs,
+    };
+    let result = apply_plans(plan.plans, &target, journal.as_ref());
+    let conflicts = rollback_on_error(result, journal.as_ref(), args.journal.rollback_on_error)?;
+    log_journal(journal.as_ref());
+    if let (Some(d), Some(s), false) = (&deid, &plan.deid, args.dry_run) {
+        d.save(&s.map)?;
+    }
+
+    if let Some(report_path) = &args.report {
+        let r = report::Report { series: &series, conflicts: &conflicts, ..Default::default() };
+        report::write_json(report_path, &r)?;
+        tracing::info!("Wrote report: {}", report_path.display());
+    }
+    Ok(())
+}
+
+fn run_undo(args: &cli::UndoArgs) -> Result<()> {
+    let records = Journal::read(&args.journal)?;
+    let unfinished = records.iter().filter(|r| !r.done).count();
+    tracing::info!("Undoing {} journaled operations ({} unfinished) from {}", records.len(), unfinished, args.journal.display());
+    let u = journal::undo(&records);
+    log_undone(&u);
+    if !u.problems.is_empty() {
+        bail!("{} operations could not be undone", u.problems.len());
+    }
+    Ok(())
+}
+
+fn run_verify(args: &cli::VerifyArgs) -> Result<()> {
+    let scan = |path: &Path| -> Result<Vec<DicomMeta>> {
+        let outcome = sort::scan(&fs_ops::collect_files(path, false)?);
+        tracing::info!("{}: {} instances ({} files rejected)", path.display(), outcome.metas.len(), outcome.failures.len());
+        Ok(outcome.metas)
+    };
+    let sources = scan(&args.input)?;
+    let outputs = scan(&args.output)?;
+    let v = verify::verify(&sources, &outputs)?;
+
+    for i in &v.issues {
+        tracing::warn!("{} ({}): {:?} in output {:?}", i.src.display(), i.sop_uid, i.problem, i.found);
+    }
+    for p in &v.unidentified {
+        tracing::warn!("{} has no SOPInstanceUID and cannot be matched", p.display());
+    }
+    if !v.extra.is_empty() {
+        tracing::info!("{} output instances are not in the input", v.extra.len());
+    }
+    tracing::info!("Checked {} instances: {} problems", v.checked, v.issues.len() + v.unidentified.len());
+
+    if let Some(report_path) = &args.report {
+        report::write_json(report_path, &report::Report { verify: Some(&v), ..Default::default() })?;
+        tracing::info!("Wrote report: {}", report_path.display());
+    }
+    if !v.is_ok() {
+        bail!("{} does not hold every instance of {} exactly once", args.output.display(), args.input.display());
+    }
+    Ok(())
+}
+
+fn run_watch(args: &cli::WatchArgs) -> Result<()> {
+    let cli = &args.sort;
+    let out = &cli.output;
+    if out.output_archive.is_some() {
+        bail!("watch sorts batch after batch into one folder; use --output instead of --output-archive");
+    }
+    let unsupported = [
+        (cli.stream, "--stream"),
+        (cli.dicomdir, "--dicomdir"),
+        (cli.report.is_some(), "--report (use --batch-log)"),
+        (cli.scan_cache.is_some(), "--scan-cache"),
+        (cli.journal.rollback_on_error, "--rollback-on-error (a failed batch is retried)"),
+    ];
+    if let Some((_, name)) = unsupported.iter().find(|(set, _)| *set) {
+        bail!("watch does not support {}", name);
+    }
+    // Without it a series split over batches would restart at 00001_ and collide with itself.
+    let Some(state_path) = cli.state.as_deref() else {
+        bail!("watch needs --state, so a series that arrives over several batches is numbered as a whole");
+    };
+    let input = cli.input();
+    if !input.is_dir() {
+        bail!("watch needs a folder as --input: {}", input.display());
+    }
+    let (abs_input, abs_output) = (std::path::absolute(input)?, std::path::absolute(out.output())?);
+    if abs_output.starts_with(&abs_input) {
+        bail!("the output {} is inside the watched input {}", out.output().display(), input.display());
+    }
+    let setup = OutputSetup::new(out)?.with_filter(&cli.filter)?;
+    let output = out.target();
+    check_output(&output, out.on_conflict)?;
+    let mut state = State::open(state_path, &output)?;
+    let journal = open_journal(&cli.journal, &output, cli.mode, out.on_conflict, setup.deid.is_some())?;
+
+    // Ctrl-C, SIGTERM and SIGHUP let the batch in progress finish.
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    ctrlc::set_handler(move || {
+        tracing::info!("Stopping after the batch in progress");
+        flag.store(true, Ordering::Relaxed);
+    })
+    .context("install signal handler")?;
+
+    let opts = watch::WatchOptions {
+        settle: Duration::from_secs(args.settle),
+        quiet: args.series_quiet.map(Duration::from_secs),
+        max_backoff: Duration::from_secs(args.max_backoff),
+        batch_log: args.batch_log.as_deref(),
+        follow_symlinks: cli.follow_symlinks,
+    };
+    watch::run(input, &opts, &stop, |files| {
+        let batch = Input { files, media: None, root: input.to_path_buf() };
+        let sorted = sort_input(cli, &setup, &output, batch, Some(&mut state), journal.as_ref())?;
+        Ok(watch::BatchOutcome {
+            placed: sorted.placed,
+            renumbered: sorted.renumbered,
+            conflicts: sorted.conflicts.len(),
+            rejected: sorted.planned.failures.len(),
+            excluded: sorted.planned.excluded.len(),
+        })
+    })?;
+    log_journal(journal.as_ref());
+    Ok(())
+}
+
+fn run_listen(args: &cli::ListenArgs) -> Result<()> {
+    let out = &args.output;
+    if out.output_archive.is_some() {
+        bail!("listen files instances as they arrive; use --output instead of --output-archive");
+    }
+    let setup = OutputSetup::new(out)?;
+
+    // The process normally runs until killed, so mapping files are rewritten as
+    // each association closes. Associations close concurrently; one write at a time.
+    let persisting = Mutex::new(());
+    let persist = || {
+        let _guard = persisting.lock().unwrap();
+        if let Err(e) = setup.save_deid_map(out).and_then(|_| setup.write_pseudonym_table(out)) {
+            tracing::warn!("{:#}", e);
+        }
+    };
+    // The salt is saved before the first instance arrives.
+    setup.save_deid_map(out)?;
+    let mut filer = Filer::new(out.output(), setup.plan_options(out));
+    filer.extra_tags = &setup.extra_tags;
+    filer.on_conflict = out.on_conflict;
+    filer.deid = setup.deid.as_ref();
+    filer.after_association = Some(&persist);
+
+    let cfg = ScpConfig { ae_title: args.ae_title.clone(), accepted_calling_aes: args.accept_ae.clone() };
+    let addr = format!("{}:{}", args.bind, args.port);
+    let listener = TcpListener::bind(&addr).with_context(|| format!("bind {}", addr))?;
+    tracing::info!("Listening on {} as {} -> {}", addr, cfg.ae_title, out.output().display());
+    scp::serve(listener, &cfg, &filer)
+}
+
+fn run_send(args: &cli::SendArgs) -> Result<()> {
+    // Templates need not start file names with the index, so order by header, not path.
+    let files = sorted_files(&args.input)?;
+    tracing::info!("Sending {} files from {} to {}@{}:{}", files.len(), args.input.display(), args.called_ae, args.host, args.port);
+
+    let opts = SendOptions {
+        addr: format!("{}:{}", args.host, args.port),
+        calling_ae: args.calling_ae.clone(),
+        called_ae: args.called_ae.clone(),
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+    };
+    let sent = send_files(&files, &opts);
+    let failed = sent.iter().filter(|r| !r.success).count();
+    tracing::info!("Sent {} instances ({} failed)", sent.len() - failed, failed);
+
+    if let Some(report_path) = &args.report {
+        report::write_json(report_path, &report::Report { sent: &sent, ..Default::default() })?;
+        tracing::info!("Wrote report: {}", report_path.display());
+    }
+    if failed > 0 {
+        bail!("{} of {} instances could not be sent", failed, sent.len());
+    }
+    Ok(())
+}
 
-    fs_ops::execute(plans, cli.mode, cli.dry_run)?;
+fn run_synth(args: &cli::SynthArgs) -> Result<()> {
+    let opts = SynthOptions {
+        seed: args.seed,
+        patients: args.patients,
+        studies: args.studies,
+        series: args.series,
+        slices: args.slices,
+        rows: args.rows,
+        columns: args.columns,
+        slice_spacing: args.slice_spacing,
+        oblique_degrees: args.oblique,
+        missing_tags: args.missing_tags,
+        duplicate_sops: args.duplicates,
+        multiframe: args.multiframe,
+        junk: args.junk,
+    };
+    let summary = synth::generate(&args.output, &opts)?;
+    tracing::info!(
+        "Wrote {} instances in {} series ({} duplicates) and {} junk files to {}",
+        summary.instances.len(),
+        summary.series,
+        summary.duplicates,
+        summary.junk.len(),
+        args.output.display(),
+    );
     Ok(())
 }
@@ -0,0 +1,174 @@
+//! DIMSE command sets (PS3.7 Section 9 and Annex E), always encoded as
+//! Implicit VR Little Endian.
+
+use super::trim_uid;
+use anyhow::{ensure, Result};
+use std::collections::BTreeMap;
+
+pub const C_STORE_RQ: u16 = 0x0001;
+pub const C_STORE_RSP: u16 = 0x8001;
+pub const C_ECHO_RQ: u16 = 0x0030;
+pub const C_ECHO_RSP: u16 = 0x8030;
+
+/// CommandDataSetType value meaning "no dataset follows".
+pub const NO_DATASET: u16 = 0x0101;
+
+pub const STATUS_SUCCESS: u16 = 0x0000;
+pub const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+pub const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+pub const STATUS_UNRECOGNIZED_OPERATION: u16 = 0x0211;
+
+// Group 0000 element numbers.
+const GROUP_LENGTH: u16 = 0x0000;
+const AFFECTED_SOP_CLASS_UID: u16 = 0x0002;
+const COMMAND_FIELD: u16 = 0x0100;
+const MESSAGE_ID: u16 = 0x0110;
+const MESSAGE_ID_BEING_RESPONDED_TO: u16 = 0x0120;
+const PRIORITY: u16 = 0x0700;
+const COMMAND_DATA_SET_TYPE: u16 = 0x0800;
+const STATUS: u16 = 0x0900;
+const AFFECTED_SOP_INSTANCE_UID: u16 = 0x1000;
+
+/// A command set: group 0000 elements keyed by element number.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Command {
+    elements: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Command {
+    pub fn echo_rq(message_id: u16) -> Self {
+        let mut c = Self::default();
+        c.set_ui(AFFECTED_SOP_CLASS_UID, super::VERIFICATION);
+        c.set_us(COMMAND_FIELD, C_ECHO_RQ);
+        c.set_us(MESSAGE_ID, message_id);
+        c.set_us(COMMAND_DATA_SET_TYPE, NO_DATASET);
+        c
+    }
+
+    pub fn store_rq(message_id: u16, sop_class: &str, sop_instance: &str) -> Self {
+        let mut c = Self::default();
+        c.set_ui(AFFECTED_SOP_CLASS_UID, sop_class);
+        c.set_us(COMMAND_FIELD, C_STORE_RQ);
+        c.set_us(MESSAGE_ID, message_id);
+        c.set_us(PRIORITY, 0);
+        c.set_us(COMMAND_DATA_SET_TYPE, 0x0000);
+        c.set_ui(AFFECTED_SOP_INSTANCE_UID, sop_instance);
+        c
+    }
+
+    /// Response to `rq` with the given status; echoes the affected SOP class/instance.
+    pub fn response(rq: &Command, status: u16) -> Self {
+        let mut c = Self::default();
+        if let Some(class) = rq.affected_sop_class_uid() {
+            c.set_ui(AFFECTED_SOP_CLASS_UID, &class);
+        }
+        c.set_us(COMMAND_FIELD, rq.command_field().unwrap_or(0) | 0x8000);
+        c.set_us(MESSAGE_ID_BEING_RESPONDED_TO, rq.message_id().unwrap_or(0));
+        c.set_us(COMMAND_DATA_SET_TYPE, NO_DATASET);
+        c.set_us(STATUS, status);
+        if let Some(inst) = rq.affected_sop_instance_uid() {
+            c.set_ui(AFFECTED_SOP_INSTANCE_UID, &inst);
+        }
+        c
+    }
+
+    pub fn command_field(&self) -> Option<u16> {
+        self.us(COMMAND_FIELD)
+    }
+
+    pub fn message_id(&self) -> Option<u16> {
+        self.us(MESSAGE_ID)
+    }
+
+    pub fn message_id_being_responded_to(&self) -> Option<u16> {
+        self.us(MESSAGE_ID_BEING_RESPONDED_TO)
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.us(STATUS)
+    }
+
+    pub fn has_dataset(&self) -> bool {
+        self.us(COMMAND_DATA_SET_TYPE).is_some_and(|t| t != NO_DATASET)
+    }
+
+    pub fn affected_sop_class_uid(&self) -> Option<String> {
+        self.ui(AFFECTED_SOP_CLASS_UID)
+    }
+
+    pub fn affected_sop_instance_uid(&self) -> Option<String> {
+        self.ui(AFFECTED_SOP_INSTANCE_UID)
+    }
+
+    fn set_us(&mut self, element: u16, v: u16) {
+        self.elements.insert(element, v.to_le_bytes().to_vec());
+    }
+
+    fn set_ui(&mut self, element: u16, uid: &str) {
+        let mut v = uid.as_bytes().to_vec();
+        if v.len() % 2 == 1 {
+            v.push(0);
+        }
+        self.elements.insert(element, v);
+    }
+
+    fn us(&self, element: u16) -> Option<u16> {
+        let v = self.elements.get(&element)?;
+        Some(u16::from_le_bytes([*v.first()?, *v.get(1)?]))
+    }
+
+    fn ui(&self, element: u16) -> Option<String> {
+        let v = self.elements.get(&element)?;
+        let s = trim_uid(&String::from_utf8_lossy(v)).to_string();
+        if s.is_empty() { None } else { Some(s) }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (&element, value) in self.elements.iter().filter(|(e, _)| **e != GROUP_LENGTH) {
+            body.extend(0x0000u16.to_le_bytes());
+            body.extend(element.to_le_bytes());
+            body.extend((value.len() as u32).to_le_bytes());
+            body.extend(value);
+        }
+        let mut out = Vec::with_capacity(body.len() + 12);
+        out.extend([0, 0, 0, 0, 4, 0, 0, 0]);
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        let mut c = Self::default();
+        while !buf.is_empty() {
+            ensure!(buf.len() >= 8, "truncated command element");
+            let group = u16::from_le_bytes([buf[0], buf[1]]);
+            let element = u16::from_le_bytes([buf[2], buf[3]]);
+            let len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+            ensure!(group == 0x0000, "command set contains group {:04X}", group);
+            ensure!(buf.len() >= 8 + len, "command element (0000,{:04X}) overruns the set", element);
+            c.elements.insert(element, buf[8..8 + len].to_vec());
+            buf = &buf[8 + len..];
+        }
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_roundtrip_and_response() {
+        let rq = Command::store_rq(7, "1.2.840.10008.5.1.4.1.1.2", "1.2.3");
+        let decoded = Command::decode(&rq.encode()).unwrap();
+        assert_eq!(decoded.command_field(), Some(C_STORE_RQ));
+        assert_eq!(decoded.affected_sop_instance_uid().as_deref(), Some("1.2.3"));
+        assert!(decoded.has_dataset());
+
+        let rsp = Command::response(&decoded, STATUS_SUCCESS);
+        assert_eq!(rsp.command_field(), Some(C_STORE_RSP));
+        assert_eq!(rsp.message_id_being_responded_to(), Some(7));
+        assert!(!rsp.has_dataset());
+    }
+}
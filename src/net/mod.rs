@@ -0,0 +1,143 @@
+//! Minimal DICOM networking: the Upper Layer protocol (PS3.8) and the DIMSE
+//! C-ECHO / C-STORE services (PS3.7) needed to receive and send instances.
+//!
+//! Datasets are passed through as raw bytes in the negotiated transfer
+//! syntax, so nothing is transcoded and compressed pixel data is untouched.
+
+pub mod dimse;
+pub mod pdu;
+pub mod scp;
+pub mod scu;
+pub mod send;
+
+use anyhow::{bail, Context, Result};
+use dicom_object::meta::FileMetaTable;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const APPLICATION_CONTEXT: &str = "1.2.840.10008.3.1.1.1";
+pub const VERIFICATION: &str = "1.2.840.10008.1.1";
+/// Root of the Storage SOP Classes (PS3.4 Annex B).
+pub const STORAGE_ROOT: &str = "1.2.840.10008.5.1.4.1.1.";
+
+pub const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+pub const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+pub const EXPLICIT_VR_BE: &str = "1.2.840.10008.1.2.2";
+
+/// Transfer syntaxes accepted by the SCP, in order of preference. Encapsulated
+/// syntaxes are stored as received; only the header has to be readable.
+pub const ACCEPTED_TRANSFER_SYNTAXES: &[&str] = &[
+    EXPLICIT_VR_LE,
+    IMPLICIT_VR_LE,
+    EXPLICIT_VR_BE,
+    "1.2.840.10008.1.2.4.50", // JPEG Baseline
+    "1.2.840.10008.1.2.4.51", // JPEG Extended
+    "1.2.840.10008.1.2.4.57", // JPEG Lossless
+    "1.2.840.10008.1.2.4.70", // JPEG Lossless SV1
+    "1.2.840.10008.1.2.4.80", // JPEG-LS Lossless
+    "1.2.840.10008.1.2.4.81", // JPEG-LS Near-Lossless
+    "1.2.840.10008.1.2.4.90", // JPEG 2000 Lossless
+    "1.2.840.10008.1.2.4.91", // JPEG 2000
+    "1.2.840.10008.1.2.5",    // RLE Lossless
+];
+
+pub const IMPLEMENTATION_CLASS_UID: &str = "2.25.246185925839148231776186466350474652813";
+pub const IMPLEMENTATION_VERSION_NAME: &str = concat!("DCMSORT_", env!("CARGO_PKG_VERSION"));
+
+/// Our receive limit for a single P-DATA PDU.
+pub const MAX_PDU_LENGTH: u32 = 64 * 1024;
+
+pub fn is_storage_class(uid: &str) -> bool {
+    uid.starts_with(STORAGE_ROOT)
+}
+
+/// Strip the padding allowed on UIDs and AE titles.
+pub(crate) fn trim_uid(s: &str) -> &str {
+    s.trim_end_matches(['\0', ' ']).trim_start()
+}
+
+/// Send a command, and optionally its dataset, on presentation context `ctx`,
+/// split into P-DATA-TF PDUs no larger than the peer's `max_pdu_length`.
+pub(crate) fn send_message<W: std::io::Write>(
+    w: &mut W,
+    ctx: u8,
+    command: &dimse::Command,
+    dataset: Option<&[u8]>,
+    max_pdu_length: u32,
+) -> Result<()> {
+    // The limit covers the PDV item header (4-byte length, context id, control byte).
+    let chunk = match max_pdu_length {
+        0 => MAX_PDU_LENGTH as usize,
+        n => (n as usize).saturating_sub(6).max(1),
+    };
+    let mut send = |bytes: &[u8], is_command: bool| -> Result<()> {
+        let mut parts = bytes.chunks(chunk).peekable();
+        if parts.peek().is_none() {
+            let pdv = pdu::Pdv { context_id: ctx, is_command, is_last: true, data: Vec::new() };
+            return pdu::write_pdu(w, &pdu::Pdu::PData(vec![pdv]));
+        }
+        while let Some(part) = parts.next() {
+            let pdv = pdu::Pdv { context_id: ctx, is_command, is_last: parts.peek().is_none(), data: part.to_vec() };
+            pdu::write_pdu(w, &pdu::Pdu::PData(vec![pdv]))?;
+        }
+        Ok(())
+    };
+    send(&command.encode(), true)?;
+    if let Some(data) = dataset {
+        send(data, false)?;
+    }
+    Ok(())
+}
+
+/// A DICOM file split into its meta group and the raw dataset bytes that follow it.
+pub struct FileParts {
+    pub meta: FileMetaTable,
+    pub dataset: Vec<u8>,
+}
+
+/// Read a Part 10 file without decoding the dataset.
+pub fn read_file_parts(path: &Path) -> Result<FileParts> {
+    let mut f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let meta = read_meta_group(&mut f, path)?;
+    let mut dataset = Vec::new();
+    f.read_to_end(&mut dataset).with_context(|| format!("read {}", path.display()))?;
+    Ok(FileParts { meta, dataset })
+}
+
+/// Read only the file meta group (SOP class, instance and transfer syntax).
+pub fn read_file_meta(path: &Path) -> Result<FileMetaTable> {
+    let mut f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    read_meta_group(&mut f, path)
+}
+
+/// Parse the meta group and leave `r` at the first dataset byte.
+fn read_meta_group<R: Read>(r: &mut R, path: &Path) -> Result<FileMetaTable> {
+    let truncated = || format!("truncated meta group: {}", path.display());
+
+    // Either a 128-byte preamble followed by "DICM", or "DICM" right away.
+    let mut head = [0u8; 132];
+    r.read_exact(&mut head[..4]).with_context(truncated)?;
+    if &head[..4] != b"DICM" {
+        r.read_exact(&mut head[4..]).with_context(truncated)?;
+        if &head[128..132] != b"DICM" {
+            bail!("not a DICOM file: {}", path.display());
+        }
+    }
+
+    // (0002,0000) UL, explicit VR little endian: tag, "UL", 2-byte length, 4-byte value.
+    let mut group = [0u8; 12];
+    r.read_exact(&mut group).with_context(truncated)?;
+    if group[..4] != [0x02, 0x00, 0x00, 0x00] {
+        bail!("meta group does not start with a group length: {}", path.display());
+    }
+    let group_len = u32::from_le_bytes([group[8], group[9], group[10], group[11]]) as u64;
+    let mut bytes = b"DICM".to_vec();
+    bytes.extend(group);
+    r.take(group_len).read_to_end(&mut bytes).with_context(truncated)?;
+    if bytes.len() as u64 != 16 + group_len {
+        bail!("truncated meta group: {}", path.display());
+    }
+
+    FileMetaTable::from_reader(bytes.as_slice()).with_context(|| format!("parse meta group: {}", path.display()))
+}
@@ -0,0 +1,308 @@
+//! Upper Layer PDUs (PS3.8 Section 9.3).
+
+use super::{trim_uid, APPLICATION_CONTEXT, IMPLEMENTATION_CLASS_UID, IMPLEMENTATION_VERSION_NAME};
+use anyhow::{bail, ensure, Context, Result};
+use std::io::{Read, Write};
+
+const ASSOCIATE_RQ: u8 = 0x01;
+const ASSOCIATE_AC: u8 = 0x02;
+const ASSOCIATE_RJ: u8 = 0x03;
+const P_DATA_TF: u8 = 0x04;
+const RELEASE_RQ: u8 = 0x05;
+const RELEASE_RP: u8 = 0x06;
+const ABORT: u8 = 0x07;
+
+const ITEM_APPLICATION_CONTEXT: u8 = 0x10;
+const ITEM_PRESENTATION_CONTEXT_RQ: u8 = 0x20;
+const ITEM_PRESENTATION_CONTEXT_AC: u8 = 0x21;
+const ITEM_ABSTRACT_SYNTAX: u8 = 0x30;
+const ITEM_TRANSFER_SYNTAX: u8 = 0x40;
+const ITEM_USER_INFORMATION: u8 = 0x50;
+const ITEM_MAX_LENGTH: u8 = 0x51;
+const ITEM_IMPLEMENTATION_CLASS_UID: u8 = 0x52;
+const ITEM_IMPLEMENTATION_VERSION_NAME: u8 = 0x55;
+
+/// Presentation context results (PS3.8 Section 9.3.3.2).
+pub const PC_ACCEPTANCE: u8 = 0;
+pub const PC_ABSTRACT_SYNTAX_NOT_SUPPORTED: u8 = 3;
+pub const PC_TRANSFER_SYNTAXES_NOT_SUPPORTED: u8 = 4;
+
+/// A-ASSOCIATE-RJ reasons for source 1 (service user).
+pub const RJ_NO_REASON: u8 = 1;
+pub const RJ_CALLING_AE_NOT_RECOGNIZED: u8 = 3;
+pub const RJ_CALLED_AE_NOT_RECOGNIZED: u8 = 7;
+
+/// Largest PDU we are willing to buffer, whatever the peer announces.
+const PDU_HARD_LIMIT: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresentationContext {
+    pub id: u8,
+    /// Empty in an A-ASSOCIATE-AC.
+    pub abstract_syntax: String,
+    /// Proposed syntaxes in an RQ; the single accepted one in an AC.
+    pub transfer_syntaxes: Vec<String>,
+    /// Only meaningful in an AC.
+    pub result: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssociateParams {
+    pub called_ae: String,
+    pub calling_ae: String,
+    pub contexts: Vec<PresentationContext>,
+    /// Largest P-DATA PDU the sender of this PDU accepts; 0 means unlimited.
+    pub max_pdu_length: u32,
+    pub implementation_class_uid: String,
+    pub implementation_version_name: Option<String>,
+}
+
+impl AssociateParams {
+    pub fn new(calling_ae: &str, called_ae: &str, contexts: Vec<PresentationContext>, max_pdu_length: u32) -> Self {
+        Self {
+            called_ae: called_ae.to_string(),
+            calling_ae: calling_ae.to_string(),
+            contexts,
+            max_pdu_length,
+            implementation_class_uid: IMPLEMENTATION_CLASS_UID.to_string(),
+            implementation_version_name: Some(IMPLEMENTATION_VERSION_NAME.to_string()),
+        }
+    }
+}
+
+/// One presentation data value of a P-DATA-TF.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pdv {
+    pub context_id: u8,
+    pub is_command: bool,
+    pub is_last: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pdu {
+    AssociateRq(AssociateParams),
+    AssociateAc(AssociateParams),
+    AssociateRj { result: u8, source: u8, reason: u8 },
+    PData(Vec<Pdv>),
+    ReleaseRq,
+    ReleaseRp,
+    Abort { source: u8, reason: u8 },
+}
+
+pub fn read_pdu<R: Read>(r: &mut R) -> Result<Pdu> {
+    let mut head = [0u8; 6];
+    r.read_exact(&mut head).context("read PDU header")?;
+    let len = u32::from_be_bytes([head[2], head[3], head[4], head[5]]);
+    ensure!(len <= PDU_HARD_LIMIT, "PDU of {} bytes exceeds limit", len);
+    let mut body = vec![0u8; len as usize];
+    r.read_exact(&mut body).context("read PDU body")?;
+
+    Ok(match head[0] {
+        ASSOCIATE_RQ => Pdu::AssociateRq(decode_associate(&body)?),
+        ASSOCIATE_AC => Pdu::AssociateAc(decode_associate(&body)?),
+        ASSOCIATE_RJ => {
+            ensure!(body.len() >= 4, "short A-ASSOCIATE-RJ");
+            Pdu::AssociateRj { result: body[1], source: body[2], reason: body[3] }
+        }
+        P_DATA_TF => Pdu::PData(decode_pdvs(&body)?),
+        RELEASE_RQ => Pdu::ReleaseRq,
+        RELEASE_RP => Pdu::ReleaseRp,
+        ABORT => {
+            ensure!(body.len() >= 4, "short A-ABORT");
+            Pdu::Abort { source: body[2], reason: body[3] }
+        }
+        t => bail!("unknown PDU type 0x{:02X}", t),
+    })
+}
+
+pub fn write_pdu<W: Write>(w: &mut W, pdu: &Pdu) -> Result<()> {
+    let (kind, body) = match pdu {
+        Pdu::AssociateRq(p) => (ASSOCIATE_RQ, encode_associate(p, false)),
+        Pdu::AssociateAc(p) => (ASSOCIATE_AC, encode_associate(p, true)),
+        Pdu::AssociateRj { result, source, reason } => (ASSOCIATE_RJ, vec![0, *result, *source, *reason]),
+        Pdu::PData(pdvs) => (P_DATA_TF, encode_pdvs(pdvs)),
+        Pdu::ReleaseRq => (RELEASE_RQ, vec![0; 4]),
+        Pdu::ReleaseRp => (RELEASE_RP, vec![0; 4]),
+        Pdu::Abort { source, reason } => (ABORT, vec![0, 0, *source, *reason]),
+    };
+    let mut out = Vec::with_capacity(body.len() + 6);
+    out.extend([kind, 0]);
+    out.extend((body.len() as u32).to_be_bytes());
+    out.extend(body);
+    w.write_all(&out).context("write PDU")?;
+    w.flush().context("flush PDU")
+}
+
+/// AE titles are 16 bytes, space padded.
+fn ae_bytes(ae: &str) -> [u8; 16] {
+    let mut out = [b' '; 16];
+    for (o, b) in out.iter_mut().zip(ae.bytes()) {
+        *o = b;
+    }
+    out
+}
+
+fn item(kind: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind, 0];
+    out.extend((value.len() as u16).to_be_bytes());
+    out.extend(value);
+    out
+}
+
+fn encode_associate(p: &AssociateParams, accept: bool) -> Vec<u8> {
+    let mut out = vec![0x00, 0x01, 0, 0];
+    out.extend(ae_bytes(&p.called_ae));
+    out.extend(ae_bytes(&p.calling_ae));
+    out.extend([0u8; 32]);
+    out.extend(item(ITEM_APPLICATION_CONTEXT, APPLICATION_CONTEXT.as_bytes()));
+
+    for pc in &p.contexts {
+        let mut v = vec![pc.id, 0, if accept { pc.result } else { 0 }, 0];
+        if !accept {
+            v.extend(item(ITEM_ABSTRACT_SYNTAX, pc.abstract_syntax.as_bytes()));
+        }
+        for ts in &pc.transfer_syntaxes {
+            v.extend(item(ITEM_TRANSFER_SYNTAX, ts.as_bytes()));
+        }
+        let kind = if accept { ITEM_PRESENTATION_CONTEXT_AC } else { ITEM_PRESENTATION_CONTEXT_RQ };
+        out.extend(item(kind, &v));
+    }
+
+    let mut user = item(ITEM_MAX_LENGTH, &p.max_pdu_length.to_be_bytes());
+    user.extend(item(ITEM_IMPLEMENTATION_CLASS_UID, p.implementation_class_uid.as_bytes()));
+    if let Some(name) = &p.implementation_version_name {
+        user.extend(item(ITEM_IMPLEMENTATION_VERSION_NAME, name.as_bytes()));
+    }
+    out.extend(item(ITEM_USER_INFORMATION, &user));
+    out
+}
+
+/// Split a run of `type, reserved, u16 length, value` items.
+fn items(mut buf: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut out = Vec::new();
+    while !buf.is_empty() {
+        ensure!(buf.len() >= 4, "truncated item");
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        ensure!(buf.len() >= 4 + len, "item 0x{:02X} overruns its PDU", buf[0]);
+        out.push((buf[0], &buf[4..4 + len]));
+        buf = &buf[4 + len..];
+    }
+    Ok(out)
+}
+
+fn text(v: &[u8]) -> String {
+    trim_uid(&String::from_utf8_lossy(v)).to_string()
+}
+
+fn decode_associate(body: &[u8]) -> Result<AssociateParams> {
+    ensure!(body.len() >= 68, "short A-ASSOCIATE PDU");
+    let mut p = AssociateParams {
+        called_ae: text(&body[4..20]),
+        calling_ae: text(&body[20..36]),
+        contexts: Vec::new(),
+        max_pdu_length: 0,
+        implementation_class_uid: String::new(),
+        implementation_version_name: None,
+    };
+
+    for (kind, value) in items(&body[68..])? {
+        match kind {
+            ITEM_PRESENTATION_CONTEXT_RQ | ITEM_PRESENTATION_CONTEXT_AC => {
+                ensure!(value.len() >= 4, "short presentation context item");
+                let mut pc = PresentationContext {
+                    id: value[0],
+                    abstract_syntax: String::new(),
+                    transfer_syntaxes: Vec::new(),
+                    result: value[2],
+                };
+                for (sub, v) in items(&value[4..])? {
+                    match sub {
+                        ITEM_ABSTRACT_SYNTAX => pc.abstract_syntax = text(v),
+                        ITEM_TRANSFER_SYNTAX => pc.transfer_syntaxes.push(text(v)),
+                        _ => {}
+                    }
+                }
+                p.contexts.push(pc);
+            }
+            ITEM_USER_INFORMATION => {
+                for (sub, v) in items(value)? {
+                    match sub {
+                        ITEM_MAX_LENGTH if v.len() == 4 => {
+                            p.max_pdu_length = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+                        }
+                        ITEM_IMPLEMENTATION_CLASS_UID => p.implementation_class_uid = text(v),
+                        ITEM_IMPLEMENTATION_VERSION_NAME => p.implementation_version_name = Some(text(v)),
+                        // Extended negotiation, async ops, user identity: not supported, ignored.
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(p)
+}
+
+fn decode_pdvs(mut body: &[u8]) -> Result<Vec<Pdv>> {
+    let mut out = Vec::new();
+    while !body.is_empty() {
+        ensure!(body.len() >= 6, "truncated PDV item");
+        let len = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+        ensure!(len >= 2 && body.len() >= 4 + len, "PDV item overruns its PDU");
+        let header = body[5];
+        out.push(Pdv {
+            context_id: body[4],
+            is_command: header & 0x01 != 0,
+            is_last: header & 0x02 != 0,
+            data: body[6..4 + len].to_vec(),
+        });
+        body = &body[4 + len..];
+    }
+    Ok(out)
+}
+
+fn encode_pdvs(pdvs: &[Pdv]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for p in pdvs {
+        out.extend((p.data.len() as u32 + 2).to_be_bytes());
+        out.push(p.context_id);
+        out.push(u8::from(p.is_command) | (u8::from(p.is_last) << 1));
+        out.extend(&p.data);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_associate_roundtrip() {
+        let rq = AssociateParams::new(
+            "MODALITY",
+            "DCMSORT",
+            vec![PresentationContext {
+                id: 1,
+                abstract_syntax: "1.2.840.10008.5.1.4.1.1.2".into(),
+                transfer_syntaxes: vec!["1.2.840.10008.1.2.1".into(), "1.2.840.10008.1.2".into()],
+                result: 0,
+            }],
+            16384,
+        );
+        let mut buf = Vec::new();
+        write_pdu(&mut buf, &Pdu::AssociateRq(rq.clone())).unwrap();
+        assert_eq!(read_pdu(&mut buf.as_slice()).unwrap(), Pdu::AssociateRq(rq));
+    }
+
+    #[test]
+    fn test_pdata_roundtrip() {
+        let pdu = Pdu::PData(vec![
+            Pdv { context_id: 3, is_command: true, is_last: true, data: vec![1, 2, 3] },
+            Pdv { context_id: 3, is_command: false, is_last: false, data: vec![4; 10] },
+        ]);
+        let mut buf = Vec::new();
+        write_pdu(&mut buf, &pdu).unwrap();
+        assert_eq!(read_pdu(&mut buf.as_slice()).unwrap(), pdu);
+    }
+}
@@ -0,0 +1,401 @@
+//! Storage SCP: accept associations, answer C-ECHO, and file every C-STORE
+//! instance into the output tree as soon as it has been received.
+
+use super::dimse::{self, Command};
+use super::pdu::{self, AssociateParams, Pdu, PresentationContext};
+use super::{is_storage_class, send_message, ACCEPTED_TRANSFER_SYNTAXES, MAX_PDU_LENGTH, VERIFICATION};
+use crate::deid::Deidentifier;
+use crate::dicom::read_meta_with_tags;
+use crate::fs_ops::execute;
+use crate::sink::MoveSink;
+use crate::sort::{plan_instance, PlanOptions};
+use crate::types::OnConflict;
+use anyhow::{bail, Context, Result};
+use dicom_object::meta::FileMetaTableBuilder;
+use dicom_object::Tag;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Drop associations that stay silent this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Received files are written here first, then moved into place.
+const SPOOL_DIR: &str = ".incoming";
+
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct ScpConfig {
+    /// Our AE title; associations addressed to another called AE are rejected.
+    pub ae_title: String,
+    /// Calling AE titles allowed to associate; empty accepts any.
+    pub accepted_calling_aes: Vec<String>,
+}
+
+/// Files received instances with the same planning logic as directory input.
+pub struct Filer<'a> {
+    pub out_dir: &'a Path,
+    pub plan: PlanOptions<'a>,
+    /// Raw tags needed by the path template.
+    pub extra_tags: &'a [Tag],
+    pub on_conflict: OnConflict,
+    pub deid: Option<&'a Deidentifier>,
+    /// Called when an association that stored instances closes, e.g. to persist
+    /// mapping files of a long-running listener. Not called under the filing lock.
+    pub after_association: Option<&'a (dyn Fn() + Sync)>,
+    // Associations run concurrently; conflict resolution is check-then-write.
+    lock: Mutex<()>,
+}
+
+impl<'a> Filer<'a> {
+    pub fn new(out_dir: &'a Path, plan: PlanOptions<'a>) -> Self {
+        Self {
+            out_dir,
+            plan,
+            extra_tags: &[],
+            on_conflict: OnConflict::SkipIdentical,
+            deid: None,
+            after_association: None,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn spool_path(&self, sop_instance: &str) -> PathBuf {
+        let n = SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = crate::sanitize::sanitize_component(sop_instance);
+        self.out_dir.join(SPOOL_DIR).join(format!("{}.{}.{}.part", name, std::process::id(), n))
+    }
+
+    /// Move a received file from the spool into the output tree. Returns the
+    /// DIMSE status and, on success, where the instance ended up.
+    fn file(&self, spool: &Path) -> (u16, Option<PathBuf>) {
+        let meta = match read_meta_with_tags(spool, self.extra_tags) {
+            Ok(m) => m,
+            Err(e) => {
+                tracing::warn!("Received unreadable instance: {:#}", e);
+                let _ = fs::remove_file(spool);
+                return (dimse::STATUS_CANNOT_UNDERSTAND, None);
+            }
+        };
+        let meta = match self.deid {
+            Some(d) => d.apply_meta(&meta),
+            None => meta,
+        };
+        let plan = plan_instance(&meta, self.out_dir, &self.plan);
+        let dst = plan.dst.clone();
+
+        let result = {
+            let _guard = self.lock.lock().unwrap();
+            execute(vec![plan], &MoveSink::default(), self.on_conflict, false, self.deid, 1)
+        };
+        // Filing moves the spool file away, or drops it as a duplicate; a failure leaves it behind.
+        if result.is_err() {
+            let _ = fs::remove_file(spool);
+        }
+        match result {
+            Ok(records) => (dimse::STATUS_SUCCESS, Some(records.first().map(|r| r.actual.clone()).unwrap_or(dst))),
+            Err(e) => {
+                tracing::warn!("Failed to file received instance: {:#}", e);
+                (dimse::STATUS_OUT_OF_RESOURCES, None)
+            }
+        }
+    }
+}
+
+/// Accept associations forever, one thread each.
+pub fn serve(listener: TcpListener, cfg: &ScpConfig, filer: &Filer) -> Result<()> {
+    let spool = filer.out_dir.join(SPOOL_DIR);
+    fs::create_dir_all(&spool).with_context(|| format!("create dir: {}", spool.display()))?;
+
+    std::thread::scope(|s| {
+        for conn in listener.incoming() {
+            match conn {
+                Ok(stream) => {
+                    s.spawn(move || {
+                        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                        if let Err(e) = handle_association(stream, cfg, filer) {
+                            tracing::warn!("Association from {} failed: {:#}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// C-STORE in progress: command received, dataset still arriving.
+struct Incoming {
+    command: Command,
+    context_id: u8,
+    spool: PathBuf,
+    // None if the spool file could not be created; the data is drained and a failure returned.
+    sink: Option<BufWriter<File>>,
+}
+
+/// Serve one association until release or abort.
+pub fn handle_association(stream: TcpStream, cfg: &ScpConfig, filer: &Filer) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT)).context("set read timeout")?;
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let mut reader = BufReader::new(stream.try_clone().context("clone stream")?);
+    let mut writer = &stream;
+
+    let rq = match pdu::read_pdu(&mut reader)? {
+        Pdu::AssociateRq(p) => p,
+        other => {
+            pdu::write_pdu(&mut writer, &Pdu::Abort { source: 0, reason: 0 })?;
+            bail!("expected A-ASSOCIATE-RQ, got {:?}", other);
+        }
+    };
+
+    let reject = if !cfg.ae_title.is_empty() && rq.called_ae != cfg.ae_title {
+        Some(pdu::RJ_CALLED_AE_NOT_RECOGNIZED)
+    } else if !cfg.accepted_calling_aes.is_empty() && !cfg.accepted_calling_aes.contains(&rq.calling_ae) {
+        Some(pdu::RJ_CALLING_AE_NOT_RECOGNIZED)
+    } else {
+        None
+    };
+    if let Some(reason) = reject {
+        pdu::write_pdu(&mut writer, &Pdu::AssociateRj { result: 1, source: 1, reason })?;
+        bail!("rejected association {} -> {} from {}", rq.calling_ae, rq.called_ae, peer);
+    }
+
+    let contexts: Vec<PresentationContext> = rq.contexts.iter().map(negotiate).collect();
+    // Accepted context id -> transfer syntax.
+    let accepted: HashMap<u8, String> = rq
+        .contexts
+        .iter()
+        .zip(&contexts)
+        .filter(|(_, ac)| ac.result == pdu::PC_ACCEPTANCE)
+        .map(|(pc, ac)| (pc.id, ac.transfer_syntaxes[0].clone()))
+        .collect();
+    let ac = AssociateParams::new(&rq.calling_ae, &rq.called_ae, contexts, MAX_PDU_LENGTH);
+    pdu::write_pdu(&mut writer, &Pdu::AssociateAc(ac))?;
+    tracing::info!(
+        "Association from {} ({}): {} of {} presentation contexts accepted",
+        rq.calling_ae,
+        peer,
+        accepted.len(),
+        rq.contexts.len()
+    );
+
+    let mut command_buf = Vec::new();
+    let mut incoming: Option<Incoming> = None;
+    let mut stored = 0usize;
+
+    let result = (|| -> Result<()> {
+        loop {
+            match pdu::read_pdu(&mut reader)? {
+                Pdu::PData(pdvs) => {
+                    for pdv in pdvs {
+                        if !pdv.is_command {
+                            let Some(inc) = incoming.as_mut() else {
+                                bail!("dataset fragment without a C-STORE command");
+                            };
+                            if let Some(sink) = inc.sink.as_mut() {
+                                if let Err(e) = sink.write_all(&pdv.data) {
+                                    tracing::warn!("Write to {} failed: {}", inc.spool.display(), e);
+                                    inc.sink = None;
+                                }
+                            }
+                            if pdv.is_last {
+                                let inc = incoming.take().unwrap();
+                                let ctx = inc.context_id;
+                                let rsp = finish_store(inc, filer);
+                                stored += usize::from(rsp.status() == Some(dimse::STATUS_SUCCESS));
+                                send_message(&mut writer, ctx, &rsp, None, rq.max_pdu_length)?;
+                            }
+                            continue;
+                        }
+
+                        command_buf.extend(pdv.data);
+                        if !pdv.is_last {
+                            continue;
+                        }
+                        let command = Command::decode(&std::mem::take(&mut command_buf))?;
+                        match command.command_field() {
+                            Some(dimse::C_ECHO_RQ) => {
+                                let rsp = Command::response(&command, dimse::STATUS_SUCCESS);
+                                send_message(&mut writer, pdv.context_id, &rsp, None, rq.max_pdu_length)?;
+                            }
+                            Some(dimse::C_STORE_RQ) if command.has_dataset() => {
+                                let ts = accepted.get(&pdv.context_id).cloned().unwrap_or_default();
+                                let spool = filer.spool_path(&command.affected_sop_instance_uid().unwrap_or_default());
+                                let sink = match open_spool(&spool, &command, &ts, &rq.calling_ae, &cfg.ae_title) {
+                                    Ok(f) => Some(f),
+                                    Err(e) => {
+                                        tracing::warn!("{:#}", e);
+                                        None
+                                    }
+                                };
+                                incoming = Some(Incoming { command, context_id: pdv.context_id, spool, sink });
+                            }
+                            _ => {
+                                let rsp = Command::response(&command, dimse::STATUS_UNRECOGNIZED_OPERATION);
+                                send_message(&mut writer, pdv.context_id, &rsp, None, rq.max_pdu_length)?;
+                            }
+                        }
+                    }
+                }
+                Pdu::ReleaseRq => {
+                    pdu::write_pdu(&mut writer, &Pdu::ReleaseRp)?;
+                    return Ok(());
+                }
+                Pdu::Abort { source, reason } => bail!("peer aborted (source {}, reason {})", source, reason),
+                other => {
+                    pdu::write_pdu(&mut writer, &Pdu::Abort { source: 2, reason: 2 })?;
+                    bail!("unexpected PDU {:?}", other);
+                }
+            }
+        }
+    })();
+
+    if let Some(inc) = incoming {
+        let _ = fs::remove_file(&inc.spool);
+    }
+    tracing::info!("Association from {} closed: {} instances stored", rq.calling_ae, stored);
+    if let (true, Some(hook)) = (stored > 0, filer.after_association) {
+        hook();
+    }
+    result
+}
+
+/// Accept Verification and Storage abstract syntaxes with our preferred transfer syntax.
+fn negotiate(pc: &PresentationContext) -> PresentationContext {
+    let supported = pc.abstract_syntax == VERIFICATION || is_storage_class(&pc.abstract_syntax);
+    let ts = ACCEPTED_TRANSFER_SYNTAXES
+        .iter()
+        .find(|ts| pc.transfer_syntaxes.iter().any(|p| p == *ts));
+    let (result, ts) = match (supported, ts) {
+        (false, _) => (pdu::PC_ABSTRACT_SYNTAX_NOT_SUPPORTED, super::IMPLICIT_VR_LE),
+        (true, None) => (pdu::PC_TRANSFER_SYNTAXES_NOT_SUPPORTED, super::IMPLICIT_VR_LE),
+        (true, Some(ts)) => (pdu::PC_ACCEPTANCE, *ts),
+    };
+    PresentationContext {
+        id: pc.id,
+        abstract_syntax: String::new(),
+        transfer_syntaxes: vec![ts.to_string()],
+        result,
+    }
+}
+
+/// Create the spool file with a Part 10 header so the dataset can be appended as it arrives.
+fn open_spool(path: &Path, command: &Command, ts: &str, calling_ae: &str, called_ae: &str) -> Result<BufWriter<File>> {
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(command.affected_sop_class_uid().unwrap_or_default())
+        .media_storage_sop_instance_uid(command.affected_sop_instance_uid().unwrap_or_default())
+        .transfer_syntax(ts)
+        .source_application_entity_title(calling_ae)
+        .receiving_application_entity_title(called_ae)
+        .build()
+        .context("build file meta group")?;
+
+    let file = File::create(path).with_context(|| format!("create spool file: {}", path.display()))?;
+    let mut w = BufWriter::new(file);
+    w.write_all(&[0u8; 128])?;
+    w.write_all(b"DICM")?;
+    meta.write(&mut w).context("write file meta group")?;
+    Ok(w)
+}
+
+/// Close the spool file, file it, and build the C-STORE-RSP.
+fn finish_store(inc: Incoming, filer: &Filer) -> Command {
+    let flushed = inc.sink.map(|mut w| w.flush().is_ok()).unwrap_or(false);
+    let status = if flushed {
+        let (status, dst) = filer.file(&inc.spool);
+        if let Some(dst) = dst {
+            tracing::debug!("Stored {}", dst.display());
+        }
+        status
+    } else {
+        let _ = fs::remove_file(&inc.spool);
+        dimse::STATUS_OUT_OF_RESOURCES
+    };
+    Command::response(&inc.command, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::scu::{Association, Proposal};
+    use crate::net::{read_file_parts, EXPLICIT_VR_LE};
+    use crate::types::Layout;
+    use crate::synth::instance;
+    use dicom_core::VR;
+    use dicom_dictionary_std::tags;
+
+    const CT: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+    fn write_ct(path: &Path) {
+        let bytes = instance(CT, "1.2.3.4.5", &[
+            (tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3"),
+            (tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4"),
+            (tags::INSTANCE_NUMBER, VR::IS, "3"),
+        ]);
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_loopback_echo_and_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("in.dcm");
+        write_ct(&src);
+        let out = dir.path().join("out");
+        fs::create_dir_all(out.join(SPOOL_DIR)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cfg = ScpConfig { ae_title: "DCMSORT".into(), accepted_calling_aes: vec!["TESTSCU".into()] };
+        let filer = Filer::new(&out, PlanOptions { layout: Layout::SeriesOnly, ..Default::default() });
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                handle_association(stream, &cfg, &filer).unwrap();
+            });
+
+            let proposals = [
+                Proposal { abstract_syntax: VERIFICATION.into(), transfer_syntaxes: vec![super::super::IMPLICIT_VR_LE.into()] },
+                Proposal { abstract_syntax: CT.into(), transfer_syntaxes: vec![EXPLICIT_VR_LE.into()] },
+            ];
+            let mut assoc = Association::connect(addr, "TESTSCU", "DCMSORT", &proposals).unwrap();
+            assert_eq!(assoc.echo().unwrap(), dimse::STATUS_SUCCESS);
+
+            let parts = read_file_parts(&src).unwrap();
+            let ctx = assoc.context_for(CT, EXPLICIT_VR_LE).unwrap();
+            assert_eq!(assoc.store(ctx, CT, "1.2.3.4.5", &parts.dataset).unwrap(), dimse::STATUS_SUCCESS);
+            assoc.release().unwrap();
+        });
+
+        let stored = out.join("1.2.3.4").join("00003_1.2.3.4.5.dcm");
+        let m = crate::dicom::read_meta(&stored).unwrap();
+        assert_eq!(m.sop_uid.as_deref(), Some("1.2.3.4.5"));
+        assert_eq!(fs::read_dir(out.join(SPOOL_DIR)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_calling_ae_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cfg = ScpConfig { ae_title: "DCMSORT".into(), accepted_calling_aes: vec!["PACS".into()] };
+        let filer = Filer::new(dir.path(), PlanOptions::default());
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                assert!(handle_association(stream, &cfg, &filer).is_err());
+            });
+            let proposals = [Proposal { abstract_syntax: VERIFICATION.into(), transfer_syntaxes: vec![super::super::IMPLICIT_VR_LE.into()] }];
+            let err = Association::connect(addr, "INTRUDER", "DCMSORT", &proposals).err().unwrap();
+            assert!(err.to_string().contains("rejected"));
+        });
+    }
+}
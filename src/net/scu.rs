@@ -0,0 +1,168 @@
+//! Storage/Verification SCU: request an association and issue C-ECHO / C-STORE.
+
+use super::dimse::{self, Command};
+use super::pdu::{self, AssociateParams, Pdu, PresentationContext};
+use super::{send_message, MAX_PDU_LENGTH};
+use anyhow::{bail, ensure, Context, Result};
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long to wait for a response before giving up on the association.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A presentation context to propose: abstract syntax plus acceptable transfer syntaxes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proposal {
+    pub abstract_syntax: String,
+    pub transfer_syntaxes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptedContext {
+    pub id: u8,
+    pub abstract_syntax: String,
+    pub transfer_syntax: String,
+}
+
+pub struct Association {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    peer_max_pdu_length: u32,
+    accepted: Vec<AcceptedContext>,
+    next_message_id: u16,
+}
+
+impl Association {
+    /// Connect and negotiate; at most 128 proposals fit in one association.
+    pub fn connect<A: ToSocketAddrs>(addr: A, calling_ae: &str, called_ae: &str, proposals: &[Proposal]) -> Result<Self> {
+        ensure!(!proposals.is_empty(), "no presentation contexts to propose");
+        ensure!(proposals.len() <= 128, "too many presentation contexts ({})", proposals.len());
+
+        let stream = TcpStream::connect(addr).context("connect to SCP")?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT)).context("set read timeout")?;
+        let mut reader = BufReader::new(stream.try_clone().context("clone stream")?);
+
+        // Presentation context IDs are odd: 1, 3, 5, ...
+        let contexts: Vec<PresentationContext> = proposals
+            .iter()
+            .enumerate()
+            .map(|(i, p)| PresentationContext {
+                id: (i * 2 + 1) as u8,
+                abstract_syntax: p.abstract_syntax.clone(),
+                transfer_syntaxes: p.transfer_syntaxes.clone(),
+                result: 0,
+            })
+            .collect();
+        let rq = AssociateParams::new(calling_ae, called_ae, contexts.clone(), MAX_PDU_LENGTH);
+        pdu::write_pdu(&mut &stream, &Pdu::AssociateRq(rq))?;
+
+        let ac = match pdu::read_pdu(&mut reader)? {
+            Pdu::AssociateAc(ac) => ac,
+            Pdu::AssociateRj { result, source, reason } => {
+                bail!("association rejected (result {}, source {}, reason {})", result, source, reason)
+            }
+            other => bail!("unexpected reply to A-ASSOCIATE-RQ: {:?}", other),
+        };
+
+        let accepted = ac
+            .contexts
+            .iter()
+            .filter(|pc| pc.result == pdu::PC_ACCEPTANCE)
+            .filter_map(|pc| {
+                let proposed = contexts.iter().find(|c| c.id == pc.id)?;
+                Some(AcceptedContext {
+                    id: pc.id,
+                    abstract_syntax: proposed.abstract_syntax.clone(),
+                    transfer_syntax: pc.transfer_syntaxes.first()?.clone(),
+                })
+            })
+            .collect();
+
+        Ok(Self { stream, reader, peer_max_pdu_length: ac.max_pdu_length, accepted, next_message_id: 1 })
+    }
+
+    pub fn accepted(&self) -> &[AcceptedContext] {
+        &self.accepted
+    }
+
+    /// An accepted context for this abstract syntax and exact transfer syntax.
+    pub fn context_for(&self, abstract_syntax: &str, transfer_syntax: &str) -> Option<u8> {
+        self.accepted
+            .iter()
+            .find(|c| c.abstract_syntax == abstract_syntax && c.transfer_syntax == transfer_syntax)
+            .map(|c| c.id)
+    }
+
+    /// C-ECHO; returns the DIMSE status.
+    pub fn echo(&mut self) -> Result<u16> {
+        let ctx = self
+            .accepted
+            .iter()
+            .find(|c| c.abstract_syntax == super::VERIFICATION)
+            .map(|c| c.id)
+            .context("Verification SOP Class was not accepted")?;
+        let id = self.message_id();
+        self.request(ctx, &Command::echo_rq(id), None)
+    }
+
+    /// C-STORE `dataset`, already encoded in the context's transfer syntax; returns the DIMSE status.
+    pub fn store(&mut self, ctx: u8, sop_class: &str, sop_instance: &str, dataset: &[u8]) -> Result<u16> {
+        let id = self.message_id();
+        self.request(ctx, &Command::store_rq(id, sop_class, sop_instance), Some(dataset))
+    }
+
+    pub fn release(mut self) -> Result<()> {
+        pdu::write_pdu(&mut &self.stream, &Pdu::ReleaseRq)?;
+        match pdu::read_pdu(&mut self.reader)? {
+            Pdu::ReleaseRp => Ok(()),
+            other => bail!("unexpected reply to A-RELEASE-RQ: {:?}", other),
+        }
+    }
+
+    pub fn abort(self) {
+        let _ = pdu::write_pdu(&mut &self.stream, &Pdu::Abort { source: 0, reason: 0 });
+    }
+
+    fn message_id(&mut self) -> u16 {
+        let id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1).max(1);
+        id
+    }
+
+    fn request(&mut self, ctx: u8, command: &Command, dataset: Option<&[u8]>) -> Result<u16> {
+        send_message(&mut &self.stream, ctx, command, dataset, self.peer_max_pdu_length)?;
+        let rsp = self.recv_command()?;
+        ensure!(
+            rsp.message_id_being_responded_to() == command.message_id(),
+            "response to message {:?}, expected {:?}",
+            rsp.message_id_being_responded_to(),
+            command.message_id()
+        );
+        rsp.status().context("response without a status")
+    }
+
+    fn recv_command(&mut self) -> Result<Command> {
+        let mut buf = Vec::new();
+        loop {
+            match pdu::read_pdu(&mut self.reader)? {
+                Pdu::PData(pdvs) => {
+                    for pdv in pdvs {
+                        ensure!(pdv.is_command, "unexpected dataset in a response");
+                        buf.extend(pdv.data);
+                        if pdv.is_last {
+                            return Command::decode(&buf);
+                        }
+                    }
+                }
+                Pdu::Abort { source, reason } => bail!("peer aborted (source {}, reason {})", source, reason),
+                other => bail!("unexpected PDU while awaiting a response: {:?}", other),
+            }
+        }
+    }
+}
+
+/// Whether a DIMSE status means the operation succeeded (warnings included).
+pub fn is_success(status: u16) -> bool {
+    status == dimse::STATUS_SUCCESS || status == 0x0001 || (status & 0xF000) == 0xB000
+}
@@ -0,0 +1,343 @@
+//! Push files to a remote Storage SCP in a given order, with retries.
+
+use super::scu::{is_success, Association, Proposal};
+use super::{read_file_meta, read_file_parts};
+use crate::sort::{self, PlanOptions};
+use crate::{archive, fs_ops};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// One association can carry at most 128 presentation contexts.
+const MAX_CONTEXTS: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    /// `host:port` of the SCP.
+    pub addr: String,
+    pub calling_ae: String,
+    pub called_ae: String,
+    /// Extra attempts per instance after the first failure.
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+/// Outcome of sending one file, as listed under `sent` in the report.
+#[derive(Debug, Clone, Serialize)]
+pub struct SendRecord {
+    pub path: PathBuf,
+    pub sop_instance_uid: Option<String>,
+    pub success: bool,
+    /// Last DIMSE status received, e.g. `0x0000`.
+    #[serde(serialize_with = "serialize_status")]
+    pub status: Option<u16>,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+fn serialize_status<S: serde::Serializer>(status: &Option<u16>, s: S) -> Result<S::Ok, S::Error> {
+    match status {
+        Some(v) => s.serialize_some(&format!("0x{:04X}", v)),
+        None => s.serialize_none(),
+    }
+}
+
+/// A file queued for sending, with what negotiation needs to know about it.
+struct Item<'a> {
+    /// Position in the caller's list, so records can be put back in that order.
+    index: usize,
+    path: &'a Path,
+    sop_class: String,
+    sop_instance: String,
+    transfer_syntax: String,
+}
+
+/// The files of a sorted tree, in sorted order: series by series, each ordered
+/// the way [`sort::plan_operations`] orders it, so file names do not matter.
+/// Hidden files and folders, such as the listener spool and renumbering
+/// temporaries, and `.part` files are skipped. Files that cannot be parsed
+/// come last, in path order, so they are still reported.
+pub fn sorted_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let (archives, files): (Vec<PathBuf>, Vec<PathBuf>) = fs_ops::collect_files(root, false)?
+        .into_iter()
+        .filter(|p| !is_temporary(root, p))
+        .partition(|p| archive::is_archive(p));
+    let scanned = sort::scan(&files);
+
+    let mut plans = sort::plan_operations(&scanned.metas, root, &PlanOptions::default());
+    // Series come out in hash order; a stable sort fixes it without touching the order within.
+    plans.sort_by_key(|p| sort::series_key(&p.meta));
+
+    let mut rest: Vec<PathBuf> = scanned.failures.into_iter().map(|f| f.path).chain(archives).collect();
+    rest.sort();
+    Ok(plans.into_iter().map(|p| p.src).chain(rest).collect())
+}
+
+/// True for files that are not finished instances: anything hidden below
+/// `root` and `.part` files.
+fn is_temporary(root: &Path, path: &Path) -> bool {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    rel.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+        || path.extension().is_some_and(|e| e == "part")
+}
+
+/// Send `paths` in order. Each file is offered in its own transfer syntax (no
+/// transcoding), one presentation context per SOP Class/transfer syntax pair;
+/// if there are more than 128 pairs, the list is split over several associations.
+pub fn send_files(paths: &[PathBuf], opts: &SendOptions) -> Vec<SendRecord> {
+    let mut records = Vec::with_capacity(paths.len());
+    let mut batch: Vec<Item> = Vec::new();
+    let mut pairs: BTreeSet<(String, String)> = BTreeSet::new();
+
+    for (index, path) in paths.iter().enumerate() {
+        let meta = match read_file_meta(path) {
+            Ok(m) => m,
+            Err(e) => {
+                records.push((index, failed(path, None, 0, format!("{:#}", e))));
+                continue;
+            }
+        };
+        let item = Item {
+            index,
+            path,
+            sop_class: super::trim_uid(&meta.media_storage_sop_class_uid).to_string(),
+            sop_instance: super::trim_uid(&meta.media_storage_sop_instance_uid).to_string(),
+            transfer_syntax: meta.transfer_syntax().to_string(),
+        };
+        let pair = (item.sop_class.clone(), item.transfer_syntax.clone());
+        if !pairs.contains(&pair) && pairs.len() == MAX_CONTEXTS {
+            records.extend(send_batch(&batch, &pairs, opts));
+            batch.clear();
+            pairs.clear();
+        }
+        pairs.insert(pair);
+        batch.push(item);
+    }
+    if !batch.is_empty() {
+        records.extend(send_batch(&batch, &pairs, opts));
+    }
+
+    // Keep the caller's order; unreadable files were recorded as they were seen.
+    records.sort_by_key(|(index, _)| *index);
+    records.into_iter().map(|(_, r)| r).collect()
+}
+
+fn send_batch(items: &[Item], pairs: &BTreeSet<(String, String)>, opts: &SendOptions) -> Vec<(usize, SendRecord)> {
+    let proposals: Vec<Proposal> = pairs
+        .iter()
+        .map(|(class, ts)| Proposal { abstract_syntax: class.clone(), transfer_syntaxes: vec![ts.clone()] })
+        .collect();
+    let connect = || Association::connect(opts.addr.as_str(), &opts.calling_ae, &opts.called_ae, &proposals);
+
+    let mut assoc: Option<Association> = None;
+    let mut records = Vec::with_capacity(items.len());
+
+    for item in items {
+        let mut attempts = 0;
+        let mut last_status = None;
+        let mut last_error = None;
+
+        while attempts <= opts.retries {
+            if attempts > 0 {
+                std::thread::sleep(opts.retry_delay);
+            }
+            attempts += 1;
+
+            let a = match assoc.as_mut() {
+                Some(a) => a,
+                None => match connect() {
+                    Ok(a) => assoc.insert(a),
+                    Err(e) => {
+                        last_error = Some(format!("{:#}", e));
+                        continue;
+                    }
+                },
+            };
+
+            let Some(ctx) = a.context_for(&item.sop_class, &item.transfer_syntax) else {
+                // The SCP will not take this class/syntax; retrying cannot help.
+                last_error = Some(format!(
+                    "presentation context not accepted: {} in {}",
+                    item.sop_class, item.transfer_syntax
+                ));
+                break;
+            };
+
+            let parts = match read_file_parts(item.path) {
+                Ok(p) => p,
+                Err(e) => {
+                    last_error = Some(format!("{:#}", e));
+                    break;
+                }
+            };
+
+            match a.store(ctx, &item.sop_class, &item.sop_instance, &parts.dataset) {
+                Ok(status) => {
+                    last_status = Some(status);
+                    if is_success(status) {
+                        last_error = None;
+                        break;
+                    }
+                    last_error = Some(format!("C-STORE failed with status 0x{:04X}", status));
+                }
+                Err(e) => {
+                    // The association is in an unknown state; start a fresh one.
+                    last_error = Some(format!("{:#}", e));
+                    if let Some(a) = assoc.take() {
+                        a.abort();
+                    }
+                }
+            }
+        }
+
+        let record = match last_error {
+            None => SendRecord {
+                path: item.path.to_path_buf(),
+                sop_instance_uid: Some(item.sop_instance.clone()),
+                success: true,
+                status: last_status,
+                attempts,
+                error: None,
+            },
+            Some(e) => {
+                tracing::warn!("Send {} failed after {} attempt(s): {}", item.path.display(), attempts, e);
+                let mut r = failed(item.path, Some(item.sop_instance.clone()), attempts, e);
+                r.status = last_status;
+                r
+            }
+        };
+        records.push((item.index, record));
+    }
+
+    if let Some(a) = assoc {
+        if let Err(e) = a.release() {
+            tracing::warn!("Release failed: {:#}", e);
+        }
+    }
+    records
+}
+
+fn failed(path: &Path, sop_instance_uid: Option<String>, attempts: u32, error: String) -> SendRecord {
+    SendRecord { path: path.to_path_buf(), sop_instance_uid, success: false, status: None, attempts, error: Some(error) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::dimse::{self, Command};
+    use crate::net::pdu::{self, AssociateParams, Pdu, PresentationContext};
+    use crate::synth::instance;
+    use dicom_core::VR;
+    use dicom_dictionary_std::tags;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    const CT: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+    fn write_ct(path: &Path, sop: &str, number: &str) {
+        let bytes = instance(CT, sop, &[(tags::SERIES_INSTANCE_UID, VR::UI, "1.9"), (tags::INSTANCE_NUMBER, VR::IS, number)]);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    /// Stand-in SCP: accepts everything, answers C-STORE with `statuses` in
+    /// turn (then success) and records the SOP Instance UIDs in arrival order.
+    fn stand_in(listener: TcpListener, mut statuses: Vec<u16>) -> Vec<String> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut w = &stream;
+        let Pdu::AssociateRq(rq) = pdu::read_pdu(&mut reader).unwrap() else { panic!() };
+        let contexts = rq
+            .contexts
+            .iter()
+            .map(|pc| PresentationContext { id: pc.id, abstract_syntax: String::new(), transfer_syntaxes: pc.transfer_syntaxes[..1].to_vec(), result: 0 })
+            .collect();
+        pdu::write_pdu(&mut w, &Pdu::AssociateAc(AssociateParams::new(&rq.calling_ae, &rq.called_ae, contexts, 0))).unwrap();
+
+        let mut received = Vec::new();
+        let mut command = Vec::new();
+        loop {
+            match pdu::read_pdu(&mut reader).unwrap() {
+                Pdu::PData(pdvs) => {
+                    for pdv in pdvs {
+                        if pdv.is_command {
+                            command.extend(pdv.data);
+                        } else if pdv.is_last {
+                            let rq = Command::decode(&std::mem::take(&mut command)).unwrap();
+                            let status = if statuses.is_empty() { dimse::STATUS_SUCCESS } else { statuses.remove(0) };
+                            if status == dimse::STATUS_SUCCESS {
+                                received.push(rq.affected_sop_instance_uid().unwrap());
+                            }
+                            crate::net::send_message(&mut w, pdv.context_id, &Command::response(&rq, status), None, 0).unwrap();
+                        }
+                    }
+                }
+                Pdu::ReleaseRq => {
+                    pdu::write_pdu(&mut w, &Pdu::ReleaseRp).unwrap();
+                    return received;
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_in_order_with_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["1.1", "1.2", "1.3"]
+            .iter()
+            .map(|sop| {
+                let p = dir.path().join(format!("{}.dcm", sop));
+                write_ct(&p, sop, "1");
+                p
+            })
+            .collect();
+        let mut all = paths.clone();
+        all.insert(1, dir.path().join("missing.dcm"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let opts = SendOptions {
+            addr: listener.local_addr().unwrap().to_string(),
+            calling_ae: "DCMSORT".into(),
+            called_ae: "PACS".into(),
+            retries: 1,
+            retry_delay: Duration::ZERO,
+        };
+
+        let (received, records) = std::thread::scope(|s| {
+            // First attempt at the second file is refused with "out of resources".
+            let scp = s.spawn(|| stand_in(listener, vec![dimse::STATUS_SUCCESS, dimse::STATUS_OUT_OF_RESOURCES]));
+            let records = send_files(&all, &opts);
+            (scp.join().unwrap(), records)
+        });
+
+        assert_eq!(received, ["1.1", "1.2", "1.3"]);
+        assert_eq!(records.len(), 4);
+        assert!(!records[1].success && records[1].path.ends_with("missing.dcm"));
+        assert!(records[2].success && records[2].attempts == 2);
+        assert!(records[3].success && records[3].attempts == 1);
+    }
+
+    #[test]
+    fn test_sorted_files_follow_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        // `{InstanceNumber}.dcm` names: path order would put 10 before 2.
+        for n in ["1", "2", "10"] {
+            write_ct(&root.join(format!("{}.dcm", n)), &format!("1.{}", n), n);
+        }
+        std::fs::create_dir(root.join(".incoming")).unwrap();
+        write_ct(&root.join(".incoming/x.1.1.part"), "1.90", "90");
+        write_ct(&root.join(".renumber-0-3.dcm"), "1.91", "91");
+        write_ct(&root.join("4.dcm.part"), "1.92", "92");
+        std::fs::write(root.join("junk.txt"), b"not dicom").unwrap();
+
+        let names: Vec<String> = sorted_files(root)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["1.dcm", "2.dcm", "10.dcm", "junk.txt"]);
+    }
+}
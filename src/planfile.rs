@@ -0,0 +1,256 @@
+//! Plans saved by `dcmsort plan` and carried out later by `dcmsort apply`.
+//!
+//! A [`PlanFile`] is plain JSON, so it can be reviewed or edited in between.
+//! It holds every [`Plan`] with the header it was built from, the settings
+//! needed to execute it, and a [`Fingerprint`] of each source file as it was
+//! when planned. [`PlanFile::changed_sources`] compares those fingerprints with
+//! the files on disk, so `apply` can refuse to run against changed input.
+
+use crate::archive;
+use crate::deid::DeidOptions;
+use crate::fs_ops::hash_file;
+use crate::sort::Plan;
+use crate::types::{Mode, OnConflict};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Format version written to [`PlanFile::version`].
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    pub output: Output,
+    pub mode: Mode,
+    /// Check cross-device move copies before deleting their source.
+    #[serde(default)]
+    pub verify_moves: bool,
+    pub on_conflict: OnConflict,
+    /// Write a DICOMDIR at the output root once the plans are carried out.
+    #[serde(default)]
+    pub dicomdir: bool,
+    /// Set when the plans were made from de-identified headers; files are
+    /// rewritten with the same mapping file when applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deid: Option<DeidSettings>,
+    /// Every file the plans read from (the archive, for archive members).
+    pub sources: BTreeMap<PathBuf, Fingerprint>,
+    pub plans: Vec<Plan>,
+}
+
+/// Where the planned destinations live.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    Dir(PathBuf),
+    /// Destinations are entry names relative to the archive root.
+    Archive(PathBuf),
+}
+
+impl Output {
+    /// The directory destinations are joined onto (empty for an archive).
+    pub fn root(&self) -> &Path {
+        match self {
+            Output::Dir(dir) => dir,
+            Output::Archive(_) => Path::new(""),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeidSettings {
+    pub map: PathBuf,
+    #[serde(flatten)]
+    pub options: DeidOptions,
+}
+
+/// What a source file looked like when it was planned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Hex SHA-256 of the content, if requested when planning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path, hash: bool) -> Result<Self> {
+        let md = fs::metadata(path).with_context(|| format!("stat {}", path.display()))?;
+        Ok(Self {
+            size: md.len(),
+            modified: md.modified().with_context(|| format!("mtime {}", path.display()))?,
+            sha256: hash.then(|| hash_file(path)).transpose()?,
+        })
+    }
+}
+
+/// How a source differs from its [`Fingerprint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Missing,
+    Size,
+    Modified,
+    Content,
+    /// A plan reads from a file that has no fingerprint (e.g. added by hand).
+    Unrecorded,
+}
+
+impl Change {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Change::Missing => "missing",
+            Change::Size => "size changed",
+            Change::Modified => "modification time changed",
+            Change::Content => "content changed",
+            Change::Unrecorded => "not in the plan's source list",
+        }
+    }
+}
+
+/// The file on disk that `p` reads from.
+fn source_file(p: &Plan) -> PathBuf {
+    archive::split_member(&p.src).map_or_else(|| p.src.clone(), |(a, _)| a)
+}
+
+/// Fingerprint every file `plans` read from, hashing the content when `hash` is set.
+pub fn fingerprint_sources(plans: &[Plan], hash: bool) -> Result<BTreeMap<PathBuf, Fingerprint>> {
+    let mut out = BTreeMap::new();
+    for p in plans {
+        if let Entry::Vacant(e) = out.entry(source_file(p)) {
+            let fp = Fingerprint::of(e.key(), hash)?;
+            e.insert(fp);
+        }
+    }
+    Ok(out)
+}
+
+impl PlanFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read plan: {}", path.display()))?;
+        let pf: Self = serde_json::from_str(&s).with_context(|| format!("parse plan: {}", path.display()))?;
+        if pf.version != VERSION {
+            bail!("plan {} has version {}; this dcmsort reads version {}", path.display(), pf.version, VERSION);
+        }
+        Ok(pf)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let s = serde_json::to_string_pretty(self).context("serialize plan")?;
+        fs::write(path, s).with_context(|| format!("write plan: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Sources that no longer match their fingerprint, in path order. Content is
+    /// only hashed when a hash was recorded and size and mtime still agree.
+    pub fn changed_sources(&self) -> Vec<(PathBuf, Change)> {
+        let mut changed = Vec::new();
+        for (path, fp) in &self.sources {
+            let change = match Fingerprint::of(path, false) {
+                Err(_) => Some(Change::Missing),
+                Ok(now) if now.size != fp.size => Some(Change::Size),
+                Ok(now) if now.modified != fp.modified => Some(Change::Modified),
+                Ok(_) => match &fp.sha256 {
+                    Some(h) if hash_file(path).ok().as_ref() != Some(h) => Some(Change::Content),
+                    _ => None,
+                },
+            };
+            changed.extend(change.map(|c| (path.clone(), c)));
+        }
+        let mut unrecorded: Vec<_> = self.plans.iter().map(source_file).filter(|f| !self.sources.contains_key(f)).collect();
+        unrecorded.sort();
+        unrecorded.dedup();
+        changed.extend(unrecorded.into_iter().map(|f| (f, Change::Unrecorded)));
+        changed
+    }
+
+    /// Fail unless every destination stays inside the output. A plan file may
+    /// have been edited by hand, so a `..`, an absolute path or (for an archive)
+    /// a drive prefix is refused before anything is written.
+    pub fn check_destinations(&self) -> Result<()> {
+        let root = self.output.root();
+        for p in &self.plans {
+            let rel = p.dst.strip_prefix(root).ok().filter(|rel| {
+                rel.components().next().is_some() && rel.components().all(|c| matches!(c, Component::Normal(_)))
+            });
+            if rel.is_none() {
+                bail!("{}: destination {} is outside the output {}", p.src.display(), p.dst.display(), root.display());
+            }
+            if let (Output::Archive(_), Some(why)) = (&self.output, archive::unsafe_name(&archive::entry_name(&p.dst))) {
+                bail!("{}: archive entry {} {}", p.src.display(), p.dst.display(), why);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom::DicomMeta;
+    use crate::types::SortBy;
+
+    #[test]
+    fn test_plan_file_round_trip_and_source_checks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a.dcm");
+        let b = tmp.path().join("b.dcm");
+        fs::write(&a, b"aaaa").unwrap();
+        fs::write(&b, b"bbbb").unwrap();
+
+        let meta = |p: &Path| DicomMeta { path: p.to_path_buf(), series_uid: Some("1.2".into()), instance_number: Some(1), ..Default::default() };
+        let mut plans = vec![Plan::new(a.clone(), "out/1.2/00001.dcm".into(), meta(&a)), Plan::new(b.clone(), "out/1.2/00002.dcm".into(), meta(&b))];
+        plans[1].sorted_by = SortBy::Geometry;
+        let pf = PlanFile {
+            version: VERSION,
+            output: Output::Dir("out".into()),
+            mode: Mode::Copy,
+            verify_moves: false,
+            on_conflict: OnConflict::SkipIdentical,
+            dicomdir: false,
+            deid: None,
+            sources: fingerprint_sources(&plans, true).unwrap(),
+            plans,
+        };
+        let path = tmp.path().join("plan.json");
+        pf.save(&path).unwrap();
+
+        let loaded = PlanFile::load(&path).unwrap();
+        assert_eq!(loaded.output, pf.output);
+        assert_eq!(loaded.sources, pf.sources);
+        assert_eq!(loaded.plans[1].dst, Path::new("out/1.2/00002.dcm"));
+        assert_eq!(loaded.plans[1].sorted_by, SortBy::Geometry);
+        assert_eq!(loaded.plans[0].meta.series_uid.as_deref(), Some("1.2"));
+        assert!(loaded.changed_sources().is_empty());
+
+        // Same size, same mtime, different bytes: only the hash notices.
+        let mtime = fs::metadata(&a).unwrap().modified().unwrap();
+        fs::write(&a, b"AAAA").unwrap();
+        fs::File::options().write(true).open(&a).unwrap().set_modified(mtime).unwrap();
+        fs::remove_file(&b).unwrap();
+        let mut edited = loaded;
+        edited.plans.push(Plan::new(tmp.path().join("c.dcm"), "out/c.dcm".into(), DicomMeta::default()));
+        assert_eq!(edited.changed_sources(), [(a, Change::Content), (b, Change::Missing), (tmp.path().join("c.dcm"), Change::Unrecorded)]);
+
+        // Hand-edited destinations must stay inside the output.
+        edited.check_destinations().unwrap();
+        edited.plans.push(Plan::new("x.dcm".into(), "out/1.2/../../escape.dcm".into(), DicomMeta::default()));
+        assert!(edited.check_destinations().is_err());
+        edited.plans.pop();
+        edited.output = Output::Archive("out.zip".into());
+        edited.check_destinations().unwrap();
+        edited.plans.push(Plan::new("x.dcm".into(), "/etc/00001.dcm".into(), DicomMeta::default()));
+        assert!(edited.check_destinations().is_err());
+        edited.plans.pop();
+        edited.plans.push(Plan::new("x.dcm".into(), "C:/1.2/00001.dcm".into(), DicomMeta::default()));
+        assert!(edited.check_destinations().is_err());
+
+        fs::write(&path, "{\"version\": 99}").unwrap();
+        assert!(PlanFile::load(&path).is_err());
+    }
+}
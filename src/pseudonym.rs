@@ -0,0 +1,168 @@
+//! Keyed pseudonymisation of the patient, study and series folder names.
+//!
+//! Each component is replaced by a truncated HMAC-SHA256 of its value under a
+//! secret key, so the same PatientID always yields the same folder across runs
+//! and machines that share the key, while the folder name itself reveals
+//! nothing without it. Authorised staff can re-identify through the optional
+//! lookup table, which is written separately from the output.
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use crate::fs_ops;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Minimum key length; anything shorter is trivially brute-forced over MRN space.
+const MIN_KEY_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Patient,
+    Study,
+    Series,
+}
+
+impl Level {
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Patient => "P_",
+            Level::Study => "ST_",
+            Level::Series => "SE_",
+        }
+    }
+
+    fn domain(self) -> &'static [u8] {
+        match self {
+            Level::Patient => b"patient",
+            Level::Study => b"study",
+            Level::Series => b"series",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LookupEntry {
+    level: Level,
+    pseudonym: String,
+    original: String,
+}
+
+pub struct Pseudonymizer {
+    key: Vec<u8>,
+    // Everything handed out so far, for the re-identification table.
+    seen: Mutex<BTreeMap<(Level, String), String>>,
+}
+
+// Never print the key.
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pseudonymizer").finish_non_exhaustive()
+    }
+}
+
+impl Pseudonymizer {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() < MIN_KEY_LEN {
+            bail!("pseudonymisation key must be at least {} bytes (got {})", MIN_KEY_LEN, key.len());
+        }
+        Ok(Self { key: key.to_vec(), seen: Mutex::new(BTreeMap::new()) })
+    }
+
+    /// Key from a file; surrounding whitespace (e.g. a trailing newline) is ignored.
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let raw = fs::read(path).with_context(|| format!("read pseudonymisation key: {}", path.display()))?;
+        Self::new(raw.trim_ascii())
+    }
+
+    /// Key from the named environment variable.
+    pub fn from_env(var: &str) -> Result<Self> {
+        let raw = std::env::var(var).with_context(|| format!("read pseudonymisation key from ${}", var))?;
+        Self::new(raw.trim().as_bytes())
+    }
+
+    /// Opaque, filesystem-safe replacement for `value`, e.g. `P_3F9A0C12D4E5B6A7`.
+    pub fn component(&self, level: Level, value: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(level.domain());
+        mac.update(&[0]);
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest[..8].iter().map(|b| format!("{:02X}", b)).collect();
+        let out = format!("{}{}", level.prefix(), hex);
+
+        self.seen.lock().unwrap().insert((level, value.to_string()), out.clone());
+        out
+    }
+
+    /// Add every pseudonym issued so far, with its original value, to the JSON
+    /// table at `path`. Entries of earlier runs are kept, so the table covers
+    /// every run that used it. The file is replaced in one rename and, on Unix,
+    /// created with mode 0600.
+    pub fn write_lookup(&self, path: &Path) -> Result<()> {
+        let mut table: BTreeMap<(Level, String), String> = BTreeMap::new();
+        if path.exists() {
+            let s = fs::read_to_string(path).with_context(|| format!("read pseudonym lookup: {}", path.display()))?;
+            let earlier: Vec<LookupEntry> =
+                serde_json::from_str(&s).with_context(|| format!("parse pseudonym lookup: {}", path.display()))?;
+            table.extend(earlier.into_iter().map(|e| ((e.level, e.original), e.pseudonym)));
+        }
+        table.extend(self.seen.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())));
+        let entries: Vec<LookupEntry> = table
+            .into_iter()
+            .map(|((level, original), pseudonym)| LookupEntry { level, pseudonym, original })
+            .collect();
+        let json = serde_json::to_string_pretty(&entries).context("serialize pseudonym lookup")?;
+        fs_ops::write_private(path, json.as_bytes()).with_context(|| format!("write pseudonym lookup: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_is_keyed_and_stable() {
+        let a = Pseudonymizer::new(b"0123456789abcdef").unwrap();
+        let b = Pseudonymizer::new(b"fedcba9876543210").unwrap();
+
+        let p = a.component(Level::Patient, "MRN0001");
+        assert_eq!(p, a.component(Level::Patient, "MRN0001"));
+        assert!(p.starts_with("P_") && p.len() == 18);
+        assert_ne!(p, b.component(Level::Patient, "MRN0001"));
+        // The level is part of the input, so a study UID equal to a PatientID differs.
+        assert_ne!(&p[2..], &a.component(Level::Study, "MRN0001")[3..]);
+    }
+
+    #[test]
+    fn test_short_key_rejected() {
+        assert!(Pseudonymizer::new(b"short").is_err());
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = Pseudonymizer::new(b"0123456789abcdef").unwrap();
+        let pseudo = p.component(Level::Patient, "MRN0001");
+        let path = dir.path().join("lookup.json");
+        p.write_lookup(&path).unwrap();
+
+        let v: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(v[0]["level"], "patient");
+        assert_eq!(v[0]["original"], "MRN0001");
+        assert_eq!(v[0]["pseudonym"], pseudo.as_str());
+
+        // A later run adds its patients to the table instead of replacing it.
+        let later = Pseudonymizer::new(b"0123456789abcdef").unwrap();
+        later.component(Level::Patient, "MRN0002");
+        later.write_lookup(&path).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let originals: Vec<_> = v.as_array().unwrap().iter().map(|e| e["original"].as_str().unwrap()).collect();
+        assert_eq!(originals, ["MRN0001", "MRN0002"]);
+    }
+}
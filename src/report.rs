@@ -1,9 +1,78 @@
+use crate::dicom::{DicomMeta, ScanFailure};
+use crate::dicomdir::read::MediaIssue;
+use crate::filter::Excluded;
+use crate::fs_ops::ConflictRecord;
+use crate::net::send::SendRecord;
+use crate::sort::Plan;
+use crate::split::VolumeAxis;
+use crate::types::SortBy;
+use crate::verify::Verification;
 use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
+/// Top-level layout of the `--report` JSON file.
+#[derive(Debug, Default, Serialize)]
+pub struct Report<'a> {
+    pub instances: &'a [DicomMeta],
+    pub series: &'a [SeriesSummary],
+    pub failures: &'a [ScanFailure],
+    pub conflicts: &'a [ConflictRecord],
+    /// Missing files and record/header mismatches when reading from a DICOMDIR.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub dicomdir: &'a [MediaIssue],
+    /// Instances left out by `--filter`, with the clause that excluded each.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub excluded: &'a [Excluded],
+    /// Per-instance C-STORE outcome when sending to a remote node.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub sent: &'a [SendRecord],
+    /// Result of comparing an output with its input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<&'a Verification>,
+}
+
 pub fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Result<()> {
     let s = serde_json::to_string_pretty(value).context("serialize json")?;
     fs::write(path, s).with_context(|| format!("write report: {}", path.display()))?;
     Ok(())
 }
+
+/// How one planned series (or sub-stack) was ordered and split.
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesSummary {
+    pub study_uid: Option<String>,
+    pub series_uid: Option<String>,
+    pub stack: Option<String>,
+    pub instances: usize,
+    pub sorted_by: SortBy,
+    /// Attribute used to split repeated slice positions, if any.
+    pub volume_axis: Option<VolumeAxis>,
+    pub volumes: usize,
+}
+
+/// (StudyInstanceUID, SeriesInstanceUID, stack label)
+type SeriesKey<'a> = (Option<&'a str>, Option<&'a str>, Option<&'a str>);
+
+pub fn summarize_series(plans: &[Plan]) -> Vec<SeriesSummary> {
+    let mut out: BTreeMap<SeriesKey, SeriesSummary> = BTreeMap::new();
+    for p in plans {
+        let key = (p.meta.study_uid.as_deref(), p.meta.series_uid.as_deref(), p.stack.as_deref());
+        let s = out.entry(key).or_insert_with(|| SeriesSummary {
+            study_uid: p.meta.study_uid.clone(),
+            series_uid: p.meta.series_uid.clone(),
+            stack: p.stack.clone(),
+            instances: 0,
+            sorted_by: p.sorted_by,
+            volume_axis: p.volume.map(|v| v.axis),
+            volumes: 1,
+        });
+        s.instances += 1;
+        if let Some(v) = p.volume {
+            s.volumes = s.volumes.max(v.index as usize);
+        }
+    }
+    out.into_values().collect()
+}
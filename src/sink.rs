@@ -0,0 +1,353 @@
+//! Where [`crate::fs_ops::execute`] stores instances.
+//!
+//! A [`Sink`] is addressed by the plan destinations. For each instance,
+//! `execute` resolves collisions with [`Sink::exists`] and [`Sink::holds`],
+//! then calls [`Sink::create_parent`] and either [`Sink::link`] (a file on
+//! disk) or [`Sink::put`] (a stream: an archive member or a de-identified
+//! rewrite). Whoever created the sink calls [`Sink::finish`] once nothing more
+//! will be written.
+//!
+//! The three `--mode`s are [`CopySink`], [`MoveSink`] and [`HardLinkSink`];
+//! [`crate::archive::ArchiveWriter`] is the archive output.
+
+use crate::dicom::read_meta;
+use crate::fs_ops::hash_file;
+use crate::types::Mode;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Why a [`Sink`] operation failed.
+#[derive(Debug)]
+pub enum SinkError {
+    /// The destination is taken and the sink cannot replace it.
+    Exists(PathBuf),
+    /// The sink does not support the operation, e.g. removing an archive entry.
+    Unsupported { sink: &'static str, op: &'static str },
+    /// An I/O error while doing `op` on `path`.
+    Io { op: &'static str, path: PathBuf, source: io::Error },
+    /// A copy made before deleting its source does not match the source; the source is kept.
+    Mismatch { src: PathBuf, dst: PathBuf },
+    /// Anything else, e.g. from a remote store.
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl SinkError {
+    pub fn io(op: &'static str, path: &Path, source: io::Error) -> Self {
+        SinkError::Io { op, path: path.to_path_buf(), source }
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Exists(path) => write!(f, "destination already exists: {}", path.display()),
+            SinkError::Unsupported { sink, op } => write!(f, "{} output cannot {}", sink, op),
+            SinkError::Io { op, path, .. } => write!(f, "{} {}", op, path.display()),
+            SinkError::Mismatch { src, dst } => write!(f, "copy {} does not match {}; source kept", dst.display(), src.display()),
+            SinkError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for SinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SinkError::Io { source, .. } => Some(source),
+            SinkError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+pub type SinkResult<T> = Result<T, SinkError>;
+
+/// The bytes of an incoming instance, as offered to [`Sink::holds`].
+#[derive(Debug, Clone, Copy)]
+pub enum Content<'a> {
+    /// A file on disk (the plan's source).
+    File(&'a Path),
+    /// An archive member or a de-identified rewrite.
+    Bytes(&'a [u8]),
+}
+
+impl Content<'_> {
+    pub fn len(&self) -> SinkResult<u64> {
+        match self {
+            Content::File(path) => fs::metadata(path).map(|m| m.len()).map_err(|e| SinkError::io("stat", path, e)),
+            Content::Bytes(b) => Ok(b.len() as u64),
+        }
+    }
+
+    pub fn is_empty(&self) -> SinkResult<bool> {
+        self.len().map(|n| n == 0)
+    }
+
+    /// Hex-encoded SHA-256.
+    pub fn sha256(&self) -> SinkResult<String> {
+        match self {
+            Content::File(path) => hash_file(path).map_err(|e| SinkError::Other(e.into())),
+            Content::Bytes(b) => Ok(Sha256::digest(b).iter().map(|b| format!("{:02x}", b)).collect()),
+        }
+    }
+}
+
+/// Storage for sorted instances, addressed by plan destination.
+///
+/// `execute` may call a sink from several threads at once (`--jobs`), but
+/// never for the same destination; see [`crate::fs_ops::execute`].
+pub trait Sink: Sync {
+    /// Make sure `dst` can be written, e.g. by creating its directory.
+    /// `execute` calls it once per folder, before anything is stored there.
+    fn create_parent(&self, _dst: &Path) -> SinkResult<()> {
+        Ok(())
+    }
+
+    /// Whether something is already stored at `dst`.
+    fn exists(&self, dst: &Path) -> SinkResult<bool>;
+
+    /// Whether the existing entry at `dst` is `content`, for `--on-conflict
+    /// skip-identical`. `sop_uid` is the incoming SOPInstanceUID.
+    fn holds(&self, dst: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool>;
+
+    /// Store `data` at `dst`.
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()>;
+
+    /// Store the file `src` at `dst`. Defaults to streaming it through
+    /// [`Self::put`]; on-disk sinks copy, rename or hard-link instead.
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        let mut f = fs::File::open(src).map_err(|e| SinkError::io("open", src, e))?;
+        self.put(dst, &mut f)
+    }
+
+    /// Delete the entry at `dst`, for `--on-conflict overwrite`.
+    fn remove(&self, _dst: &Path) -> SinkResult<()> {
+        Err(SinkError::Unsupported { sink: "this", op: "replace entries" })
+    }
+
+    /// Whether sources are used up, as with `--mode move`. `execute` then
+    /// deletes a source itself after [`Self::put`]ting a rewrite of it, and
+    /// through [`Self::drop_source`] when `dst` already holds it.
+    fn consumes_sources(&self) -> bool {
+        false
+    }
+
+    /// Delete the source `src` without storing it, because the entry at `kept`
+    /// already holds the same content (`--on-conflict skip-identical`).
+    fn drop_source(&self, src: &Path, _kept: &Path) -> SinkResult<()> {
+        fs::remove_file(src).map_err(|e| SinkError::io("remove (move)", src, e))
+    }
+
+    /// Complete the output. Nothing written is guaranteed to be readable before this returns.
+    fn finish(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+}
+
+/// The built-in sink for `--mode`.
+pub fn for_mode(mode: Mode) -> Box<dyn Sink> {
+    match mode {
+        Mode::Copy => Box::new(CopySink),
+        Mode::Move => Box::new(MoveSink::default()),
+        Mode::HardLink => Box::new(HardLinkSink),
+    }
+}
+
+/// Copies sources into the output tree (`--mode copy`).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CopySink;
+
+/// Renames sources into the output tree, copying across devices (`--mode move`).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MoveSink {
+    verify: bool,
+}
+
+impl MoveSink {
+    /// A move sink that hashes the source before a cross-device copy and the
+    /// copy after it, and only deletes the source if they match (`--verify-moves`).
+    pub fn verified() -> Self {
+        Self { verify: true }
+    }
+}
+
+/// Hard-links sources into the output tree, copying across devices (`--mode hard-link`).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HardLinkSink;
+
+impl Sink for CopySink {
+    fn create_parent(&self, dst: &Path) -> SinkResult<()> {
+        disk::create_parent(dst)
+    }
+    fn exists(&self, dst: &Path) -> SinkResult<bool> {
+        Ok(dst.exists())
+    }
+    fn holds(&self, dst: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool> {
+        disk::holds(dst, content, sop_uid)
+    }
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        disk::put(dst, data)
+    }
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        disk::copy(src, dst, "copy")
+    }
+    fn remove(&self, dst: &Path) -> SinkResult<()> {
+        disk::remove(dst)
+    }
+}
+
+impl Sink for MoveSink {
+    fn create_parent(&self, dst: &Path) -> SinkResult<()> {
+        disk::create_parent(dst)
+    }
+    fn exists(&self, dst: &Path) -> SinkResult<bool> {
+        Ok(dst.exists())
+    }
+    fn holds(&self, dst: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool> {
+        disk::holds(dst, content, sop_uid)
+    }
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        disk::put(dst, data)
+    }
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        if fs::rename(src, dst).is_err() {
+            // Cross-device rename fallback
+            match self.verify {
+                true => disk::copy_verified(src, dst, "copy (move fallback)")?,
+                false => disk::copy(src, dst, "copy (move fallback)")?,
+            }
+            fs::remove_file(src).map_err(|e| SinkError::io("remove (move fallback)", src, e))?;
+        }
+        Ok(())
+    }
+    fn remove(&self, dst: &Path) -> SinkResult<()> {
+        disk::remove(dst)
+    }
+    fn consumes_sources(&self) -> bool {
+        true
+    }
+}
+
+impl Sink for HardLinkSink {
+    fn create_parent(&self, dst: &Path) -> SinkResult<()> {
+        disk::create_parent(dst)
+    }
+    fn exists(&self, dst: &Path) -> SinkResult<bool> {
+        Ok(dst.exists())
+    }
+    fn holds(&self, dst: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool> {
+        disk::holds(dst, content, sop_uid)
+    }
+    fn put(&self, dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        disk::put(dst, data)
+    }
+    fn link(&self, src: &Path, dst: &Path) -> SinkResult<()> {
+        // Fallback to copy if hardlink not possible (different volume, permissions, etc.)
+        match fs::hard_link(src, dst) {
+            Ok(()) => Ok(()),
+            Err(_) => disk::copy(src, dst, "copy (hardlink fallback)"),
+        }
+    }
+    fn remove(&self, dst: &Path) -> SinkResult<()> {
+        disk::remove(dst)
+    }
+}
+
+/// What the on-disk sinks share.
+mod disk {
+    use super::*;
+
+    pub fn create_parent(dst: &Path) -> SinkResult<()> {
+        match dst.parent() {
+            Some(parent) => fs::create_dir_all(parent).map_err(|e| SinkError::io("create dir", parent, e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Size, then SOPInstanceUID read back from `existing`, then SHA-256 of both.
+    pub fn holds(existing: &Path, content: Content<'_>, sop_uid: Option<&str>) -> SinkResult<bool> {
+        let len = fs::metadata(existing).map_err(|e| SinkError::io("stat", existing, e))?.len();
+        if content.len()? != len {
+            return Ok(false);
+        }
+        if let (Some(sop), Ok(m)) = (sop_uid, read_meta(existing)) {
+            if m.sop_uid.as_deref() != Some(sop) {
+                return Ok(false);
+            }
+        }
+        Ok(content.sha256()? == Content::File(existing).sha256()?)
+    }
+
+    /// Stream into a hidden `.<name>.part` beside `dst`, then rename, so a
+    /// failed write never leaves a truncated file under the final name.
+    pub fn put(dst: &Path, data: &mut dyn Read) -> SinkResult<()> {
+        let name = dst.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let part = dst.with_file_name(format!(".{}.part", name));
+        let written = fs::File::create(&part)
+            .and_then(|mut f| io::copy(data, &mut f).and_then(|_| f.flush()))
+            .map_err(|e| SinkError::io("write", &part, e))
+            .and_then(|_| fs::rename(&part, dst).map_err(|e| SinkError::io("rename to", dst, e)));
+        if written.is_err() {
+            let _ = fs::remove_file(&part);
+        }
+        written
+    }
+
+    pub fn copy(src: &Path, dst: &Path, op: &'static str) -> SinkResult<()> {
+        fs::copy(src, dst).map(|_| ()).map_err(|e| SinkError::io(op, dst, e))
+    }
+
+    /// [`copy`], then compare SHA-256 of both ends; a bad copy is deleted.
+    pub fn copy_verified(src: &Path, dst: &Path, op: &'static str) -> SinkResult<()> {
+        let before = Content::File(src).sha256()?;
+        copy(src, dst, op)?;
+        if Content::File(dst).sha256()? != before {
+            let _ = fs::remove_file(dst);
+            return Err(SinkError::Mismatch { src: src.to_path_buf(), dst: dst.to_path_buf() });
+        }
+        Ok(())
+    }
+
+    pub fn remove(dst: &Path) -> SinkResult<()> {
+        fs::remove_file(dst).map_err(|e| SinkError::io("remove (overwrite)", dst, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_sinks_place_and_compare() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.dcm");
+        fs::write(&src, b"payload").unwrap();
+
+        for (i, sink) in [for_mode(Mode::Copy), for_mode(Mode::HardLink)].into_iter().enumerate() {
+            let dst = dir.path().join(format!("out{}/x/a.dcm", i));
+            assert!(!sink.exists(&dst).unwrap());
+            sink.create_parent(&dst).unwrap();
+            sink.link(&src, &dst).unwrap();
+            assert!(sink.holds(&dst, Content::File(&src), None).unwrap());
+            assert!(!sink.holds(&dst, Content::Bytes(b"payloaD"), None).unwrap());
+        }
+        assert!(src.exists());
+
+        let sink = MoveSink::default();
+        let dst = dir.path().join("moved/b.dcm");
+        sink.create_parent(&dst).unwrap();
+        sink.put(&dst, &mut &b"streamed"[..]).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"streamed");
+        assert_eq!(fs::read_dir(dst.parent().unwrap()).unwrap().count(), 1);
+        sink.link(&src, &dst).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read(&dst).unwrap(), b"payload");
+
+        let copied = dir.path().join("verified.dcm");
+        disk::copy_verified(&dst, &copied, "copy").unwrap();
+        assert_eq!(fs::read(&copied).unwrap(), b"payload");
+    }
+}
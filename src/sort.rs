@@ -1,76 +1,218 @@
-use crate::types::{Layout, SortBy};
-use crate::dicom::{read_meta, DicomMeta};
+use crate::archive;
+use crate::types::{Layout, SortBy, VolumeLayout};
+use crate::dicom::{DicomMeta, ScanFailure};
+use crate::dicomdir::media_name;
 use crate::sanitize::sanitize_component;
+use crate::source::{FileSource, MetaSource};
+use crate::split::{split_series, split_volumes, SplitOptions, VolumeAxis, Volumes};
+use crate::pseudonym::{Level, Pseudonymizer};
+use crate::template::{PathTemplate, Placement};
+use serde::{Deserialize, Serialize};
+use dicom_object::Tag;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plan {
     pub src: PathBuf,
     pub dst: PathBuf,
     pub meta: DicomMeta,
+    /// Sub-stack label when the series was split (see [`crate::split`]).
+    pub stack: Option<String>,
+    /// Ordering actually used for this stack (never `Auto`).
+    pub sorted_by: SortBy,
+    /// Set when repeated slice positions were split into volumes.
+    pub volume: Option<VolumeInfo>,
 }
 
-pub fn scan(paths: &[PathBuf]) -> Vec<DicomMeta> {
+impl Plan {
+    /// A plan for a single unsplit instance; planner-specific fields get their defaults.
+    pub fn new(src: PathBuf, dst: PathBuf, meta: DicomMeta) -> Self {
+        Self { src, dst, meta, stack: None, sorted_by: SortBy::Instance, volume: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeInfo {
+    pub axis: VolumeAxis,
+    /// 1-based volume number.
+    pub index: u32,
+}
+
+/// How `plan_operations` names and orders its output.
+#[derive(Debug, Clone, Copy)]
+pub struct PlanOptions<'a> {
+    pub layout: Layout,
+    /// Takes precedence over `layout` when set.
+    pub template: Option<&'a PathTemplate>,
+    pub sort_by: SortBy,
+    pub include_phi: bool,
+    /// Split heterogeneous series into sub-stacks; `None` keeps one folder per series.
+    pub split: Option<SplitOptions>,
+    /// Detect repeated slice positions and lay out volumes separately.
+    pub volumes: VolumeLayout,
+    /// Replace the patient/study/series folders of the built-in layouts with keyed pseudonyms.
+    pub pseudonymize: Option<&'a Pseudonymizer>,
+}
+
+impl Default for PlanOptions<'_> {
+    fn default() -> Self {
+        Self {
+            layout: Layout::PatientStudySeries,
+            template: None,
+            sort_by: SortBy::Auto,
+            include_phi: false,
+            split: None,
+            volumes: VolumeLayout::None,
+            pseudonymize: None,
+        }
+    }
+}
+
+/// Result of scanning a set of paths: parsed headers plus every rejected file.
+#[derive(Debug, Default)]
+pub struct ScanOutcome {
+    pub metas: Vec<DicomMeta>,
+    pub failures: Vec<ScanFailure>,
+}
+
+pub fn scan(paths: &[PathBuf]) -> ScanOutcome {
+    scan_with_tags(paths, &[])
+}
+
+/// Like [`scan`], but also captures the given raw tags into `DicomMeta::extra`.
+/// Zip and tar archives among `paths` are scanned member by member (see [`crate::archive`]).
+pub fn scan_with_tags(paths: &[PathBuf], extra: &[Tag]) -> ScanOutcome {
+    scan_files(&FileSource, paths, extra)
+}
+
+/// Like [`scan_with_tags`], reading plain files through `source` (e.g. a [`crate::cache::ScanCache`]).
+pub fn scan_files(source: &dyn MetaSource, paths: &[PathBuf], extra: &[Tag]) -> ScanOutcome {
+    let (archives, paths): (Vec<PathBuf>, Vec<PathBuf>) = paths.iter().cloned().partition(|p| archive::is_archive(p));
+    let mut outcome = scan_source(source, &paths, extra);
+    // Archive members can only be read in order, one archive at a time.
+    for a in archives {
+        let o = archive::scan(&a, extra);
+        outcome.metas.extend(o.metas);
+        outcome.failures.extend(o.failures);
+    }
+    outcome
+}
+
+/// Read every path through `source`, capturing `extra` tags; see [`crate::source`].
+pub fn scan_source(source: &dyn MetaSource, paths: &[PathBuf], extra: &[Tag]) -> ScanOutcome {
     #[cfg(feature = "parallel")]
-    {
+    let results: Vec<_> = {
         use rayon::prelude::*;
-        paths.par_iter().filter_map(|p| read_meta(p).ok()).collect()
-    }
+        paths.par_iter().map(|p| (p, source.read(p, extra))).collect()
+    };
     #[cfg(not(feature = "parallel"))]
-    {
-        paths.iter().filter_map(|p| read_meta(p).ok()).collect()
+    let results: Vec<_> = paths.iter().map(|p| (p, source.read(p, extra))).collect();
+
+    let mut outcome = ScanOutcome::default();
+    for (p, r) in results {
+        match r {
+            Ok(m) => outcome.metas.push(m),
+            Err(e) => outcome.failures.push(source.failure(p, e)),
+        }
     }
+    outcome
 }
 
 pub fn plan_operations(
     metas: &[DicomMeta],
     out_dir: &Path,
-    layout: Layout,
-    sort_by: SortBy,
-    include_phi: bool,
+    opts: &PlanOptions,
 ) -> Vec<Plan> {
     // Group by (StudyUID, SeriesUID) with fallbacks
     let mut groups: HashMap<(String, String), Vec<&DicomMeta>> = HashMap::new();
     for m in metas {
-        let study = m.study_uid.clone().unwrap_or_else(|| "UNKNOWN_STUDY".into());
-        let series = m.series_uid.clone().unwrap_or_else(|| "UNKNOWN_SERIES".into());
-        groups.entry((study, series)).or_default().push(m);
+        groups.entry(series_key(m)).or_default().push(m);
     }
 
     let mut plans = Vec::new();
 
-    for ((_study, _series), mut items) in groups {
-        let use_geom = match sort_by {
-            SortBy::Geometry => true,
-            SortBy::Instance => false,
-            SortBy::Auto => items.iter().all(|m| m.geom_order().is_some()),
+    for ((_study, _series), items) in groups {
+        let stacks = match &opts.split {
+            Some(split) => split_series(items, split),
+            None => vec![crate::split::Stack { label: None, items }],
         };
 
-        items.sort_by(|a, b| compare(a, b, use_geom));
+        for stack in stacks {
+            let label = stack.label.as_deref();
+            let items = stack.items;
+
+            let use_geom = match opts.sort_by {
+                SortBy::Geometry => true,
+                SortBy::Instance => false,
+                SortBy::Auto => items.iter().all(|m| m.geom_order().is_some()),
+            };
+            let sorted_by = if use_geom { SortBy::Geometry } else { SortBy::Instance };
+
+            let detected = match opts.volumes {
+                VolumeLayout::None => Volumes::Single,
+                _ => split_volumes(&items),
+            };
+            let (axis, volumes) = match detected {
+                Volumes::Split { axis, volumes } => (Some(axis), volumes),
+                Volumes::Unresolved { max_repeats } => {
+                    tracing::warn!(
+                        "Series {} has up to {} images per slice position but no attribute separates them; keeping one volume",
+                        items[0].series_uid.as_deref().unwrap_or("UNKNOWN_SERIES"),
+                        max_repeats,
+                    );
+                    (None, vec![items])
+                }
+                Volumes::Single => (None, vec![items]),
+            };
+
+            for (vi, mut items) in volumes.into_iter().enumerate() {
+                items.sort_by(|a, b| compare(a, b, use_geom));
+                let volume = axis.map(|axis| VolumeInfo { axis, index: vi as u32 + 1 });
 
-        for (idx, m) in items.into_iter().enumerate() {
-            let dst = build_dst(out_dir, layout, include_phi, m, idx as u32);
-            plans.push(Plan {
-                src: m.path.clone(),
-                dst,
-                meta: m.clone(),
-            });
+                for (idx, m) in items.into_iter().enumerate() {
+                    let at = Placement { index: idx as u32, stack: label, volume: volume.map(|v| v.index) };
+                    let mut dst = match opts.template {
+                        Some(t) => t.render(out_dir, m, &at),
+                        None => build_dst(out_dir, opts, m, &at),
+                    };
+                    if let Some(v) = volume {
+                        if opts.template.is_none() && matches!(opts.layout, Layout::Media) {
+                            // A prefix would break the 8-character limit, so media volumes always get folders.
+                            dst = place_volume(&dst, v.index, VolumeLayout::Folders, true);
+                        } else if !opts.template.is_some_and(|t| t.places_volume()) {
+                            dst = place_volume(&dst, v.index, opts.volumes, false);
+                        }
+                    }
+                    plans.push(Plan {
+                        src: m.path.clone(),
+                        dst,
+                        meta: m.clone(),
+                        stack: stack.label.clone(),
+                        sorted_by,
+                        volume,
+                    });
+                }
+            }
         }
     }
 
     plans
 }
 
+/// The (StudyInstanceUID, SeriesInstanceUID) a meta is grouped by, with fallbacks.
+pub(crate) fn series_key(m: &DicomMeta) -> (String, String) {
+    let study = m.study_uid.clone().unwrap_or_else(|| "UNKNOWN_STUDY".into());
+    let series = m.series_uid.clone().unwrap_or_else(|| "UNKNOWN_SERIES".into());
+    (study, series)
+}
+
 fn compare(a: &DicomMeta, b: &DicomMeta, use_geom: bool) -> Ordering {
     if use_geom {
-        match (a.geom_order(), b.geom_order()) {
-            (Some(x), Some(y)) => {
-                let ord = x.partial_cmp(&y).unwrap_or(Ordering::Equal);
-                if ord != Ordering::Equal { return ord; }
-            }
-            _ => {}
+        if let (Some(x), Some(y)) = (a.geom_order(), b.geom_order()) {
+            let ord = x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+            if ord != Ordering::Equal { return ord; }
         }
     } else {
         match (a.instance_number, b.instance_number) {
@@ -88,18 +230,52 @@ fn compare(a: &DicomMeta, b: &DicomMeta, use_geom: bool) -> Ordering {
     a.stable_id().cmp(&b.stable_id())
 }
 
-fn build_dst(
-    out_dir: &Path,
-    layout: Layout,
-    include_phi: bool,
-    m: &DicomMeta,
-    order_index: u32,
-) -> PathBuf {
-    let patient_id = m.patient_id.clone().unwrap_or_else(|| "UNKNOWN_PATIENT".into());
-    let study_uid = m.study_uid.clone().unwrap_or_else(|| "UNKNOWN_STUDY".into());
-    let series_uid = m.series_uid.clone().unwrap_or_else(|| "UNKNOWN_SERIES".into());
+/// Plan one instance without seeing the rest of its series, as when receiving
+/// over the network. The final geometric order is unknown, so the index comes
+/// from InstanceNumber; splitting and volume detection need the whole series
+/// and are not applied. Media names carry nothing but the index, so an
+/// instance without a usable InstanceNumber gets a name hashed from its
+/// SOPInstanceUID instead of `I0000001`.
+pub fn plan_instance(m: &DicomMeta, out_dir: &Path, opts: &PlanOptions) -> Plan {
+    let index = m.instance_number.and_then(|n| u32::try_from(n.saturating_sub(1)).ok());
+    let at = Placement { index: index.unwrap_or(0), stack: None, volume: None };
+    let mut dst = match opts.template {
+        Some(t) => t.render(out_dir, m, &at),
+        None => build_dst(out_dir, opts, m, &at),
+    };
+    if let (None, None, Layout::Media) = (index, opts.template, opts.layout) {
+        dst.set_file_name(media_name('I', &m.stable_id()));
+    }
+    Plan::new(m.path.clone(), dst, m.clone())
+}
+
+fn build_dst(out_dir: &Path, opts: &PlanOptions, m: &DicomMeta, at: &Placement) -> PathBuf {
+    let mut patient_id = m.patient_id.clone().unwrap_or_else(|| "UNKNOWN_PATIENT".into());
+    let mut study_uid = m.study_uid.clone().unwrap_or_else(|| "UNKNOWN_STUDY".into());
+    let mut series_uid = m.series_uid.clone().unwrap_or_else(|| "UNKNOWN_SERIES".into());
     let sop_uid = m.sop_uid.clone().unwrap_or_else(|| "UNKNOWN_SOP".into());
 
+    // Pseudonyms are derived from the fallbacks too, so missing values still group together.
+    if let Some(p) = opts.pseudonymize {
+        patient_id = p.component(Level::Patient, &patient_id);
+        study_uid = p.component(Level::Study, &study_uid);
+        series_uid = p.component(Level::Series, &series_uid);
+    }
+    if let Layout::Media = opts.layout {
+        // Hashed 8-character names: identifiers never reach the media, and sub-stacks
+        // still get a folder of their own.
+        let series_key = match at.stack {
+            Some(label) => format!("{}/{}", series_uid, label),
+            None => series_uid,
+        };
+        return out_dir
+            .join(media_name('P', &patient_id))
+            .join(media_name('S', &study_uid))
+            .join(media_name('E', &series_key))
+            .join(format!("I{:07}", at.index + 1));
+    }
+    let include_phi = opts.include_phi && opts.pseudonymize.is_none();
+
     let patient = if include_phi {
         let name = m.patient_name.clone().unwrap_or_default();
         sanitize_component(&format!("{}_{}", patient_id, name))
@@ -119,17 +295,137 @@ fn build_dst(
         let modl = m.modality.clone().unwrap_or_default();
         let sn = m.series_number.map(|x| x.to_string()).unwrap_or_default();
         let desc = m.series_description.clone().unwrap_or_default();
-        sanitize_component(&format!("{}_{}_{}_{}", modl, sn, desc, series_uid))
+        format!("{}_{}_{}_{}", modl, sn, desc, series_uid)
     } else {
-        sanitize_component(&series_uid)
+        series_uid
+    };
+    // Sub-stacks get their own folder; Flat needs nothing since SOP UIDs keep names unique.
+    let series = match at.stack {
+        Some(label) => with_suffix(&sanitize_component(&series), label),
+        None => sanitize_component(&series),
     };
 
-    let file_name = format!("{:05}_{}.dcm", order_index + 1, sanitize_component(&sop_uid));
+    let file_name = format!("{:05}_{}.dcm", at.index + 1, sanitize_component(&sop_uid));
 
-    match layout {
+    match opts.layout {
         Layout::PatientStudySeries => out_dir.join(patient).join(study).join(series).join(file_name),
         Layout::StudySeries => out_dir.join(study).join(series).join(file_name),
         Layout::SeriesOnly => out_dir.join(series).join(file_name),
         Layout::Flat => out_dir.join(file_name),
+        Layout::Media => unreachable!("handled above"),
+    }
+}
+
+/// Put a volume into its own `volNNNN` folder (`VNNNNNNN` on media) or prefix
+/// the file name with `vNNNN_`.
+fn place_volume(dst: &Path, volume: u32, layout: VolumeLayout, media: bool) -> PathBuf {
+    let parent = dst.parent().unwrap_or_else(|| Path::new(""));
+    let name = dst.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    match layout {
+        VolumeLayout::None => dst.to_path_buf(),
+        VolumeLayout::Folders if media => parent.join(format!("V{:07}", volume)).join(name),
+        VolumeLayout::Folders => parent.join(format!("vol{:04}", volume)).join(name),
+        VolumeLayout::Filenames => parent.join(format!("v{:04}_{}", volume, name)),
+    }
+}
+
+/// Append `_suffix` to an already sanitized component without letting the
+/// 80-character limit cut the suffix off.
+pub(crate) fn with_suffix(base: &str, suffix: &str) -> String {
+    let suffix = sanitize_component(suffix);
+    let keep = 80usize.saturating_sub(suffix.len() + 1);
+    format!("{}_{}", base.chars().take(keep).collect::<String>(), suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn img(n: i32, iop: [f64; 6], pos: f64) -> DicomMeta {
+        DicomMeta {
+            path: PathBuf::from(format!("in/{}", n)),
+            patient_id: Some("P".into()),
+            study_uid: Some("1.1".into()),
+            series_uid: Some("1.1.1".into()),
+            sop_uid: Some(format!("1.1.1.{}", n)),
+            instance_number: Some(n),
+            image_orientation_patient: Some(iop),
+            image_position_patient: Some([pos, pos, pos]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_series_gets_folders_and_own_ordering() {
+        let ax = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let sag = [0.0, 1.0, 0.0, 0.0, 0.0, -1.0];
+        let metas = vec![img(1, ax, 5.0), img(2, sag, 3.0), img(3, ax, 1.0), img(4, sag, 9.0)];
+
+        let opts = PlanOptions { layout: Layout::SeriesOnly, split: Some(SplitOptions::default()), ..Default::default() };
+        let mut plans = plan_operations(&metas, Path::new("out"), &opts);
+        plans.sort_by(|a, b| a.dst.cmp(&b.dst));
+
+        let dsts: Vec<_> = plans.iter().map(|p| p.dst.to_string_lossy().into_owned()).collect();
+        assert_eq!(dsts, [
+            "out/1.1.1_s01_AX/00001_1.1.1.3.dcm",
+            "out/1.1.1_s01_AX/00002_1.1.1.1.dcm",
+            "out/1.1.1_s02_SAG/00001_1.1.1.4.dcm",
+            "out/1.1.1_s02_SAG/00002_1.1.1.2.dcm",
+        ]);
+
+        let unsplit = plan_operations(&metas, Path::new("out"), &PlanOptions { layout: Layout::SeriesOnly, ..Default::default() });
+        assert!(unsplit.iter().all(|p| p.stack.is_none() && p.dst.starts_with("out/1.1.1")));
+    }
+
+    #[test]
+    fn test_pseudonymized_layout_hides_ids() {
+        let ax = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let metas = vec![img(1, ax, 1.0), img(2, ax, 2.0)];
+        let p = Pseudonymizer::new(b"0123456789abcdef").unwrap();
+        let opts = PlanOptions { pseudonymize: Some(&p), include_phi: true, ..Default::default() };
+        let plans = plan_operations(&metas, Path::new("out"), &opts);
+
+        let expected = Path::new("out")
+            .join(p.component(Level::Patient, "P"))
+            .join(p.component(Level::Study, "1.1"))
+            .join(p.component(Level::Series, "1.1.1"));
+        assert!(plans.iter().all(|pl| pl.dst.parent() == Some(expected.as_path())));
+    }
+
+    #[test]
+    fn test_volume_folders_and_filenames() {
+        let ax = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let metas: Vec<_> = (0..4)
+            .map(|i| DicomMeta { temporal_position_identifier: Some(1 + i / 2), ..img(i + 1, ax, (i % 2) as f64) })
+            .collect();
+
+        let opts = PlanOptions { layout: Layout::SeriesOnly, volumes: VolumeLayout::Folders, ..Default::default() };
+        let mut dsts: Vec<_> = plan_operations(&metas, Path::new("out"), &opts).into_iter().map(|p| p.dst).collect();
+        dsts.sort();
+        assert_eq!(dsts, [
+            PathBuf::from("out/1.1.1/vol0001/00001_1.1.1.1.dcm"),
+            PathBuf::from("out/1.1.1/vol0001/00002_1.1.1.2.dcm"),
+            PathBuf::from("out/1.1.1/vol0002/00001_1.1.1.3.dcm"),
+            PathBuf::from("out/1.1.1/vol0002/00002_1.1.1.4.dcm"),
+        ]);
+
+        let opts = PlanOptions { volumes: VolumeLayout::Filenames, ..opts };
+        let plans = plan_operations(&metas, Path::new("out"), &opts);
+        assert!(plans.iter().any(|p| p.dst == Path::new("out/1.1.1/v0002_00002_1.1.1.4.dcm")));
+        assert!(plans.iter().all(|p| p.volume.unwrap().axis == VolumeAxis::TemporalPositionIdentifier));
+    }
+
+    #[test]
+    fn test_received_media_names_without_instance_number() {
+        let ax = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let opts = PlanOptions { layout: Layout::Media, ..Default::default() };
+        let numbered = plan_instance(&img(3, ax, 0.0), Path::new("out"), &opts);
+        assert!(numbered.dst.ends_with("I0000003"));
+
+        let a = plan_instance(&DicomMeta { instance_number: None, ..img(1, ax, 0.0) }, Path::new("out"), &opts);
+        let b = plan_instance(&DicomMeta { instance_number: None, ..img(2, ax, 0.0) }, Path::new("out"), &opts);
+        assert_ne!(a.dst, b.dst);
+        assert!(!a.dst.ends_with("I0000001"));
+        crate::dicomdir::file_id(Path::new("out"), &a.dst).unwrap();
     }
 }
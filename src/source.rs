@@ -0,0 +1,155 @@
+//! Where [`crate::sort::scan_source`] gets header metadata from.
+//!
+//! [`FileSource`] reads DICOM files from disk and is what the CLI uses.
+//! [`MemorySource`] serves encoded DICOM bytes or hand-built [`DicomMeta`]
+//! records by path, so scanning, planning, layouts and splitting can be
+//! exercised without touching the filesystem.
+
+use crate::dicom::{classify_error, has_dicom_prefix, read_meta_from_reader, read_meta_with_tags, DicomMeta, FailureKind, ScanFailure};
+use anyhow::{Context, Result};
+use dicom_object::Tag;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Produces the [`DicomMeta`] for a path.
+pub trait MetaSource: Sync {
+    /// Read the header behind `path`, capturing the string value of each tag in `extra`.
+    fn read(&self, path: &Path, extra: &[Tag]) -> Result<DicomMeta>;
+
+    /// Turn a failed [`Self::read`] into a report entry. Defaults to
+    /// classifying by the error chain alone.
+    fn failure(&self, path: &Path, error: anyhow::Error) -> ScanFailure {
+        ScanFailure { path: path.to_path_buf(), kind: classify_error(&error), error }
+    }
+}
+
+/// DICOM files on disk, read header-only.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSource;
+
+impl MetaSource for FileSource {
+    fn read(&self, path: &Path, extra: &[Tag]) -> Result<DicomMeta> {
+        read_meta_with_tags(path, extra)
+    }
+
+    fn failure(&self, path: &Path, error: anyhow::Error) -> ScanFailure {
+        ScanFailure::new(path, error)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Bytes(Vec<u8>),
+    Meta(Box<DicomMeta>),
+}
+
+/// Paths mapped to encoded DICOM files or ready-made records, all in memory.
+#[derive(Debug, Default, Clone)]
+pub struct MemorySource {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A source holding `metas`, each under its own `path`.
+    pub fn from_metas(metas: impl IntoIterator<Item = DicomMeta>) -> Self {
+        let mut s = Self::new();
+        for m in metas {
+            s.insert_meta(m);
+        }
+        s
+    }
+
+    /// Serve `bytes` (a Part 10 file) at `path`; it is parsed on every read.
+    pub fn insert_bytes(&mut self, path: impl Into<PathBuf>, bytes: Vec<u8>) -> &mut Self {
+        self.entries.insert(path.into(), Entry::Bytes(bytes));
+        self
+    }
+
+    /// Serve `meta` at `meta.path` as is.
+    pub fn insert_meta(&mut self, meta: DicomMeta) -> &mut Self {
+        self.entries.insert(meta.path.clone(), Entry::Meta(Box::new(meta)));
+        self
+    }
+
+    /// Every path held, in order.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.entries.keys().cloned().collect()
+    }
+}
+
+impl MetaSource for MemorySource {
+    fn read(&self, path: &Path, extra: &[Tag]) -> Result<DicomMeta> {
+        match self.entries.get(path) {
+            Some(Entry::Bytes(bytes)) => read_meta_from_reader(path, bytes.as_slice(), extra),
+            Some(Entry::Meta(m)) => {
+                // Like a file, only the requested raw tags are reported.
+                let mut m = DicomMeta::clone(m);
+                m.extra.retain(|k, _| extra.iter().any(|t| t.to_string() == *k));
+                Ok(m)
+            }
+            None => Err(io::Error::from(io::ErrorKind::NotFound)).with_context(|| format!("open {}", path.display())),
+        }
+    }
+
+    /// Like [`ScanFailure::new`]: bytes that do not start like DICOM are `not_dicom`.
+    fn failure(&self, path: &Path, error: anyhow::Error) -> ScanFailure {
+        let mut kind = classify_error(&error);
+        if let Some(Entry::Bytes(bytes)) = self.entries.get(path) {
+            if matches!(kind, FailureKind::Truncated | FailureKind::Malformed) && !has_dicom_prefix(bytes) {
+                kind = FailureKind::NotDicom;
+            }
+        }
+        ScanFailure { path: path.to_path_buf(), kind, error }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sort::{plan_operations, scan_source, PlanOptions};
+    use crate::types::Layout;
+    use crate::synth::{instance, CT_IMAGE_STORAGE};
+    use dicom_core::VR;
+    use dicom_dictionary_std::tags;
+
+    fn part10(sop: &str) -> Vec<u8> {
+        instance(CT_IMAGE_STORAGE, sop, &[(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3"), (tags::MODALITY, VR::CS, "CT")])
+    }
+
+    #[test]
+    fn test_memory_source_scans_without_io() {
+        let mut src = MemorySource::from_metas([DicomMeta {
+            path: "mem/synthetic".into(),
+            series_uid: Some("1.2.3".into()),
+            instance_number: Some(2),
+            extra: [("(0008,0060)".to_string(), "MR".to_string()), ("(0010,1010)".to_string(), "042Y".to_string())].into(),
+            ..Default::default()
+        }]);
+        src.insert_bytes("mem/encoded", part10("1.2.3.1"));
+        src.insert_bytes("mem/junk", b"not dicom".to_vec());
+
+        let mut paths = src.paths();
+        paths.push("mem/absent".into());
+        let outcome = scan_source(&src, &paths, &[tags::MODALITY]);
+
+        assert_eq!(outcome.metas.len(), 2);
+        let encoded = outcome.metas.iter().find(|m| m.path == Path::new("mem/encoded")).unwrap();
+        assert_eq!(encoded.sop_uid.as_deref(), Some("1.2.3.1"));
+        assert_eq!(encoded.extra_value(tags::MODALITY), Some("CT"));
+        let synthetic = outcome.metas.iter().find(|m| m.path == Path::new("mem/synthetic")).unwrap();
+        assert_eq!(synthetic.extra.len(), 1);
+
+        let kinds: Vec<_> = outcome.failures.iter().map(|f| (f.path.to_string_lossy().into_owned(), f.kind)).collect();
+        assert!(kinds.contains(&("mem/junk".into(), FailureKind::NotDicom)));
+        assert!(kinds.contains(&("mem/absent".into(), FailureKind::Malformed)));
+
+        let opts = PlanOptions { layout: Layout::SeriesOnly, ..Default::default() };
+        let plans = plan_operations(&outcome.metas, Path::new("out"), &opts);
+        assert!(plans.iter().all(|p| p.dst.starts_with("out/1.2.3")));
+    }
+}
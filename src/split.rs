@@ -0,0 +1,347 @@
+//! Splitting one SeriesInstanceUID into homogeneous sub-stacks.
+//!
+//! Localizers and 3-plane series often mix axial, sagittal and coronal slices
+//! (or different matrix sizes / frames of reference) under a single series UID.
+//! Sorting those by `geom_order` produces a meaningless interleaving, so each
+//! series is first partitioned by FrameOfReferenceUID, Rows x Columns and
+//! ImageOrientationPatient (within a tolerance).
+//!
+//! Within a stack, repeated slice positions (dynamics, fMRI, perfusion,
+//! multi-echo) are further split into volumes by [`split_volumes`].
+
+use crate::dicom::DicomMeta;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SplitOptions {
+    /// Maximum absolute difference per ImageOrientationPatient component
+    /// for two images to count as the same orientation.
+    pub orientation_tolerance: f64,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        Self { orientation_tolerance: 1e-3 }
+    }
+}
+
+/// One homogeneous group of images from a series.
+#[derive(Debug)]
+pub struct Stack<'a> {
+    /// Folder suffix such as `s01_AX`; `None` when the series was not split.
+    pub label: Option<String>,
+    pub items: Vec<&'a DicomMeta>,
+}
+
+/// Partition a series into stacks. A homogeneous series yields a single
+/// unlabeled stack, so callers can treat the unsplit case uniformly.
+pub fn split_series<'a>(items: Vec<&'a DicomMeta>, opts: &SplitOptions) -> Vec<Stack<'a>> {
+    struct Group<'a> {
+        frame_of_reference: Option<&'a str>,
+        dims: (Option<u32>, Option<u32>),
+        orientation: Option<[f64; 6]>,
+        items: Vec<&'a DicomMeta>,
+    }
+
+    let mut groups: Vec<Group<'a>> = Vec::new();
+    for m in items {
+        let for_uid = m.frame_of_reference_uid.as_deref();
+        let dims = (m.rows, m.columns);
+        let iop = m.image_orientation_patient;
+
+        let found = groups.iter_mut().find(|g| {
+            g.frame_of_reference == for_uid
+                && g.dims == dims
+                && same_orientation(g.orientation, iop, opts.orientation_tolerance)
+        });
+        match found {
+            Some(g) => g.items.push(m),
+            None => groups.push(Group { frame_of_reference: for_uid, dims, orientation: iop, items: vec![m] }),
+        }
+    }
+
+    if groups.len() <= 1 {
+        return groups.into_iter().map(|g| Stack { label: None, items: g.items }).collect();
+    }
+
+    // Stable, input-order independent numbering: earliest acquisition first.
+    groups.sort_by_cached_key(|g| {
+        let first_instance = g.items.iter().filter_map(|m| m.instance_number).min();
+        let first_id = g.items.iter().map(|m| m.stable_id()).min();
+        (first_instance.is_none(), first_instance, first_id)
+    });
+
+    groups
+        .into_iter()
+        .enumerate()
+        .map(|(i, g)| {
+            let label = match g.orientation.map(plane) {
+                Some(p) => format!("s{:02}_{}", i + 1, p),
+                None => format!("s{:02}", i + 1),
+            };
+            Stack { label: Some(label), items: g.items }
+        })
+        .collect()
+}
+
+fn same_orientation(a: Option<[f64; 6]>, b: Option<[f64; 6]>, tol: f64) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() <= tol),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Anatomical plane of an orientation: AX, SAG, COR, or OBL when no axis dominates.
+pub fn plane(iop: [f64; 6]) -> &'static str {
+    let m = DicomMeta { image_orientation_patient: Some(iop), ..Default::default() };
+    let n = match m.slice_normal() {
+        Some(n) => n,
+        None => return "OBL",
+    };
+    let (x, y, z) = (n[0].abs(), n[1].abs(), n[2].abs());
+    let len = (x * x + y * y + z * z).sqrt();
+    if len == 0.0 {
+        return "OBL";
+    }
+    let max = x.max(y).max(z);
+    if max / len < 0.8 {
+        "OBL"
+    } else if max == z {
+        "AX"
+    } else if max == x {
+        "SAG"
+    } else {
+        "COR"
+    }
+}
+
+/// Attribute used to separate volumes that share slice positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VolumeAxis {
+    TemporalPositionIdentifier,
+    TriggerTime,
+    AcquisitionNumber,
+    EchoNumbers,
+    AcquisitionTime,
+}
+
+impl VolumeAxis {
+    /// Candidates in order of preference.
+    pub const ALL: [VolumeAxis; 5] = [
+        VolumeAxis::TemporalPositionIdentifier,
+        VolumeAxis::TriggerTime,
+        VolumeAxis::AcquisitionNumber,
+        VolumeAxis::EchoNumbers,
+        VolumeAxis::AcquisitionTime,
+    ];
+
+    /// Integer key for grouping; times are kept to millisecond precision.
+    fn key(self, m: &DicomMeta) -> Option<i64> {
+        match self {
+            VolumeAxis::TemporalPositionIdentifier => m.temporal_position_identifier.map(i64::from),
+            VolumeAxis::TriggerTime => m.trigger_time.map(|t| (t * 1000.0).round() as i64),
+            VolumeAxis::AcquisitionNumber => m.acquisition_number.map(i64::from),
+            VolumeAxis::EchoNumbers => m.echo_numbers.map(i64::from),
+            VolumeAxis::AcquisitionTime => m.acquisition_time.as_deref().and_then(tm_millis),
+        }
+    }
+}
+
+/// Result of [`split_volumes`].
+#[derive(Debug)]
+pub enum Volumes<'a> {
+    /// No slice position occurs more than once (or positions are unknown).
+    Single,
+    /// Repeated positions, but no candidate axis separates them cleanly.
+    Unresolved { max_repeats: usize },
+    /// Volumes in ascending order of the axis value.
+    Split { axis: VolumeAxis, volumes: Vec<Vec<&'a DicomMeta>> },
+}
+
+/// Detect repeated slice positions and split a stack into volumes.
+///
+/// An axis is accepted when every image has it, its distinct values match the
+/// maximum number of images sharing one position, and no position repeats
+/// within a single value. This rejects e.g. AcquisitionTime on 2D
+/// acquisitions, where every slice has its own time.
+pub fn split_volumes<'a>(items: &[&'a DicomMeta]) -> Volumes<'a> {
+    let positions: Option<Vec<i64>> = items
+        .iter()
+        .map(|m| m.geom_order().map(|o| (o * 1000.0).round() as i64))
+        .collect();
+    let positions = match positions {
+        Some(p) => p,
+        None => return Volumes::Single,
+    };
+
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for &p in &positions {
+        *counts.entry(p).or_default() += 1;
+    }
+    let max_repeats = counts.values().copied().max().unwrap_or(0);
+    if max_repeats <= 1 {
+        return Volumes::Single;
+    }
+
+    'axes: for axis in VolumeAxis::ALL {
+        let mut groups: BTreeMap<i64, Vec<(i64, &'a DicomMeta)>> = BTreeMap::new();
+        for (&m, &pos) in items.iter().zip(&positions) {
+            match axis.key(m) {
+                Some(k) => groups.entry(k).or_default().push((pos, m)),
+                None => continue 'axes,
+            }
+        }
+        if groups.len() != max_repeats {
+            continue;
+        }
+        for g in groups.values() {
+            let mut seen: Vec<i64> = g.iter().map(|(p, _)| *p).collect();
+            seen.sort_unstable();
+            if seen.windows(2).any(|w| w[0] == w[1]) {
+                continue 'axes;
+            }
+        }
+        let volumes = groups
+            .into_values()
+            .map(|g| g.into_iter().map(|(_, m)| m).collect())
+            .collect();
+        return Volumes::Split { axis, volumes };
+    }
+
+    Volumes::Unresolved { max_repeats }
+}
+
+/// DICOM TM (`HHMMSS.FFFFFF`, components optional) to milliseconds since midnight.
+fn tm_millis(tm: &str) -> Option<i64> {
+    let tm = tm.trim();
+    let (hms, frac) = tm.split_once('.').unwrap_or((tm, ""));
+    if hms.is_empty() || hms.len() > 6 || hms.len() % 2 != 0 || !hms.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let part = |i: usize| hms.get(i..i + 2).map_or(Some(0), |s| s.parse::<i64>().ok());
+    let (h, m, s) = (part(0)?, part(2)?, part(4)?);
+    let ms = if frac.is_empty() {
+        0
+    } else {
+        let digits: String = frac.chars().chain("000".chars()).take(3).collect();
+        digits.parse::<i64>().ok()?
+    };
+    Some(((h * 60 + m) * 60 + s) * 1000 + ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AX: [f64; 6] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+    const SAG: [f64; 6] = [0.0, 1.0, 0.0, 0.0, 0.0, -1.0];
+    const COR: [f64; 6] = [1.0, 0.0, 0.0, 0.0, 0.0, -1.0];
+
+    fn img(n: i32, iop: [f64; 6], rows: u32) -> DicomMeta {
+        DicomMeta {
+            sop_uid: Some(format!("1.2.{}", n)),
+            instance_number: Some(n),
+            image_orientation_patient: Some(iop),
+            image_position_patient: Some([0.0, 0.0, n as f64]),
+            rows: Some(rows),
+            columns: Some(rows),
+            frame_of_reference_uid: Some("1.9".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_homogeneous_series_is_not_split() {
+        let metas = [img(1, AX, 256), img(2, AX, 256), img(3, [1.0, 0.0, 0.0, 0.0, 1.0, 0.0005], 256)];
+        let stacks = split_series(metas.iter().collect(), &SplitOptions::default());
+        assert_eq!(stacks.len(), 1);
+        assert!(stacks[0].label.is_none());
+        assert_eq!(stacks[0].items.len(), 3);
+    }
+
+    #[test]
+    fn test_three_plane_localizer() {
+        let metas = [
+            img(4, COR, 256), img(1, AX, 256), img(7, SAG, 256),
+            img(2, AX, 256), img(5, COR, 256), img(8, SAG, 256),
+        ];
+        let stacks = split_series(metas.iter().collect(), &SplitOptions::default());
+        let labels: Vec<_> = stacks.iter().map(|s| s.label.clone().unwrap()).collect();
+        assert_eq!(labels, ["s01_AX", "s02_COR", "s03_SAG"]);
+        assert!(stacks.iter().all(|s| s.items.len() == 2));
+    }
+
+    #[test]
+    fn test_split_on_dimensions_and_frame_of_reference() {
+        let mut other_for = img(3, AX, 256);
+        other_for.frame_of_reference_uid = Some("1.10".into());
+        let metas = [img(1, AX, 256), img(2, AX, 512), other_for];
+        let stacks = split_series(metas.iter().collect(), &SplitOptions::default());
+        assert_eq!(stacks.len(), 3);
+    }
+
+    fn dyn_img(n: i32, z: f64, temporal: i32, acq_time: &str) -> DicomMeta {
+        DicomMeta {
+            temporal_position_identifier: Some(temporal),
+            acquisition_time: Some(acq_time.into()),
+            image_position_patient: Some([0.0, 0.0, z]),
+            ..img(n, AX, 256)
+        }
+    }
+
+    #[test]
+    fn test_no_repeats_is_single() {
+        let metas = [img(1, AX, 256), img(2, AX, 256)];
+        let items: Vec<_> = metas.iter().collect();
+        assert!(matches!(split_volumes(&items), Volumes::Single));
+    }
+
+    #[test]
+    fn test_dynamic_series_split_by_temporal_position() {
+        // 2 timepoints x 3 slices, with per-slice acquisition times.
+        let metas = [
+            dyn_img(1, 0.0, 1, "101500.1"), dyn_img(2, 5.0, 1, "101500.2"), dyn_img(3, 10.0, 1, "101500.3"),
+            dyn_img(4, 0.0, 2, "101510.1"), dyn_img(5, 5.0, 2, "101510.2"), dyn_img(6, 10.0, 2, "101510.3"),
+        ];
+        let items: Vec<_> = metas.iter().rev().collect();
+        match split_volumes(&items) {
+            Volumes::Split { axis, volumes } => {
+                assert_eq!(axis, VolumeAxis::TemporalPositionIdentifier);
+                assert_eq!(volumes.len(), 2);
+                assert!(volumes[0].iter().all(|m| m.temporal_position_identifier == Some(1)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_per_slice_acquisition_time_is_rejected() {
+        let mut metas = [
+            dyn_img(1, 0.0, 1, "101500.1"), dyn_img(2, 5.0, 1, "101500.2"),
+            dyn_img(3, 0.0, 1, "101510.1"), dyn_img(4, 5.0, 1, "101510.2"),
+        ];
+        for m in &mut metas {
+            m.temporal_position_identifier = None;
+        }
+        let items: Vec<_> = metas.iter().collect();
+        assert!(matches!(split_volumes(&items), Volumes::Unresolved { max_repeats: 2 }));
+    }
+
+    #[test]
+    fn test_tm_parsing() {
+        assert_eq!(tm_millis("101510.25"), Some((10 * 3600 + 15 * 60 + 10) * 1000 + 250));
+        assert_eq!(tm_millis("1015"), Some((10 * 3600 + 15 * 60) * 1000));
+        assert_eq!(tm_millis("10:15"), None);
+    }
+
+    #[test]
+    fn test_plane_classification() {
+        assert_eq!(plane(AX), "AX");
+        assert_eq!(plane(SAG), "SAG");
+        assert_eq!(plane(COR), "COR");
+        let s = std::f64::consts::FRAC_1_SQRT_2;
+        assert_eq!(plane([1.0, 0.0, 0.0, 0.0, s, s]), "OBL");
+    }
+}
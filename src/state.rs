@@ -0,0 +1,287 @@
+//! Incremental runs (`--state`): remember what was sorted where, so a run
+//! only plans and executes new input.
+//!
+//! A [`State`] records the [`Fingerprint`] of every source file that was
+//! sorted (the archive, for archive members) and, for every instance, where it
+//! went and the header it was planned from. [`State::select`] drops the files
+//! that are unchanged since. The new headers are planned together with the
+//! recorded ones of every series they join ([`State::earlier`]), so a series
+//! that grows is numbered as a whole. [`State::split`] turns recorded
+//! instances whose name changed into [`Rename`]s, which [`renumber`] carries
+//! out inside the output before the new files are placed.
+//!
+//! A source that changed is sorted again. Its earlier copies stay in the
+//! output and are forgotten, with a warning.
+
+use crate::archive;
+use crate::dicom::DicomMeta;
+use crate::fs_ops::{self, execute, ConflictRecord};
+use crate::planfile::{Fingerprint, Output};
+use crate::sink::Sink;
+use crate::sort::{series_key, Plan};
+use crate::types::OnConflict;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Format version written to the state file.
+const VERSION: u32 = 1;
+
+/// An instance an earlier run placed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Placed {
+    dst: PathBuf,
+    meta: DicomMeta,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    /// The output the instances were placed in.
+    output: PathBuf,
+    sources: BTreeMap<PathBuf, Fingerprint>,
+    /// By source path (`archive!/member` for archive members).
+    instances: BTreeMap<PathBuf, Placed>,
+}
+
+/// A recorded instance whose destination changes because its series grew.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    /// The source it was sorted from.
+    pub src: PathBuf,
+    pub from: PathBuf,
+    /// Updated by [`renumber`] to where the file ended up.
+    pub to: PathBuf,
+}
+
+pub struct State {
+    path: PathBuf,
+    file: StateFile,
+    /// Sources of this run, recorded once it succeeds.
+    pending_sources: BTreeMap<PathBuf, Fingerprint>,
+    /// Instances of this run, by source, with their planned destination.
+    pending: Vec<(PathBuf, Placed)>,
+}
+
+/// The file on disk `src` was read from.
+fn source_file(src: &Path) -> PathBuf {
+    archive::split_member(src).map_or_else(|| src.to_path_buf(), |(a, _)| a)
+}
+
+impl State {
+    /// Load the state file at `path`, or start an empty one. Only a directory
+    /// output can be renumbered, and a state file belongs to one output.
+    pub fn open(path: &Path, output: &Output) -> Result<Self> {
+        let Output::Dir(dir) = output else {
+            bail!("--state needs a directory output; archive entries cannot be renumbered");
+        };
+        let dir = std::path::absolute(dir).with_context(|| format!("resolve {}", dir.display()))?;
+        let file = match path.exists() {
+            true => {
+                let s = fs::read_to_string(path).with_context(|| format!("read state: {}", path.display()))?;
+                let f: StateFile = serde_json::from_str(&s).with_context(|| format!("parse state: {}", path.display()))?;
+                if f.version != VERSION {
+                    bail!("state {} has version {}; this dcmsort reads version {}", path.display(), f.version, VERSION);
+                }
+                if f.output != dir {
+                    bail!("state {} belongs to output {}, not {}", path.display(), f.output.display(), dir.display());
+                }
+                f
+            }
+            false => StateFile { version: VERSION, output: dir, sources: BTreeMap::new(), instances: BTreeMap::new() },
+        };
+        Ok(Self { path: path.to_path_buf(), file, pending_sources: BTreeMap::new(), pending: Vec::new() })
+    }
+
+    /// The files of `files` that are new or changed since they were sorted.
+    ///
+    /// Recorded instances that are gone from the output are forgotten first,
+    /// along with their source's fingerprint, so a source still present is
+    /// sorted again.
+    pub fn select(&mut self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let gone: Vec<PathBuf> = self.file.instances.iter().filter(|(_, p)| !p.dst.exists()).map(|(src, _)| src.clone()).collect();
+        for src in &gone {
+            let placed = self.file.instances.remove(src).expect("listed above");
+            tracing::warn!("{} is no longer in the output; forgetting it was sorted", placed.dst.display());
+            self.file.sources.remove(&source_file(src));
+        }
+
+        let mut new = Vec::new();
+        let mut unchanged = 0;
+        for f in files {
+            let key = std::path::absolute(&f).with_context(|| format!("resolve {}", f.display()))?;
+            let fp = Fingerprint::of(&f, false)?;
+            match self.file.sources.get(&key) {
+                Some(old) if *old == fp => {
+                    unchanged += 1;
+                    continue;
+                }
+                Some(_) => {
+                    tracing::warn!("{} changed since it was sorted; sorting it again (earlier copies stay in the output)", f.display());
+                    self.file.instances.retain(|src, _| source_file(src) != key);
+                }
+                None => {}
+            }
+            self.pending_sources.insert(key, fp);
+            new.push(f);
+        }
+        tracing::info!("State: {} files already sorted, {} new or changed", unchanged, new.len());
+        Ok(new)
+    }
+
+    /// Do not record the sources of `paths` as sorted, so a later run reads
+    /// them again. Used for instances a filter left out, since the next run
+    /// may filter differently.
+    pub fn forget<'a>(&mut self, paths: impl IntoIterator<Item = &'a Path>) -> Result<()> {
+        for p in paths {
+            let key = std::path::absolute(source_file(p)).with_context(|| format!("resolve {}", p.display()))?;
+            self.pending_sources.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// The recorded headers of every series `metas` belong to, to be planned with them.
+    pub fn earlier(&self, metas: &[DicomMeta]) -> Vec<DicomMeta> {
+        let series: HashSet<_> = metas.iter().map(series_key).collect();
+        self.file.instances.values().filter(|p| series.contains(&series_key(&p.meta))).map(|p| p.meta.clone()).collect()
+    }
+
+    /// Split `plans` into renames of recorded instances and plans for new ones.
+    /// Recorded instances that keep their destination are dropped; new ones
+    /// are recorded by [`Self::commit`].
+    pub fn split(&mut self, plans: Vec<Plan>) -> (Vec<Rename>, Vec<Plan>) {
+        let mut renames = Vec::new();
+        let mut new = Vec::new();
+        for p in plans {
+            match self.file.instances.get(&p.meta.path) {
+                Some(placed) if placed.dst == p.dst => {}
+                Some(placed) => renames.push(Rename { src: p.meta.path.clone(), from: placed.dst.clone(), to: p.dst }),
+                None => {
+                    self.pending.push((p.src.clone(), Placed { dst: p.dst.clone(), meta: p.meta.clone() }));
+                    new.push(p);
+                }
+            }
+        }
+        (renames, new)
+    }
+
+    /// Record a successful run: the renames, the new instances where they
+    /// actually ended up, and the fingerprints of their sources.
+    pub fn commit(&mut self, renames: &[Rename], conflicts: &[ConflictRecord]) -> Result<()> {
+        for r in renames {
+            if let Some(placed) = self.file.instances.get_mut(&r.src) {
+                placed.dst = r.to.clone();
+            }
+        }
+        let actual: HashMap<(&Path, &Path), &Path> =
+            conflicts.iter().map(|c| ((c.src.as_path(), c.planned.as_path()), c.actual.as_path())).collect();
+        for (src, mut placed) in self.pending.drain(..) {
+            if let Some(a) = actual.get(&(src.as_path(), placed.dst.as_path())) {
+                placed.dst = a.to_path_buf();
+            }
+            let key = std::path::absolute(&src).with_context(|| format!("resolve {}", src.display()))?;
+            placed.meta.path = key.clone();
+            self.file.instances.insert(key, placed);
+        }
+        self.file.sources.append(&mut self.pending_sources);
+        Ok(())
+    }
+
+    /// Write the state file, replacing it in one rename, readable by its owner only.
+    pub fn save(&self) -> Result<()> {
+        let s = serde_json::to_string_pretty(&self.file).context("serialize state")?;
+        // The recorded headers hold patient identifiers.
+        fs_ops::write_private(&self.path, s.as_bytes()).with_context(|| format!("write state: {}", self.path.display()))
+    }
+}
+
+/// Move each rename's `from` to its `to` through a hidden temporary name, so
+/// names that shift along never overwrite each other. `sink` must move (it
+/// stays within the output). A `to` that is taken by a file the state does not
+/// know gets the next free `_1`, `_2`, ... name, and `to` is updated to match.
+pub fn renumber(renames: &mut [Rename], sink: &dyn Sink, dry_run: bool, jobs: usize) -> Result<Vec<ConflictRecord>> {
+    if dry_run {
+        for r in renames.iter() {
+            println!("{} -> {} (renumber)", r.from.display(), r.to.display());
+        }
+        return Ok(Vec::new());
+    }
+    let aside: Vec<PathBuf> = renames
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let name = r.from.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            r.from.with_file_name(format!(".renumber-{}-{}", i, name))
+        })
+        .collect();
+    let plan = |src: &Path, dst: &Path| Plan::new(src.to_path_buf(), dst.to_path_buf(), DicomMeta::default());
+    let first = renames.iter().zip(&aside).map(|(r, a)| plan(&r.from, a)).collect();
+    execute(first, sink, OnConflict::Error, false, None, jobs)?;
+    let second = renames.iter().zip(&aside).map(|(r, a)| plan(a, &r.to)).collect();
+    let conflicts = execute(second, sink, OnConflict::Rename, false, None, jobs)?;
+    for c in &conflicts {
+        if let Some(i) = aside.iter().position(|a| *a == c.src) {
+            renames[i].to = c.actual.clone();
+        }
+    }
+    Ok(conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::collect_files;
+    use crate::sink::{CopySink, MoveSink};
+    use crate::sort::{plan_operations, scan, PlanOptions};
+    use crate::synth::{generate, SynthOptions};
+    use crate::types::Layout;
+
+    #[test]
+    fn test_new_slices_renumber_their_series() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in");
+        let held = tmp.path().join("held");
+        let out = tmp.path().join("out");
+        let opts = SynthOptions { patients: 1, series: 1, slices: 4, rows: 8, columns: 8, ..Default::default() };
+        generate(&input, &opts).unwrap();
+        // Hold back every second slice until the second run.
+        fs::create_dir(&held).unwrap();
+        for m in scan(&collect_files(&input, false).unwrap()).metas {
+            if m.instance_number.unwrap() % 2 == 0 {
+                fs::rename(&m.path, held.join(m.path.file_name().unwrap())).unwrap();
+            }
+        }
+
+        let path = tmp.path().join("state.json");
+        let plan_opts = PlanOptions { layout: Layout::SeriesOnly, ..Default::default() };
+        let run = || {
+            let mut state = State::open(&path, &Output::Dir(out.clone())).unwrap();
+            let files = state.select(collect_files(&input, false).unwrap()).unwrap();
+            let mut metas = scan(&files).metas;
+            metas.extend(state.earlier(&metas));
+            let (mut renames, plans) = state.split(plan_operations(&metas, &out, &plan_opts));
+            renumber(&mut renames, &MoveSink::default(), false, 2).unwrap();
+            let conflicts = execute(plans, &CopySink, OnConflict::SkipIdentical, false, None, 2).unwrap();
+            state.commit(&renames, &conflicts).unwrap();
+            state.save().unwrap();
+            (files.len(), renames.len())
+        };
+        assert_eq!(run(), (2, 0));
+        for f in fs::read_dir(&held).unwrap() {
+            let f = f.unwrap().path();
+            fs::rename(&f, input.join(f.file_name().unwrap())).unwrap();
+        }
+        // Slices 1 and 3 were 00001 and 00002; now 00002 moves to 00003.
+        assert_eq!(run(), (2, 1));
+        assert_eq!(run(), (0, 0));
+
+        let sorted = scan(&collect_files(&out, false).unwrap()).metas;
+        assert_eq!(sorted.len(), 4);
+        for m in sorted {
+            let name = m.path.file_name().unwrap().to_string_lossy().into_owned();
+            assert!(name.starts_with(&format!("{:05}_", m.instance_number.unwrap())), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Bounded-memory sorting for inputs too large to hold in memory (`--stream`).
+//!
+//! [`scan_series`] walks the input in name order and reads headers a chunk of
+//! files at a time. Each header is appended to a spill file for its series in a
+//! temporary folder, instead of being kept. A series is *sealed* once the walk
+//! has left every folder it was seen in. It is then sent over a channel, so the
+//! caller can plan and execute it while the walk goes on. Memory is bounded by
+//! a chunk of headers plus the largest series, not by the size of the input.
+//!
+//! Sealing assumes a series does not come back in a folder walked later. If it
+//! does, the late instances are spilled and sealed as a batch of their own with
+//! [`Sealed::late`] set. They are planned on their own, so their names collide
+//! with the earlier batch and `--on-conflict` decides what happens.
+
+use crate::archive;
+use crate::deid::Deidentifier;
+use crate::dicom::{DicomMeta, ScanFailure};
+use crate::dicomdir::read::is_dicomdir_name;
+use crate::filter::{Excluded, Filter};
+use crate::sort::{scan_source, series_key};
+use crate::source::FileSource;
+use anyhow::{anyhow, Context, Result};
+use dicom_object::Tag;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::SyncSender;
+use walkdir::WalkDir;
+
+/// Most files read per chunk.
+const CHUNK: usize = 256;
+/// Spill files kept open at once; the least recently written is closed first.
+const OPEN_FILES: usize = 64;
+
+type Key = (String, String);
+
+/// A series whose headers are all spilled, ready to be planned.
+#[derive(Debug)]
+pub struct Sealed {
+    /// (StudyInstanceUID, SeriesInstanceUID), with the same fallbacks as planning.
+    pub key: Key,
+    /// Instances that turned up after their series was already sealed.
+    pub late: bool,
+    path: PathBuf,
+}
+
+impl Sealed {
+    /// Read the headers back and delete the spill file.
+    pub fn load(self) -> Result<Vec<DicomMeta>> {
+        let f = File::open(&self.path).with_context(|| format!("open spill file {}", self.path.display()))?;
+        let mut metas = Vec::new();
+        for line in BufReader::new(f).lines() {
+            let line = line.with_context(|| format!("read spill file {}", self.path.display()))?;
+            metas.push(serde_json::from_str(&line).with_context(|| format!("parse spill file {}", self.path.display()))?);
+        }
+        fs::remove_file(&self.path).with_context(|| format!("remove spill file {}", self.path.display()))?;
+        Ok(metas)
+    }
+}
+
+/// How [`scan_series`] reads the input.
+#[derive(Clone, Copy, Default)]
+pub struct StreamOptions<'a> {
+    pub follow_symlinks: bool,
+    /// Raw tags to capture into `DicomMeta::extra`.
+    pub extra_tags: &'a [Tag],
+    /// Instances that fail it are left out, before de-identification.
+    pub filter: Option<&'a Filter>,
+    /// Applied to every header before it is spilled, so spill files hold no PHI.
+    pub deid: Option<&'a Deidentifier>,
+}
+
+/// What [`scan_series`] saw, besides the series it sent.
+#[derive(Debug, Default)]
+pub struct StreamOutcome {
+    pub files: usize,
+    pub instances: usize,
+    /// Batches sent, late ones included.
+    pub sealed: usize,
+    pub failures: Vec<ScanFailure>,
+    pub excluded: Vec<Excluded>,
+}
+
+/// Scan everything under `root`, spilling headers into `spill_dir` and sending
+/// each series to `tx` once it is sealed. Stops with an error if nothing is
+/// receiving any more.
+pub fn scan_series(root: &Path, spill_dir: &Path, opts: &StreamOptions, tx: SyncSender<Sealed>) -> Result<StreamOutcome> {
+    let mut spill = Spill { dir: spill_dir, tx, open: HashMap::new(), sealed: HashSet::new(), created: 0, writes: 0 };
+    let mut outcome = StreamOutcome::default();
+    let mut chunk = Vec::with_capacity(CHUNK);
+
+    let walk = WalkDir::new(root).follow_links(opts.follow_symlinks).sort_by_file_name();
+    for entry in walk.into_iter().filter_map(|e| e.ok()) {
+        // Same rules as `fs_ops::collect_files`.
+        if !entry.file_type().is_file() || is_dicomdir_name(entry.file_name()) {
+            continue;
+        }
+        outcome.files += 1;
+        let path = entry.into_path();
+        if archive::is_archive(&path) {
+            spill.scan(&mut chunk, opts, &mut outcome)?;
+            // Members share the archive's folder; an archive is read in one pass.
+            let folder = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            let o = archive::scan(&path, opts.extra_tags);
+            outcome.failures.extend(o.failures);
+            for m in o.metas {
+                spill.push(m, &folder, opts, &mut outcome)?;
+            }
+            spill.seal_left(&folder, &mut outcome)?;
+        } else {
+            // A chunk never spans folders, so series are sealed as soon as the walk moves on.
+            if chunk.len() == CHUNK || chunk.last().is_some_and(|c: &PathBuf| c.parent() != path.parent()) {
+                spill.scan(&mut chunk, opts, &mut outcome)?;
+            }
+            chunk.push(path);
+        }
+    }
+    spill.scan(&mut chunk, opts, &mut outcome)?;
+    spill.finish(&mut outcome)?;
+    Ok(outcome)
+}
+
+/// A series still being spilled.
+struct Open {
+    path: PathBuf,
+    /// Closed when too many spill files are open; reopened to append.
+    file: Option<BufWriter<File>>,
+    folders: HashSet<PathBuf>,
+    late: bool,
+    /// Creation order, so series are sealed in walk order.
+    order: usize,
+    last_write: u64,
+}
+
+struct Spill<'a> {
+    dir: &'a Path,
+    tx: SyncSender<Sealed>,
+    open: HashMap<Key, Open>,
+    sealed: HashSet<Key>,
+    created: usize,
+    writes: u64,
+}
+
+impl Spill<'_> {
+    /// Read and spill the files in `chunk`, then seal what the walk has left behind.
+    fn scan(&mut self, chunk: &mut Vec<PathBuf>, opts: &StreamOptions, outcome: &mut StreamOutcome) -> Result<()> {
+        let Some(at) = chunk.last().and_then(|p| p.parent()).map(Path::to_path_buf) else {
+            return Ok(());
+        };
+        let o = scan_source(&FileSource, chunk, opts.extra_tags);
+        chunk.clear();
+        outcome.failures.extend(o.failures);
+        for m in o.metas {
+            let folder = m.path.parent().unwrap_or(Path::new("")).to_path_buf();
+            self.push(m, &folder, opts, outcome)?;
+        }
+        self.seal_left(&at, outcome)
+    }
+
+    fn push(&mut self, m: DicomMeta, folder: &Path, opts: &StreamOptions, outcome: &mut StreamOutcome) -> Result<()> {
+        if let Some(clause) = opts.filter.and_then(|f| f.check(&m)) {
+            outcome.excluded.push(Excluded { path: m.path, sop_uid: m.sop_uid, clause });
+            return Ok(());
+        }
+        let m = match opts.deid {
+            Some(d) => d.apply_meta(&m),
+            None => m,
+        };
+        let key = series_key(&m);
+        if !self.open.contains_key(&key) {
+            let late = self.sealed.contains(&key);
+            if late {
+                tracing::warn!("Series {} continues in {} after it was sealed; its late instances follow --on-conflict", key.1, folder.display());
+            }
+            let path = self.dir.join(format!("{:08}.jsonl", self.created));
+            let open = Open { path, file: None, folders: HashSet::new(), late, order: self.created, last_write: 0 };
+            self.created += 1;
+            self.open.insert(key.clone(), open);
+        }
+
+        if self.open[&key].file.is_none() && self.open.values().filter(|o| o.file.is_some()).count() >= OPEN_FILES {
+            let lru = self.open.values_mut().filter(|o| o.file.is_some()).min_by_key(|o| o.last_write).expect("files are open");
+            close(lru)?;
+        }
+        self.writes += 1;
+        let open = self.open.get_mut(&key).expect("inserted above");
+        open.folders.insert(folder.to_path_buf());
+        open.last_write = self.writes;
+        if open.file.is_none() {
+            let f = File::options().create(true).append(true).open(&open.path);
+            open.file = Some(BufWriter::new(f.with_context(|| format!("open spill file {}", open.path.display()))?));
+        }
+        let file = open.file.as_mut().expect("opened above");
+        serde_json::to_writer(&mut *file, &m).context("spill header")?;
+        file.write_all(b"\n").with_context(|| format!("write spill file {}", open.path.display()))?;
+        outcome.instances += 1;
+        Ok(())
+    }
+
+    /// Seal every series whose folders are all outside `at`, in walk order.
+    fn seal_left(&mut self, at: &Path, outcome: &mut StreamOutcome) -> Result<()> {
+        let mut done: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(_, o)| o.folders.iter().all(|f| !at.starts_with(f)))
+            .map(|(k, o)| (o.order, k.clone()))
+            .collect();
+        done.sort();
+        for (_, key) in done {
+            self.seal(key, outcome)?;
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self, key: Key, outcome: &mut StreamOutcome) -> Result<()> {
+        let mut open = self.open.remove(&key).expect("only open series are sealed");
+        close(&mut open)?;
+        self.sealed.insert(key.clone());
+        outcome.sealed += 1;
+        let sealed = Sealed { key, late: open.late, path: open.path };
+        self.tx.send(sealed).map_err(|_| anyhow!("scan stopped: sealed series are no longer being executed"))
+    }
+
+    fn finish(mut self, outcome: &mut StreamOutcome) -> Result<()> {
+        let mut rest: Vec<_> = self.open.iter().map(|(k, o)| (o.order, k.clone())).collect();
+        rest.sort();
+        for (_, key) in rest {
+            self.seal(key, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+fn close(open: &mut Open) -> Result<()> {
+    if let Some(mut f) = open.file.take() {
+        f.flush().with_context(|| format!("write spill file {}", open.path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{generate, SynthOptions};
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn test_series_are_sealed_in_walk_order_with_late_batches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in");
+        for (folder, seed) in [("a", 1), ("b", 2)] {
+            let opts = SynthOptions { seed, patients: 1, series: 1, slices: 3, rows: 8, columns: 8, ..Default::default() };
+            generate(&input.join(folder), &opts).unwrap();
+        }
+        // One instance of the first series turns up again in a folder walked last.
+        let first = fs::read_dir(input.join("a")).unwrap().next().unwrap().unwrap().path();
+        fs::create_dir(input.join("c")).unwrap();
+        fs::copy(&first, input.join("c/again.dcm")).unwrap();
+        fs::write(input.join("c/notes.txt"), b"not dicom").unwrap();
+
+        let spill = tmp.path().join("spill");
+        fs::create_dir(&spill).unwrap();
+        let (tx, rx) = sync_channel(16);
+        let outcome = scan_series(&input, &spill, &StreamOptions::default(), tx).unwrap();
+        assert_eq!((outcome.files, outcome.instances, outcome.sealed, outcome.failures.len()), (8, 7, 3, 1));
+
+        let batches: Vec<_> = rx.try_iter().collect();
+        assert_eq!(batches[0].key, batches[2].key);
+        assert_ne!(batches[0].key, batches[1].key);
+        assert_eq!(batches.iter().map(|b| b.late).collect::<Vec<_>>(), [false, false, true]);
+        let sizes: Vec<_> = batches.into_iter().map(|b| b.load().unwrap().len()).collect();
+        assert_eq!(sizes, [3, 3, 1]);
+        assert_eq!(fs::read_dir(&spill).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,459 @@
+//! Synthetic DICOM datasets, so sorting can be tested and edge cases
+//! reproduced without real (or downloaded) images.
+//!
+//! [`generate`] writes a flat, shuffled folder of CT instances, like an
+//! unsorted import. Everything is derived from [`SynthOptions::seed`]: the
+//! same options always produce the same files, names and UIDs.
+
+use anyhow::{Context, Result};
+use dicom_core::value::DataSetSequence;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::tags;
+use dicom_object::{FileDicomObject, FileMetaTableBuilder, InMemDicomObject};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub(crate) const CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+const ENHANCED_CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2.1";
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+
+/// What [`generate`] writes. Counts are per parent level.
+#[derive(Debug, Clone)]
+pub struct SynthOptions {
+    pub seed: u64,
+    pub patients: u32,
+    /// Studies per patient.
+    pub studies: u32,
+    /// Single-frame series per study.
+    pub series: u32,
+    /// Slices per series (and frames per multi-frame object).
+    pub slices: u32,
+    pub rows: u16,
+    pub columns: u16,
+    /// Distance between slice centres, in mm.
+    pub slice_spacing: f64,
+    /// Tilt every second series by this many degrees about the patient x axis.
+    pub oblique_degrees: f64,
+    /// Instances that lose one of InstanceNumber, the geometry, SeriesInstanceUID,
+    /// StudyInstanceUID or PatientID, in turn.
+    pub missing_tags: u32,
+    /// Extra byte-identical copies of existing instances under other names.
+    pub duplicate_sops: u32,
+    /// Enhanced CT multi-frame objects per study, each holding a whole stack.
+    pub multiframe: u32,
+    /// Non-DICOM and broken files: text, empty, truncated, unknown transfer syntax, binary.
+    pub junk: u32,
+}
+
+impl Default for SynthOptions {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            patients: 2,
+            studies: 1,
+            series: 2,
+            slices: 20,
+            rows: 64,
+            columns: 64,
+            slice_spacing: 2.5,
+            oblique_degrees: 0.0,
+            missing_tags: 0,
+            duplicate_sops: 0,
+            multiframe: 0,
+            junk: 0,
+        }
+    }
+}
+
+/// What [`generate`] wrote.
+#[derive(Debug, Default, Clone)]
+pub struct SynthSummary {
+    /// Every DICOM file written, duplicates included.
+    pub instances: Vec<PathBuf>,
+    /// Distinct series (single-frame and multi-frame).
+    pub series: usize,
+    pub duplicates: usize,
+    pub junk: Vec<PathBuf>,
+}
+
+/// Write a synthetic dataset into `dir` (created if needed).
+pub fn generate(dir: &Path, opts: &SynthOptions) -> Result<SynthSummary> {
+    fs::create_dir_all(dir).with_context(|| format!("create dir: {}", dir.display()))?;
+    let mut g = Generator { dir, opts, rng: Rng(opts.seed), summary: SynthSummary::default() };
+
+    let per_study = opts.series as u64 * opts.slices as u64;
+    let total = opts.patients as u64 * opts.studies as u64 * per_study;
+    let missing: Vec<u64> = g.rng.pick(total, opts.missing_tags as u64);
+    let duplicate: BTreeSet<u64> = g.rng.pick(total, opts.duplicate_sops as u64).into_iter().collect();
+
+    let mut n = 0u64;
+    for p in 0..opts.patients {
+        for s in 0..opts.studies {
+            let study = Study::new(opts.seed, p, s);
+            for e in 0..opts.series {
+                let series = Series::new(opts, &study, e);
+                for i in 0..opts.slices {
+                    let mut obj = g.slice(&study, &series, i);
+                    if let Some(k) = missing.iter().position(|&m| m == n) {
+                        strip(&mut obj, k);
+                    }
+                    let path = g.write(&obj)?;
+                    if duplicate.contains(&n) {
+                        let copy = g.name();
+                        fs::copy(&path, &copy).with_context(|| format!("copy {} -> {}", path.display(), copy.display()))?;
+                        g.summary.instances.push(copy);
+                        g.summary.duplicates += 1;
+                    }
+                    n += 1;
+                }
+                g.summary.series += 1;
+            }
+            for m in 0..opts.multiframe {
+                let series = Series::new(opts, &study, opts.series + m);
+                let obj = g.multiframe(&study, &series);
+                g.write(&obj)?;
+                g.summary.series += 1;
+            }
+        }
+    }
+    for j in 0..opts.junk {
+        g.junk(j)?;
+    }
+    Ok(g.summary)
+}
+
+struct Generator<'a> {
+    dir: &'a Path,
+    opts: &'a SynthOptions,
+    rng: Rng,
+    summary: SynthSummary,
+}
+
+struct Study {
+    seed: u64,
+    patient_id: String,
+    patient_name: String,
+    uid: String,
+    frame_of_reference: String,
+    index: u32,
+    date: String,
+}
+
+impl Study {
+    fn new(seed: u64, patient: u32, study: u32) -> Self {
+        let label = format!("{}.{}", patient, study);
+        Self {
+            seed,
+            patient_id: format!("SYN{:04}", patient + 1),
+            patient_name: format!("SYNTHETIC^PATIENT{}", patient + 1),
+            uid: uid(seed, &format!("study/{}", label)),
+            frame_of_reference: uid(seed, &format!("for/{}", label)),
+            index: study,
+            date: format!("2024{:02}{:02}", study % 12 + 1, patient % 28 + 1),
+        }
+    }
+}
+
+struct Series {
+    uid: String,
+    number: u32,
+    /// Row and column direction cosines.
+    iop: [f64; 6],
+    normal: [f64; 3],
+    /// Feet-first: positions decrease with InstanceNumber.
+    descending: bool,
+}
+
+impl Series {
+    fn new(opts: &SynthOptions, study: &Study, index: u32) -> Self {
+        let oblique = index % 2 == 1 && opts.oblique_degrees != 0.0;
+        let a = if oblique { opts.oblique_degrees.to_radians() } else { 0.0 };
+        // Axial, rotated about x: the column direction tilts towards z.
+        let iop = [1.0, 0.0, 0.0, 0.0, a.cos(), -a.sin()];
+        let normal = [0.0, a.sin(), a.cos()];
+        Self {
+            uid: uid(study.seed, &format!("series/{}/{}", study.uid, index)),
+            number: index + 1,
+            iop,
+            normal,
+            descending: index % 3 == 2,
+        }
+    }
+
+    fn position(&self, opts: &SynthOptions, slice: u32) -> [f64; 3] {
+        let k = if self.descending { (opts.slices - 1 - slice) as f64 } else { slice as f64 };
+        let d = k * opts.slice_spacing;
+        [-100.0 + self.normal[0] * d, -100.0 + self.normal[1] * d, -50.0 + self.normal[2] * d]
+    }
+}
+
+impl Generator<'_> {
+    /// A fresh, shuffled-looking file name in `dir`.
+    fn name(&mut self) -> PathBuf {
+        self.dir.join(format!("{:016X}", self.rng.next()))
+    }
+
+    fn write(&mut self, obj: &FileDicomObject<InMemDicomObject>) -> Result<PathBuf> {
+        let path = self.name();
+        obj.write_to_file(&path).with_context(|| format!("write {}", path.display()))?;
+        self.summary.instances.push(path.clone());
+        Ok(path)
+    }
+
+    fn common(&self, study: &Study, series: &Series, sop_class: &str, sop: &str) -> InMemDicomObject {
+        let o = self.opts;
+        InMemDicomObject::from_element_iter([
+            str_el(tags::SOP_CLASS_UID, VR::UI, sop_class),
+            str_el(tags::SOP_INSTANCE_UID, VR::UI, sop),
+            str_el(tags::STUDY_DATE, VR::DA, &study.date),
+            str_el(tags::STUDY_TIME, VR::TM, "093000"),
+            str_el(tags::ACCESSION_NUMBER, VR::SH, &format!("ACC{}{:02}", &study.patient_id[3..], study.index + 1)),
+            str_el(tags::MODALITY, VR::CS, "CT"),
+            str_el(tags::STUDY_DESCRIPTION, VR::LO, "SYNTHETIC CHEST"),
+            str_el(tags::SERIES_DESCRIPTION, VR::LO, if series.iop[4] == 1.0 { "AXIAL" } else { "OBLIQUE" }),
+            str_el(tags::PATIENT_NAME, VR::PN, &study.patient_name),
+            str_el(tags::PATIENT_ID, VR::LO, &study.patient_id),
+            str_el(tags::PATIENT_BIRTH_DATE, VR::DA, "19700101"),
+            str_el(tags::PATIENT_SEX, VR::CS, "O"),
+            str_el(tags::STUDY_INSTANCE_UID, VR::UI, &study.uid),
+            str_el(tags::SERIES_INSTANCE_UID, VR::UI, &series.uid),
+            str_el(tags::STUDY_ID, VR::SH, &(study.index + 1).to_string()),
+            str_el(tags::SERIES_NUMBER, VR::IS, &series.number.to_string()),
+            str_el(tags::FRAME_OF_REFERENCE_UID, VR::UI, &study.frame_of_reference),
+            DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1u16)),
+            str_el(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2"),
+            DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(o.rows)),
+            DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(o.columns)),
+            DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16u16)),
+            DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(12u16)),
+            DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(11u16)),
+            DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, PrimitiveValue::from(0u16)),
+        ])
+    }
+
+    fn slice(&mut self, study: &Study, series: &Series, i: u32) -> FileDicomObject<InMemDicomObject> {
+        let sop = uid(study.seed, &format!("sop/{}/{}", series.uid, i));
+        let mut obj = self.common(study, series, CT_IMAGE_STORAGE, &sop);
+        obj.put(str_el(tags::INSTANCE_NUMBER, VR::IS, &(i + 1).to_string()));
+        obj.put(ds_el(tags::IMAGE_POSITION_PATIENT, &series.position(self.opts, i)));
+        obj.put(ds_el(tags::IMAGE_ORIENTATION_PATIENT, &series.iop));
+        obj.put(ds_el(tags::PIXEL_SPACING, &[0.7, 0.7]));
+        obj.put(ds_el(tags::SLICE_THICKNESS, &[self.opts.slice_spacing]));
+        obj.put(self.pixels(1, i));
+        with_meta(obj, CT_IMAGE_STORAGE, &sop)
+    }
+
+    fn multiframe(&mut self, study: &Study, series: &Series) -> FileDicomObject<InMemDicomObject> {
+        let o = self.opts;
+        let sop = uid(study.seed, &format!("sop/{}/mf", series.uid));
+        let mut obj = self.common(study, series, ENHANCED_CT_IMAGE_STORAGE, &sop);
+        obj.put(str_el(tags::INSTANCE_NUMBER, VR::IS, "1"));
+        obj.put(str_el(tags::NUMBER_OF_FRAMES, VR::IS, &o.slices.to_string()));
+        let shared = InMemDicomObject::from_element_iter([
+            sq_el(tags::PLANE_ORIENTATION_SEQUENCE, vec![InMemDicomObject::from_element_iter([ds_el(tags::IMAGE_ORIENTATION_PATIENT, &series.iop)])]),
+            sq_el(
+                tags::PIXEL_MEASURES_SEQUENCE,
+                vec![InMemDicomObject::from_element_iter([ds_el(tags::PIXEL_SPACING, &[0.7, 0.7]), ds_el(tags::SLICE_THICKNESS, &[o.slice_spacing])])],
+            ),
+        ]);
+        let frames = (0..o.slices)
+            .map(|i| {
+                InMemDicomObject::from_element_iter([
+                    sq_el(tags::PLANE_POSITION_SEQUENCE, vec![InMemDicomObject::from_element_iter([ds_el(tags::IMAGE_POSITION_PATIENT, &series.position(o, i))])]),
+                    sq_el(
+                        tags::FRAME_CONTENT_SEQUENCE,
+                        vec![InMemDicomObject::from_element_iter([DataElement::new(tags::IN_STACK_POSITION_NUMBER, VR::UL, PrimitiveValue::from(i + 1))])],
+                    ),
+                ])
+            })
+            .collect();
+        obj.put(sq_el(tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE, vec![shared]));
+        obj.put(sq_el(tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE, frames));
+        obj.put(self.pixels(o.slices, 0));
+        with_meta(obj, ENHANCED_CT_IMAGE_STORAGE, &sop)
+    }
+
+    /// A bright disc whose radius changes along the stack, so frames differ.
+    fn pixels(&self, frames: u32, first: u32) -> DataElement<InMemDicomObject> {
+        let (rows, cols) = (self.opts.rows as i64, self.opts.columns as i64);
+        let mut data = Vec::with_capacity((frames as i64 * rows * cols) as usize);
+        for f in 0..frames {
+            let r = (rows.min(cols) / 4 + ((first + f) % 8) as i64).pow(2);
+            for y in 0..rows {
+                for x in 0..cols {
+                    let (dx, dy) = (x - cols / 2, y - rows / 2);
+                    data.push(if dx * dx + dy * dy <= r { 1000u16 } else { 24 });
+                }
+            }
+        }
+        DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::U16(data.into()))
+    }
+
+    fn junk(&mut self, j: u32) -> Result<()> {
+        let (name, bytes): (&str, Vec<u8>) = match j % 5 {
+            0 => ("README.TXT", b"Synthetic dataset generated by dcmsort synth.\n".to_vec()),
+            1 => ("EMPTY", Vec::new()),
+            2 | 3 => {
+                let study = Study::new(self.opts.seed, 0, 0);
+                let series = Series::new(self.opts, &study, 0);
+                let mut buf = Vec::new();
+                self.slice(&study, &series, 0).write_all(&mut buf).context("encode broken instance")?;
+                if j % 5 == 2 {
+                    // A real header cut short inside the dataset.
+                    buf.truncate(400);
+                    ("TRUNCATED", buf)
+                } else {
+                    // Same length as the original UID, so the meta group stays consistent.
+                    let at = buf.windows(EXPLICIT_VR_LE.len()).position(|w| w == EXPLICIT_VR_LE.as_bytes()).expect("transfer syntax in meta");
+                    buf[at..at + EXPLICIT_VR_LE.len()].copy_from_slice(b"1.2.840.99999.1.2.1");
+                    ("UNKNOWN_TS", buf)
+                }
+            }
+            _ => ("Thumbs.db", (0..256).map(|_| self.rng.next() as u8).collect()),
+        };
+        let path = self.dir.join(format!("JUNK{:03}_{}", j + 1, name));
+        fs::write(&path, bytes).with_context(|| format!("write {}", path.display()))?;
+        self.summary.junk.push(path);
+        Ok(())
+    }
+}
+
+/// Remove the `k`-th kind of tag, in turn (see [`SynthOptions::missing_tags`]).
+fn strip(obj: &mut FileDicomObject<InMemDicomObject>, k: usize) {
+    let groups: [&[Tag]; 5] = [
+        &[tags::INSTANCE_NUMBER],
+        &[tags::IMAGE_POSITION_PATIENT, tags::IMAGE_ORIENTATION_PATIENT],
+        &[tags::SERIES_INSTANCE_UID],
+        &[tags::STUDY_INSTANCE_UID],
+        &[tags::PATIENT_ID],
+    ];
+    for &tag in groups[k % groups.len()] {
+        obj.remove_element(tag);
+    }
+}
+
+fn with_meta(obj: InMemDicomObject, sop_class: &str, sop: &str) -> FileDicomObject<InMemDicomObject> {
+    obj.with_meta(
+        FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(sop_class)
+            .media_storage_sop_instance_uid(sop)
+            .transfer_syntax(EXPLICIT_VR_LE),
+    )
+    .expect("complete file meta")
+}
+
+fn str_el(tag: Tag, vr: VR, value: &str) -> DataElement<InMemDicomObject> {
+    DataElement::new(tag, vr, PrimitiveValue::from(value))
+}
+
+fn ds_el(tag: Tag, values: &[f64]) -> DataElement<InMemDicomObject> {
+    let strs = values.iter().map(|v| format!("{:.6}", v).trim_end_matches('0').trim_end_matches('.').to_string());
+    DataElement::new(tag, VR::DS, PrimitiveValue::Strs(strs.collect()))
+}
+
+fn sq_el(tag: Tag, items: Vec<InMemDicomObject>) -> DataElement<InMemDicomObject> {
+    DataElement::new(tag, VR::SQ, DataSetSequence::from(items))
+}
+
+/// One Explicit VR Little Endian Part 10 instance: SOP Class and Instance UIDs,
+/// then `elements`. For tests that need one particular header rather than a
+/// generated series.
+#[cfg(test)]
+pub(crate) fn instance(sop_class: &str, sop: &str, elements: &[(Tag, VR, &str)]) -> Vec<u8> {
+    let mut obj = InMemDicomObject::from_element_iter([
+        str_el(tags::SOP_CLASS_UID, VR::UI, sop_class),
+        str_el(tags::SOP_INSTANCE_UID, VR::UI, sop),
+    ]);
+    for &(tag, vr, value) in elements {
+        obj.put(str_el(tag, vr, value));
+    }
+    let mut buf = Vec::new();
+    with_meta(obj, sop_class, sop).write_all(&mut buf).expect("encode instance");
+    buf
+}
+
+/// `2.25.<n>` with `n` from SHA-256 of the seed and `label`.
+fn uid(seed: u64, label: &str) -> String {
+    let digest = Sha256::new().chain_update(seed.to_le_bytes()).chain_update(label).finalize();
+    let n = u128::from_be_bytes(digest[..16].try_into().expect("16 bytes"));
+    format!("2.25.{}", n)
+}
+
+/// SplitMix64: small, seedable and good enough for shuffling test data.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Up to `k` distinct values below `n`, in the order drawn.
+    fn pick(&mut self, n: u64, k: u64) -> Vec<u64> {
+        let mut seen = BTreeSet::new();
+        let mut out = Vec::new();
+        while (out.len() as u64) < k.min(n) {
+            let v = self.next() % n;
+            if seen.insert(v) {
+                out.push(v);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicom::FailureKind;
+    use crate::fs_ops::collect_files;
+    use crate::sort::scan;
+
+    #[test]
+    fn test_generate_is_deterministic_and_covers_edge_cases() {
+        let opts = SynthOptions {
+            patients: 2,
+            studies: 2,
+            series: 3,
+            slices: 4,
+            rows: 8,
+            columns: 8,
+            oblique_degrees: 20.0,
+            missing_tags: 5,
+            duplicate_sops: 2,
+            multiframe: 1,
+            junk: 5,
+            ..Default::default()
+        };
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let summary = generate(a.path(), &opts).unwrap();
+        generate(b.path(), &opts).unwrap();
+
+        assert_eq!(summary.instances.len(), 2 * 2 * (3 * 4 + 1) + 2);
+        assert_eq!(summary.series, 2 * 2 * 4);
+        for p in summary.instances.iter().chain(&summary.junk) {
+            let twin = b.path().join(p.file_name().unwrap());
+            assert_eq!(fs::read(p).unwrap(), fs::read(twin).unwrap());
+        }
+
+        let outcome = scan(&collect_files(a.path(), false).unwrap());
+        assert_eq!(outcome.metas.len(), summary.instances.len());
+        let mut kinds: Vec<_> = outcome.failures.iter().map(|f| f.kind).collect();
+        kinds.sort();
+        assert_eq!(kinds, [FailureKind::NotDicom, FailureKind::NotDicom, FailureKind::NotDicom, FailureKind::Truncated, FailureKind::UnsupportedTransferSyntax]);
+
+        let missing = |f: fn(&crate::dicom::DicomMeta) -> bool| outcome.metas.iter().filter(|m| f(m)).count();
+        assert!(missing(|m| m.instance_number.is_none()) >= 1);
+        assert!(missing(|m| m.patient_id.is_none()) >= 1);
+        let oblique = outcome.metas.iter().filter_map(|m| m.image_orientation_patient).filter(|iop| iop[4] != 1.0).count();
+        assert!(oblique > 0);
+        let sops: BTreeSet<_> = outcome.metas.iter().filter_map(|m| m.sop_uid.clone()).collect();
+        assert_eq!(sops.len(), outcome.metas.len() - 2);
+    }
+}
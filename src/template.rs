@@ -0,0 +1,478 @@
+//! User-defined output path templates.
+//!
+//! A template is a `/`-separated list of segments; the last segment is the
+//! file name. Each segment mixes literal text with `{Key[:format][|default]}`
+//! placeholders, e.g.
+//!
+//! ```text
+//! {PatientID}/{StudyDate:%Y-%m}/{Modality}_{SeriesNumber:03}/{InstanceNumber:04}.dcm
+//! ```
+//!
+//! `Key` is a DICOM keyword (`Modality`), a tag (`(0018,0050)` or `00180050`),
+//! or one of the pseudo-keys `Index` (1-based position within the sorted
+//! series or volume), `Stack` (sub-stack label when a series is split) and
+//! `Volume` (1-based volume number when repeated positions are split).
+//! Formats are either a zero-padded width (`03`) or a date pattern using
+//! `%Y`, `%y`, `%m`, `%d`. Every expanded segment goes through
+//! [`sanitize_component`].
+
+use crate::dicom::DicomMeta;
+use crate::sanitize::sanitize_component;
+use anyhow::{anyhow, bail, Result};
+use dicom_core::dictionary::{DataDictionary, DataDictionaryEntry, VirtualVr};
+use dicom_core::VR;
+use dicom_dictionary_std::tags;
+use dicom_object::{StandardDataDictionary, Tag};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    source: String,
+    segments: Vec<Vec<Part>>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    key: Key,
+    format: Format,
+    default: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Index,
+    Stack,
+    Volume,
+    PatientId,
+    PatientName,
+    StudyUid,
+    SeriesUid,
+    SopUid,
+    Modality,
+    StudyDate,
+    SeriesNumber,
+    InstanceNumber,
+    StudyDescription,
+    SeriesDescription,
+    Tag(Tag),
+}
+
+/// Where an instance sits within its (possibly split) series.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Placement<'a> {
+    /// 0-based position within the sorted stack or volume.
+    pub index: u32,
+    pub stack: Option<&'a str>,
+    /// 1-based volume number.
+    pub volume: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+enum Format {
+    Plain,
+    Pad(usize),
+    Date(String),
+}
+
+impl PathTemplate {
+    /// Parse and validate a template. Unknown keywords, malformed tags,
+    /// bad format specifiers and empty segments are rejected here so that
+    /// mistakes surface before any file is touched.
+    pub fn parse(source: &str) -> Result<Self> {
+        let trimmed = source.trim().trim_matches('/');
+        if trimmed.is_empty() {
+            bail!("template is empty");
+        }
+
+        let mut segments = Vec::new();
+        for (i, seg) in trimmed.split('/').enumerate() {
+            if seg.trim().is_empty() {
+                bail!("template segment {} is empty in {:?}", i + 1, source);
+            }
+            let parts = parse_segment(seg)
+                .map_err(|e| anyhow!("template segment {:?}: {}", seg, e))?;
+            segments.push(parts);
+        }
+
+        Ok(Self { source: source.to_string(), segments })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Raw tags that must be captured at scan time for this template to render.
+    pub fn extra_tags(&self) -> Vec<Tag> {
+        let mut out: Vec<Tag> = self
+            .fields()
+            .filter_map(|f| match f.key {
+                Key::Tag(t) => Some(t),
+                _ => None,
+            })
+            .collect();
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    /// True if the template references PHI-like fields: PatientName,
+    /// descriptions, the identifying study fields in [`PHI_TAGS`], any
+    /// Patient module tag other than PatientID, or any date or time
+    /// (DA, DT or TM), since HIPAA counts all dates as identifiers.
+    pub fn uses_phi(&self) -> bool {
+        self.fields().any(|f| match f.key {
+            Key::PatientName | Key::StudyDescription | Key::SeriesDescription | Key::StudyDate => true,
+            Key::Tag(t) => t.group() == 0x0010 || PHI_TAGS.contains(&t) || is_temporal(t),
+            _ => false,
+        })
+    }
+
+    /// Expand the template for one instance.
+    ///
+    /// If the series was split and the template has no `{Stack}` placeholder,
+    /// the stack label is appended to the last directory segment (or prefixed
+    /// to the file name for single-segment templates) so stacks don't collide.
+    pub fn render(&self, out_dir: &Path, m: &DicomMeta, at: &Placement) -> PathBuf {
+        let mut comps: Vec<String> = self
+            .segments
+            .iter()
+            .map(|seg| {
+                let mut s = String::new();
+                for part in seg {
+                    match part {
+                        Part::Literal(l) => s.push_str(l),
+                        Part::Field(f) => s.push_str(&f.expand(m, at)),
+                    }
+                }
+                sanitize_component(&s)
+            })
+            .collect();
+
+        if let Some(label) = at.stack {
+            if !self.fields().any(|f| f.key == Key::Stack) {
+                let n = comps.len();
+                if n >= 2 {
+                    comps[n - 2] = crate::sort::with_suffix(&comps[n - 2], label);
+                } else {
+                    comps[0] = sanitize_component(&format!("{}_{}", label, comps[0]));
+                }
+            }
+        }
+
+        let mut path = out_dir.to_path_buf();
+        path.extend(comps);
+        path
+    }
+
+    /// True if the template positions volumes itself via `{Volume}`.
+    pub fn places_volume(&self) -> bool {
+        self.fields().any(|f| f.key == Key::Volume)
+    }
+
+    fn fields(&self) -> impl Iterator<Item = &Field> {
+        self.segments.iter().flatten().filter_map(|p| match p {
+            Part::Field(f) => Some(f),
+            Part::Literal(_) => None,
+        })
+    }
+}
+
+impl std::str::FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl Field {
+    fn expand(&self, m: &DicomMeta, at: &Placement) -> String {
+        let raw = match self.key {
+            Key::Index => Some((at.index + 1).to_string()),
+            Key::Stack => at.stack.map(str::to_string),
+            Key::Volume => at.volume.map(|v| v.to_string()),
+            Key::PatientId => m.patient_id.clone(),
+            Key::PatientName => m.patient_name.clone(),
+            Key::StudyUid => m.study_uid.clone(),
+            Key::SeriesUid => m.series_uid.clone(),
+            Key::SopUid => m.sop_uid.clone(),
+            Key::Modality => m.modality.clone(),
+            Key::StudyDate => m.study_date.clone(),
+            Key::SeriesNumber => m.series_number.map(|x| x.to_string()),
+            Key::InstanceNumber => m.instance_number.map(|x| x.to_string()),
+            Key::StudyDescription => m.study_description.clone(),
+            Key::SeriesDescription => m.series_description.clone(),
+            Key::Tag(t) => m.extra_value(t).map(str::to_string),
+        };
+
+        raw.and_then(|v| self.format.apply(&v))
+            .or_else(|| self.default.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Format {
+    /// Returns None if the value cannot be formatted (e.g. not a date),
+    /// in which case the field default is used.
+    fn apply(&self, v: &str) -> Option<String> {
+        match self {
+            Format::Plain => Some(v.to_string()),
+            Format::Pad(width) => match v.trim().parse::<i64>() {
+                Ok(n) => Some(format!("{:0width$}", n, width = *width)),
+                Err(_) => Some(v.to_string()),
+            },
+            Format::Date(pattern) => {
+                let d = v.trim();
+                // Check bytes, not a str slice: byte 8 may fall inside a multi-byte character.
+                match d.as_bytes().get(..8) {
+                    Some(head) if head.iter().all(u8::is_ascii_digit) => {}
+                    _ => return None,
+                }
+                let (y, mo, day) = (&d[0..4], &d[4..6], &d[6..8]);
+                let mut out = String::new();
+                let mut chars = pattern.chars();
+                while let Some(c) = chars.next() {
+                    if c != '%' {
+                        out.push(c);
+                        continue;
+                    }
+                    match chars.next() {
+                        Some('Y') => out.push_str(y),
+                        Some('y') => out.push_str(&y[2..]),
+                        Some('m') => out.push_str(mo),
+                        Some('d') => out.push_str(day),
+                        Some('%') => out.push('%'),
+                        // Rejected at parse time.
+                        _ => {}
+                    }
+                }
+                Some(out)
+            }
+        }
+    }
+}
+
+fn parse_segment(seg: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = seg;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        if rest.as_bytes()[pos] == b'}' {
+            bail!("unmatched '}}'");
+        }
+        literal.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let end = after.find('}').ok_or_else(|| anyhow!("unclosed '{{'"))?;
+        let body = &after[..end];
+        if body.contains('{') {
+            bail!("nested '{{' in placeholder");
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(Part::Field(parse_field(body)?));
+        rest = &after[end + 1..];
+    }
+    if rest.contains('}') {
+        bail!("unmatched '}}'");
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+fn parse_field(body: &str) -> Result<Field> {
+    let (spec, default) = match body.split_once('|') {
+        Some((s, d)) => (s, Some(d.to_string())),
+        None => (body, None),
+    };
+    // A tag written as `(gggg,eeee)` has no ':' so splitting on the first one is safe.
+    let (key, fmt) = match spec.split_once(':') {
+        Some((k, f)) => (k.trim(), Some(f)),
+        None => (spec.trim(), None),
+    };
+    if key.is_empty() {
+        bail!("empty placeholder");
+    }
+
+    Ok(Field {
+        key: parse_key(key)?,
+        format: fmt.map(parse_format).transpose()?.unwrap_or(Format::Plain),
+        default,
+    })
+}
+
+/// Study-level tags outside the Patient module that identify the patient or
+/// staff, or can be looked up in the RIS.
+const PHI_TAGS: &[Tag] = &[
+    tags::ACCESSION_NUMBER,
+    tags::REFERRING_PHYSICIAN_NAME,
+    tags::INSTITUTION_NAME,
+    tags::OPERATORS_NAME,
+    tags::STUDY_ID,
+];
+
+/// Whether the standard dictionary gives `tag` a DA, DT or TM VR.
+fn is_temporal(tag: Tag) -> bool {
+    let vr = StandardDataDictionary.by_tag(tag).map(|e| e.vr());
+    matches!(vr, Some(VirtualVr::Exact(VR::DA | VR::DT | VR::TM)))
+}
+
+fn parse_key(key: &str) -> Result<Key> {
+    match key {
+        "Index" => return Ok(Key::Index),
+        "Stack" => return Ok(Key::Stack),
+        "Volume" => return Ok(Key::Volume),
+        _ => {}
+    }
+    let tag = StandardDataDictionary
+        .parse_tag(key)
+        .ok_or_else(|| anyhow!("unknown DICOM keyword or tag {:?}", key))?;
+
+    Ok(match tag {
+        tags::PATIENT_ID => Key::PatientId,
+        tags::PATIENT_NAME => Key::PatientName,
+        tags::STUDY_INSTANCE_UID => Key::StudyUid,
+        tags::SERIES_INSTANCE_UID => Key::SeriesUid,
+        tags::SOP_INSTANCE_UID => Key::SopUid,
+        tags::MODALITY => Key::Modality,
+        tags::STUDY_DATE => Key::StudyDate,
+        tags::SERIES_NUMBER => Key::SeriesNumber,
+        tags::INSTANCE_NUMBER => Key::InstanceNumber,
+        tags::STUDY_DESCRIPTION => Key::StudyDescription,
+        tags::SERIES_DESCRIPTION => Key::SeriesDescription,
+        tags::PIXEL_DATA => bail!("PixelData cannot be used in a template"),
+        other => Key::Tag(other),
+    })
+}
+
+fn parse_format(fmt: &str) -> Result<Format> {
+    if fmt.contains('%') {
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' && !matches!(chars.next(), Some('Y' | 'y' | 'm' | 'd' | '%')) {
+                bail!("unsupported date directive in {:?} (use %Y %y %m %d)", fmt);
+            }
+        }
+        return Ok(Format::Date(fmt.to_string()));
+    }
+    if !fmt.is_empty() && fmt.bytes().all(|b| b.is_ascii_digit()) {
+        let width: usize = fmt.parse()?;
+        if width > 32 {
+            bail!("pad width {} is too large", width);
+        }
+        return Ok(Format::Pad(width));
+    }
+    bail!("unsupported format specifier {:?}", fmt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn meta() -> DicomMeta {
+        DicomMeta {
+            path: PathBuf::from("in/IM0001"),
+            patient_id: Some("PAT01".into()),
+            patient_name: Some("DOE^JOHN".into()),
+            study_uid: Some("1.2.3".into()),
+            series_uid: Some("1.2.3.4".into()),
+            sop_uid: Some("1.2.3.4.5".into()),
+            modality: Some("MR".into()),
+            study_date: Some("20240315".into()),
+            series_number: Some(7),
+            instance_number: Some(12),
+            study_description: None,
+            series_description: Some("T1 MPRAGE".into()),
+            extra: BTreeMap::from([("(0018,0050)".to_string(), "1.5".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render_full_template() {
+        let t = PathTemplate::parse(
+            "{PatientID}/{StudyDate:%Y-%m}/{Modality}_{SeriesNumber:03}/{InstanceNumber:04}.dcm",
+        )
+        .unwrap();
+        let p = t.render(Path::new("out"), &meta(), &Placement::default());
+        assert_eq!(p, Path::new("out/PAT01/2024-03/MR_007/0012.dcm"));
+    }
+
+    #[test]
+    fn test_defaults_and_raw_tags() {
+        let t = PathTemplate::parse("{StudyDescription|NODESC}/{(0018,0050)}_{AccessionNumber|NA}/{Index:05}").unwrap();
+        assert_eq!(t.extra_tags(), vec![tags::ACCESSION_NUMBER, tags::SLICE_THICKNESS]);
+        let p = t.render(Path::new("out"), &meta(), &Placement { index: 2, ..Default::default() });
+        assert_eq!(p, Path::new("out/NODESC/1.5_NA/00003"));
+    }
+
+    #[test]
+    fn test_missing_without_default_is_unknown() {
+        let t = PathTemplate::parse("{AccessionNumber}/{SOPInstanceUID}.dcm").unwrap();
+        let p = t.render(Path::new("out"), &meta(), &Placement::default());
+        assert_eq!(p, Path::new("out/UNKNOWN/1.2.3.4.5.dcm"));
+    }
+
+    #[test]
+    fn test_segments_are_sanitized() {
+        let t = PathTemplate::parse("{SeriesDescription}/{Index}.dcm").unwrap();
+        let p = t.render(Path::new("out"), &meta(), &Placement::default());
+        assert_eq!(p, Path::new("out/T1_MPRAGE/1.dcm"));
+    }
+
+    #[test]
+    fn test_stack_label() {
+        let t = PathTemplate::parse("{Modality}/{SeriesNumber}/{Index}.dcm").unwrap();
+        let p = t.render(Path::new("out"), &meta(), &Placement { stack: Some("s02_SAG"), ..Default::default() });
+        assert_eq!(p, Path::new("out/MR/7_s02_SAG/1.dcm"));
+
+        let t = PathTemplate::parse("{SeriesNumber}{Stack|}/{Index}.dcm").unwrap();
+        assert_eq!(t.render(Path::new("out"), &meta(), &Placement { stack: Some("s01"), ..Default::default() }), Path::new("out/7s01/1.dcm"));
+        assert_eq!(t.render(Path::new("out"), &meta(), &Placement::default()), Path::new("out/7/1.dcm"));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(PathTemplate::parse("").is_err());
+        assert!(PathTemplate::parse("{PatientId}").is_err());
+        assert!(PathTemplate::parse("{PatientID").is_err());
+        assert!(PathTemplate::parse("PatientID}").is_err());
+        assert!(PathTemplate::parse("{PatientID}//{Index}").is_err());
+        assert!(PathTemplate::parse("{StudyDate:%B}").is_err());
+        assert!(PathTemplate::parse("{SeriesNumber:x}").is_err());
+        assert!(PathTemplate::parse("{}").is_err());
+    }
+
+    #[test]
+    fn test_phi_detection() {
+        assert!(!PathTemplate::parse("{PatientID}/{Modality}").unwrap().uses_phi());
+        assert!(PathTemplate::parse("{PatientName}").unwrap().uses_phi());
+        assert!(PathTemplate::parse("{PatientBirthDate}").unwrap().uses_phi());
+        for key in ["AccessionNumber", "ReferringPhysicianName", "InstitutionName", "OperatorsName", "StudyID"] {
+            let t = PathTemplate::parse(&format!("{{PatientID}}/{{{}}}", key)).unwrap();
+            assert!(t.uses_phi(), "{} should count as PHI", key);
+        }
+        for key in ["StudyDate", "SeriesDate", "AcquisitionDateTime", "StudyTime", "(0008,0022)"] {
+            let t = PathTemplate::parse(&format!("{{{}}}/{{SeriesNumber}}", key)).unwrap();
+            assert!(t.uses_phi(), "{} should count as PHI", key);
+        }
+        assert!(!PathTemplate::parse("{Modality}/{SeriesNumber}/{SliceThickness}").unwrap().uses_phi());
+
+        // A multi-byte character straddling byte 8 is not a date, and must not panic.
+        let date = Format::Date("%Y".to_string());
+        assert_eq!(date.apply("2024031\u{e9}"), None);
+        assert_eq!(date.apply("20240315"), Some("2024".to_string()));
+    }
+}
@@ -1,6 +1,8 @@
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Mode {
     Copy,
     Move,
@@ -13,11 +15,35 @@ pub enum Layout {
     StudySeries,
     SeriesOnly,
     Flat,
+    /// PS3.11 media names (`Pxxxxxxx/Sxxxxxxx/Exxxxxxx/I0000001`) that a DICOMDIR can reference
+    Media,
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SortBy {
     Auto,
     Instance,
     Geometry,
 }
+
+#[derive(Copy, Clone, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Skip if an identical file already exists, otherwise rename
+    SkipIdentical,
+    Overwrite,
+    Rename,
+    Error,
+}
+
+/// How detected 4D volumes are laid out on disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum VolumeLayout {
+    /// Do not detect volumes
+    None,
+    /// One `volNNNN` subfolder per volume
+    Folders,
+    /// `vNNNN_` filename prefix per volume
+    Filenames,
+}
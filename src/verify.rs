@@ -0,0 +1,179 @@
+//! `dcmsort verify`: check that a sorted output holds every input instance
+//! exactly once, byte for byte.
+//!
+//! Instances are matched by SOPInstanceUID and compared by SHA-256, so this
+//! only makes sense for outputs written without `--anonymize`. Either side may
+//! include archive members.
+
+use crate::archive;
+use crate::dicom::DicomMeta;
+use crate::fs_ops::hash_file;
+use crate::sort::Plan;
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// No output instance has the SOPInstanceUID.
+    Missing,
+    /// More than one output instance has it.
+    Duplicated,
+    /// Exactly one has it, with different bytes.
+    Mismatch,
+}
+
+/// An input instance that is not in the output exactly once.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyIssue {
+    pub src: PathBuf,
+    pub sop_uid: String,
+    pub problem: Problem,
+    /// Output instances with the same SOPInstanceUID.
+    pub found: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Verification {
+    /// Input instances compared.
+    pub checked: usize,
+    pub issues: Vec<VerifyIssue>,
+    /// Input instances without a SOPInstanceUID, which cannot be matched.
+    pub unidentified: Vec<PathBuf>,
+    /// Output instances whose SOPInstanceUID is not in the input.
+    pub extra: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty() && self.unidentified.is_empty()
+    }
+}
+
+/// SHA-256 of every instance in `metas`; archive members are read one pass per archive.
+fn hashes(metas: &[&DicomMeta]) -> Result<HashMap<PathBuf, String>> {
+    let mut out = HashMap::new();
+    let mut members = Vec::new();
+    for m in metas {
+        match archive::split_member(&m.path) {
+            Some(_) => members.push(Plan::new(m.path.clone(), PathBuf::new(), DicomMeta::clone(m))),
+            None => {
+                out.insert(m.path.clone(), hash_file(&m.path)?);
+            }
+        }
+    }
+    archive::for_each_member(&members, |p, entry| {
+        out.insert(p.src.clone(), sha256(entry)?);
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+fn sha256(r: &mut dyn Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(r, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Check that each of `sources` appears exactly once among `outputs` with identical content.
+pub fn verify(sources: &[DicomMeta], outputs: &[DicomMeta]) -> Result<Verification> {
+    let mut by_sop: HashMap<&str, Vec<&DicomMeta>> = HashMap::new();
+    for m in outputs {
+        if let Some(sop) = m.sop_uid.as_deref() {
+            by_sop.entry(sop).or_default().push(m);
+        }
+    }
+
+    let mut result = Verification::default();
+    // Only single matches are compared, so only those need hashing.
+    let mut pairs = Vec::new();
+    for s in sources {
+        let Some(sop) = s.sop_uid.as_deref() else {
+            result.unidentified.push(s.path.clone());
+            continue;
+        };
+        result.checked += 1;
+        let found = by_sop.get(sop).map(Vec::as_slice).unwrap_or_default();
+        let problem = match found.len() {
+            0 => Problem::Missing,
+            1 => {
+                pairs.push((s, found[0]));
+                continue;
+            }
+            _ => Problem::Duplicated,
+        };
+        result.issues.push(VerifyIssue {
+            src: s.path.clone(),
+            sop_uid: sop.to_string(),
+            problem,
+            found: found.iter().map(|m| m.path.clone()).collect(),
+        });
+    }
+
+    let wanted: Vec<&DicomMeta> = pairs.iter().flat_map(|(s, o)| [*s, *o]).collect();
+    let hashes = hashes(&wanted)?;
+    for (s, o) in pairs {
+        if hashes.get(&s.path) != hashes.get(&o.path) {
+            result.issues.push(VerifyIssue {
+                src: s.path.clone(),
+                sop_uid: s.sop_uid.clone().unwrap_or_default(),
+                problem: Problem::Mismatch,
+                found: vec![o.path.clone()],
+            });
+        }
+    }
+
+    let input_sops: HashSet<_> = sources.iter().filter_map(|m| m.sop_uid.as_deref()).collect();
+    result.extra = outputs
+        .iter()
+        .filter(|m| m.sop_uid.as_deref().is_none_or(|s| !input_sops.contains(s)))
+        .map(|m| m.path.clone())
+        .collect();
+    result.issues.sort_by(|a, b| a.src.cmp(&b.src));
+    result.extra.sort();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_ops::{collect_files, execute};
+    use crate::sink::CopySink;
+    use crate::sort::{plan_operations, scan, PlanOptions};
+    use crate::synth::{generate, SynthOptions};
+    use crate::types::OnConflict;
+    use std::fs;
+
+    #[test]
+    fn test_verify_finds_missing_duplicated_and_changed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opts = SynthOptions { patients: 1, series: 1, slices: 4, rows: 8, columns: 8, ..Default::default() };
+        generate(&tmp.path().join("in"), &opts).unwrap();
+        let sources = scan(&collect_files(&tmp.path().join("in"), false).unwrap()).metas;
+        let plans = plan_operations(&sources, &tmp.path().join("out"), &PlanOptions::default());
+        execute(plans, &CopySink, OnConflict::SkipIdentical, false, None, 1).unwrap();
+
+        let rescan = || scan(&collect_files(&tmp.path().join("out"), false).unwrap()).metas;
+        let v = verify(&sources, &rescan()).unwrap();
+        assert_eq!(v.checked, 4);
+        assert!(v.is_ok() && v.extra.is_empty());
+
+        let mut outputs: Vec<_> = rescan().into_iter().map(|m| m.path).collect();
+        outputs.sort();
+        fs::remove_file(&outputs[0]).unwrap();
+        fs::copy(&outputs[1], outputs[1].with_extension("copy")).unwrap();
+        let mut bytes = fs::read(&outputs[2]).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&outputs[2], bytes).unwrap();
+
+        let v = verify(&sources, &rescan()).unwrap();
+        let mut problems: Vec<_> = v.issues.iter().map(|i| i.problem).collect();
+        problems.sort_by_key(|p| *p as u8);
+        assert_eq!(problems, [Problem::Missing, Problem::Duplicated, Problem::Mismatch]);
+        assert!(!v.is_ok());
+    }
+}
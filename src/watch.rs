@@ -0,0 +1,346 @@
+//! Hot-folder sorting (`dcmsort watch`): sort files as they arrive in a folder.
+//!
+//! A [`notify`] watcher reports files created or written under the input.
+//! [`Batcher`] holds each one until it is *stable*: its size and modification
+//! time have not changed for the settle time. With a series quiet period,
+//! stable files are then held by series until no new file of that series has
+//! turned up for that long, so a series sent over several minutes is sorted in
+//! one batch. Files that are not DICOM are released as soon as they are stable.
+//!
+//! [`run`] hands every batch to the caller, which sorts it like a normal run.
+//! A batch that fails is retried, with those of its files still present, after
+//! a backoff that doubles up to a limit. Every attempt is appended to the batch
+//! log as one JSON line. Setting the stop flag ends the loop after the batch in
+//! progress. Files not sorted by then are picked up at the next start, since
+//! [`run`] begins with everything already in the input.
+
+use crate::dicomdir::read::is_dicomdir_name;
+use crate::fs_ops::collect_files;
+use crate::sort::series_key;
+use crate::source::{FileSource, MetaSource};
+use anyhow::{bail, Context, Result};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often pending files are checked and the stop flag is read.
+const TICK: Duration = Duration::from_millis(250);
+/// Wait before the first retry of a failed batch; it doubles with every failure.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+type Key = (String, String);
+
+/// How [`run`] waits for files and retries batches.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions<'a> {
+    /// How long a file's size and modification time must stay unchanged.
+    pub settle: Duration,
+    /// Hold stable files until their series has had no new file for this long.
+    pub quiet: Option<Duration>,
+    /// Longest wait before retrying a failed batch.
+    pub max_backoff: Duration,
+    /// Append one JSON line per batch attempt to this file.
+    pub batch_log: Option<&'a Path>,
+    pub follow_symlinks: bool,
+}
+
+/// What the caller did with a batch, for the batch log.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct BatchOutcome {
+    /// Instances placed in the output (or skipped by `--on-conflict`).
+    pub placed: usize,
+    /// Earlier instances renamed because their series grew.
+    pub renumbered: usize,
+    pub conflicts: usize,
+    /// Files that are not DICOM or could not be read.
+    pub rejected: usize,
+    /// Instances left out by `--filter`.
+    pub excluded: usize,
+}
+
+/// One line of the batch log: one attempt at one batch.
+#[derive(Debug, Serialize)]
+struct BatchRecord {
+    batch: u64,
+    attempt: u32,
+    /// Seconds since the Unix epoch.
+    started: u64,
+    seconds: f64,
+    files: usize,
+    #[serde(flatten)]
+    outcome: Option<BatchOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// What a file looked like when it was last seen changing.
+#[derive(Debug, PartialEq, Eq)]
+struct Look {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Look {
+    /// `None` once the file is gone.
+    fn of(path: &Path) -> Option<Self> {
+        let md = fs::metadata(path).ok().filter(|md| md.is_file())?;
+        Some(Self { len: md.len(), modified: md.modified().ok() })
+    }
+}
+
+struct Growing {
+    look: Look,
+    since: Instant,
+}
+
+/// Stable files of one series, waiting for it to go quiet.
+struct Held {
+    files: BTreeSet<PathBuf>,
+    last: Instant,
+}
+
+/// Files waiting to be stable, and stable files waiting for their series to go quiet.
+pub struct Batcher {
+    settle: Duration,
+    quiet: Option<Duration>,
+    growing: HashMap<PathBuf, Growing>,
+    held: HashMap<Key, Held>,
+}
+
+impl Batcher {
+    pub fn new(settle: Duration, quiet: Option<Duration>) -> Self {
+        Self { settle, quiet, growing: HashMap::new(), held: HashMap::new() }
+    }
+
+    /// Note that `path` changed at `now`; it has to settle again.
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        match Look::of(&path) {
+            Some(look) => {
+                self.growing.insert(path, Growing { look, since: now });
+            }
+            None => {
+                self.growing.remove(&path);
+            }
+        }
+    }
+
+    /// The files ready to be sorted at `now`, in name order. `series` reads the
+    /// series of a stable file, or `None` for one that is not DICOM.
+    pub fn ready(&mut self, now: Instant, series: impl Fn(&Path) -> Option<Key>) -> Vec<PathBuf> {
+        let settle = self.settle;
+        let mut stable = Vec::new();
+        self.growing.retain(|path, g| match Look::of(path) {
+            None => false,
+            Some(look) if look != g.look => {
+                *g = Growing { look, since: now };
+                true
+            }
+            Some(_) if now.duration_since(g.since) >= settle => {
+                stable.push(path.clone());
+                false
+            }
+            Some(_) => true,
+        });
+        stable.sort();
+        let Some(quiet) = self.quiet else {
+            return stable;
+        };
+
+        let mut out = Vec::new();
+        for path in stable {
+            match series(&path) {
+                Some(key) => {
+                    let held = self.held.entry(key).or_insert_with(|| Held { files: BTreeSet::new(), last: now });
+                    held.files.insert(path);
+                    held.last = now;
+                }
+                None => out.push(path),
+            }
+        }
+        let mut done: Vec<Key> = self.held.iter().filter(|(_, h)| now.duration_since(h.last) >= quiet).map(|(k, _)| k.clone()).collect();
+        done.sort();
+        for key in done {
+            let held = self.held.remove(&key).expect("listed above");
+            // A file written again meanwhile is held again once it is stable.
+            out.extend(held.files.into_iter().filter(|f| !self.growing.contains_key(f)));
+        }
+        out.sort();
+        out
+    }
+
+    /// Files seen but not released yet.
+    pub fn pending(&self) -> usize {
+        self.growing.len() + self.held.values().map(|h| h.files.len()).sum::<usize>()
+    }
+}
+
+/// Watch `root` until `stop` is set, calling `sort` with every batch of files
+/// that is ready. Only failing to watch, or to write the batch log, ends it early.
+pub fn run(root: &Path, opts: &WatchOptions, stop: &AtomicBool, mut sort: impl FnMut(Vec<PathBuf>) -> Result<BatchOutcome>) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("start file watcher")?;
+    watcher.watch(root, RecursiveMode::Recursive).with_context(|| format!("watch {}", root.display()))?;
+    let mut log = match opts.batch_log {
+        Some(path) => Some(File::options().create(true).append(true).open(path).with_context(|| format!("open batch log: {}", path.display()))?),
+        None => None,
+    };
+
+    let mut batcher = Batcher::new(opts.settle, opts.quiet);
+    // Left by an earlier run, or written while none was running.
+    let now = Instant::now();
+    let existing = collect_files(root, opts.follow_symlinks)?;
+    tracing::info!("Watching {} ({} files already there)", root.display(), existing.len());
+    for f in existing {
+        batcher.touch(f, now);
+    }
+
+    let series = |path: &Path| FileSource.read(path, &[]).ok().map(|m| series_key(&m));
+    let mut waiting: BTreeSet<PathBuf> = BTreeSet::new();
+    let (mut batch, mut attempt, mut retry_at) = (0u64, 0u32, None);
+    while !stop.load(Ordering::Relaxed) {
+        match rx.recv_timeout(TICK) {
+            Ok(event) => {
+                let now = Instant::now();
+                for event in std::iter::once(event).chain(rx.try_iter()) {
+                    changed(event, &mut batcher, opts.follow_symlinks, now);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("file watcher for {} stopped", root.display()),
+        }
+        let now = Instant::now();
+        waiting.extend(batcher.ready(now, series));
+        if retry_at.is_some_and(|t| now < t) {
+            continue;
+        }
+        // A failed attempt may have moved some files already.
+        waiting.retain(|p| p.exists());
+        if waiting.is_empty() {
+            continue;
+        }
+
+        if attempt == 0 {
+            batch += 1;
+        }
+        attempt += 1;
+        let files: Vec<PathBuf> = waiting.iter().cloned().collect();
+        match attempt {
+            1 => tracing::info!("Batch {}: sorting {} files", batch, files.len()),
+            n => tracing::info!("Batch {}: sorting {} files (attempt {})", batch, files.len(), n),
+        }
+        let record = BatchRecord {
+            batch,
+            attempt,
+            started: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            seconds: 0.0,
+            files: files.len(),
+            outcome: None,
+            error: None,
+        };
+        let clock = Instant::now();
+        let result = sort(files);
+        let record = BatchRecord {
+            seconds: clock.elapsed().as_secs_f64(),
+            outcome: result.as_ref().ok().copied(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            ..record
+        };
+        if let Some(f) = &mut log {
+            let line = serde_json::to_string(&record).context("serialize batch record")?;
+            writeln!(f, "{}", line).and_then(|_| f.flush()).context("write batch log")?;
+        }
+        match result {
+            Ok(_) => {
+                waiting.clear();
+                attempt = 0;
+                retry_at = None;
+            }
+            Err(e) => {
+                let wait = backoff(attempt, opts.max_backoff);
+                tracing::error!("Batch {} failed: {:#}; retrying in {:.0?}", batch, e, wait);
+                retry_at = Some(Instant::now() + wait);
+            }
+        }
+    }
+    let left = batcher.pending() + waiting.len();
+    tracing::info!("Stopped watching {}; {} files not sorted yet are picked up at the next start", root.display(), left);
+    Ok(())
+}
+
+/// Feed the files of a watcher event to `batcher`.
+fn changed(event: notify::Result<Event>, batcher: &mut Batcher, follow_symlinks: bool, now: Instant) {
+    let event = match event {
+        Ok(e) => e,
+        Err(e) => {
+            tracing::warn!("File watcher: {}", e);
+            return;
+        }
+    };
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+    for path in event.paths {
+        if path.is_dir() {
+            // A folder moved in whole, or files written before its watch was added.
+            for f in collect_files(&path, follow_symlinks).unwrap_or_default() {
+                batcher.touch(f, now);
+            }
+        } else if !path.file_name().is_some_and(is_dicomdir_name) {
+            batcher.touch(path, now);
+        }
+    }
+}
+
+/// The wait after the `attempt`th failure in a row.
+fn backoff(attempt: u32, max: Duration) -> Duration {
+    FIRST_BACKOFF.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_are_released_once_stable_and_their_series_is_quiet() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = |name: &str| {
+            let p = tmp.path().join(name);
+            fs::write(&p, b"first").unwrap();
+            p
+        };
+        let (a1, a2, junk) = (file("a1"), file("a2"), file("junk"));
+        // The series is the first letter of the name; "junk" is not DICOM.
+        let series = |p: &Path| {
+            let name = p.file_name().unwrap().to_string_lossy().into_owned();
+            (name != "junk").then(|| ("study".to_string(), name[..1].to_string()))
+        };
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        let mut b = Batcher::new(Duration::from_secs(2), Some(Duration::from_secs(5)));
+        b.touch(a1.clone(), t0);
+        b.touch(junk.clone(), t0);
+        assert!(b.ready(at(1), series).is_empty());
+        // a1 is still being written, so it settles again; junk is not DICOM and goes at once.
+        fs::write(&a1, b"first and more").unwrap();
+        assert_eq!(b.ready(at(2), series), [junk]);
+        b.touch(a2.clone(), at(3));
+        assert!(b.ready(at(4), series).is_empty());
+        // Both are stable now, and held until series "a" has been quiet for 5s.
+        assert!(b.ready(at(5), series).is_empty());
+        assert!(b.ready(at(9), series).is_empty());
+        assert_eq!(b.pending(), 2);
+        assert_eq!(b.ready(at(10), series), [a1, a2]);
+        assert_eq!(b.pending(), 0);
+
+        assert_eq!(backoff(1, Duration::from_secs(60)), Duration::from_secs(1));
+        assert_eq!(backoff(4, Duration::from_secs(60)), Duration::from_secs(8));
+        assert_eq!(backoff(30, Duration::from_secs(60)), Duration::from_secs(60));
+    }
+}
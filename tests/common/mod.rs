@@ -1,41 +1,25 @@
-use std::path::{Path, PathBuf};
-use std::fs;
-use std::io::Cursor;
-
-/// Downloads and sets up test DICOM data from dcm_qa_ct repository
-/// Source: https://github.com/neurolabusc/dcm_qa_ct
-/// License: BSD 2-Clause
-pub fn setup_test_data() -> PathBuf {
-    let target_dir = Path::new("target").join("test-data");
-    let data_dir = target_dir.join("dcm_qa_ct-master");
-
-    // Skip download if already exists
-    if data_dir.exists() {
-        println!("Test data already exists at: {}", data_dir.display());
-        return data_dir;
-    }
-
-    fs::create_dir_all(&target_dir).expect("Failed to create test data directory");
-
-    let url = "https://github.com/neurolabusc/dcm_qa_ct/archive/refs/heads/master.zip";
-    println!("Downloading test data from {}", url);
-
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .build()
-        .expect("Failed to build reqwest client");
-
-    let response = client.get(url)
-        .send()
-        .expect("Failed to download test data");
-    let content = response.bytes()
-        .expect("Failed to read response bytes");
-
-    let mut archive = zip::ZipArchive::new(Cursor::new(content))
-        .expect("Failed to open zip archive");
-    archive.extract(&target_dir)
-        .expect("Failed to extract zip archive");
-
-    println!("Test data extracted to: {}", data_dir.display());
-    data_dir
-}
\ No newline at end of file
+use dcmsort::synth::{self, SynthOptions, SynthSummary};
+use tempfile::TempDir;
+
+/// Writes a synthetic, shuffled CT dataset into a fresh temporary directory.
+/// Oblique and feet-first series, missing tags, duplicates, a multi-frame
+/// object per study and junk files are all included.
+pub fn setup_test_data() -> (TempDir, SynthSummary) {
+    let dir = tempfile::tempdir().expect("Failed to create test data directory");
+    let opts = SynthOptions {
+        patients: 2,
+        studies: 2,
+        series: 3,
+        slices: 12,
+        rows: 16,
+        columns: 16,
+        oblique_degrees: 25.0,
+        missing_tags: 5,
+        duplicate_sops: 3,
+        multiframe: 1,
+        junk: 5,
+        ..Default::default()
+    };
+    let summary = synth::generate(dir.path(), &opts).expect("Failed to generate test data");
+    (dir, summary)
+}
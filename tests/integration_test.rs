@@ -1,41 +1,46 @@
 mod common;
 
 use dcmsort::{sort, fs_ops};
+use std::collections::{BTreeMap, HashSet};
 
 #[test]
-fn test_sort_real_dicom_series() {
-    let data_dir = common::setup_test_data();
-    let _dicom_dir = data_dir.join("In"); // Adjust based on actual repo structure if needed
-    
-    // The zip might extract to dcm_qa_ct-master/In or just dcm_qa_ct-master depending on repo structure.
-    // Let's assume the repo root has the dicom files or a folder.
-    // checking dcm_qa_ct structure: usually has 'In' folder or the dicoms are in root.
-    // We will list files recursively so it should be fine.
-    
-    // Verify we can find some files
-    let files = fs_ops::collect_files(&data_dir, false).expect("Failed to collect files");
-    assert!(!files.is_empty(), "Should find some files in the downloaded dataset");
+fn test_sort_synthetic_series() {
+    let (data_dir, summary) = common::setup_test_data();
 
-    let metas = sort::scan(&files);
-    assert!(!metas.is_empty(), "Should find valid DICOM files");
+    let files = fs_ops::collect_files(data_dir.path(), false).expect("Failed to collect files");
+    assert_eq!(files.len(), summary.instances.len() + summary.junk.len());
 
-    let output_dir = data_dir.join("sorted_output");
-    // Use proper enums
-    let plan = sort::plan_operations(
-        &metas, 
-        &output_dir, 
-        dcmsort::types::Layout::SeriesOnly,
-        dcmsort::types::SortBy::Auto, 
-        false
-    );
-    
-    assert!(!plan.is_empty());
-    
-    // Verify some expected grouping
-    // This dataset (dcm_qa_ct) typically contains CT series. 
-    // We expect at least one series.
-    
-    let unique_series = metas.iter().map(|m| &m.series_uid).collect::<std::collections::HashSet<_>>();
-    println!("Found {} unique series", unique_series.len());
-    assert!(unique_series.len() >= 1);
+    let outcome = sort::scan(&files);
+    assert_eq!(outcome.metas.len(), summary.instances.len());
+    assert_eq!(outcome.failures.len(), summary.junk.len());
+
+    let output_dir = data_dir.path().join("sorted_output");
+    let opts = sort::PlanOptions {
+        layout: dcmsort::types::Layout::SeriesOnly,
+        sort_by: dcmsort::types::SortBy::Auto,
+        ..Default::default()
+    };
+    let plans = sort::plan_operations(&outcome.metas, &output_dir, &opts);
+    assert_eq!(plans.len(), outcome.metas.len());
+
+    // Series that kept their UID, plus instances that lost it, all get a folder.
+    let unique_series = outcome.metas.iter().map(|m| &m.series_uid).collect::<HashSet<_>>();
+    assert!(unique_series.len() > summary.series);
+
+    // Within every complete series, path order follows the slice normal.
+    let mut by_folder: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for p in &plans {
+        by_folder.entry(p.dst.parent().unwrap().to_path_buf()).or_default().push(p);
+    }
+    let mut checked = 0;
+    for stack in by_folder.values_mut() {
+        if stack.len() < 2 || stack.iter().any(|p| p.meta.geom_order().is_none()) {
+            continue;
+        }
+        stack.sort_by(|a, b| a.dst.cmp(&b.dst));
+        let order: Vec<f64> = stack.iter().map(|p| p.meta.geom_order().unwrap()).collect();
+        assert!(order.windows(2).all(|w| w[0] <= w[1]), "{:?}", order);
+        checked += 1;
+    }
+    assert!(checked >= 8, "only {} stacks checked", checked);
 }